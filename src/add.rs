@@ -0,0 +1,327 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::hash_object;
+use crate::ignore::{self, IgnoreRules};
+use crate::index::{Index, IndexEntry};
+use crate::objects::ObjectType;
+use crate::pathspec::Pathspec;
+
+// Never part of the work tree, wherever they are.
+const REPOSITORY_DIRS: [&str; 2] = ["not-git", ".git"];
+
+pub struct AddConfig {
+    pub pathspecs: Vec<String>,
+    // Stage removals too, and with no pathspec, the whole work tree.
+    pub all: bool,
+    // Only stage tracked files, including their removals, and with no pathspec, every
+    // tracked file.
+    pub update: bool,
+    // Record new files without staging their contents.
+    pub intent_to_add: bool,
+    // Work out what would be staged without writing anything.
+    pub dry_run: bool,
+}
+
+impl AddConfig {
+    pub fn new(pathspecs: Vec<String>) -> Self {
+        Self {
+            pathspecs,
+            all: false,
+            update: false,
+            intent_to_add: false,
+            dry_run: false,
+        }
+    }
+
+    pub fn with_all(mut self, all: bool) -> Self {
+        self.all = all;
+        self
+    }
+
+    pub fn with_update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    pub fn with_intent_to_add(mut self, intent_to_add: bool) -> Self {
+        self.intent_to_add = intent_to_add;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// A change to the index made by `add`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddChange {
+    Add(String),
+    Remove(String),
+}
+
+impl fmt::Display for AddChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddChange::Add(path) => write!(f, "add '{}'", path),
+            AddChange::Remove(path) => write!(f, "remove '{}'", path),
+        }
+    }
+}
+
+pub fn add_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let config = parse_add_config(args)?;
+    let dry_run = config.dry_run;
+    let changes = add(None, config)?;
+
+    if dry_run {
+        for change in changes {
+            println!("{}", change);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stage the files matching the pathspecs: new and changed files, and files that were
+/// removed if `all` or `update` is set, or if they're named. Untracked files that are
+/// ignored are left out, and naming one is an error. Nothing is staged if any pathspec
+/// doesn't match. Returns the changes made to the index, in path order.
+pub fn add(
+    base_path: Option<&PathBuf>,
+    config: AddConfig,
+) -> Result<Vec<AddChange>, anyhow::Error> {
+    let work_tree = base_path.cloned().unwrap_or_default();
+    let mut pathspecs: Vec<Pathspec> = config
+        .pathspecs
+        .iter()
+        .map(|pathspec| Pathspec::new(pathspec))
+        .collect();
+    if pathspecs.is_empty() {
+        if !config.all && !config.update {
+            return Err(anyhow::anyhow!("Nothing specified, nothing added."));
+        }
+        pathspecs.push(Pathspec::new("."));
+    }
+
+    let mut index = Index::read(base_path)?;
+
+    // The files that aren't ignored, unless only tracked files are updated, and the tracked
+    // files, whether they're still in the work tree or not.
+    let untracked = match config.update {
+        true => vec![],
+        false => list_files(base_path)?,
+    };
+    let mut matched = vec![false; pathspecs.len()];
+    let mut paths = BTreeSet::new();
+    for path in untracked
+        .iter()
+        .chain(index.entries.iter().map(|entry| &entry.path))
+    {
+        for (pathspec, matched) in pathspecs.iter().zip(&mut matched) {
+            if pathspec.matches(path) {
+                *matched = true;
+                paths.insert(path.clone());
+            }
+        }
+    }
+
+    for (pathspec, _) in pathspecs.iter().zip(&matched).filter(|(_, &m)| !m) {
+        check_unmatched(base_path, pathspec, config.update)?;
+    }
+
+    let mut changes = vec![];
+    for path in paths {
+        let metadata = fs::symlink_metadata(work_tree.join(&path))
+            .ok()
+            .filter(|metadata| !metadata.is_dir());
+        let tracked = index.get(&path).cloned();
+
+        let Some(metadata) = metadata else {
+            // Removals are only staged when asked for, or when the file itself is named.
+            let named = pathspecs.iter().any(|pathspec| {
+                !pathspec.is_glob()
+                    && pathspec.matches(&path)
+                    && fs::symlink_metadata(work_tree.join(&pathspec.pattern)).is_err()
+            });
+            if tracked.is_some() && (config.all || config.update || named) {
+                index.remove(&path);
+                changes.push(AddChange::Remove(path));
+            }
+            continue;
+        };
+
+        if config.intent_to_add {
+            if tracked.is_none() {
+                index.add(IndexEntry::intent_to_add(&path, &metadata)?);
+                changes.push(AddChange::Add(path));
+            }
+            continue;
+        }
+
+        // A file whose stat data hasn't changed doesn't need to be hashed again.
+        if let Some(tracked) = &tracked {
+            if !tracked.is_intent_to_add() && tracked.matches_stat(&metadata) {
+                continue;
+            }
+        }
+
+        let entry = stage_file(base_path, &path, config.dry_run)?;
+        let changed = tracked.is_none_or(|tracked| {
+            tracked.is_intent_to_add() || tracked.hash != entry.hash || tracked.mode != entry.mode
+        });
+        index.add(entry);
+        if changed {
+            changes.push(AddChange::Add(path));
+        }
+    }
+
+    if !config.dry_run {
+        index.write(base_path)?;
+    }
+    Ok(changes)
+}
+
+/// Hash a file in the work tree, returning its index entry. Its blob is written with it
+/// unless `dry_run` is set. A symlink's blob is the path it points to.
+pub fn stage_file(
+    base_path: Option<&PathBuf>,
+    path: &str,
+    dry_run: bool,
+) -> Result<IndexEntry, anyhow::Error> {
+    let file = base_path.cloned().unwrap_or_default().join(path);
+    let metadata = fs::symlink_metadata(&file).context(format!("Reading {:?}", file))?;
+    let contents = match metadata.file_type().is_symlink() {
+        true => fs::read_link(&file)?.into_os_string().into_vec(),
+        false => fs::read(&file).context(format!("Reading {:?}", file))?,
+    };
+
+    let hash = match dry_run {
+        true => hash_object::hash_contents(&ObjectType::Blob, &contents)?,
+        false => hash_object::hash_and_write_object(base_path, &ObjectType::Blob, &contents)?,
+    };
+    Ok(IndexEntry::new(path, hash, &metadata))
+}
+
+/// The files in the work tree that aren't ignored, sorted. The directories that are ignored
+/// aren't looked into at all.
+pub fn list_files(base_path: Option<&PathBuf>) -> Result<Vec<String>, anyhow::Error> {
+    // An empty path can't be read as a directory.
+    let work_tree = base_path.cloned().unwrap_or_else(|| PathBuf::from("."));
+    let mut rules = IgnoreRules::load(base_path)?;
+    let mut files = vec![];
+    collect_files(&work_tree, "", &mut rules, &mut files)?;
+
+    files.sort();
+    Ok(files)
+}
+
+fn collect_files(
+    work_tree: &Path,
+    directory: &str,
+    rules: &mut IgnoreRules,
+    files: &mut Vec<String>,
+) -> Result<(), anyhow::Error> {
+    rules.push_directory(work_tree, directory)?;
+    for entry in fs::read_dir(work_tree.join(directory))? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("File {:?} name cannot be converted to utf-8", name))?;
+        if REPOSITORY_DIRS.contains(&name.as_str()) {
+            continue;
+        }
+
+        let path = match directory {
+            "" => name,
+            directory => format!("{}/{}", directory, name),
+        };
+        let is_directory = entry.file_type()?.is_dir();
+        if rules.is_ignored(&path, is_directory) {
+            continue;
+        }
+
+        match is_directory {
+            true => collect_files(work_tree, &path, rules, files)?,
+            false => files.push(path),
+        }
+    }
+    rules.pop_directory(directory);
+
+    Ok(())
+}
+
+// A pathspec that matched nothing is an error, unless it's a directory with nothing to add
+// in it. Like git, naming an ignored file gets its own error, as it's likely a mistake.
+fn check_unmatched(
+    base_path: Option<&PathBuf>,
+    pathspec: &Pathspec,
+    update: bool,
+) -> Result<(), anyhow::Error> {
+    let work_tree = base_path.cloned().unwrap_or_default();
+    if pathspec.pattern.is_empty() {
+        return Ok(());
+    }
+    if pathspec.is_glob() {
+        return Err(no_match(pathspec));
+    }
+
+    let Ok(metadata) = fs::symlink_metadata(work_tree.join(&pathspec.pattern)) else {
+        return Err(no_match(pathspec));
+    };
+    if !update && ignore::is_path_ignored(base_path, &pathspec.pattern, metadata.is_dir())? {
+        return Err(anyhow::anyhow!(
+            "The following paths are ignored by one of your .gitignore files:\n{}",
+            pathspec.pattern
+        ));
+    }
+    match update || !metadata.is_dir() {
+        true => Err(no_match(pathspec)),
+        false => Ok(()),
+    }
+}
+
+fn no_match(pathspec: &Pathspec) -> anyhow::Error {
+    anyhow::anyhow!("pathspec '{}' did not match any files", pathspec.pattern)
+}
+
+fn parse_add_config(args: &[String]) -> Result<AddConfig, anyhow::Error> {
+    let usage = || anyhow::anyhow!("Usage: add [-A | -u] [-N] [-n] [<pathspec>...]");
+
+    let mut all = false;
+    let mut update = false;
+    let mut intent_to_add = false;
+    let mut dry_run = false;
+    let mut pathspecs = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "-A" | "--all" => all = true,
+            "-u" | "--update" => update = true,
+            "-N" | "--intent-to-add" => intent_to_add = true,
+            "-n" | "--dry-run" => dry_run = true,
+            arg if arg.starts_with('-') => return Err(usage()),
+            arg => pathspecs.push(arg.to_string()),
+        }
+    }
+
+    if all && update {
+        return Err(anyhow::anyhow!("-A and -u are mutually incompatible"));
+    }
+    if pathspecs.is_empty() && !all && !update {
+        return Err(usage());
+    }
+
+    Ok(AddConfig::new(pathspecs)
+        .with_all(all)
+        .with_update(update)
+        .with_intent_to_add(intent_to_add)
+        .with_dry_run(dry_run))
+}
@@ -2,8 +2,8 @@ use std::fs;
 use std::path::PathBuf;
 
 use crate::objects::ObjectHash;
-use crate::update_refs;
 use crate::utils::get_head_ref;
+use crate::{refs, update_refs};
 
 pub enum BranchConfig {
     List(bool),
@@ -71,7 +71,7 @@ pub fn delete_branch(
 
 // TODO: Does this functionality need to be revisited?
 pub fn create_branch(base_path: Option<&PathBuf>, branch_name: &str) -> Result<(), anyhow::Error> {
-    let path: PathBuf = ["not-git", "refs", "heads", &branch_name].iter().collect();
+    let path: PathBuf = ["not-git", "refs", "heads", branch_name].iter().collect();
     let path = match base_path {
         Some(base_path) => base_path.join(path),
         None => path,
@@ -119,7 +119,7 @@ fn print_branches(branches: ListBranchOptions) -> Result<(), anyhow::Error> {
 
 pub fn list_branches(
     base_path: Option<&PathBuf>,
-    _list_all_branches: bool,
+    list_all_branches: bool,
 ) -> Result<ListBranchOptions, anyhow::Error> {
     // TODO: Handle tags
 
@@ -129,9 +129,12 @@ pub fn list_branches(
         None => head_path,
     };
 
-    let branches = collect_branches(vec![], head_path)?;
+    let mut branches = collect_branches(vec![], head_path)?;
+    branches.sort();
 
-    // TODO: List all branches if -a tag - decode packed-refs
+    if list_all_branches {
+        branches.extend(list_remote_branches(base_path)?);
+    }
 
     let head_ref = get_head_ref(base_path)?;
 
@@ -139,6 +142,37 @@ pub fn list_branches(
     Ok(branch_options)
 }
 
+/// Remote-tracking branches are shown as `remotes/origin/main`, and a remote's HEAD
+/// as `remotes/origin/HEAD -> origin/main`.
+fn list_remote_branches(base_path: Option<&PathBuf>) -> Result<Vec<String>, anyhow::Error> {
+    let mut branches = vec![];
+    let mut remotes: Vec<String> = vec![];
+
+    for (name, _) in refs::list_refs(base_path, "refs/remotes/")? {
+        let short_name = refs::short_name(&name);
+        if let Some((remote, _)) = short_name.split_once('/') {
+            if !remotes.iter().any(|r| r == remote) {
+                remotes.push(remote.to_string());
+            }
+        }
+        branches.push(format!("remotes/{}", short_name));
+    }
+
+    for remote in remotes {
+        let head = format!("refs/remotes/{}/HEAD", remote);
+        if let Some(target) = refs::read_symbolic_ref(base_path, &head)? {
+            branches.push(format!(
+                "remotes/{}/HEAD -> {}",
+                remote,
+                refs::short_name(&target)
+            ));
+        }
+    }
+
+    branches.sort();
+    Ok(branches)
+}
+
 fn collect_branches(
     preceding_dirs: Vec<String>,
     path: PathBuf,
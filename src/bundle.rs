@@ -0,0 +1,595 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::fetch;
+use crate::history;
+use crate::objects::{ObjectFile, ObjectHash};
+use crate::packfile::{self, PackChecksum, PackSpool};
+use crate::progress::{self, Progress};
+use crate::protocol::{
+    FetchOptions, GitRef, ObjectFilter, RefUpdateCommand, ReportStatus, ShallowInfo,
+};
+use crate::refs;
+use crate::transport::Transport;
+
+// A bundle is a packfile with a header listing the refs it contains and the commits it
+// needs the repository it's unbundled into to have already.
+// CF https://git-scm.com/docs/gitformat-bundle
+const V2_SIGNATURE: &str = "# v2 git bundle";
+const V3_SIGNATURE: &str = "# v3 git bundle";
+
+const FILE_URL_PREFIX: &str = "file://";
+
+pub enum BundleConfig {
+    // bundle create [--version=<version>] <file> <rev-list-args>...
+    Create {
+        file: String,
+        version: u32,
+        revisions: Vec<String>,
+    },
+    Verify(String),
+    // bundle list-heads <file> [<refname>...]
+    ListHeads(String, Vec<String>),
+    Unbundle(String),
+}
+
+/// A bundle file, with its header read. The packfile is only read when it's needed.
+#[derive(Debug)]
+pub struct Bundle {
+    pub path: PathBuf,
+    pub version: u32,
+    // Only v3 bundles can say which objects their packfile leaves out.
+    pub filter: Option<ObjectFilter>,
+    pub prerequisites: Vec<ObjectHash>,
+    pub refs: Vec<(String, ObjectHash)>,
+    // Where the packfile starts.
+    pack_offset: u64,
+}
+
+pub fn bundle_command(args: &[String]) -> Result<(), anyhow::Error> {
+    match parse_bundle_config(args)? {
+        BundleConfig::Create {
+            file,
+            version,
+            revisions,
+        } => {
+            create_bundle(None, Path::new(&file), &revisions, version)?;
+            Ok(())
+        }
+        BundleConfig::Verify(file) => {
+            let bundle = Bundle::open(Path::new(&file))?;
+            bundle.verify(None)?;
+            print_verified(&bundle, &file);
+            Ok(())
+        }
+        BundleConfig::ListHeads(file, names) => {
+            let bundle = Bundle::open(Path::new(&file))?;
+            print_heads(&bundle, &names);
+            Ok(())
+        }
+        BundleConfig::Unbundle(file) => {
+            let bundle = Bundle::open(Path::new(&file))?;
+            bundle.unbundle(None, progress::stderr().as_mut())?;
+            print_heads(&bundle, &[]);
+            Ok(())
+        }
+    }
+}
+
+impl Bundle {
+    /// Open the bundle a URL points to if it's a `file://` URL or a path of a bundle file.
+    /// Anything else, including a directory, isn't a bundle.
+    pub fn open_url(url: &str) -> Result<Option<Self>, anyhow::Error> {
+        let path = Path::new(url.strip_prefix(FILE_URL_PREFIX).unwrap_or(url));
+        if !path.is_file() || !is_bundle(path)? {
+            return Ok(None);
+        }
+
+        Bundle::open(path).map(Some)
+    }
+
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::open(path).context(format!("Could not open '{}'", path.display()))?;
+        let mut reader = BufReader::new(file);
+
+        let not_a_bundle = || {
+            anyhow::anyhow!(
+                "'{}' does not look like a v2 or v3 bundle file",
+                path.display()
+            )
+        };
+        let mut pack_offset = 0;
+        let mut read_line = |reader: &mut BufReader<File>| -> Result<String, anyhow::Error> {
+            let mut line = String::new();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                return Err(not_a_bundle());
+            }
+            pack_offset += read as u64;
+            Ok(line.trim_end_matches('\n').to_string())
+        };
+
+        let version = match read_line(&mut reader)?.as_str() {
+            V2_SIGNATURE => 2,
+            V3_SIGNATURE => 3,
+            _ => return Err(not_a_bundle()),
+        };
+
+        let mut filter = None;
+        let mut prerequisites = vec![];
+        let mut refs = vec![];
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+
+            // Capabilities come first, and only in v3 bundles.
+            if let Some(capability) = line.strip_prefix('@') {
+                if version < 3 || !prerequisites.is_empty() || !refs.is_empty() {
+                    return Err(not_a_bundle());
+                }
+                match capability.split_once('=') {
+                    Some(("object-format", "sha1")) => {}
+                    Some(("object-format", format)) => {
+                        return Err(anyhow::anyhow!("Unsupported object format {}", format))
+                    }
+                    Some(("filter", spec)) => filter = Some(spec.parse()?),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Unknown bundle capability '{}'",
+                            capability
+                        ))
+                    }
+                }
+                continue;
+            }
+
+            // A prerequisite can be followed by a comment, usually the commit's subject.
+            if let Some(prerequisite) = line.strip_prefix('-') {
+                let hash = prerequisite.split(' ').next().unwrap_or_default();
+                prerequisites.push(ObjectHash::new(hash).map_err(|_| not_a_bundle())?);
+                continue;
+            }
+
+            let (hash, name) = line.split_once(' ').ok_or_else(not_a_bundle)?;
+            refs.push((
+                name.to_string(),
+                ObjectHash::new(hash).map_err(|_| not_a_bundle())?,
+            ));
+        }
+
+        // The path is kept absolute, like a local repository's, to be recorded as a remote.
+        Ok(Bundle {
+            path: fs::canonicalize(path)?,
+            version,
+            filter,
+            prerequisites,
+            refs,
+            pack_offset,
+        })
+    }
+
+    /// The packfile that follows the header.
+    pub fn pack_reader(&self) -> Result<impl Read, anyhow::Error> {
+        let mut file =
+            File::open(&self.path).context(format!("Could not open '{}'", self.path.display()))?;
+        file.seek(SeekFrom::Start(self.pack_offset))?;
+        Ok(BufReader::new(file))
+    }
+
+    /// The prerequisites the repository doesn't have.
+    pub fn missing_prerequisites(&self, base_path: Option<&PathBuf>) -> Vec<&ObjectHash> {
+        self.prerequisites
+            .iter()
+            .filter(|hash| !ObjectFile::exists(base_path, hash))
+            .collect()
+    }
+
+    /// Check that the bundle can be unbundled into the repository: it has every commit
+    /// the bundle needs, and the packfile is whole.
+    pub fn verify(&self, base_path: Option<&PathBuf>) -> Result<(), anyhow::Error> {
+        self.check_prerequisites(base_path)?;
+
+        let mut checksum = PackChecksum::new();
+        io::copy(&mut self.pack_reader()?, &mut checksum)?;
+        checksum.verify()
+    }
+
+    pub fn check_prerequisites(&self, base_path: Option<&PathBuf>) -> Result<(), anyhow::Error> {
+        let missing = self.missing_prerequisites(base_path);
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let hashes: Vec<String> = missing.iter().map(|hash| hash.full_hash()).collect();
+        Err(anyhow::anyhow!(
+            "Repository lacks these prerequisite commits:\n{}",
+            hashes.join("\n")
+        ))
+    }
+
+    /// Unpack the objects of the bundle into the repository, returning how many there were.
+    /// The refs are left for the caller to update.
+    pub fn unbundle(
+        &self,
+        base_path: Option<&PathBuf>,
+        progress: &mut dyn Progress,
+    ) -> Result<usize, anyhow::Error> {
+        self.check_prerequisites(base_path)?;
+
+        let mut spool = PackSpool::create(base_path)?;
+        io::copy(&mut self.pack_reader()?, &mut spool)?;
+        fetch::store_pack(base_path, spool, &ShallowInfo::default(), progress)
+    }
+}
+
+impl Transport for Bundle {
+    /// The refs the bundle contains. If it contains HEAD too, the branch at the same commit
+    /// is marked as the one HEAD points to.
+    fn list_refs(&mut self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error> {
+        let mut head = self
+            .refs
+            .iter()
+            .find(|(name, _)| name == "HEAD")
+            .map(|(_, hash)| hash);
+
+        let mut git_refs = vec![];
+        for (name, hash) in &self.refs {
+            if !ref_prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+                continue;
+            }
+
+            let is_head = name.starts_with("refs/heads/") && head == Some(hash);
+            if is_head {
+                head = None;
+            }
+            git_refs.push(GitRef::new(hash.clone(), name.clone(), is_head));
+        }
+
+        Ok(git_refs)
+    }
+
+    /// Copy out the packfile of the bundle. It has every object the bundle has whatever is
+    /// wanted, like a server that doesn't negotiate.
+    fn fetch_pack(
+        &mut self,
+        _wants: &[&ObjectHash],
+        _haves: &[ObjectHash],
+        options: &FetchOptions,
+        progress: &mut dyn Write,
+        pack: &mut dyn Write,
+    ) -> Result<ShallowInfo, anyhow::Error> {
+        if options.is_shallow() {
+            return Err(anyhow::anyhow!(
+                "Shallow clones and fetches from bundles are not supported"
+            ));
+        }
+        if options.filter.is_some() {
+            writeln!(progress, "{}", fetch::FILTER_UNSUPPORTED_WARNING)?;
+        }
+
+        io::copy(&mut self.pack_reader()?, pack)?;
+        Ok(ShallowInfo::default())
+    }
+
+    fn send_pack(
+        &mut self,
+        _commands: &[RefUpdateCommand],
+        _pack: Option<&[u8]>,
+        _progress: &mut dyn Write,
+    ) -> Result<ReportStatus, anyhow::Error> {
+        Err(anyhow::anyhow!("Pushing to a bundle is not supported"))
+    }
+
+    fn bundle(&self) -> Option<&Bundle> {
+        Some(self)
+    }
+}
+
+/// Whether the file starts like a bundle does.
+pub fn is_bundle(path: &Path) -> Result<bool, anyhow::Error> {
+    let mut start = vec![];
+    File::open(path)?
+        .take(V2_SIGNATURE.len() as u64)
+        .read_to_end(&mut start)?;
+    Ok(start == V2_SIGNATURE.as_bytes() || start == V3_SIGNATURE.as_bytes())
+}
+
+/// Write a bundle of the objects reachable from the revisions, like `git bundle create`.
+/// The revisions are given as to `git rev-list`, e.g. `main`, `--all` or `v1.0..main`, and
+/// the refs among them are what the bundle contains. Returns how many objects there were.
+pub fn create_bundle(
+    base_path: Option<&PathBuf>,
+    path: &Path,
+    revisions: &[String],
+    version: u32,
+) -> Result<usize, anyhow::Error> {
+    if version != 2 && version != 3 {
+        return Err(anyhow::anyhow!("Unsupported bundle version {}", version));
+    }
+
+    let (refs, include, exclude) = parse_revisions(base_path, revisions)?;
+    if refs.is_empty() {
+        return Err(anyhow::anyhow!("Refusing to create empty bundle"));
+    }
+
+    let objects = history::list_objects(base_path, &include, &exclude)?;
+    let prerequisites = find_prerequisites(base_path, &include, &exclude)?;
+
+    let mut header = match version {
+        2 => format!("{}\n", V2_SIGNATURE),
+        _ => format!("{}\n@object-format=sha1\n", V3_SIGNATURE),
+    };
+    for hash in &prerequisites {
+        let commit = history::read_commit(base_path, hash)?;
+        let subject = commit.message.lines().next().unwrap_or_default();
+        header.push_str(&format!("-{} {}\n", hash.full_hash(), subject));
+    }
+    for (name, hash) in &refs {
+        header.push_str(&format!("{} {}\n", hash.full_hash(), name));
+    }
+    header.push('\n');
+
+    let pack = packfile::create_packfile(base_path, &objects)?;
+
+    // Like git, we write to a lock file first so that a failure leaves no partial bundle.
+    // Creating the lock fails if it's already there, so we don't clobber another writer.
+    let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_path)
+    {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(anyhow::anyhow!(
+                "{} exists: another process seems to be writing the bundle",
+                lock_path.display()
+            ))
+        }
+        Err(error) => {
+            return Err(error).context(format!("Could not create '{}'", lock_path.display()));
+        }
+    };
+
+    let mut write_and_rename = || -> Result<(), anyhow::Error> {
+        file.write_all(header.as_bytes())?;
+        file.write_all(&pack)?;
+        file.sync_all()?;
+        fs::rename(&lock_path, path)?;
+        Ok(())
+    };
+    let result = write_and_rename();
+    if result.is_err() {
+        let _ = fs::remove_file(&lock_path);
+    }
+    result.context(format!("Writing {}", path.display()))?;
+
+    Ok(objects.len())
+}
+
+type Revisions = (Vec<(String, ObjectHash)>, Vec<ObjectHash>, Vec<ObjectHash>);
+
+/// The refs named by the revisions, and the commits to include and exclude. Besides names
+/// and hashes, `^<rev>` and `<rev>..<rev>` exclude commits, `--not` excludes the revisions
+/// after it, and `--all`, `--branches` and `--tags` stand for the refs they name.
+fn parse_revisions(
+    base_path: Option<&PathBuf>,
+    args: &[String],
+) -> Result<Revisions, anyhow::Error> {
+    let mut refs: Vec<(String, ObjectHash)> = vec![];
+    let mut include = vec![];
+    let mut exclude = vec![];
+    let mut not = false;
+
+    let mut add_ref = |name: String, hash: ObjectHash, include: &mut Vec<ObjectHash>| {
+        if !refs.iter().any(|(existing, _)| *existing == name) {
+            refs.push((name, hash.clone()));
+        }
+        include.push(hash);
+    };
+
+    for arg in args {
+        let prefix = match arg.as_str() {
+            "--not" => {
+                not = !not;
+                continue;
+            }
+            "--all" => Some("refs/"),
+            "--branches" => Some("refs/heads/"),
+            "--tags" => Some("refs/tags/"),
+            _ => None,
+        };
+        if let Some(prefix) = prefix {
+            if arg == "--all" && !not {
+                if let Some(hash) = refs::read_ref(base_path, "HEAD")? {
+                    add_ref("HEAD".to_string(), hash, &mut include);
+                }
+            }
+            for (name, hash) in refs::list_refs(base_path, prefix)? {
+                match not {
+                    true => exclude.push(hash),
+                    false => add_ref(name, hash, &mut include),
+                }
+            }
+            continue;
+        }
+
+        if arg.starts_with('-') {
+            return Err(anyhow::anyhow!("Unsupported option {}", arg));
+        }
+
+        if let Some((from, to)) = arg.split_once("..") {
+            // A missing side means HEAD, as in `main..`.
+            let side = |side: &str| match side {
+                "" => "HEAD".to_string(),
+                side => side.to_string(),
+            };
+            exclude.push(resolve_revision(base_path, &side(from))?.1);
+            let (name, hash) = resolve_revision(base_path, &side(to))?;
+            match name {
+                Some(name) => add_ref(name, hash, &mut include),
+                None => include.push(hash),
+            }
+            continue;
+        }
+
+        let (negative, revision) = match arg.strip_prefix('^') {
+            Some(revision) => (!not, revision),
+            None => (not, arg.as_str()),
+        };
+        let (name, hash) = resolve_revision(base_path, revision)?;
+        match (negative, name) {
+            (true, _) => exclude.push(hash),
+            (false, Some(name)) => add_ref(name, hash, &mut include),
+            (false, None) => include.push(hash),
+        }
+    }
+
+    Ok((refs, include, exclude))
+}
+
+/// The ref a revision names, if it names one, and the object it points to. Short names are
+/// looked for in the same order as git does.
+fn resolve_revision(
+    base_path: Option<&PathBuf>,
+    revision: &str,
+) -> Result<(Option<String>, ObjectHash), anyhow::Error> {
+    let candidates = [
+        revision.to_string(),
+        format!("refs/{}", revision),
+        format!("refs/tags/{}", revision),
+        format!("refs/heads/{}", revision),
+        format!("refs/remotes/{}", revision),
+    ];
+    for name in candidates {
+        if name != "HEAD" && !name.starts_with("refs/") {
+            continue;
+        }
+        if let Some(hash) = refs::read_ref(base_path, &name)? {
+            return Ok((Some(name), hash));
+        }
+    }
+
+    match ObjectHash::new(revision) {
+        Ok(hash) if ObjectFile::exists(base_path, &hash) => Ok((None, hash)),
+        _ => Err(anyhow::anyhow!("Bad revision '{}'", revision)),
+    }
+}
+
+/// The commits the bundle leaves out that the commits in it have as parents, which the
+/// repository it's unbundled into needs to have.
+fn find_prerequisites(
+    base_path: Option<&PathBuf>,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+) -> Result<Vec<ObjectHash>, anyhow::Error> {
+    let commits = |hashes: &[ObjectHash]| -> Result<Vec<ObjectHash>, anyhow::Error> {
+        let mut commits = vec![];
+        for hash in hashes {
+            let peeled = history::peel_tag(base_path, hash)?;
+            if history::is_commit(base_path, &peeled) {
+                commits.push(peeled);
+            }
+        }
+        Ok(commits)
+    };
+
+    let excluded: HashSet<ObjectHash> = history::walk_commits(base_path, &commits(exclude)?, None)?
+        .into_iter()
+        .collect();
+
+    let mut prerequisites = vec![];
+    for hash in history::walk_commits(base_path, &commits(include)?, None)? {
+        if excluded.contains(&hash) {
+            continue;
+        }
+        for parent in history::read_commit(base_path, &hash)?.parents {
+            if excluded.contains(&parent) && !prerequisites.contains(&parent) {
+                prerequisites.push(parent);
+            }
+        }
+    }
+
+    Ok(prerequisites)
+}
+
+fn print_verified(bundle: &Bundle, file: &str) {
+    match bundle.refs.len() {
+        1 => println!("The bundle contains this ref:"),
+        count => println!("The bundle contains these {} refs:", count),
+    }
+    print_heads(bundle, &[]);
+
+    match bundle.prerequisites.len() {
+        0 => println!("The bundle records a complete history."),
+        1 => println!("The bundle requires this ref:"),
+        count => println!("The bundle requires these {} refs:", count),
+    }
+    for hash in &bundle.prerequisites {
+        println!("{}", hash.full_hash());
+    }
+
+    println!("{} is okay", file);
+}
+
+// Only the refs with one of the names are listed if any are given.
+fn print_heads(bundle: &Bundle, names: &[String]) {
+    for (name, hash) in &bundle.refs {
+        if names.is_empty() || names.contains(name) {
+            println!("{} {}", hash.full_hash(), name);
+        }
+    }
+}
+
+fn parse_bundle_config(args: &[String]) -> Result<BundleConfig, anyhow::Error> {
+    let usage = || {
+        anyhow::anyhow!(
+            "Usage: bundle create [--version=<version>] <file> <rev-list-args>... | verify <file> | list-heads <file> [<refname>...] | unbundle <file>"
+        )
+    };
+
+    let (command, rest) = args.split_first().ok_or_else(usage)?;
+    match command.as_str() {
+        "create" => {
+            let mut version = 2;
+            let mut rest = rest.iter();
+            let file = loop {
+                let arg = rest.next().ok_or_else(usage)?;
+                match arg.strip_prefix("--version=") {
+                    Some(value) => version = value.parse().map_err(|_| usage())?,
+                    None if arg.starts_with('-') => return Err(usage()),
+                    None => break arg.to_string(),
+                }
+            };
+            let revisions: Vec<String> = rest.cloned().collect();
+            if revisions.is_empty() {
+                return Err(usage());
+            }
+
+            Ok(BundleConfig::Create {
+                file,
+                version,
+                revisions,
+            })
+        }
+        "verify" => match rest {
+            [file] => Ok(BundleConfig::Verify(file.to_string())),
+            _ => Err(usage()),
+        },
+        "list-heads" => match rest.split_first() {
+            Some((file, names)) => Ok(BundleConfig::ListHeads(file.to_string(), names.to_vec())),
+            None => Err(usage()),
+        },
+        "unbundle" => match rest {
+            [file] => Ok(BundleConfig::Unbundle(file.to_string())),
+            _ => Err(usage()),
+        },
+        _ => Err(usage()),
+    }
+}
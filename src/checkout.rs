@@ -3,7 +3,10 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 
+use crate::index::Index;
 use crate::objects::{ObjectFile, ObjectHash, ObjectType, TreeObject};
+use crate::progress::Progress;
+use crate::promisor;
 
 pub struct CheckoutConfig {
     branch_name: String,
@@ -18,23 +21,53 @@ impl CheckoutConfig {
 pub fn checkout_branch(
     base_path: Option<&PathBuf>,
     config: &CheckoutConfig,
+    progress: &mut dyn Progress,
 ) -> Result<usize, anyhow::Error> {
-    let initial_tree = get_initial_tree(base_path, config)?;
+    let commit_hash = get_branch_commit(base_path, config)?;
+    checkout_commit(base_path, &commit_hash, progress)
+        .context(format!("Checking out branch {}", config.branch_name))
+}
+
+/// Write the files of a commit's tree, as when checking out a detached HEAD.
+pub fn checkout_commit(
+    base_path: Option<&PathBuf>,
+    commit_hash: &ObjectHash,
+    progress: &mut dyn Progress,
+) -> Result<usize, anyhow::Error> {
+    let (tree_hash, initial_tree) = get_initial_tree(base_path, commit_hash)?;
+    if promisor::is_partial_clone(base_path) {
+        prefetch_missing_objects(base_path, &initial_tree)?;
+    }
 
     let starting_path: Vec<&str> = match base_path {
         Some(base_path) => base_path.iter().map(|p| p.to_str().unwrap()).collect(),
         None => vec![],
     };
-    create_tree(base_path, initial_tree, starting_path)
+
+    let mut num_files_written = 0;
+    progress.start("Updating files", None);
+    create_tree(
+        base_path,
+        initial_tree,
+        starting_path,
+        progress,
+        &mut num_files_written,
+    )?;
+    progress.finish();
+
+    // What's staged is now what was checked out.
+    Index::from_tree(base_path, &tree_hash)?.write(base_path)?;
+
+    Ok(num_files_written)
 }
 
 fn create_tree(
     base_path: Option<&PathBuf>,
     tree_objects: Vec<TreeObject>,
     path_until_now: Vec<&str>,
-) -> Result<usize, anyhow::Error> {
-    let mut num_files_written = 0;
-
+    progress: &mut dyn Progress,
+    num_files_written: &mut usize,
+) -> Result<(), anyhow::Error> {
     for tree_object in tree_objects {
         let object: ObjectFile = ObjectFile::new(base_path, &tree_object.hash)?;
 
@@ -45,7 +78,13 @@ fn create_tree(
 
                 fs::create_dir_all(new_path.iter().collect::<PathBuf>())?;
 
-                num_files_written += create_tree(base_path, object_contents.contents, new_path)?;
+                create_tree(
+                    base_path,
+                    object_contents.contents,
+                    new_path,
+                    progress,
+                    num_files_written,
+                )?;
             }
             ObjectFile::Other(object_contents) => {
                 let file_path = path_until_now
@@ -54,18 +93,77 @@ fn create_tree(
                     .join(&tree_object.file_name);
 
                 fs::write(file_path, object_contents.contents)?;
-                num_files_written += 1;
+                *num_files_written += 1;
+                progress.update(*num_files_written, None);
             }
         }
     }
 
-    Ok(num_files_written)
+    Ok(())
 }
 
-fn get_initial_tree(
+/// Fetch the objects a partial clone left out in a request per level of the tree, rather
+/// than one request per object as each one is read.
+fn prefetch_missing_objects(
+    base_path: Option<&PathBuf>,
+    tree_objects: &[TreeObject],
+) -> Result<(), anyhow::Error> {
+    let mut missing_blobs: Vec<ObjectHash> = vec![];
+    let mut entries: Vec<(ObjectType, ObjectHash)> = tree_objects
+        .iter()
+        .map(|entry| (entry.object_type.clone(), entry.hash.clone()))
+        .collect();
+
+    while !entries.is_empty() {
+        let mut trees = vec![];
+        for (object_type, hash) in entries {
+            match object_type {
+                ObjectType::Tree => trees.push(hash),
+                // Submodules point to commits in another repository.
+                ObjectType::Commit => {}
+                _ => {
+                    if !ObjectFile::exists(base_path, &hash) && !missing_blobs.contains(&hash) {
+                        missing_blobs.push(hash);
+                    }
+                }
+            }
+        }
+
+        let missing_trees: Vec<ObjectHash> = trees
+            .iter()
+            .filter(|hash| !ObjectFile::exists(base_path, hash))
+            .cloned()
+            .collect();
+        if !missing_trees.is_empty() {
+            promisor::fetch_missing(base_path, &missing_trees)?;
+        }
+
+        entries = vec![];
+        for tree in trees {
+            if let ObjectFile::Tree(contents) = ObjectFile::new(base_path, &tree)? {
+                entries.extend(
+                    contents
+                        .contents
+                        .into_iter()
+                        .map(|entry| (entry.object_type, entry.hash)),
+                );
+            }
+        }
+    }
+
+    // Fetching a tree brings its blobs along with it.
+    missing_blobs.retain(|hash| !ObjectFile::exists(base_path, hash));
+    if !missing_blobs.is_empty() {
+        promisor::fetch_missing(base_path, &missing_blobs)?;
+    }
+
+    Ok(())
+}
+
+fn get_branch_commit(
     base_path: Option<&PathBuf>,
     config: &CheckoutConfig,
-) -> Result<Vec<TreeObject>, anyhow::Error> {
+) -> Result<ObjectHash, anyhow::Error> {
     let path: PathBuf = if config.branch_name.starts_with("remote") {
         ["not-git", "refs"].iter().collect()
     } else {
@@ -78,12 +176,15 @@ fn get_initial_tree(
 
     let branch_path = path.join(&config.branch_name);
     let commit_hash = fs::read_to_string(branch_path)?;
-    let commit_hash = ObjectHash::new(&commit_hash)?;
+    ObjectHash::new(&commit_hash)
+}
 
-    let object_file = ObjectFile::new(base_path, &commit_hash).context(format!(
-        "Unable to find commit associated with branch {}",
-        config.branch_name
-    ))?;
+fn get_initial_tree(
+    base_path: Option<&PathBuf>,
+    commit_hash: &ObjectHash,
+) -> Result<(ObjectHash, Vec<TreeObject>), anyhow::Error> {
+    let object_file = ObjectFile::new(base_path, commit_hash)
+        .context(format!("Unable to find commit {}", commit_hash.full_hash()))?;
 
     let readable_contents = match object_file {
         ObjectFile::Other(object_contents) if object_contents.object_type == ObjectType::Commit => {
@@ -106,7 +207,7 @@ fn get_initial_tree(
     let tree_object =
         ObjectFile::new(base_path, &tree_hash).context("Unable to find tree object")?;
     match tree_object {
-        ObjectFile::Tree(tree_object) => Ok(tree_object.contents),
+        ObjectFile::Tree(tree_object) => Ok((tree_hash, tree_object.contents)),
         ObjectFile::Other(_) => Err(anyhow::anyhow!("Expected tree object")),
     }
 }
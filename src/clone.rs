@@ -1,104 +1,190 @@
-use std::fs;
-use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, process, thread};
 
 use anyhow::Context;
-use bytes::Bytes;
 
-use reqwest::blocking::Client;
-use reqwest::header::CONTENT_TYPE;
+use crate::config::Config;
+use crate::http::UPLOAD_PACK;
+use crate::objects::ObjectHash;
+use crate::packfile::PackSpool;
+use crate::progress::{self, CountingWriter, NoProgress, Progress, RECEIVING_OBJECTS};
+use crate::protocol::{Deepen, FetchOptions, ObjectFilter};
+use crate::transport::{self, Transport};
+use crate::{checkout, fetch, init, promisor, refs, shallow, update_refs};
 
-use crate::objects::{ObjectHash, ObjectType};
-use crate::{checkout, init, packfile, update_refs};
+pub use crate::protocol::GitRef;
 
-const TEMP_DIR: &str = ".tmp";
+// The name of the remote we clone from.
+const ORIGIN: &str = "origin";
+
+// The branch HEAD points to when we start on a detached HEAD.
+const DEFAULT_BRANCH: &str = "main";
+
+// The refs we ask for with `ls-refs`. Protocol v0 always sends every ref.
+pub const REF_PREFIXES: [&str; 3] = ["HEAD", "refs/heads/", "refs/tags/"];
 
 pub struct CloneConfig<'a> {
     pub url: String,
     pub path: Option<&'a str>,
+    // Only fetch part of the history, making the clone shallow.
+    pub deepen: Option<Deepen>,
+    // Leave objects out, making the clone a partial clone.
+    pub filter: Option<ObjectFilter>,
+    // The branch or tag to start on instead of the remote's HEAD.
+    pub branch: Option<String>,
+    // Only fetch the ref we start on rather than every branch.
+    pub single_branch: bool,
+    pub no_checkout: bool,
+    // Config set with `-c`, used while cloning and written to the new repository.
+    pub config: Vec<(String, String)>,
+    pub progress: Box<dyn Progress + 'a>,
 }
 
 impl<'a> CloneConfig<'a> {
     pub fn new(url: String, path: Option<&'a str>) -> Self {
-        CloneConfig { url, path }
+        CloneConfig {
+            url,
+            path,
+            deepen: None,
+            filter: None,
+            branch: None,
+            single_branch: false,
+            no_checkout: false,
+            config: vec![],
+            progress: Box::new(NoProgress),
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct GitRef {
-    #[allow(dead_code)]
-    mode: String,
-    pub commit_hash: ObjectHash,
-    #[allow(dead_code)]
-    pub branch: String,
-    is_head: bool,
-}
+    pub fn with_deepen(mut self, deepen: Deepen) -> Self {
+        self.deepen = Some(deepen);
+        self
+    }
 
-impl GitRef {
-    pub fn new(mode: String, commit_hash: ObjectHash, branch: String, is_head: bool) -> Self {
-        GitRef {
-            mode,
-            commit_hash,
-            branch,
-            is_head,
-        }
+    pub fn with_filter(mut self, filter: ObjectFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_branch(mut self, branch: &str) -> Self {
+        self.branch = Some(branch.to_string());
+        self
+    }
+
+    pub fn with_single_branch(mut self, single_branch: bool) -> Self {
+        self.single_branch = single_branch;
+        self
+    }
+
+    pub fn with_no_checkout(mut self, no_checkout: bool) -> Self {
+        self.no_checkout = no_checkout;
+        self
+    }
+
+    pub fn with_config(mut self, key: &str, value: &str) -> Self {
+        self.config.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Box<dyn Progress + 'a>) -> Self {
+        self.progress = progress;
+        self
     }
 }
 
 pub fn clone_command(args: &[String]) -> Result<(), anyhow::Error> {
-    let config = parse_clone_config(args)?;
+    let config = parse_clone_config(args)?.with_progress(progress::stderr());
 
-    let test_base_path = &PathBuf::from("cool/there");
-    let test_base_path = Some(test_base_path); // For testing - this should be None
+    remove_staging_dirs_on_interrupt()?;
+    let (head_ref, objects) = perform_clone(None, config)?;
 
-    let (head_ref, objects) = perform_clone(test_base_path, config)?;
+    println!("Cloned {} objects into repository successfully.", objects);
 
-    println!(
-        "Cloned {} objects into repository successfully.",
-        objects.len()
-    );
-
-    let branch_name = get_branch_name(&head_ref.branch);
-    println!("On branch '{}'", branch_name);
+    match head_ref.branch.strip_prefix("refs/tags/") {
+        Some(tag) => println!("HEAD is now detached at '{}'", tag),
+        None => println!("On branch '{}'", get_branch_name(&head_ref.branch)),
+    }
 
     Ok(())
 }
 
+/// Clone into the path given, or a directory named after the repository, relative to the
+/// base path. The clone is staged next to the destination and only moved into place once
+/// it succeeds, so a failed clone leaves nothing behind.
 pub fn perform_clone(
     base_path: Option<&PathBuf>,
     config: CloneConfig,
-) -> Result<(GitRef, Vec<packfile::PackfileObject>), anyhow::Error> {
-    prep_temp_dir(base_path)?;
+) -> Result<(GitRef, usize), anyhow::Error> {
+    let dest_dir = destination(base_path, &config)?;
+    let staging_dir = StagingDir::create(&dest_dir)?;
+    let (head_ref, objects) = clone(&staging_dir.path, config)?;
+    staging_dir.persist(&dest_dir)?;
 
-    let temp_dir = match base_path {
-        Some(base_path) => base_path.join(TEMP_DIR),
-        None => PathBuf::from(TEMP_DIR),
-    };
+    Ok((head_ref, objects))
+}
 
+/// The directory to clone into, which must not exist or be empty.
+pub fn destination(
+    base_path: Option<&PathBuf>,
+    config: &CloneConfig,
+) -> Result<PathBuf, anyhow::Error> {
     let dest_dir = match config.path {
         Some(path) => PathBuf::from(path),
-        None => PathBuf::from("clone_folder"),
+        None => PathBuf::from(directory_from_url(&config.url)?),
     };
-
     let dest_dir = match base_path {
         Some(base_path) => base_path.join(dest_dir),
         None => dest_dir,
     };
+    check_destination(&dest_dir)?;
 
-    let clone_result = clone(&temp_dir, config);
+    Ok(dest_dir)
+}
 
-    let (head_ref, objects) = match clone_result {
-        Ok((head_ref, objects)) => {
-            fs::rename(temp_dir, dest_dir)?;
-            (head_ref, objects)
-        }
-        Err(e) => {
-            fs::remove_dir_all(temp_dir)?;
-            return Err(e);
-        }
+/// The directory git would clone a URL into: the last part of its path without `.git` or
+/// `.bundle`, e.g. `foo` for `https://example.com/foo.git` or `git@example.com:foo/.git`.
+pub fn directory_from_url(url: &str) -> Result<String, anyhow::Error> {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map(|(_, path)| path).unwrap_or(""),
+        None => url.rsplit_once(':').map(|(_, path)| path).unwrap_or(url),
     };
 
-    Ok((head_ref, objects))
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix("/.git").unwrap_or(path);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    let name = name.strip_suffix(".bundle").unwrap_or(name);
+
+    if name.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Unable to guess a directory name from {}, please specify one",
+            url
+        ));
+    }
+
+    Ok(name.to_string())
+}
+
+// We clone into a directory that doesn't exist yet or is empty, never over existing files.
+fn check_destination(dest_dir: &PathBuf) -> Result<(), anyhow::Error> {
+    if !dest_dir.exists() {
+        return Ok(());
+    }
+
+    let is_empty_dir = dest_dir.is_dir()
+        && dest_dir
+            .read_dir()
+            .context(format!("Reading {:?}", dest_dir))?
+            .next()
+            .is_none();
+    if !is_empty_dir {
+        return Err(anyhow::anyhow!(
+            "Destination path {:?} already exists and is not an empty directory",
+            dest_dir
+        ));
+    }
+
+    Ok(())
 }
 
 fn get_branch_name(branch: &str) -> String {
@@ -108,301 +194,466 @@ fn get_branch_name(branch: &str) -> String {
     }
 }
 
-fn prep_temp_dir(base_path: Option<&PathBuf>) -> Result<(), anyhow::Error> {
-    let path = match base_path {
-        Some(base_path) => base_path.join(TEMP_DIR),
-        None => PathBuf::from(TEMP_DIR),
-    };
+// The staging directories of clones in progress, removed if we are interrupted.
+static STAGING_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
 
-    if path.exists() {
-        fs::remove_dir_all(&path).context(format!("Deleting pre-exsting {:?} directory", &path))?;
+/// A directory next to the destination that the clone is written to. It is removed when
+/// dropped unless it was moved to the destination.
+pub struct StagingDir {
+    pub path: PathBuf,
+}
+
+impl StagingDir {
+    pub fn create(dest_dir: &Path) -> Result<Self, anyhow::Error> {
+        let name = dest_dir
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid clone destination {:?}", dest_dir))?
+            .to_string_lossy();
+        let path = dest_dir.with_file_name(format!(".{}.clone-{}", name, process::id()));
+
+        if path.exists() {
+            fs::remove_dir_all(&path)
+                .context(format!("Deleting pre-existing {:?} directory", &path))?;
+        }
+        fs::create_dir_all(&path).context(format!("Creating {:?} directory", &path))?;
+        STAGING_DIRS.lock().unwrap().push(path.clone());
+
+        Ok(StagingDir { path })
     }
 
-    fs::create_dir_all(&path).context(format!("Creating {:?} directory", &path))?;
+    pub fn persist(self, dest_dir: &PathBuf) -> Result<(), anyhow::Error> {
+        // Renaming onto an empty directory replaces it.
+        fs::rename(&self.path, dest_dir).context(format!("Moving clone to {:?}", dest_dir))
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        STAGING_DIRS.lock().unwrap().retain(|dir| *dir != self.path);
+        if self.path.exists() {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Remove the staging directories of clones in progress when we get Ctrl-C, since the
+/// process exits without running their destructors.
+fn remove_staging_dirs_on_interrupt() -> Result<(), anyhow::Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    thread::spawn(move || {
+        if runtime.block_on(tokio::signal::ctrl_c()).is_err() {
+            return;
+        }
+
+        for dir in STAGING_DIRS.lock().unwrap().iter() {
+            let _ = fs::remove_dir_all(dir);
+        }
+        process::exit(130);
+    });
 
     Ok(())
 }
 
-pub fn clone(
+pub fn clone(base_path: &PathBuf, config: CloneConfig) -> Result<(GitRef, usize), anyhow::Error> {
+    let client_config = client_config(base_path, &config)?;
+    let mut transport =
+        transport::connect(Some(base_path), &client_config, &config.url, UPLOAD_PACK)?;
+
+    clone_from(base_path, config, transport.as_mut())
+}
+
+/// The config to connect with. The repository doesn't have a config yet, so the client only
+/// sees what was set with -c.
+pub fn client_config(base_path: &PathBuf, config: &CloneConfig) -> Result<Config, anyhow::Error> {
+    let mut client_config = Config::load(Some(base_path))?;
+    for (key, value) in &config.config {
+        client_config.add(key, value)?;
+    }
+    Ok(client_config)
+}
+
+/// Clone into the base path over a transport that is already open.
+pub fn clone_from(
     base_path: &PathBuf,
-    config: CloneConfig,
-) -> Result<(GitRef, Vec<packfile::PackfileObject>), anyhow::Error> {
-    // https://www.git-scm.com/docs/http-protocol
-    // We could use async functions or we could run this as single-threaded with blocking calls
-    // We will use blocking calls for simplicity/ease of use. I don't think there's a part that
-    // would benefit from async calls yet.
-
-    let client = Client::new();
-    let mut refs = discover_references(
-        &client,
-        &format!("{}/info/refs", config.url),
-        "git-upload-pack",
+    mut config: CloneConfig,
+    transport: &mut dyn Transport,
+) -> Result<(GitRef, usize), anyhow::Error> {
+    let refs = transport.list_refs(&REF_PREFIXES)?;
+    let plan = plan_clone(refs, &config)?;
+
+    // A path is recorded as an absolute one so fetching still works from anywhere.
+    let local_path = match (transport.local_repository(), transport.bundle()) {
+        (Some(repository), _) => Some(&repository.path),
+        (None, Some(bundle)) => Some(&bundle.path),
+        (None, None) => None,
+    };
+    let url = match local_path {
+        Some(path) if !config.url.starts_with("file://") => path.to_string_lossy().to_string(),
+        _ => config.url.clone(),
+    };
+    start_clone(base_path, &config, &url, &plan)?;
+
+    let objects = download_objects(
+        base_path,
+        transport,
+        &plan.wants(),
+        plan.options.clone(),
+        config.progress.as_mut(),
     )?;
 
-    let head_ref_index = refs
-        .iter()
-        .position(|r| r.is_head)
-        .ok_or_else(|| anyhow::anyhow!("No HEAD ref found"))?;
-
-    let head_ref = refs.remove(head_ref_index);
+    finish_clone(
+        base_path,
+        config.filter.as_ref(),
+        config.no_checkout,
+        plan,
+        objects,
+        config.progress.as_mut(),
+    )
+}
 
-    // All files are written to a temporary directory
-    // If successful, move all files and folders over to .git and delete temporary directory
+/// What a clone gets, worked out from the refs the remote has.
+pub struct ClonePlan {
+    pub head_ref: GitRef,
+    // The branch the remote's HEAD points to, which becomes `refs/remotes/origin/HEAD`.
+    remote_head: Option<String>,
+    // The refs fetched along with the one we start on.
+    fetched_refs: Vec<GitRef>,
+    pub options: FetchOptions,
+}
 
-    // The whole ref name is ref/heads/{branch_name} - we want the last part
-    let head_path = match head_ref.branch.split_once("refs/heads/") {
-        Some((_, branch)) => branch,
-        None => head_ref.branch.as_str(),
-    };
+impl ClonePlan {
+    pub fn wants(&self) -> Vec<&ObjectHash> {
+        let mut wants: Vec<&ObjectHash> = vec![&self.head_ref.commit_hash];
+        for git_ref in &self.fetched_refs {
+            if !wants.contains(&&git_ref.commit_hash) {
+                wants.push(&git_ref.commit_hash);
+            }
+        }
+        wants
+    }
 
-    let init_config = init::InitConfig::new(head_path, Some(base_path));
-    init::create_directories(init_config)?;
+    // A tag is checked out on a detached HEAD, so the branch HEAD points to is left unborn.
+    fn is_tag(&self) -> bool {
+        self.head_ref.branch.starts_with("refs/tags/")
+    }
 
-    let objects = download_commit(base_path, &client, &config.url, &head_ref.commit_hash)?;
+    fn head_path(&self) -> String {
+        match self.is_tag() {
+            true => DEFAULT_BRANCH.to_string(),
+            false => get_branch_name(&self.head_ref.branch),
+        }
+    }
+}
 
-    // Update HEAD ref
-    // Requires the commit to already be written to a file.
-    let commit_hash = ObjectHash::new(&head_ref.commit_hash.full_hash())?;
-    let path = PathBuf::from(head_path);
-    let update_ref_config = update_refs::UpdateRefsConfig::new(&commit_hash, &path);
-    update_refs::update_refs(Some(base_path), update_ref_config)?;
+pub fn plan_clone(mut refs: Vec<GitRef>, config: &CloneConfig) -> Result<ClonePlan, anyhow::Error> {
+    let remote_head = refs.iter().find(|r| r.is_head).map(|r| r.branch.clone());
+    let head_ref = select_start_ref(&mut refs, config.branch.as_deref())?;
 
-    let checkout_config = checkout::CheckoutConfig::new(get_branch_name(&head_ref.branch));
-    checkout::checkout_branch(Some(base_path), &checkout_config)?;
+    // With --single-branch we only want the ref we start on, otherwise every branch and tag.
+    let fetched_refs: Vec<GitRef> = if config.single_branch {
+        vec![]
+    } else {
+        refs.into_iter()
+            .filter(|r| r.branch.starts_with("refs/heads/") || r.branch.starts_with("refs/tags/"))
+            .filter(|r| r.branch != head_ref.branch)
+            .collect()
+    };
 
-    Ok((head_ref, objects))
+    Ok(ClonePlan {
+        head_ref,
+        remote_head,
+        fetched_refs,
+        options: FetchOptions::new(vec![], config.deepen.clone())
+            .with_filter(config.filter.clone()),
+    })
 }
 
-pub fn download_commit(
+/// Create the repository and record where it was cloned from, before downloading anything.
+pub fn start_clone(
     base_path: &PathBuf,
-    client: &Client,
+    config: &CloneConfig,
     url: &str,
-    hash: &ObjectHash,
-) -> Result<Vec<packfile::PackfileObject>, anyhow::Error> {
-    let commit = get_commit(client, url, hash)?;
-    let header = packfile::PackfileHeader::from_bytes(commit[..20].to_vec())?;
-
-    let mut objects: Vec<packfile::PackfileObject> = vec![];
-    let mut cursor = Cursor::new(&commit[20..]);
-
-    for _ in 0..header.num_objects {
-        let position = cursor.position() as usize;
-        let object_type = packfile::read_type_and_length(&mut cursor)?;
-
-        let ((data, file_hash, file_type), size) = match object_type {
-            packfile::PackfileObjectType::Commit(size) => (
-                packfile::decode_undeltified_data(base_path, ObjectType::Commit, &mut cursor)?,
-                size,
-            ),
-            packfile::PackfileObjectType::Tree(size) => (
-                packfile::decode_undeltified_data(base_path, ObjectType::Tree, &mut cursor)?,
-                size,
-            ),
-            packfile::PackfileObjectType::Blob(size) => (
-                packfile::decode_undeltified_data(base_path, ObjectType::Blob, &mut cursor)?,
-                size,
-            ),
-            packfile::PackfileObjectType::Tag(size) => (
-                packfile::decode_undeltified_data(base_path, ObjectType::Tag, &mut cursor)?,
-                size,
-            ),
-            packfile::PackfileObjectType::OfsDelta(size) => (
-                packfile::read_obj_offset_data(base_path, &objects, &mut cursor)?,
-                size,
-            ),
-            packfile::PackfileObjectType::RefDelta(size) => (
-                packfile::read_obj_ref_data(base_path, &objects, &mut cursor)?,
-                size,
-            ),
-        };
-
-        let object = packfile::PackfileObject {
-            position,
-            object_type,
-            data,
-            size,
-            file_hash,
-            file_type,
-        };
-
-        // Though we need to look up values by an exact value, until we get to 50k+ objects,
-        // a linear search using a vector is more efficient than a hash table since the items are stored
-        // in a contiguous block of memory. That said, if we used a hash map, we should either
-        // need to look up values by position or by their hash.
-        // We could use two different hash maps, but I doubt the memory cost is worth it.
-        objects.push(object);
-    }
-
-    Ok(objects)
+    plan: &ClonePlan,
+) -> Result<(), anyhow::Error> {
+    let head_path = plan.head_path();
+    let init_config = init::InitConfig::new(&head_path, Some(base_path));
+    init::create_directories(init_config)?;
+
+    write_remote_config(base_path, config, url, &plan.head_ref)
 }
 
-fn get_commit(
-    client: &Client,
-    url: &str,
-    commit_hash: &ObjectHash,
-) -> Result<Bytes, anyhow::Error> {
-    let request_url = format!("{}/git-upload-pack", url);
-    // 0000 is the termination code
-    // 0009done is added to indicate that this is the final request in negotiation
-    let body = format!("0032want {}\n00000009done\n", commit_hash.full_hash());
-
-    let resp = client
-        .post(request_url)
-        .body(body)
-        .header(CONTENT_TYPE, "application/x-git-upload-pack-request")
-        .send()?;
-
-    let status = resp.status().as_u16();
-    if status != 200 && status != 304 {
-        return Err(anyhow::anyhow!(format!(
-            "Failed to get commit: Status code must be either 200 or 304, received {}",
-            status
-        )));
-    }
-
-    let want_content_type = "application/x-git-upload-pack-result";
-    let headers = resp.headers();
-    let content_type = headers
-        .get(CONTENT_TYPE)
-        .ok_or_else(|| anyhow::anyhow!("Content-Type must equal {}", want_content_type))?
-        .to_str()?;
-
-    if content_type != want_content_type {
-        return Err(anyhow::anyhow!(
-            "Content-Type must equal {}, received {}",
-            want_content_type,
-            content_type
-        ));
+/// Write the refs and check out the ref we start on once the objects have been downloaded,
+/// with the filter and `no_checkout` of the clone's config. It only borrows what it needs
+/// from the config, so that it can run away from the caller, e.g. on a blocking thread.
+pub fn finish_clone(
+    base_path: &PathBuf,
+    filter: Option<&ObjectFilter>,
+    no_checkout: bool,
+    plan: ClonePlan,
+    objects: usize,
+    progress: &mut dyn Progress,
+) -> Result<(GitRef, usize), anyhow::Error> {
+    // The objects the filter left out are fetched from origin when checking out.
+    if let Some(filter) = filter {
+        promisor::configure_promisor(Some(base_path), ORIGIN, filter)?;
     }
 
-    // BEWARE: DO NOT CONVERT TO STRING
-    // Some of the response can be encoded to string
-    // but some of it can't. Calling `.text` is like calling
-    // .from_utf8_lossy - bytes that cannot be decoded to string
-    // are replaced with a special unicode character
-    let bytes = resp.bytes()?;
-    Ok(bytes)
-}
-
-fn discover_references(
-    client: &Client,
-    url: &str,
-    service_name: &str,
-) -> Result<Vec<GitRef>, anyhow::Error> {
-    let request_url = format!("{}?service={}", url, service_name);
-    let resp = client.get(request_url).send()?;
-
-    let status = resp.status().as_u16();
-    if status != 200 && status != 304 {
-        return Err(anyhow::anyhow!(format!(
-            "Failed to get refs: Status code must be either 200 or 304, received {}",
-            status
-        )));
-    }
-
-    let want_content_type = format!("application/x-{}-advertisement", service_name);
-    let headers = resp.headers();
-    let content_type = headers
-        .get(CONTENT_TYPE)
-        .ok_or_else(|| anyhow::anyhow!(format!("Content-Type must equal {}", want_content_type)))?
-        .to_str()?;
-
-    if content_type != want_content_type {
-        return Err(anyhow::anyhow!(format!(
-            "Content-Type must equal {}, received {}",
-            want_content_type, content_type
-        )));
-    }
-
-    let text = resp.text()?;
-    let first_chars_err = Err(anyhow::anyhow!(
-        "Invalid packet format: first five bytes must match the pattern [0-9a-z]#"
-    ));
-
-    let mut first_chars = text[..5].chars();
-    for _ in 0..4 {
-        let character = first_chars.next();
-        if character.is_none() {
-            return first_chars_err;
+    // Refs require their objects to already be written to a file.
+    for git_ref in plan
+        .fetched_refs
+        .iter()
+        .chain(std::iter::once(&plan.head_ref))
+    {
+        write_remote_ref(base_path, git_ref)?;
+    }
+    if let Some(branch) = plan
+        .remote_head
+        .as_deref()
+        .and_then(|b| b.strip_prefix("refs/heads/"))
+    {
+        let target = format!("refs/remotes/{}/{}", ORIGIN, branch);
+        if refs::read_ref(Some(base_path), &target)?.is_some() {
+            let name = format!("refs/remotes/{}/HEAD", ORIGIN);
+            refs::write_symbolic_ref(Some(base_path), &name, &target)?;
         }
+    }
 
-        let character = character.unwrap();
+    // An annotated tag is checked out at the commit it points to.
+    let start_hash = plan
+        .head_ref
+        .peeled
+        .clone()
+        .unwrap_or_else(|| plan.head_ref.commit_hash.clone());
 
-        if !character.is_numeric() && !character.is_ascii_lowercase() {
-            return first_chars_err;
-        }
+    if plan.is_tag() {
+        refs::write_ref(Some(base_path), "HEAD", &start_hash)?;
+    } else {
+        let path = PathBuf::from(plan.head_path());
+        let update_ref_config = update_refs::UpdateRefsConfig::new(&start_hash, &path);
+        update_refs::update_refs(Some(base_path), update_ref_config)?;
     }
 
-    let pound_char = first_chars.next();
-    if pound_char != Some('#') {
-        return first_chars_err;
+    if !no_checkout {
+        checkout::checkout_commit(Some(base_path), &start_hash, progress)?;
     }
 
-    if !text.ends_with("0000") {
-        return Err(anyhow::anyhow!(
-            "Invalid packet format: last four bytes must be 0000"
-        ));
+    Ok((plan.head_ref, objects))
+}
+
+/// Find the ref to start on: the branch or tag asked for, or the one the remote's HEAD is at.
+fn select_start_ref(refs: &mut Vec<GitRef>, branch: Option<&str>) -> Result<GitRef, anyhow::Error> {
+    let index = match branch {
+        Some(branch) => {
+            let candidates = [
+                format!("refs/heads/{}", branch),
+                format!("refs/tags/{}", branch),
+            ];
+            candidates
+                .iter()
+                .find_map(|name| refs.iter().position(|r| r.branch == *name))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Remote branch {} not found in upstream origin", branch)
+                })?
+        }
+        None => refs
+            .iter()
+            .position(|r| r.is_head)
+            .ok_or_else(|| anyhow::anyhow!("No HEAD ref found"))?,
+    };
+
+    Ok(refs.remove(index))
+}
+
+/// Record origin, what fetching from it should get, and the upstream of the branch we start on.
+fn write_remote_config(
+    base_path: &PathBuf,
+    config: &CloneConfig,
+    url: &str,
+    start_ref: &GitRef,
+) -> Result<(), anyhow::Error> {
+    let mut repo_config = Config::load(Some(base_path))?;
+    for (key, value) in &config.config {
+        repo_config.add(key, value)?;
     }
+    repo_config.set(&format!("remote.{}.url", ORIGIN), url)?;
 
-    let mut lines = text.lines();
-    let first_line = lines
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No lines in packet"))?;
+    let branch = start_ref.branch.strip_prefix("refs/heads/");
+    let fetch_refspec = match (config.single_branch, branch) {
+        (false, _) => format!("+refs/heads/*:refs/remotes/{}/*", ORIGIN),
+        (true, Some(branch)) => format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, ORIGIN),
+        (true, None) => format!("+{0}:{0}", start_ref.branch),
+    };
+    repo_config.set(&format!("remote.{}.fetch", ORIGIN), &fetch_refspec)?;
 
-    if !first_line.ends_with("service=git-upload-pack") {
-        return Err(anyhow::anyhow!(
-            "Invalid packet format: first line must end with service=git-upload-pack"
-        ));
+    if let Some(branch) = branch {
+        repo_config.set(&format!("branch.{}.remote", branch), ORIGIN)?;
+        repo_config.set(&format!("branch.{}.merge", branch), &start_ref.branch)?;
     }
 
-    // Head line should read 00000153{HEAD_SHA} HEAD\0{capabilities}
-    // Since we are interested only in the HEAD_SHA, we cut away everything else
-    let head_line = lines
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No HEAD line in packet"))?;
+    repo_config.save()
+}
 
-    let head_ref = ObjectHash::new(head_line[8..48].as_ref())?;
+/// Branches are written as remote-tracking branches of origin and tags as they are.
+fn write_remote_ref(base_path: &PathBuf, git_ref: &GitRef) -> Result<(), anyhow::Error> {
+    let name = match git_ref.branch.strip_prefix("refs/heads/") {
+        Some(branch) => format!("refs/remotes/{}/{}", ORIGIN, branch),
+        None if git_ref.branch.starts_with("refs/tags/") => git_ref.branch.clone(),
+        None => return Ok(()),
+    };
 
-    let refs: Vec<GitRef> = lines
-        .filter_map(|line| {
-            if line == "0000" {
-                return None;
-            }
-            let (mode_and_hash, branch) = line.split_once(' ')?;
-
-            let mode = mode_and_hash[0..4].to_string();
-            let commit_hash = mode_and_hash[4..].to_string();
-            let commit_hash = match ObjectHash::new(&commit_hash) {
-                Ok(hash) => hash,
-                Err(_) => return None,
-            };
-
-            let is_head = commit_hash.full_hash() == head_ref.full_hash();
-            let git_ref = GitRef {
-                mode,
-                commit_hash,
-                branch: branch.to_string(),
-                is_head,
-            };
-            Some(git_ref)
-        })
-        .collect();
-
-    Ok(refs)
+    refs::write_ref(Some(base_path), &name, &git_ref.commit_hash)
 }
 
-fn parse_clone_config(args: &[String]) -> Result<CloneConfig, anyhow::Error> {
-    if args.is_empty() || args.len() > 2 {
-        return Err(anyhow::anyhow!("Usage: clone <url> [<path>]"));
+/// Download the objects, returning how many there were. The objects of a local repository
+/// are linked rather than packed and unpacked, unless only some of them are wanted.
+pub fn download_objects(
+    base_path: &PathBuf,
+    transport: &mut dyn Transport,
+    wants: &[&ObjectHash],
+    options: FetchOptions,
+    progress: &mut dyn Progress,
+) -> Result<usize, anyhow::Error> {
+    if let Some(repository) = transport.local_repository() {
+        if !options.is_shallow() && options.filter.is_none() {
+            return repository.link_objects(base_path, wants);
+        }
     }
 
-    let url = args[0].to_string();
-    let path = if args.len() == 2 {
-        Some(args[1].as_ref())
-    } else {
-        None
+    // A bundle that builds on commits we don't have can't be cloned.
+    if let Some(bundle) = transport.bundle() {
+        bundle.check_prerequisites(Some(base_path))?;
+    }
+
+    // Remote progress messages are shown as they would be by git.
+    let mut spool = PackSpool::create(Some(base_path))?;
+    let mut receiving = CountingWriter::new(&mut spool, progress, RECEIVING_OBJECTS);
+    let shallow_info = transport
+        .fetch_pack(wants, &[], &options, &mut std::io::stderr(), &mut receiving)
+        .context("Failed to get commit")?;
+    receiving.finish();
+
+    fetch::store_pack(Some(base_path), spool, &shallow_info, progress)
+}
+
+fn parse_clone_config(args: &[String]) -> Result<CloneConfig<'_>, anyhow::Error> {
+    let usage = || {
+        anyhow::anyhow!(
+            "Usage: clone [-c <key>=<value>] [-b <branch>] [--single-branch] [--no-checkout] [--depth=<n> | --shallow-since=<date>] [--filter=<filter-spec>] <url> [<path>]"
+        )
     };
 
-    let config = CloneConfig::new(url, path);
+    let mut deepen = None;
+    let mut filter = None;
+    let mut branch = None;
+    let mut single_branch = false;
+    let mut no_checkout = false;
+    let mut config_values = vec![];
+    let mut positional: Vec<&str> = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            positional.push(arg);
+            continue;
+        }
+
+        match arg.as_str() {
+            "-b" | "--branch" => {
+                let name = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))?;
+                branch = Some(name.as_str());
+                continue;
+            }
+            "--single-branch" => {
+                single_branch = true;
+                continue;
+            }
+            "--no-checkout" | "-n" => {
+                no_checkout = true;
+                continue;
+            }
+            "-c" | "--config" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))?;
+                let (key, value) = value.split_once('=').ok_or_else(|| {
+                    anyhow::anyhow!("Invalid config {}, expected key=value", value)
+                })?;
+                config_values.push((key, value));
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(name) = arg.strip_prefix("--branch=") {
+            branch = Some(name);
+            continue;
+        }
+
+        if arg == "--filter" {
+            let spec = args
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("--filter requires a value"))?;
+            filter = Some(spec.parse()?);
+            continue;
+        }
+        if let Some(spec) = arg.strip_prefix("--filter=") {
+            filter = Some(spec.parse()?);
+            continue;
+        }
+
+        match shallow::parse_deepen_arg(arg, &mut args)? {
+            Some(Deepen::Unshallow) | None => return Err(usage()),
+            value => deepen = value,
+        }
+    }
+
+    if positional.is_empty() || positional.len() > 2 {
+        return Err(usage());
+    }
+
+    let mut config = CloneConfig::new(positional[0].to_string(), positional.get(1).copied())
+        .with_single_branch(single_branch)
+        .with_no_checkout(no_checkout);
+    if let Some(branch) = branch {
+        config = config.with_branch(branch);
+    }
+    for (key, value) in config_values {
+        config = config.with_config(key, value);
+    }
+    if let Some(deepen) = deepen {
+        config = config.with_deepen(deepen);
+    }
+    if let Some(filter) = filter {
+        config = config.with_filter(filter);
+    }
+
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_from_url_strips_git_suffix() {
+        let cases = [
+            ("https://example.com/user/foo.git", "foo"),
+            ("https://example.com/user/foo", "foo"),
+            ("https://example.com/user/foo/", "foo"),
+            ("https://example.com/foo/.git", "foo"),
+            ("git@example.com:user/foo.git", "foo"),
+            ("/srv/repos/foo.git", "foo"),
+            ("/srv/bundles/foo.bundle", "foo"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(directory_from_url(url).unwrap(), expected, "{}", url);
+        }
+
+        assert!(directory_from_url("https://example.com/").is_err());
+        assert!(directory_from_url("https://example.com/.git").is_err());
+    }
+}
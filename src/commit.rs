@@ -2,9 +2,10 @@ use std::{fs, path::PathBuf};
 
 use anyhow::Context;
 
+use crate::index::Index;
 use crate::objects::{ObjectFile, ObjectHash, ObjectType};
 use crate::utils::get_head_ref;
-use crate::{commit_tree, update_refs, write_tree};
+use crate::{commit_tree, update_refs};
 
 pub struct CommitConfig {
     message: String,
@@ -38,11 +39,8 @@ pub fn commit(base_path: Option<&PathBuf>, config: CommitConfig) -> Result<(), a
         None => None,
     };
 
-    let tree_path = match base_path {
-        Some(path) => path.to_str(),
-        None => None,
-    };
-    let tree_hash = write_tree::create_tree(tree_path)?;
+    // The commit has what's staged, not whatever is in the work tree.
+    let tree_hash = Index::read(base_path)?.write_tree(base_path)?;
 
     let commit_tree_config =
         commit_tree::CommitTreeConfig::new(&tree_hash, config.message, parent_hash);
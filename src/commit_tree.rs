@@ -8,6 +8,8 @@ pub struct CommitTreeConfig<'a> {
     pub tree_hash: &'a ObjectHash,
     pub message: String,
     pub parent_hash: Option<ObjectHash>,
+    // The second parent of a merge commit.
+    pub merge_parent_hash: Option<ObjectHash>,
 }
 
 impl<'a> CommitTreeConfig<'a> {
@@ -20,16 +22,22 @@ impl<'a> CommitTreeConfig<'a> {
             tree_hash,
             message,
             parent_hash,
+            merge_parent_hash: None,
         }
     }
+
+    pub fn with_merge_parent(mut self, merge_parent_hash: ObjectHash) -> Self {
+        self.merge_parent_hash = Some(merge_parent_hash);
+        self
+    }
 }
 
 pub fn create_commit(
     base_path: Option<&PathBuf>,
     config: CommitTreeConfig,
 ) -> Result<ObjectHash, anyhow::Error> {
-    let mut contents = create_commit_contents(base_path, config)?;
-    let hash = hash_object::hash_and_write_object(base_path, &ObjectType::Commit, &mut contents)?;
+    let contents = create_commit_contents(base_path, config)?;
+    let hash = hash_object::hash_and_write_object(base_path, &ObjectType::Commit, &contents)?;
 
     Ok(hash)
 }
@@ -48,6 +56,11 @@ fn create_commit_contents(
         writeln!(&mut contents, "parent {}", parent_hash.full_hash())?;
     }
 
+    if let Some(merge_parent_hash) = config.merge_parent_hash {
+        validate_commit_hash(base_path, &merge_parent_hash)?;
+        writeln!(&mut contents, "parent {}", merge_parent_hash.full_hash())?;
+    }
+
     writeln!(
         &mut contents,
         "author Ben Horowitz <benyakir.horowitz@gmail.com>",
@@ -0,0 +1,393 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// The repository configuration stored in `not-git/config`, using the same format as git:
+///
+/// ```text
+/// [remote "origin"]
+///     url = https://github.com/benyakirten/not-git
+///     fetch = +refs/heads/*:refs/remotes/origin/*
+/// ```
+///
+/// Keys are addressed as `section.subsection.name`, e.g. `remote.origin.url`. Section and
+/// key names are case-insensitive but subsections are not. A key may have several values.
+/// CF https://git-scm.com/docs/git-config#_configuration_file
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    sections: Vec<ConfigSection>,
+}
+
+#[derive(Debug)]
+struct ConfigSection {
+    name: String,
+    subsection: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl ConfigSection {
+    fn matches(&self, name: &str, subsection: Option<&str>) -> bool {
+        self.name.eq_ignore_ascii_case(name) && self.subsection.as_deref() == subsection
+    }
+}
+
+/// A key split into its parts: `branch.feature/a.b.remote` is the section `branch`,
+/// the subsection `feature/a.b` and the name `remote`.
+struct ConfigKey<'a> {
+    section: &'a str,
+    subsection: Option<&'a str>,
+    name: String,
+}
+
+impl<'a> ConfigKey<'a> {
+    fn parse(key: &'a str) -> Result<Self, anyhow::Error> {
+        let (section, rest) = key
+            .split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("Invalid config key {}: missing section", key))?;
+        let (subsection, name) = match rest.rsplit_once('.') {
+            Some((subsection, name)) => (Some(subsection), name),
+            None => (None, rest),
+        };
+
+        if section.is_empty() || name.is_empty() {
+            return Err(anyhow::anyhow!("Invalid config key {}", key));
+        }
+
+        Ok(Self {
+            section,
+            subsection,
+            name: name.to_lowercase(),
+        })
+    }
+}
+
+impl Config {
+    /// Read the configuration of the repository. A repository without a config file
+    /// has an empty configuration.
+    pub fn load(base_path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let path: PathBuf = ["not-git", "config"].iter().collect();
+        let path = match base_path {
+            Some(base_path) => base_path.join(path),
+            None => path,
+        };
+
+        let sections = if path.exists() {
+            parse_config(&fs::read_to_string(&path)?)?
+        } else {
+            vec![]
+        };
+
+        Ok(Self { path, sections })
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&self.path, self.to_string())?;
+        Ok(())
+    }
+
+    /// The last value of the key, which is the one that takes precedence.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).pop()
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = match ConfigKey::parse(key) {
+            Ok(key) => key,
+            Err(_) => return vec![],
+        };
+
+        self.sections
+            .iter()
+            .filter(|section| section.matches(key.section, key.subsection))
+            .flat_map(|section| section.entries.iter())
+            .filter(|(name, _)| *name == key.name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Interpret a value as a boolean the way git does. Missing keys are `None`.
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, anyhow::Error> {
+        match self.get(key).map(|value| value.to_lowercase()) {
+            None => Ok(None),
+            Some(value) => match value.as_str() {
+                "true" | "yes" | "on" | "1" | "" => Ok(Some(true)),
+                "false" | "no" | "off" | "0" => Ok(Some(false)),
+                _ => Err(anyhow::anyhow!(
+                    "Invalid boolean value {} for {}",
+                    value,
+                    key
+                )),
+            },
+        }
+    }
+
+    /// Replace every value of the key with a single value.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+        self.unset_all(key)?;
+        self.add(key, value)
+    }
+
+    /// Add another value to the key, keeping the existing ones.
+    pub fn add(&mut self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+        let key = ConfigKey::parse(key)?;
+
+        let index = match self
+            .sections
+            .iter()
+            .rposition(|section| section.matches(key.section, key.subsection))
+        {
+            Some(index) => index,
+            None => {
+                self.sections.push(ConfigSection {
+                    name: key.section.to_lowercase(),
+                    subsection: key.subsection.map(|s| s.to_string()),
+                    entries: vec![],
+                });
+                self.sections.len() - 1
+            }
+        };
+
+        self.sections[index]
+            .entries
+            .push((key.name, value.to_string()));
+        Ok(())
+    }
+
+    /// Remove every value of the key. Returns whether any value was removed.
+    pub fn unset_all(&mut self, key: &str) -> Result<bool, anyhow::Error> {
+        let key = ConfigKey::parse(key)?;
+        let mut removed = false;
+
+        for section in self
+            .sections
+            .iter_mut()
+            .filter(|section| section.matches(key.section, key.subsection))
+        {
+            let length = section.entries.len();
+            section.entries.retain(|(name, _)| *name != key.name);
+            removed |= section.entries.len() != length;
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove a whole section such as `remote.origin`. Returns whether it existed.
+    pub fn remove_section(&mut self, section: &str) -> bool {
+        let (name, subsection) = split_section(section);
+        let length = self.sections.len();
+        self.sections
+            .retain(|existing| !existing.matches(name, subsection));
+        self.sections.len() != length
+    }
+
+    /// Rename a section such as `remote.origin` to `remote.upstream`. Returns whether it existed.
+    pub fn rename_section(&mut self, old: &str, new: &str) -> bool {
+        let (old_name, old_subsection) = split_section(old);
+        let (new_name, new_subsection) = split_section(new);
+        let mut renamed = false;
+
+        for section in self
+            .sections
+            .iter_mut()
+            .filter(|section| section.matches(old_name, old_subsection))
+        {
+            section.name = new_name.to_lowercase();
+            section.subsection = new_subsection.map(|s| s.to_string());
+            renamed = true;
+        }
+
+        renamed
+    }
+
+    /// The subsections of a section, e.g. the names of all remotes for `remote`.
+    pub fn subsections(&self, name: &str) -> Vec<&str> {
+        let mut subsections: Vec<&str> = vec![];
+        for section in &self.sections {
+            if !section.name.eq_ignore_ascii_case(name) {
+                continue;
+            }
+
+            if let Some(subsection) = &section.subsection {
+                if !subsections.contains(&subsection.as_str()) {
+                    subsections.push(subsection);
+                }
+            }
+        }
+
+        subsections
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for section in &self.sections {
+            // Sections that have had all their values removed are dropped, like git does.
+            if section.entries.is_empty() {
+                continue;
+            }
+
+            match &section.subsection {
+                Some(subsection) => writeln!(
+                    f,
+                    "[{} \"{}\"]",
+                    section.name,
+                    subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                )?,
+                None => writeln!(f, "[{}]", section.name)?,
+            }
+
+            for (name, value) in &section.entries {
+                writeln!(f, "\t{} = {}", name, quote_value(value))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn split_section(section: &str) -> (&str, Option<&str>) {
+    match section.split_once('.') {
+        Some((name, subsection)) => (name, Some(subsection)),
+        None => (section, None),
+    }
+}
+
+fn parse_config(contents: &str) -> Result<Vec<ConfigSection>, anyhow::Error> {
+    let mut sections: Vec<ConfigSection> = vec![];
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .split_once(']')
+                .map(|(header, _)| header)
+                .ok_or_else(|| anyhow::anyhow!("Invalid config section on line {}", index + 1))?;
+            sections.push(parse_section_header(header));
+            continue;
+        }
+
+        let section = sections.last_mut().ok_or_else(|| {
+            anyhow::anyhow!("Config value outside of a section on line {}", index + 1)
+        })?;
+
+        // A key without a value, e.g. `bare`, is shorthand for `bare = true`
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), parse_value(value.trim())),
+            None => (line, "true".to_string()),
+        };
+
+        section.entries.push((name.to_lowercase(), value));
+    }
+
+    Ok(sections)
+}
+
+fn parse_section_header(header: &str) -> ConfigSection {
+    // [remote "origin"]
+    if let Some((name, subsection)) = header.split_once(' ') {
+        let subsection = subsection.trim().trim_matches('"');
+        return ConfigSection {
+            name: name.to_lowercase(),
+            subsection: Some(subsection.replace("\\\"", "\"").replace("\\\\", "\\")),
+            entries: vec![],
+        };
+    }
+
+    // The deprecated [remote.origin] syntax
+    let (name, subsection) = split_section(header);
+    ConfigSection {
+        name: name.to_lowercase(),
+        subsection: subsection.map(|s| s.to_string()),
+        entries: vec![],
+    }
+}
+
+fn parse_value(value: &str) -> String {
+    let mut parsed = String::new();
+    let mut in_quotes = false;
+    // Whitespace inside quotes is kept even at the end of the value.
+    let mut quoted_length = 0;
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                quoted_length = parsed.len();
+            }
+            '\\' => match chars.next() {
+                Some('n') => parsed.push('\n'),
+                Some('t') => parsed.push('\t'),
+                Some(escaped) => parsed.push(escaped),
+                None => {}
+            },
+            // Comments run until the end of the line unless they're quoted.
+            '#' | ';' if !in_quotes => break,
+            c => parsed.push(c),
+        }
+    }
+
+    let trimmed_length = parsed.trim_end().len().max(quoted_length);
+    parsed.truncate(trimmed_length);
+    parsed
+}
+
+fn quote_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+
+    let needs_quotes = value.starts_with(' ')
+        || value.ends_with(' ')
+        || value.contains('#')
+        || value.contains(';');
+
+    if needs_quotes {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_value_handles_quotes_escapes_and_comments() {
+        assert_eq!(parse_value("abc # comment"), "abc");
+        assert_eq!(parse_value("\"a # b\" ; comment"), "a # b");
+        assert_eq!(parse_value("a\\\"b"), "a\"b");
+        assert_eq!(parse_value("line\\nbreak"), "line\nbreak");
+    }
+
+    #[test]
+    fn quote_value_round_trips() {
+        for value in ["plain", " spaced ", "a#b", "quote\"s", "back\\slash"] {
+            assert_eq!(parse_value(&quote_value(value)), value);
+        }
+    }
+
+    #[test]
+    fn config_key_splits_subsection_with_dots() {
+        let key = ConfigKey::parse("branch.feature/a.b.Remote").unwrap();
+        assert_eq!(key.section, "branch");
+        assert_eq!(key.subsection, Some("feature/a.b"));
+        assert_eq!(key.name, "remote");
+
+        let key = ConfigKey::parse("core.bare").unwrap();
+        assert_eq!(key.subsection, None);
+
+        assert!(ConfigKey::parse("core").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+
+use anyhow::Context;
+use bytes::Bytes;
+
+use crate::config::Config;
+use crate::daemon::DaemonUrl;
+use crate::http::UPLOAD_PACK;
+use crate::pkt_line::{self, PktLine};
+use crate::protocol::{self, ProtocolVersion, RefAdvertisement};
+use crate::sideband::RemoteError;
+use crate::ssh;
+
+// Other transports pass the protocol version in this environment variable instead of a header.
+const GIT_PROTOCOL_ENV: &str = "GIT_PROTOCOL";
+
+/// A conversation with `git-upload-pack` or `git-receive-pack` through a stream: the stdin
+/// and stdout of the service running in another process, over ssh or an `ext::` command,
+/// or a TCP connection to a git daemon. Unlike over HTTP, the conversation has state: the
+/// server sends its advertisement as soon as it starts and every request after is
+/// answered on the same connection.
+/// CF https://git-scm.com/docs/pack-protocol#_ssh_transport
+pub struct Connection {
+    // There's no process to wait for when talking to a daemon.
+    child: Option<Child>,
+    input: Option<Box<dyn Write>>,
+    output: Box<dyn Read>,
+}
+
+impl Connection {
+    /// Start the service for the URL and read its advertisement, if the URL is for ssh,
+    /// an `ext::` command or a git daemon.
+    pub fn open(
+        config: &Config,
+        url: &str,
+        service: &str,
+    ) -> Result<Option<(Self, RefAdvertisement)>, anyhow::Error> {
+        let mut connection = match DaemonUrl::parse(url) {
+            Some(daemon_url) => Self::connect(&daemon_url, service)?,
+            None => match ssh::remote_command(config, url, service)? {
+                Some(command) => Self::spawn(command, url, service)?,
+                None => return Ok(None),
+            },
+        };
+
+        match read_until_flush(&mut connection.output)
+            .and_then(|data| protocol::parse_advertisement(&data, service))
+        {
+            Ok(advertisement) => Ok(Some((connection, advertisement))),
+            Err(e) => Err(e.context(format!("Reading the advertisement of {}", url))),
+        }
+    }
+
+    fn spawn(mut command: Command, url: &str, service: &str) -> Result<Self, anyhow::Error> {
+        // Servers that don't speak protocol v2 ignore this and answer with v0.
+        if service == UPLOAD_PACK {
+            command.env(GIT_PROTOCOL_ENV, protocol::PROTOCOL_V2);
+        }
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context(format!("Running {} for {}", service, url))?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+
+        match (stdin, stdout) {
+            (Some(stdin), Some(stdout)) => Ok(Connection {
+                child: Some(child),
+                input: Some(Box::new(stdin)),
+                output: Box::new(stdout),
+            }),
+            _ => Err(anyhow::anyhow!("No stdin or stdout for {}", service)),
+        }
+    }
+
+    fn connect(daemon_url: &DaemonUrl, service: &str) -> Result<Self, anyhow::Error> {
+        // The daemon passes the protocol version on to the service for us.
+        let extra_parameters = match service {
+            UPLOAD_PACK => vec![protocol::PROTOCOL_V2],
+            _ => vec![],
+        };
+        let stream = daemon_url.connect(service, &extra_parameters)?;
+
+        Ok(Connection {
+            child: None,
+            input: Some(Box::new(stream.try_clone()?)),
+            output: Box::new(stream),
+        })
+    }
+
+    /// Send a request and read the response. In protocol v2, every response ends with a
+    /// flush packet and the server waits for the next command. In protocol v0 the response
+    /// is the end of the conversation, so we read until the server is done.
+    pub fn request(
+        &mut self,
+        version: ProtocolVersion,
+        body: Vec<u8>,
+    ) -> Result<Bytes, anyhow::Error> {
+        let mut output = self.stream(version, body)?;
+        if version == ProtocolVersion::V2 {
+            return Ok(Bytes::from(read_until_flush(&mut output)?));
+        }
+
+        let mut response = vec![];
+        output.read_to_end(&mut response)?;
+        self.finish()?;
+        Ok(Bytes::from(response))
+    }
+
+    /// Send a request and return what to read its response from as it arrives, for
+    /// responses such as packfiles that are too large to keep in memory. The response is
+    /// read up to its last flush packet in protocol v2. In protocol v0 it's the end of the
+    /// conversation, so `finish` is called once it has been read.
+    pub fn stream(
+        &mut self,
+        version: ProtocolVersion,
+        body: Vec<u8>,
+    ) -> Result<&mut dyn Read, anyhow::Error> {
+        let input = self
+            .input
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The connection has already been closed"))?;
+        input.write_all(&body)?;
+        input.flush()?;
+
+        // Closing stdin tells a process there is nothing more to come. A daemon closes the
+        // connection itself once it has answered.
+        if version == ProtocolVersion::V0 {
+            self.input = None;
+        }
+
+        Ok(self.output.as_mut())
+    }
+
+    /// Wait for the service to exit at the end of the conversation, to tell whether it
+    /// succeeded.
+    pub fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.input = None;
+        let Some(child) = self.child.as_mut() else {
+            return Ok(());
+        };
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "The remote end hung up unexpectedly ({})",
+                status
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    // The server may still be waiting for another command, or writing a response we gave
+    // up on, so we don't wait for it to finish by itself.
+    fn drop(&mut self) {
+        self.input = None;
+        if let Some(child) = self.child.as_mut() {
+            if let Ok(None) = child.try_wait() {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Read pkt-lines up to and including the next flush packet, keeping them encoded so the
+/// same parsers can be used as for HTTP responses.
+fn read_until_flush<R: Read>(reader: &mut R) -> Result<Vec<u8>, anyhow::Error> {
+    let mut data = vec![];
+
+    loop {
+        let line =
+            pkt_line::read_pkt_line(reader).context("The remote end hung up unexpectedly")?;
+        match line {
+            PktLine::Flush => {
+                data.extend(pkt_line::FLUSH_PKT);
+                return Ok(data);
+            }
+            PktLine::Delimiter => data.extend(pkt_line::DELIMITER_PKT),
+            PktLine::ResponseEnd => data.extend(pkt_line::RESPONSE_END_PKT),
+            PktLine::Data(line) => {
+                // A daemon that won't serve the repository says why instead of answering.
+                if let Some(message) = line.strip_prefix(b"ERR ") {
+                    let message = String::from_utf8_lossy(message).trim_end().to_string();
+                    return Err(RemoteError::Err(message).into());
+                }
+                data.extend(pkt_line::encode(&line));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn read_until_flush_stops_after_flush() {
+        let mut data = pkt_line::encode_text("a");
+        data.extend(pkt_line::DELIMITER_PKT);
+        data.extend(pkt_line::encode_text("b"));
+        data.extend(pkt_line::FLUSH_PKT);
+        let rest = pkt_line::encode_text("c");
+
+        let mut input = data.clone();
+        input.extend(&rest);
+        let mut cursor = Cursor::new(input.as_slice());
+
+        assert_eq!(read_until_flush(&mut cursor).unwrap(), data);
+        assert!(read_until_flush(&mut cursor).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+
+use crate::config::Config;
+
+const ASKPASS_ENV: &str = "GIT_ASKPASS";
+
+/// Credentials in the form git's credential helpers exchange them, as `key=value` lines.
+/// CF https://git-scm.com/docs/git-credential
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Credential {
+    pub protocol: String,
+    pub host: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Schemes other than Basic, e.g. `authtype=Bearer` with the token in `credential`.
+    pub authtype: Option<String>,
+    pub credential: Option<String>,
+    // The WWW-Authenticate headers the server answered a 401 with.
+    pub wwwauth: Vec<String>,
+}
+
+impl Credential {
+    pub fn new(protocol: &str, host: &str) -> Self {
+        Credential {
+            protocol: protocol.to_string(),
+            host: host.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether there is enough to authenticate with.
+    pub fn is_complete(&self) -> bool {
+        let has_password = self.username.is_some() && self.password.is_some();
+        let has_token = self.authtype.is_some() && self.credential.is_some();
+        has_password || has_token
+    }
+
+    /// Refuse values that would add lines of their own to the helper protocol, like a
+    /// `%0A` in a URL's username adding a `host=` for another server.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        let values = [
+            Some(&self.protocol),
+            Some(&self.host),
+            self.username.as_ref(),
+            self.password.as_ref(),
+            self.authtype.as_ref(),
+            self.credential.as_ref(),
+        ];
+        let values = values.into_iter().flatten().chain(&self.wwwauth);
+        for value in values {
+            if value.contains(['\n', '\0']) {
+                return Err(anyhow::anyhow!(
+                    "Credential value for {}://{} contains a newline or NUL",
+                    self.protocol,
+                    self.host
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn to_protocol(&self) -> Result<String, anyhow::Error> {
+        self.check()?;
+
+        let mut lines = vec![
+            // We can handle schemes other than Basic, such as Bearer tokens.
+            "capability[]=authtype".to_string(),
+            format!("protocol={}", self.protocol),
+            format!("host={}", self.host),
+        ];
+        let optional = [
+            ("username", &self.username),
+            ("password", &self.password),
+            ("authtype", &self.authtype),
+            ("credential", &self.credential),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                lines.push(format!("{}={}", key, value));
+            }
+        }
+        for header in &self.wwwauth {
+            lines.push(format!("wwwauth[]={}", header));
+        }
+
+        Ok(format!("{}\n\n", lines.join("\n")))
+    }
+
+    // Take the attributes a helper answered with. Returns whether the helper asked us
+    // not to consult any more helpers.
+    fn read_protocol(&mut self, output: &str) -> bool {
+        let mut quit = false;
+        for line in output.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+
+            let value = Some(value.to_string());
+            match key {
+                "username" => self.username = value,
+                "password" => self.password = value,
+                "authtype" => self.authtype = value,
+                "credential" => self.credential = value,
+                "quit" => quit = matches!(value.as_deref(), Some("1" | "true")),
+                _ => {}
+            }
+        }
+
+        quit
+    }
+}
+
+/// Where credentials come from: the helpers in `credential.helper`, then `GIT_ASKPASS`
+/// or `core.askPass`.
+#[derive(Debug, Clone, Default)]
+pub struct CredentialSources {
+    pub helpers: Vec<String>,
+    pub askpass: Option<String>,
+}
+
+impl CredentialSources {
+    pub fn from_config(config: &Config) -> Self {
+        // Like git, an empty helper clears the ones before it.
+        let mut helpers = vec![];
+        for helper in config.get_all("credential.helper") {
+            match helper.is_empty() {
+                true => helpers.clear(),
+                false => helpers.push(helper.to_string()),
+            }
+        }
+
+        let askpass = std::env::var(ASKPASS_ENV)
+            .ok()
+            .filter(|askpass| !askpass.is_empty())
+            .or_else(|| config.get("core.askpass").map(String::from));
+
+        CredentialSources { helpers, askpass }
+    }
+
+    /// Complete the credential from the helpers, or by asking for what is still missing.
+    pub fn fill(&self, credential: &mut Credential) -> Result<(), anyhow::Error> {
+        for helper in &self.helpers {
+            let output = match run_helper(helper, "get", credential)? {
+                Some(output) => output,
+                None => continue,
+            };
+
+            let quit = credential.read_protocol(&output);
+            if credential.is_complete() {
+                return Ok(());
+            }
+            if quit {
+                break;
+            }
+        }
+
+        let askpass = self.askpass.as_ref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Could not read credentials for {}://{}: no credential helper or askpass program",
+                credential.protocol,
+                credential.host
+            )
+        })?;
+
+        if credential.username.is_none() {
+            let prompt = format!(
+                "Username for '{}://{}': ",
+                credential.protocol, credential.host
+            );
+            credential.username = Some(ask(askpass, &prompt)?);
+        }
+        if credential.password.is_none() {
+            let prompt = format!(
+                "Password for '{}://{}@{}': ",
+                credential.protocol,
+                credential.username.as_deref().unwrap_or_default(),
+                credential.host
+            );
+            credential.password = Some(ask(askpass, &prompt)?);
+        }
+
+        Ok(())
+    }
+
+    /// Tell the helpers the credential worked, so they can store it.
+    pub fn approve(&self, credential: &Credential) {
+        self.notify("store", credential);
+    }
+
+    /// Tell the helpers the credential was rejected, so they can forget it.
+    pub fn reject(&self, credential: &Credential) {
+        self.notify("erase", credential);
+    }
+
+    // Helpers failing to store or erase shouldn't fail the command, like in git.
+    fn notify(&self, action: &str, credential: &Credential) {
+        for helper in &self.helpers {
+            let _ = run_helper(helper, action, credential);
+        }
+    }
+}
+
+/// Run a helper with the credential on stdin. A helper starting with `!` is a shell
+/// snippet, an absolute path is run as it is and any other name is `git-credential-<name>`.
+/// Returns the helper's output, or `None` if it failed.
+fn run_helper(
+    helper: &str,
+    action: &str,
+    credential: &Credential,
+) -> Result<Option<String>, anyhow::Error> {
+    let input = credential.to_protocol()?;
+    let command = match helper.strip_prefix('!') {
+        Some(snippet) => snippet.to_string(),
+        None if helper.starts_with('/') => helper.to_string(),
+        None => format!("git-credential-{}", helper),
+    };
+
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} {}", command, action))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn();
+    // A helper that isn't installed is skipped like one that fails.
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return Ok(None),
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The helper may exit without reading its input.
+        let _ = stdin.write_all(input.as_bytes());
+    }
+
+    let output = child
+        .wait_with_output()
+        .context(format!("Running credential helper {}", helper))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).to_string()))
+}
+
+// The askpass program is given the prompt as its argument and answers on stdout.
+fn ask(askpass: &str, prompt: &str) -> Result<String, anyhow::Error> {
+    let output = Command::new(askpass)
+        .arg(prompt)
+        .stderr(Stdio::inherit())
+        .output()
+        .context(format!("Running askpass program {}", askpass))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("Askpass program {} failed", askpass));
+    }
+
+    let answer = String::from_utf8_lossy(&output.stdout);
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Decode the `%XX` escapes of a URL's username or password.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+    while index < bytes.len() {
+        let escape = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("user%40example.com"), "user@example.com");
+        assert_eq!(percent_decode("p%3Ass%2"), "p:ss%2");
+        assert_eq!(percent_decode("plain"), "plain");
+    }
+
+    #[test]
+    fn read_protocol_takes_helper_attributes() {
+        let mut credential = Credential::new("https", "example.com");
+        let quit = credential.read_protocol("username=alice\npassword=secret\nunknown=1\n");
+
+        assert!(!quit);
+        assert!(credential.is_complete());
+        assert_eq!(credential.username.as_deref(), Some("alice"));
+        assert_eq!(credential.password.as_deref(), Some("secret"));
+
+        let mut credential = Credential::new("https", "example.com");
+        credential.read_protocol("authtype=Bearer\ncredential=token\n");
+        assert!(credential.is_complete());
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::Context;
+
+use crate::http::UPLOAD_PACK;
+use crate::pkt_line;
+use crate::server;
+
+const GIT_SCHEME: &str = "git://";
+const DEFAULT_ADDRESS: &str = "0.0.0.0";
+pub const DEFAULT_PORT: u16 = 9418;
+
+// Like git, a repository is only served if it has this file, unless everything is exported.
+const EXPORT_OK_FILE: &str = "git-daemon-export-ok";
+
+/// Where a repository is on a machine running `git daemon`.
+#[derive(Debug, PartialEq)]
+pub struct DaemonUrl {
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+impl DaemonUrl {
+    /// Parse `git://host[:port]/path`. Returns `None` for any other URL.
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix(GIT_SCHEME)?;
+        let (authority, path) = rest.split_once('/')?;
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().ok()?)),
+            None => (authority, None),
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(DaemonUrl {
+            host: host.to_string(),
+            port,
+            path: format!("/{}", path),
+        })
+    }
+
+    /// Connect to the daemon and ask for the service. The request is a single pkt-line with
+    /// the service, the path and the host, followed by extra parameters such as the
+    /// protocol version after an empty one. The daemon answers as the service would.
+    /// CF https://git-scm.com/docs/pack-protocol#_git_transport
+    pub fn connect(
+        &self,
+        service: &str,
+        extra_parameters: &[&str],
+    ) -> Result<TcpStream, anyhow::Error> {
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        let mut stream = TcpStream::connect((self.host.as_str(), port))
+            .context(format!("Connecting to {}:{}", self.host, port))?;
+
+        let host = match self.port {
+            Some(port) => format!("{}:{}", self.host, port),
+            None => self.host.clone(),
+        };
+        let mut request = format!("{} {}\0host={}\0", service, self.path, host);
+        if !extra_parameters.is_empty() {
+            request.push('\0');
+            for parameter in extra_parameters {
+                request.push_str(&format!("{}\0", parameter));
+            }
+        }
+        stream.write_all(&pkt_line::encode(request.as_bytes()))?;
+
+        Ok(stream)
+    }
+}
+
+/// Serves the repositories in a directory read-only over the git protocol, like
+/// `git daemon --base-path=<directory>`. Only fetching is served, and only from
+/// repositories with a `git-daemon-export-ok` file in their `not-git` directory, unless
+/// `--export-all` is given.
+/// CF https://git-scm.com/docs/git-daemon
+pub struct Daemon {
+    listener: TcpListener,
+    root: PathBuf,
+    export_all: bool,
+}
+
+struct DaemonArgs {
+    address: String,
+    port: u16,
+    export_all: bool,
+    root: PathBuf,
+}
+
+pub fn daemon_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let args = parse_daemon_args(args)?;
+    let daemon = Daemon::bind(
+        &format!("{}:{}", args.address, args.port),
+        &args.root,
+        args.export_all,
+    )?;
+
+    println!(
+        "Serving {} at git://{}",
+        daemon.root.display(),
+        daemon.local_addr()?
+    );
+    daemon.run()
+}
+
+fn parse_daemon_args(args: &[String]) -> Result<DaemonArgs, anyhow::Error> {
+    let usage = || {
+        anyhow::anyhow!(
+            "Usage: daemon [--listen=<address>] [--port=<port>] [--export-all] [<directory>]"
+        )
+    };
+
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut port = DEFAULT_PORT;
+    let mut export_all = false;
+    let mut root = None;
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--listen=") {
+            address = value.to_string();
+        } else if let Some(value) = arg.strip_prefix("--port=") {
+            port = value.parse().map_err(|_| usage())?;
+        } else if arg == "--export-all" {
+            export_all = true;
+        } else if arg.starts_with('-') || root.is_some() {
+            return Err(usage());
+        } else {
+            root = Some(PathBuf::from(arg));
+        }
+    }
+
+    Ok(DaemonArgs {
+        address,
+        port,
+        export_all,
+        root: root.unwrap_or_else(|| PathBuf::from(".")),
+    })
+}
+
+impl Daemon {
+    /// Listen on the address, which can have port 0 to pick any free port.
+    pub fn bind(address: &str, root: &Path, export_all: bool) -> Result<Self, anyhow::Error> {
+        let listener = TcpListener::bind(address).context(format!("Listening on {}", address))?;
+        let root = root
+            .canonicalize()
+            .context(format!("Serving {}", root.display()))?;
+
+        Ok(Daemon {
+            listener,
+            root,
+            export_all,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answer connections until the process is stopped, each on a thread of its own since
+    /// a conversation lasts until the client is done fetching.
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let root = self.root.clone();
+            let export_all = self.export_all;
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &root, export_all) {
+                    eprintln!("Error: {:#}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    root: &Path,
+    export_all: bool,
+) -> Result<(), anyhow::Error> {
+    let mut input = stream.try_clone()?;
+    let mut output = BufWriter::new(stream);
+
+    let line = pkt_line::read_pkt_line(&mut input)?;
+    let request = line
+        .as_text()
+        .ok_or_else(|| anyhow::anyhow!("Invalid request {:?}", line))?;
+    // We don't need the host, and only serve the protocol version every client speaks.
+    let command = request.split('\0').next().unwrap_or_default();
+    let (service, path) = command
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("Invalid request '{}'", command))?;
+
+    if service != UPLOAD_PACK {
+        return deny(&mut output, &format!("service not enabled: '{}'", service));
+    }
+
+    let repository = server::open_repository(root, path).filter(|repository| {
+        export_all
+            || repository
+                .path
+                .join("not-git")
+                .join(EXPORT_OK_FILE)
+                .is_file()
+    });
+    let Some(mut repository) = repository else {
+        return deny(
+            &mut output,
+            &format!("access denied or repository not exported: {}", path),
+        );
+    };
+
+    output.write_all(&server::advertise_refs(&mut repository, service)?)?;
+    output.flush()?;
+    server::upload_pack(&mut repository, &mut input, &mut output, false)
+}
+
+// The client shows the message as the reason it couldn't fetch.
+fn deny<W: Write>(output: &mut W, message: &str) -> Result<(), anyhow::Error> {
+    output.write_all(&pkt_line::encode_text(&format!("ERR {}", message)))?;
+    output.flush()?;
+    Err(anyhow::anyhow!(message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_git_urls() {
+        assert_eq!(
+            DaemonUrl::parse("git://example.com:9419/org/repo.git"),
+            Some(DaemonUrl {
+                host: "example.com".to_string(),
+                port: Some(9419),
+                path: "/org/repo.git".to_string(),
+            })
+        );
+        assert_eq!(
+            DaemonUrl::parse("git://example.com/repo").unwrap().port,
+            None
+        );
+        assert_eq!(DaemonUrl::parse("https://example.com/repo"), None);
+        assert_eq!(DaemonUrl::parse("git://example.com"), None);
+    }
+}
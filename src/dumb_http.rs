@@ -0,0 +1,420 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+
+use crate::history;
+use crate::http::{self, HttpClient};
+use crate::http_config::BodyReader;
+use crate::objects::{ObjectFile, ObjectHash, ObjectType, TreeObject};
+use crate::packfile::{self, PackfileHeader, PACKFILE_HEADER_SIZE};
+use crate::protocol::{self, RefAdvertisement};
+use crate::utils::{read_next_zlib_data, split_header_from_contents};
+
+// https://git-scm.com/docs/http-protocol#_dumb_clients
+// A dumb server is a plain file server in front of a bare repository, such as a static
+// mirror. There is no one to negotiate with, so we walk the history ourselves, downloading
+// the objects we're missing, and hand them to the caller as a packfile like a smart server
+// would have sent.
+
+const SYMBOLIC_REF_PREFIX: &str = "ref: ";
+const PACK_LINE_PREFIX: &str = "P ";
+const PACK_INDEX_SIGNATURE: [u8; 4] = [0xff, b't', b'O', b'c'];
+const PACK_INDEX_FANOUT_ENTRIES: usize = 256;
+const HASH_SIZE: usize = 20;
+const CHECKSUM_SIZE: usize = 20;
+
+/// Build the advertisement of a dumb server from its `info/refs` file, which the server sent
+/// in response to our smart request, and its `HEAD` file.
+pub fn discover_references(
+    client: &HttpClient,
+    url: &str,
+    info_refs: &[u8],
+) -> Result<RefAdvertisement, anyhow::Error> {
+    // HEAD is usually a symbolic ref that reads `ref: refs/heads/main`.
+    let head = http::get_file(client, url, "HEAD")?;
+    let head_target = head
+        .as_deref()
+        .and_then(|head| std::str::from_utf8(head).ok())
+        .and_then(|head| head.trim().strip_prefix(SYMBOLIC_REF_PREFIX))
+        .map(String::from);
+
+    protocol::parse_info_refs(info_refs, head_target.as_deref())
+}
+
+/// Download the objects reachable from the wants but not from the haves into a packfile,
+/// which is written to `pack`. Objects we already have are skipped along with what they
+/// link to. Loose objects are downloaded one at a time, following the links of commits,
+/// trees and tags. An object that isn't loose is looked up in the indexes of the server's
+/// packfiles and the whole packfile containing it is taken. We can't read the objects of a
+/// packfile without unpacking it, so like git, we trust it to contain what its objects link
+/// to.
+pub fn fetch_pack(
+    base_path: Option<&PathBuf>,
+    client: &HttpClient,
+    url: &str,
+    wants: &[&ObjectHash],
+    haves: &[ObjectHash],
+    pack: &mut dyn Write,
+) -> Result<(), anyhow::Error> {
+    let mut seen: HashSet<String> = haves.iter().map(|have| have.full_hash()).collect();
+    let mut queue: VecDeque<ObjectHash> = wants.iter().map(|want| (*want).clone()).collect();
+    let mut entries = PackEntries::create(base_path)?;
+    let mut packs = RemotePacks::new(client, url);
+
+    while let Some(hash) = queue.pop_front() {
+        if !seen.insert(hash.full_hash())
+            || packs.contains(&hash)
+            || ObjectFile::exists(base_path, &hash)
+        {
+            continue;
+        }
+
+        match read_loose_object(client, url, &hash)? {
+            Some((object_type, body)) => {
+                queue.extend(linked_objects(&hash, &object_type, &body)?);
+                entries.add_object(&object_type, &body)?;
+            }
+            None => entries.add_pack(&mut packs.download(&hash)?)?,
+        }
+    }
+
+    entries.write_pack(pack)
+}
+
+/// Download a loose object, which is stored compressed in `objects/<2 chars>/<38 chars>`.
+fn read_loose_object(
+    client: &HttpClient,
+    url: &str,
+    hash: &ObjectHash,
+) -> Result<Option<(ObjectType, Vec<u8>)>, anyhow::Error> {
+    let full_hash = hash.full_hash();
+    let path = format!("objects/{}/{}", &full_hash[..2], &full_hash[2..]);
+    let compressed = match http::get_file(client, url, &path)? {
+        Some(compressed) => compressed,
+        None => return Ok(None),
+    };
+
+    let contents = read_next_zlib_data(&mut Cursor::new(compressed.as_slice()))
+        .context(format!("Decompressing object {}", full_hash))?;
+
+    // The server is just serving files, so nothing but the hash tells us they're intact.
+    let actual_hash = hex::encode(Sha1::digest(&contents));
+    if actual_hash != full_hash {
+        return Err(anyhow::anyhow!(
+            "Object {} downloaded from {} is corrupt, its contents hash to {}",
+            full_hash,
+            url,
+            actual_hash
+        ));
+    }
+
+    let (header, body) = split_header_from_contents(&contents)?;
+    let header = String::from_utf8(header.to_vec())?;
+    let object_type = header
+        .split_once(' ')
+        .map(|(object_type, _)| object_type)
+        .ok_or_else(|| anyhow::anyhow!("Invalid object header {}", header))?;
+
+    Ok(Some((ObjectType::from_str(object_type)?, body.to_vec())))
+}
+
+/// The objects an object points to: the tree and parents of a commit, the entries of a tree
+/// and the object of a tag. Submodules are commits in another repository, so we skip them.
+fn linked_objects(
+    hash: &ObjectHash,
+    object_type: &ObjectType,
+    body: &[u8],
+) -> Result<Vec<ObjectHash>, anyhow::Error> {
+    match object_type {
+        ObjectType::Commit => {
+            let contents = String::from_utf8(body.to_vec())
+                .context("Parsing commit object contents as utf8")?;
+            let commit = history::parse_commit(hash, &contents)?;
+
+            let mut linked = vec![commit.tree];
+            linked.extend(commit.parents);
+            Ok(linked)
+        }
+        ObjectType::Tree => Ok(TreeObject::from_object(body)?
+            .into_iter()
+            .filter(|entry| entry.object_type != ObjectType::Commit)
+            .map(|entry| entry.hash)
+            .collect()),
+        ObjectType::Tag => {
+            let contents = String::from_utf8_lossy(body);
+            let object = contents
+                .lines()
+                .find_map(|line| line.strip_prefix("object "))
+                .ok_or_else(|| anyhow::anyhow!("No object found in tag {}", hash.full_hash()))?;
+            Ok(vec![ObjectHash::new(object)?])
+        }
+        _ => Ok(vec![]),
+    }
+}
+
+/// A packfile on the server, listed in `objects/info/packs`, and the objects its index says
+/// it contains.
+struct RemotePack {
+    name: String,
+    hashes: HashSet<String>,
+    downloaded: bool,
+}
+
+/// The server's packfiles, which are only listed once an object isn't found loose since
+/// many dumb servers have none.
+struct RemotePacks<'a> {
+    client: &'a HttpClient,
+    url: &'a str,
+    packs: Option<Vec<RemotePack>>,
+}
+
+impl<'a> RemotePacks<'a> {
+    fn new(client: &'a HttpClient, url: &'a str) -> Self {
+        RemotePacks {
+            client,
+            url,
+            packs: None,
+        }
+    }
+
+    /// Whether the object is in a packfile we already downloaded.
+    fn contains(&self, hash: &ObjectHash) -> bool {
+        self.packs
+            .iter()
+            .flatten()
+            .any(|pack| pack.downloaded && pack.hashes.contains(&hash.full_hash()))
+    }
+
+    /// Start downloading the packfile containing the object.
+    fn download(&mut self, hash: &ObjectHash) -> Result<BodyReader, anyhow::Error> {
+        if self.packs.is_none() {
+            self.packs = Some(list_packs(self.client, self.url)?);
+        }
+
+        let pack = self
+            .packs
+            .iter_mut()
+            .flatten()
+            .find(|pack| pack.hashes.contains(&hash.full_hash()))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unable to find {} on {}, neither as a loose object nor in a packfile",
+                    hash.full_hash(),
+                    self.url
+                )
+            })?;
+
+        let path = format!("objects/pack/{}", pack.name);
+        let body = http::stream_file(self.client, self.url, &path)?
+            .ok_or_else(|| anyhow::anyhow!("Packfile {} is listed but missing", pack.name))?;
+        pack.downloaded = true;
+
+        Ok(body)
+    }
+}
+
+/// List the server's packfiles from `objects/info/packs`, where each one is on a line that
+/// reads `P pack-<sha>.pack`, and download their indexes.
+fn list_packs(client: &HttpClient, url: &str) -> Result<Vec<RemotePack>, anyhow::Error> {
+    let info = match http::get_file(client, url, "objects/info/packs")? {
+        Some(info) => String::from_utf8(info).context("Parsing objects/info/packs as utf8")?,
+        None => return Ok(vec![]),
+    };
+
+    let mut packs = vec![];
+    for name in info
+        .lines()
+        .filter_map(|line| line.strip_prefix(PACK_LINE_PREFIX))
+    {
+        let index_name = name.replace(".pack", ".idx");
+        let index = http::get_file(client, url, &format!("objects/pack/{}", index_name))?
+            .ok_or_else(|| anyhow::anyhow!("Packfile index {} is missing", index_name))?;
+
+        packs.push(RemotePack {
+            name: name.to_string(),
+            hashes: read_pack_index(&index).context(format!("Reading {}", index_name))?,
+            downloaded: false,
+        });
+    }
+
+    Ok(packs)
+}
+
+/// Read the hashes of the objects in a packfile from its index. Both versions start with
+/// a fanout table whose last entry is the number of objects. Version 2 is preceded by a
+/// signature and version number and lists the hashes on their own, while version 1 puts a
+/// 4 byte offset before every hash.
+/// CF https://git-scm.com/docs/gitformat-pack#_pack_idx_files_have_the_following_format
+fn read_pack_index(index: &[u8]) -> Result<HashSet<String>, anyhow::Error> {
+    let (fanout_start, entry_size, hash_offset) = match index.starts_with(&PACK_INDEX_SIGNATURE) {
+        true => {
+            if index.get(4..8) != Some(&2u32.to_be_bytes()) {
+                return Err(anyhow::anyhow!("Unsupported packfile index version"));
+            }
+            (8, HASH_SIZE, 0)
+        }
+        false => (0, 4 + HASH_SIZE, 4),
+    };
+
+    let truncated = || anyhow::anyhow!("Packfile index is truncated");
+
+    let count_start = fanout_start + (PACK_INDEX_FANOUT_ENTRIES - 1) * 4;
+    let count = index
+        .get(count_start..count_start + 4)
+        .ok_or_else(truncated)?;
+    let count = u32::from_be_bytes(count.try_into()?) as usize;
+
+    let entries_start = fanout_start + PACK_INDEX_FANOUT_ENTRIES * 4;
+    (0..count)
+        .map(|entry| {
+            let start = entries_start + entry * entry_size + hash_offset;
+            let hash = index.get(start..start + HASH_SIZE).ok_or_else(truncated)?;
+            Ok(hex::encode(hash))
+        })
+        .collect()
+}
+
+/// The loose objects and the server's packfiles put together as the entries of one
+/// packfile. They're kept in a temporary file in the repository until we know how many
+/// objects there are, which the header of the packfile starts with. The file is removed
+/// when dropped.
+struct PackEntries {
+    path: PathBuf,
+    file: BufWriter<File>,
+    num_objects: u32,
+}
+
+impl PackEntries {
+    fn create(base_path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let path = packfile::temporary_pack_path(base_path)?;
+        let file = File::create(&path).context(format!("Creating {:?}", path))?;
+
+        Ok(PackEntries {
+            path,
+            file: BufWriter::new(file),
+            num_objects: 0,
+        })
+    }
+
+    fn add_object(&mut self, object_type: &ObjectType, body: &[u8]) -> Result<(), anyhow::Error> {
+        self.file
+            .write_all(&packfile::encode_object(object_type.as_str(), body)?)?;
+        self.num_objects += 1;
+        Ok(())
+    }
+
+    /// Deltas in a packfile only refer to objects in the same packfile, by hash or by their
+    /// distance from the delta, so the objects of the server's packfiles can be copied over
+    /// as they are. They're copied as they arrive, holding back what could be the checksum
+    /// at the end, which is checked against the rest.
+    fn add_pack(&mut self, pack: &mut dyn Read) -> Result<(), anyhow::Error> {
+        let too_short = || anyhow::anyhow!("Packfile is too short to contain a header");
+
+        let mut header = vec![0; PACKFILE_HEADER_SIZE];
+        pack.read_exact(&mut header).map_err(|_| too_short())?;
+        let mut checksum = Sha1::new();
+        checksum.update(&header);
+        let header = PackfileHeader::from_bytes(header)?;
+
+        let mut pending = vec![];
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = pack.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            pending.extend_from_slice(&buffer[..read]);
+
+            let ready = pending.len().saturating_sub(CHECKSUM_SIZE);
+            checksum.update(&pending[..ready]);
+            self.file.write_all(&pending[..ready])?;
+            pending.drain(..ready);
+        }
+
+        if pending.len() < CHECKSUM_SIZE {
+            return Err(too_short());
+        }
+        if checksum.finalize().as_slice() != pending.as_slice() {
+            return Err(anyhow::anyhow!("Packfile checksum does not match"));
+        }
+
+        self.num_objects += header.num_objects;
+        Ok(())
+    }
+
+    /// Write the packfile: the header, the entries read back from the file and the checksum
+    /// of everything before it.
+    fn write_pack(mut self, pack: &mut dyn Write) -> Result<(), anyhow::Error> {
+        self.file.flush()?;
+
+        let mut header = b"PACK".to_vec();
+        header.extend(2u32.to_be_bytes());
+        header.extend(self.num_objects.to_be_bytes());
+        let mut checksum = Sha1::new();
+        checksum.update(&header);
+        pack.write_all(&header)?;
+
+        let file = File::open(&self.path).context(format!("Reading {:?}", self.path))?;
+        let mut entries = BufReader::new(file);
+        loop {
+            let buffer = entries.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            checksum.update(buffer);
+            pack.write_all(buffer)?;
+
+            let length = buffer.len();
+            entries.consume(length);
+        }
+
+        pack.write_all(&checksum.finalize())?;
+        Ok(())
+    }
+}
+
+impl Drop for PackEntries {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> Vec<u8> {
+        vec![byte; HASH_SIZE]
+    }
+
+    #[test]
+    fn read_pack_index_reads_both_versions() {
+        let mut fanout = vec![0u8; (PACK_INDEX_FANOUT_ENTRIES - 1) * 4];
+        fanout.extend(2u32.to_be_bytes());
+
+        let mut v2 = PACK_INDEX_SIGNATURE.to_vec();
+        v2.extend(2u32.to_be_bytes());
+        v2.extend(&fanout);
+        v2.extend(hash(0xaa));
+        v2.extend(hash(0xbb));
+
+        let mut v1 = fanout.clone();
+        v1.extend(12u32.to_be_bytes());
+        v1.extend(hash(0xaa));
+        v1.extend(40u32.to_be_bytes());
+        v1.extend(hash(0xbb));
+
+        let want: HashSet<String> = ["aa".repeat(HASH_SIZE), "bb".repeat(HASH_SIZE)]
+            .into_iter()
+            .collect();
+        assert_eq!(read_pack_index(&v2).unwrap(), want);
+        assert_eq!(read_pack_index(&v1).unwrap(), want);
+
+        assert!(read_pack_index(&v2[..v2.len() - 1]).is_err());
+    }
+}
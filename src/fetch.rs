@@ -0,0 +1,852 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::slice::Chunks;
+
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::http::UPLOAD_PACK;
+use crate::objects::{ObjectFile, ObjectHash};
+use crate::packfile::PackSpool;
+use crate::progress::{self, CountingWriter, NoProgress, Progress, RECEIVING_OBJECTS};
+use crate::protocol::{
+    self, Deepen, FetchOptions, GitRef, ObjectFilter, ProtocolVersion, RefAdvertisement,
+    ShallowInfo,
+};
+use crate::refspec::Refspec;
+use crate::sideband::{self, Sideband};
+use crate::transport::{self, Transport};
+use crate::{history, promisor, refs, shallow, ssh};
+
+// We tell the server about at most this many of our most recent commits. Anything older
+// than that is unlikely to save the server from sending much.
+const MAX_HAVES: usize = 256;
+
+// The haves sent in each round of a protocol v2 negotiation.
+const HAVES_PER_ROUND: usize = 32;
+
+// Like git, we fall back to fetching everything when the server can't filter.
+pub const FILTER_UNSUPPORTED_WARNING: &str =
+    "warning: filtering not recognized by server, ignoring";
+
+pub struct FetchConfig<'a> {
+    pub remote: &'a str,
+    pub refspecs: Vec<Refspec>,
+    pub prune: bool,
+    // Limit or extend how much history is fetched, for shallow repositories.
+    pub deepen: Option<Deepen>,
+    // Leave objects out, making the remote a promisor remote for a partial clone.
+    pub filter: Option<ObjectFilter>,
+    pub progress: Box<dyn Progress + 'a>,
+}
+
+impl<'a> FetchConfig<'a> {
+    pub fn new(remote: &'a str, refspecs: Vec<Refspec>, prune: bool) -> Self {
+        Self {
+            remote,
+            refspecs,
+            prune,
+            deepen: None,
+            filter: None,
+            progress: Box::new(NoProgress),
+        }
+    }
+
+    pub fn with_deepen(mut self, deepen: Deepen) -> Self {
+        self.deepen = Some(deepen);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: ObjectFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Box<dyn Progress + 'a>) -> Self {
+        self.progress = progress;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefUpdateStatus {
+    UpToDate,
+    New,
+    FastForward,
+    Forced,
+    // A non-fast-forward update without `+` in the refspec.
+    Rejected,
+    // The ref was removed from the remote and `--prune` was given.
+    Deleted,
+}
+
+#[derive(Debug)]
+pub struct RefUpdate {
+    // The name of the ref on the remote. Deleted refs no longer have one.
+    pub source: Option<String>,
+    pub destination: String,
+    pub old_hash: Option<ObjectHash>,
+    pub new_hash: Option<ObjectHash>,
+    pub status: RefUpdateStatus,
+}
+
+#[derive(Debug)]
+pub struct FetchResult {
+    pub url: String,
+    // Every ref the remote sent that matched one of the refspecs.
+    pub refs: Vec<GitRef>,
+    pub updates: Vec<RefUpdate>,
+    pub objects: usize,
+}
+
+/// A remote ref matched by a refspec and the local ref it should be stored in, if any.
+struct RefMapping {
+    source: String,
+    hash: ObjectHash,
+    destination: Option<String>,
+    force: bool,
+}
+
+pub fn fetch_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let args = parse_fetch_args(args)?;
+    let remote = match args.remote {
+        Some(remote) => remote,
+        None => default_remote(None)?,
+    };
+
+    let mut config =
+        FetchConfig::new(&remote, args.refspecs, args.prune).with_progress(progress::stderr());
+    if let Some(deepen) = args.deepen {
+        config = config.with_deepen(deepen);
+    }
+    if let Some(filter) = args.filter {
+        config = config.with_filter(filter);
+    }
+    let result = fetch(None, config)?;
+
+    print_updates(&result);
+
+    if result
+        .updates
+        .iter()
+        .any(|update| update.status == RefUpdateStatus::Rejected)
+    {
+        return Err(anyhow::anyhow!(
+            "Some local refs could not be updated. Use a refspec starting with '+' to force them"
+        ));
+    }
+
+    Ok(())
+}
+
+struct FetchArgs {
+    remote: Option<String>,
+    refspecs: Vec<Refspec>,
+    prune: bool,
+    deepen: Option<Deepen>,
+    filter: Option<ObjectFilter>,
+}
+
+fn parse_fetch_args(args: &[String]) -> Result<FetchArgs, anyhow::Error> {
+    let mut prune = false;
+    let mut deepen = None;
+    let mut filter = None;
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prune" | "-p" => prune = true,
+            "--filter" => {
+                let spec = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--filter requires a value"))?;
+                filter = Some(spec.parse()?);
+            }
+            arg if arg.starts_with("--filter=") => filter = Some(arg["--filter=".len()..].parse()?),
+            arg if arg.starts_with('-') => match shallow::parse_deepen_arg(arg, &mut args)? {
+                Some(value) => deepen = Some(value),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "Usage: fetch [--prune] [--depth=<n> | --shallow-since=<date> | --unshallow] [--filter=<filter-spec>] [<remote>] [<refspec>...]"
+                    ))
+                }
+            },
+            arg => positional.push(arg),
+        }
+    }
+
+    let remote = positional.first().map(|remote| remote.to_string());
+    let refspecs = positional
+        .iter()
+        .skip(1)
+        .map(|refspec| refspec.parse())
+        .collect::<Result<Vec<Refspec>, _>>()?;
+
+    Ok(FetchArgs {
+        remote,
+        refspecs,
+        prune,
+        deepen,
+        filter,
+    })
+}
+
+/// The remote of the current branch's upstream, or `origin` if it doesn't have one.
+pub fn default_remote(base_path: Option<&PathBuf>) -> Result<String, anyhow::Error> {
+    let config = Config::load(base_path)?;
+
+    let remote = refs::read_symbolic_ref(base_path, "HEAD")?
+        .and_then(|head| head.strip_prefix("refs/heads/").map(|b| b.to_string()))
+        .and_then(|branch| config.get(&format!("branch.{}.remote", branch)))
+        .map(|remote| remote.to_string());
+
+    Ok(remote.unwrap_or_else(|| "origin".to_string()))
+}
+
+/// The URL of a configured remote. A URL can also be used in place of a remote name.
+pub fn remote_url(config: &Config, remote: &str) -> Result<String, anyhow::Error> {
+    match config.get(&format!("remote.{}.url", remote)) {
+        Some(url) => Ok(url.to_string()),
+        None if remote.contains("://")
+            || ssh::is_command_url(remote)
+            || Path::new(remote).is_dir() =>
+        {
+            Ok(remote.to_string())
+        }
+        None => Err(anyhow::anyhow!(
+            "'{}' does not appear to be a git repository",
+            remote
+        )),
+    }
+}
+
+pub fn fetch(
+    base_path: Option<&PathBuf>,
+    config: FetchConfig,
+) -> Result<FetchResult, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    let url = remote_url(&repo_config, config.remote)?;
+    let mut transport = transport::connect(base_path, &repo_config, &url, UPLOAD_PACK)?;
+
+    fetch_from(base_path, config, transport.as_mut())
+}
+
+/// Fetch from the remote over a transport that is already open.
+pub fn fetch_from(
+    base_path: Option<&PathBuf>,
+    mut config: FetchConfig,
+    transport: &mut dyn Transport,
+) -> Result<FetchResult, anyhow::Error> {
+    let refspecs = fetch_refspecs(base_path, &config)?;
+    let remote_refs = transport.list_refs(&ref_prefixes(&refspecs))?;
+    let plan = plan_fetch(base_path, &config, refspecs, remote_refs)?;
+
+    let mut objects = 0;
+    if !plan.wants.is_empty() {
+        if let Some(bundle) = transport.bundle() {
+            bundle.check_prerequisites(base_path)?;
+        }
+
+        let progress = config.progress.as_mut();
+        let mut spool = PackSpool::create(base_path)?;
+        let mut receiving = CountingWriter::new(&mut spool, progress, RECEIVING_OBJECTS);
+        let shallow_info = transport.fetch_pack(
+            &plan.wants(),
+            &plan.haves,
+            &plan.options,
+            &mut std::io::stderr(),
+            &mut receiving,
+        )?;
+        receiving.finish();
+        objects = store_pack(base_path, spool, &shallow_info, progress)?;
+    }
+
+    finish_fetch(base_path, &config, plan, objects)
+}
+
+/// What a fetch gets, worked out from the refs the remote has and what we already have.
+pub struct FetchPlan {
+    url: String,
+    refspecs: Vec<Refspec>,
+    remote_refs: Vec<GitRef>,
+    mappings: Vec<RefMapping>,
+    // Nothing needs to be downloaded without any wants.
+    pub wants: Vec<ObjectHash>,
+    pub haves: Vec<ObjectHash>,
+    pub options: FetchOptions,
+}
+
+impl FetchPlan {
+    pub fn wants(&self) -> Vec<&ObjectHash> {
+        self.wants.iter().collect()
+    }
+}
+
+/// The refspecs the fetch uses, from the config or the command line.
+pub fn fetch_refspecs(
+    base_path: Option<&PathBuf>,
+    config: &FetchConfig,
+) -> Result<Vec<Refspec>, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    resolve_refspecs(&repo_config, config.remote, config.refspecs.clone())
+}
+
+/// The prefixes of the remote refs the refspecs can match, to list refs with.
+pub fn ref_prefixes(refspecs: &[Refspec]) -> Vec<&str> {
+    let mut prefixes: Vec<&str> = refspecs.iter().map(|r| r.source_prefix()).collect();
+    prefixes.sort_unstable();
+    prefixes.dedup();
+    prefixes
+}
+
+pub fn plan_fetch(
+    base_path: Option<&PathBuf>,
+    config: &FetchConfig,
+    refspecs: Vec<Refspec>,
+    remote_refs: Vec<GitRef>,
+) -> Result<FetchPlan, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    let url = remote_url(&repo_config, config.remote)?;
+    let mappings = map_refs(&remote_refs, &refspecs)?;
+
+    // Like git, we refuse to move the branch that is checked out, since its files and the
+    // index would no longer match it.
+    let head_target = refs::read_symbolic_ref(base_path, "HEAD")?;
+    if let Some(current) = mappings
+        .iter()
+        .filter_map(|mapping| mapping.destination.as_deref())
+        .find(|destination| head_target.as_deref() == Some(*destination))
+    {
+        return Err(anyhow::anyhow!(
+            "Refusing to fetch into current branch {} of non-bare repository",
+            current
+        ));
+    }
+
+    let shallow_commits: Vec<ObjectHash> = shallow::read_shallow(base_path)?.into_iter().collect();
+    if config.deepen == Some(Deepen::Unshallow) && shallow_commits.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--unshallow on a complete repository does not make sense"
+        ));
+    }
+
+    // Fetches from a promisor remote keep leaving out what the partial clone left out.
+    let filter = match &config.filter {
+        Some(filter) => Some(filter.clone()),
+        None => promisor::remote_filter(&repo_config, config.remote)?,
+    };
+
+    // Deepening needs the history behind commits we already have, so they're wanted too.
+    let mut wants: Vec<ObjectHash> = vec![];
+    for mapping in &mappings {
+        let missing = config.deepen.is_some() || !ObjectFile::exists(base_path, &mapping.hash);
+        if !wants.contains(&mapping.hash) && missing {
+            wants.push(mapping.hash.clone());
+        }
+    }
+
+    let haves = match wants.is_empty() {
+        true => vec![],
+        false => local_haves(base_path)?,
+    };
+
+    Ok(FetchPlan {
+        url,
+        refspecs,
+        remote_refs,
+        mappings,
+        wants,
+        haves,
+        options: FetchOptions::new(shallow_commits, config.deepen.clone()).with_filter(filter),
+    })
+}
+
+/// Unpack a downloaded packfile into the repository and record the new boundary of its
+/// shallow history, returning how many objects there were.
+pub fn store_pack(
+    base_path: Option<&PathBuf>,
+    spool: PackSpool,
+    shallow_info: &ShallowInfo,
+    progress: &mut dyn Progress,
+) -> Result<usize, anyhow::Error> {
+    let pack_base_path = base_path.cloned().unwrap_or_default();
+    let objects = spool.unpack(&pack_base_path, progress)?;
+    shallow::update_shallow(base_path, shallow_info)?;
+    Ok(objects.len())
+}
+
+/// Update the refs once the objects they point to have been downloaded.
+pub fn finish_fetch(
+    base_path: Option<&PathBuf>,
+    config: &FetchConfig,
+    plan: FetchPlan,
+    objects: usize,
+) -> Result<FetchResult, anyhow::Error> {
+    if let Some(filter) = &config.filter {
+        promisor::configure_promisor(base_path, config.remote, filter)?;
+    }
+
+    let mut updates = vec![];
+    for mapping in &plan.mappings {
+        if let Some(destination) = &mapping.destination {
+            updates.push(update_ref(base_path, mapping, destination)?);
+        }
+    }
+
+    if config.prune {
+        updates.extend(prune_refs(base_path, &plan.refspecs, &plan.mappings)?);
+    }
+
+    let refs = plan
+        .remote_refs
+        .into_iter()
+        .filter(|r| plan.mappings.iter().any(|m| m.source == r.branch))
+        .collect();
+
+    Ok(FetchResult {
+        url: plan.url,
+        refs,
+        updates,
+        objects,
+    })
+}
+
+/// The refspecs to fetch with. Refspecs given on the command line without a destination
+/// still update the remote-tracking ref the configured refspecs map them to.
+fn resolve_refspecs(
+    config: &Config,
+    remote: &str,
+    refspecs: Vec<Refspec>,
+) -> Result<Vec<Refspec>, anyhow::Error> {
+    let configured = config
+        .get_all(&format!("remote.{}.fetch", remote))
+        .into_iter()
+        .map(|refspec| refspec.parse())
+        .collect::<Result<Vec<Refspec>, _>>()?;
+
+    if refspecs.is_empty() {
+        if configured.is_empty() {
+            // Without any refspecs, git only fetches HEAD.
+            return Ok(vec!["HEAD".parse()?]);
+        }
+        return Ok(configured);
+    }
+
+    Ok(refspecs
+        .into_iter()
+        .map(|refspec| {
+            if refspec.destination.is_some() || refspec.is_pattern() {
+                return refspec;
+            }
+
+            match configured
+                .iter()
+                .find_map(|c| c.map_source(&refspec.source).map(|d| (c.force, d)))
+            {
+                Some((force, destination)) => Refspec {
+                    force: refspec.force || force,
+                    source: refspec.source,
+                    destination: Some(destination),
+                },
+                None => refspec,
+            }
+        })
+        .collect())
+}
+
+fn map_refs(
+    remote_refs: &[GitRef],
+    refspecs: &[Refspec],
+) -> Result<Vec<RefMapping>, anyhow::Error> {
+    // HEAD isn't in the list of refs, only marked on the branch it points to.
+    let mut names: Vec<(&str, &ObjectHash)> = remote_refs
+        .iter()
+        .map(|r| (r.branch.as_str(), &r.commit_hash))
+        .collect();
+    if let Some(head) = remote_refs.iter().find(|r| r.is_head) {
+        names.push(("HEAD", &head.commit_hash));
+    }
+
+    let mut mappings = vec![];
+    for refspec in refspecs {
+        let mut matched = false;
+        for (name, hash) in &names {
+            if !refspec.matches_source(name) {
+                continue;
+            }
+
+            matched = true;
+            mappings.push(RefMapping {
+                source: name.to_string(),
+                hash: (*hash).clone(),
+                destination: refspec.map_source(name),
+                force: refspec.force,
+            });
+        }
+
+        if !matched && !refspec.is_pattern() {
+            return Err(anyhow::anyhow!(
+                "Couldn't find remote ref {}",
+                refspec.source
+            ));
+        }
+    }
+
+    Ok(mappings)
+}
+
+/// The most recent commits reachable from our branches and remote-tracking branches.
+fn local_haves(base_path: Option<&PathBuf>) -> Result<Vec<ObjectHash>, anyhow::Error> {
+    let mut starts = vec![];
+    for prefix in ["refs/heads/", "refs/remotes/"] {
+        for (_, hash) in refs::list_refs(base_path, prefix)? {
+            if !starts.contains(&hash) {
+                starts.push(hash);
+            }
+        }
+    }
+
+    history::walk_commits(base_path, &starts, Some(MAX_HAVES))
+}
+
+fn update_ref(
+    base_path: Option<&PathBuf>,
+    mapping: &RefMapping,
+    destination: &str,
+) -> Result<RefUpdate, anyhow::Error> {
+    let old_hash = refs::read_ref(base_path, destination)?;
+
+    let status = match &old_hash {
+        None => RefUpdateStatus::New,
+        Some(old_hash) if *old_hash == mapping.hash => RefUpdateStatus::UpToDate,
+        Some(old_hash) if history::is_ancestor(base_path, old_hash, &mapping.hash)? => {
+            RefUpdateStatus::FastForward
+        }
+        Some(_) if mapping.force => RefUpdateStatus::Forced,
+        Some(_) => RefUpdateStatus::Rejected,
+    };
+
+    if matches!(
+        status,
+        RefUpdateStatus::New | RefUpdateStatus::FastForward | RefUpdateStatus::Forced
+    ) {
+        refs::write_ref(base_path, destination, &mapping.hash)?;
+    }
+
+    Ok(RefUpdate {
+        source: Some(mapping.source.clone()),
+        destination: destination.to_string(),
+        old_hash,
+        new_hash: Some(mapping.hash.clone()),
+        status,
+    })
+}
+
+/// Delete the local refs that a refspec would have fetched into if their source still
+/// existed on the remote.
+fn prune_refs(
+    base_path: Option<&PathBuf>,
+    refspecs: &[Refspec],
+    mappings: &[RefMapping],
+) -> Result<Vec<RefUpdate>, anyhow::Error> {
+    let mut updates = vec![];
+
+    for refspec in refspecs.iter().filter(|r| r.is_pattern()) {
+        let destination = match &refspec.destination {
+            Some(destination) => destination,
+            None => continue,
+        };
+        let prefix = destination.split('*').next().unwrap_or_default();
+
+        for (name, hash) in refs::list_refs(base_path, prefix)? {
+            let source = match refspec.map_destination(&name) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            let fetched = mappings
+                .iter()
+                .any(|m| m.source == source || m.destination.as_deref() == Some(&name));
+            if fetched || updates.iter().any(|u: &RefUpdate| u.destination == name) {
+                continue;
+            }
+
+            refs::delete_ref(base_path, &name)?;
+            updates.push(RefUpdate {
+                source: None,
+                destination: name,
+                old_hash: Some(hash),
+                new_hash: None,
+                status: RefUpdateStatus::Deleted,
+            });
+        }
+    }
+
+    Ok(updates)
+}
+
+/// The part of fetching a packfile that doesn't depend on how we talk to the server, which
+/// `send` takes care of: it sends a request and returns the response to read.
+pub fn negotiate_pack<W, P, R, F>(
+    advertisement: &RefAdvertisement,
+    wants: &[&ObjectHash],
+    haves: &[ObjectHash],
+    options: &FetchOptions,
+    progress: &mut W,
+    pack: &mut P,
+    mut send: F,
+) -> Result<ShallowInfo, anyhow::Error>
+where
+    W: Write,
+    P: Write,
+    R: Read,
+    F: FnMut(ProtocolVersion, Vec<u8>) -> Result<R, anyhow::Error>,
+{
+    let mut negotiation = Negotiation::new(advertisement, wants, haves, options, progress)?;
+
+    loop {
+        let body = negotiation.next_request();
+        let response = send(advertisement.version, body).context("Failed to fetch packfile")?;
+
+        if let Some(shallow_info) = negotiation.read_response(response, pack, progress)? {
+            return Ok(shallow_info);
+        }
+    }
+}
+
+/// Where we are in negotiating a packfile with a smart server: the requests to send and
+/// what their responses told us, without sending or receiving anything itself. In protocol
+/// v0 there is a single request, answered with the packfile. In protocol v2, the haves are
+/// sent over several rounds so the server can tell us it has found enough common commits
+/// before we've sent all of them.
+pub struct Negotiation<'a> {
+    advertisement: &'a RefAdvertisement,
+    wants: &'a [&'a ObjectHash],
+    options: FetchOptions,
+    // The capabilities of a protocol v0 request.
+    capabilities: Vec<String>,
+    // Every request is stateless, so the haves the server has acknowledged are sent again
+    // in each round along with the next batch.
+    common: Vec<&'a ObjectHash>,
+    rounds: Peekable<Chunks<'a, ObjectHash>>,
+    batch: &'a [ObjectHash],
+    done: bool,
+}
+
+impl<'a> Negotiation<'a> {
+    pub fn new<W: Write>(
+        advertisement: &'a RefAdvertisement,
+        wants: &'a [&'a ObjectHash],
+        haves: &'a [ObjectHash],
+        options: &FetchOptions,
+        progress: &mut W,
+    ) -> Result<Self, anyhow::Error> {
+        let filter_supported = match advertisement.version {
+            ProtocolVersion::V0 => advertisement.has_capability("filter"),
+            ProtocolVersion::V2 => advertisement.has_command_feature("fetch", "filter"),
+            ProtocolVersion::Dumb => {
+                return Err(anyhow::anyhow!("A dumb server can't negotiate a packfile"))
+            }
+        };
+
+        let options = if options.filter.is_some() && !filter_supported {
+            writeln!(progress, "{}", FILTER_UNSUPPORTED_WARNING)?;
+            options.clone().with_filter(None)
+        } else {
+            options.clone()
+        };
+
+        let mut capabilities = vec![];
+        if advertisement.version == ProtocolVersion::V0 {
+            capabilities = protocol::upload_pack_capabilities(advertisement);
+            if options.is_shallow() {
+                let mut required = vec!["shallow"];
+                if matches!(options.deepen, Some(Deepen::Since(_))) {
+                    required.push("deepen-since");
+                }
+
+                for capability in required {
+                    if !advertisement.has_capability(capability) {
+                        return Err(shallow_unsupported());
+                    }
+                    capabilities.push(capability.to_string());
+                }
+            }
+            if options.filter.is_some() {
+                capabilities.push("filter".to_string());
+            }
+        } else if options.is_shallow() && !advertisement.has_command_feature("fetch", "shallow") {
+            return Err(shallow_unsupported());
+        }
+
+        Ok(Negotiation {
+            advertisement,
+            wants,
+            options,
+            capabilities,
+            common: vec![],
+            rounds: haves.chunks(HAVES_PER_ROUND).peekable(),
+            batch: &[],
+            done: false,
+        })
+    }
+
+    /// The body of the next request to send.
+    pub fn next_request(&mut self) -> Vec<u8> {
+        if self.advertisement.version == ProtocolVersion::V0 {
+            let haves: Vec<&ObjectHash> = self.rounds.by_ref().flatten().collect();
+            self.done = true;
+            return protocol::create_upload_pack_request(
+                self.wants,
+                &haves,
+                &self.options,
+                &self.capabilities,
+            );
+        }
+
+        self.batch = self.rounds.next().unwrap_or_default();
+        self.done = self.rounds.peek().is_none();
+
+        let mut haves = self.common.clone();
+        haves.extend(self.batch);
+        protocol::create_fetch_request(
+            self.advertisement,
+            self.wants,
+            &haves,
+            &self.options,
+            self.done,
+        )
+    }
+
+    /// Read the response to the last request, writing its packfile to `pack` as it arrives.
+    /// Returns the new boundary of a shallow history if there is a packfile, or `None` if
+    /// there's another round to go.
+    pub fn read_response<R: Read, P: Write, W: Write>(
+        &mut self,
+        response: R,
+        pack: &mut P,
+        progress: &mut W,
+    ) -> Result<Option<ShallowInfo>, anyhow::Error> {
+        let mut reader = BufReader::new(response);
+        let shallow_info = match self.read_response_head(&mut reader)? {
+            Some(shallow_info) => shallow_info,
+            None => return Ok(None),
+        };
+
+        // BEWARE: DO NOT CONVERT TO STRING
+        // Some of the response can be encoded to string
+        // but some of it can't. Calling `.text` is like calling
+        // .from_utf8_lossy - bytes that cannot be decoded to string
+        // are replaced with a special unicode character
+        match self.sideband() {
+            Sideband::Disabled => {
+                io::copy(&mut reader, pack)?;
+            }
+            _ => sideband::demultiplex(&mut reader, pack, progress)?,
+        }
+        Ok(Some(shallow_info))
+    }
+
+    /// Read the response to the last request up to its packfile, which is left in the
+    /// reader, like `read_response`. A response that has only partly arrived can be read
+    /// again from the start once more of it has.
+    pub fn read_response_head<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ShallowInfo>, anyhow::Error> {
+        if self.advertisement.version == ProtocolVersion::V0 {
+            let response = protocol::parse_upload_pack_response_head(reader, self.sideband())?;
+            return Ok(Some(response.shallow_info));
+        }
+
+        let response = protocol::parse_fetch_response_head(reader)?;
+        if response.pack.is_some() {
+            return Ok(Some(response.shallow_info));
+        }
+
+        if self.done {
+            return Err(anyhow::anyhow!(
+                "Invalid fetch response: no packfile section received"
+            ));
+        }
+
+        for acknowledgment in response.acknowledgments {
+            if let Some(have) = self.batch.iter().find(|have| **have == acknowledgment) {
+                if !self.common.contains(&have) {
+                    self.common.push(have);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// How the packfile is sent. It's always multiplexed in protocol v2.
+    pub fn sideband(&self) -> Sideband {
+        match self.advertisement.version {
+            ProtocolVersion::V0 => Sideband::negotiate(self.advertisement),
+            _ => Sideband::Sideband64k,
+        }
+    }
+}
+
+pub fn shallow_unsupported() -> anyhow::Error {
+    anyhow::anyhow!("Server does not support shallow clients")
+}
+
+pub fn print_updates(result: &FetchResult) {
+    if result
+        .updates
+        .iter()
+        .all(|u| u.status == RefUpdateStatus::UpToDate)
+    {
+        return;
+    }
+
+    println!("From {}", result.url);
+    for update in &result.updates {
+        let source = update
+            .source
+            .as_deref()
+            .map(refs::short_name)
+            .unwrap_or("(none)");
+        let destination = refs::short_name(&update.destination);
+
+        let (flag, summary, note) = match update.status {
+            RefUpdateStatus::UpToDate => continue,
+            RefUpdateStatus::New => {
+                let kind = match update.source.as_deref() {
+                    Some(source) if source.starts_with("refs/tags/") => "[new tag]",
+                    Some(source) if source.starts_with("refs/heads/") => "[new branch]",
+                    _ => "[new ref]",
+                };
+                ('*', kind.to_string(), "")
+            }
+            RefUpdateStatus::FastForward => (' ', update_range(update, ".."), ""),
+            RefUpdateStatus::Forced => ('+', update_range(update, "..."), "  (forced update)"),
+            RefUpdateStatus::Rejected => ('!', "[rejected]".to_string(), "  (non-fast-forward)"),
+            RefUpdateStatus::Deleted => ('-', "[deleted]".to_string(), ""),
+        };
+
+        println!(
+            " {} {:<17} {:<10} -> {}{}",
+            flag, summary, source, destination, note
+        );
+    }
+}
+
+fn update_range(update: &RefUpdate, separator: &str) -> String {
+    let abbreviate = |hash: &Option<ObjectHash>| {
+        hash.as_ref()
+            .map(|hash| hash.short_hash())
+            .unwrap_or_default()
+    };
+
+    format!(
+        "{}{}{}",
+        abbreviate(&update.old_hash),
+        separator,
+        abbreviate(&update.new_hash)
+    )
+}
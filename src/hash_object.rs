@@ -18,9 +18,9 @@ struct HashObjectConfig {
 
 pub fn hash_object_command(args: &[String]) -> Result<(), anyhow::Error> {
     let config = parse_config(args)?;
-    let mut file_contents = fs::read(config.file.as_str())?;
+    let file_contents = fs::read(config.file.as_str())?;
 
-    let hash = hash_and_write_object(None, &ObjectType::Blob, &mut file_contents)?;
+    let hash = hash_and_write_object(None, &ObjectType::Blob, &file_contents)?;
     print!("{}", &hash.full_hash());
 
     Ok(())
@@ -29,11 +29,11 @@ pub fn hash_object_command(args: &[String]) -> Result<(), anyhow::Error> {
 pub fn hash_and_write_object(
     base_path: Option<&PathBuf>,
     object_type: &ObjectType,
-    file_contents: &mut Vec<u8>,
+    file_contents: &[u8],
 ) -> Result<ObjectHash, anyhow::Error> {
     let mut header = create_header(object_type, file_contents);
 
-    header.append(file_contents);
+    header.extend(file_contents);
     let hash = hash_file(&header)?;
     let encoded_contents = encode_file_contents(header)?;
 
@@ -42,6 +42,17 @@ pub fn hash_and_write_object(
     Ok(hash)
 }
 
+/// Compute the hash an object would have without writing it, e.g. to check whether a
+/// file in the work tree has changed.
+pub fn hash_contents(
+    object_type: &ObjectType,
+    contents: &[u8],
+) -> Result<ObjectHash, anyhow::Error> {
+    let mut header = create_header(object_type, contents);
+    header.extend(contents);
+    hash_file(&header)
+}
+
 fn write_encoded_object(
     base_path: Option<&PathBuf>,
     hash: &ObjectHash,
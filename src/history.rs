@@ -0,0 +1,365 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::objects::{ObjectFile, ObjectHash, ObjectType};
+use crate::shallow;
+
+/// The parts of a commit object needed to walk history.
+/// CF https://git-scm.com/book/en/v2/Git-Internals-Git-Objects#_git_commit_objects
+#[derive(Debug)]
+pub struct Commit {
+    pub hash: ObjectHash,
+    pub tree: ObjectHash,
+    pub parents: Vec<ObjectHash>,
+    pub author: Option<String>,
+    pub committer: Option<String>,
+    pub message: String,
+}
+
+pub fn read_commit(
+    base_path: Option<&PathBuf>,
+    hash: &ObjectHash,
+) -> Result<Commit, anyhow::Error> {
+    let contents = match ObjectFile::new(base_path, hash)? {
+        ObjectFile::Other(contents) if contents.object_type == ObjectType::Commit => {
+            String::from_utf8(contents.contents)
+                .context("Parsing commit object contents as utf8")?
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Expected {} to be a commit",
+                hash.full_hash()
+            ))
+        }
+    };
+
+    parse_commit(hash, &contents)
+}
+
+/// Parse the body of a commit object, once its header has been removed.
+pub fn parse_commit(hash: &ObjectHash, contents: &str) -> Result<Commit, anyhow::Error> {
+    let mut tree = None;
+    let mut parents = vec![];
+    let mut author = None;
+    let mut committer = None;
+    let mut message_lines = vec![];
+    let mut in_headers = true;
+
+    for line in contents.lines() {
+        if in_headers {
+            // Commits written by `commit_tree` don't have the blank line between the
+            // headers and the message, so the first unknown line starts the message.
+            if line.is_empty() {
+                in_headers = false;
+                continue;
+            } else if let Some(hash) = line.strip_prefix("tree ") {
+                tree = Some(ObjectHash::new(hash)?);
+                continue;
+            } else if let Some(hash) = line.strip_prefix("parent ") {
+                parents.push(ObjectHash::new(hash)?);
+                continue;
+            } else if let Some(value) = line.strip_prefix("author ") {
+                author = Some(value.to_string());
+                continue;
+            } else if let Some(value) = line.strip_prefix("committer ") {
+                committer = Some(value.to_string());
+                continue;
+            } else if line.starts_with(' ') || line.starts_with("gpgsig ") {
+                continue;
+            }
+
+            in_headers = false;
+        }
+
+        message_lines.push(line);
+    }
+
+    let tree = tree.ok_or_else(|| anyhow::anyhow!("No tree hash found in commit"))?;
+
+    Ok(Commit {
+        hash: hash.clone(),
+        tree,
+        parents,
+        author,
+        committer,
+        message: message_lines.join("\n"),
+    })
+}
+
+/// Walk the history from the starting commits, newest first, visiting each commit once.
+/// Shallow commits and commits whose objects aren't present are treated as roots. At most
+/// `limit` commits are returned if a limit is given.
+pub fn walk_commits(
+    base_path: Option<&PathBuf>,
+    starts: &[ObjectHash],
+    limit: Option<usize>,
+) -> Result<Vec<ObjectHash>, anyhow::Error> {
+    let shallow = shallow::read_shallow(base_path)?;
+    let mut seen: HashSet<ObjectHash> = HashSet::new();
+    let mut queue: VecDeque<ObjectHash> = VecDeque::new();
+    let mut commits = vec![];
+
+    for start in starts {
+        if seen.insert(start.clone()) {
+            queue.push_back(start.clone());
+        }
+    }
+
+    while let Some(hash) = queue.pop_front() {
+        if limit.is_some_and(|limit| commits.len() >= limit) {
+            break;
+        }
+
+        if !ObjectFile::exists(base_path, &hash) {
+            continue;
+        }
+
+        // The parents of a shallow commit are missing even if it lists them.
+        if !shallow.contains(&hash) {
+            let commit = read_commit(base_path, &hash)?;
+            for parent in commit.parents {
+                if seen.insert(parent.clone()) {
+                    queue.push_back(parent);
+                }
+            }
+        }
+
+        commits.push(hash);
+    }
+
+    Ok(commits)
+}
+
+/// Whether `ancestor` can be reached by following the parents of `descendant`.
+/// A commit is its own ancestor.
+pub fn is_ancestor(
+    base_path: Option<&PathBuf>,
+    ancestor: &ObjectHash,
+    descendant: &ObjectHash,
+) -> Result<bool, anyhow::Error> {
+    if !is_commit(base_path, descendant) {
+        return Ok(false);
+    }
+
+    let commits = walk_commits(base_path, std::slice::from_ref(descendant), None)?;
+    Ok(commits.contains(ancestor))
+}
+
+/// The most recent commit both commits descend from, which a merge uses as its base. Of
+/// the commits both descend from, only those that aren't an ancestor of another one are
+/// candidates. Histories with several candidates, such as criss-cross merges, are refused
+/// since we can't merge the candidates into one base like git does.
+pub fn merge_base(
+    base_path: Option<&PathBuf>,
+    first: &ObjectHash,
+    second: &ObjectHash,
+) -> Result<Option<ObjectHash>, anyhow::Error> {
+    let ancestors: HashSet<ObjectHash> =
+        walk_commits(base_path, std::slice::from_ref(first), None)?
+            .into_iter()
+            .collect();
+    let common: Vec<ObjectHash> = walk_commits(base_path, std::slice::from_ref(second), None)?
+        .into_iter()
+        .filter(|hash| ancestors.contains(hash))
+        .collect();
+
+    // Everything reachable from the parents of a common ancestor is older than it.
+    let shallow = shallow::read_shallow(base_path)?;
+    let mut parents = vec![];
+    for hash in common.iter().filter(|hash| !shallow.contains(hash)) {
+        parents.extend(read_commit(base_path, hash)?.parents);
+    }
+    let older: HashSet<ObjectHash> = walk_commits(base_path, &parents, None)?
+        .into_iter()
+        .collect();
+
+    let mut bases: Vec<ObjectHash> = common
+        .into_iter()
+        .filter(|hash| !older.contains(hash))
+        .collect();
+    if bases.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "Found {} merge bases for {} and {}, merging them is not supported",
+            bases.len(),
+            first.full_hash(),
+            second.full_hash()
+        ));
+    }
+
+    Ok(bases.pop())
+}
+
+/// Every object reachable from the `include` commits that isn't reachable from the `exclude`
+/// commits, like `git rev-list --objects <include> --not <exclude>`. Excluded commits that
+/// aren't present are ignored. Annotated tags are followed to the object they point to.
+pub fn list_objects(
+    base_path: Option<&PathBuf>,
+    include: &[ObjectHash],
+    exclude: &[ObjectHash],
+) -> Result<Vec<ObjectHash>, anyhow::Error> {
+    let exclude: Vec<ObjectHash> = exclude
+        .iter()
+        .filter(|hash| is_commit(base_path, hash))
+        .cloned()
+        .collect();
+    let excluded_commits: HashSet<ObjectHash> = walk_commits(base_path, &exclude, None)?
+        .into_iter()
+        .collect();
+
+    // The other side has every tree and blob of the excluded tips, so there's no need to
+    // send them again. Older objects that reappear in the new commits will be sent anyway.
+    let mut seen: HashSet<ObjectHash> = HashSet::new();
+    let mut ignored = vec![];
+    for hash in &exclude {
+        let commit = read_commit(base_path, hash)?;
+        collect_tree(base_path, &commit.tree, &mut seen, &mut ignored)?;
+    }
+
+    let mut objects = vec![];
+    let mut commits = vec![];
+    for hash in include {
+        let target = peel_tags(base_path, hash, &mut seen, &mut objects)?;
+        if !excluded_commits.contains(&target) {
+            commits.push(target);
+        }
+    }
+
+    let shallow = shallow::read_shallow(base_path)?;
+    let mut queue: VecDeque<ObjectHash> = commits.into_iter().collect();
+    while let Some(hash) = queue.pop_front() {
+        if excluded_commits.contains(&hash) || !seen.insert(hash.clone()) {
+            continue;
+        }
+
+        let commit = read_commit(base_path, &hash)?;
+        if !shallow.contains(&hash) {
+            queue.extend(commit.parents);
+        }
+        objects.push(hash);
+        collect_tree(base_path, &commit.tree, &mut seen, &mut objects)?;
+    }
+
+    Ok(objects)
+}
+
+/// The object an annotated tag points to, following tags of tags. Anything else is returned
+/// as it is.
+pub fn peel_tag(
+    base_path: Option<&PathBuf>,
+    hash: &ObjectHash,
+) -> Result<ObjectHash, anyhow::Error> {
+    peel_tags(base_path, hash, &mut HashSet::new(), &mut vec![])
+}
+
+/// Follow annotated tags until we reach something that isn't a tag, adding the tags to the objects.
+fn peel_tags(
+    base_path: Option<&PathBuf>,
+    hash: &ObjectHash,
+    seen: &mut HashSet<ObjectHash>,
+    objects: &mut Vec<ObjectHash>,
+) -> Result<ObjectHash, anyhow::Error> {
+    let mut hash = hash.clone();
+
+    loop {
+        let contents = match ObjectFile::new(base_path, &hash)? {
+            ObjectFile::Other(contents) if contents.object_type == ObjectType::Tag => {
+                contents.contents
+            }
+            _ => return Ok(hash),
+        };
+
+        if seen.insert(hash.clone()) {
+            objects.push(hash.clone());
+        }
+
+        let contents = String::from_utf8(contents).context("Parsing tag object as utf8")?;
+        let target = contents
+            .lines()
+            .find_map(|line| line.strip_prefix("object "))
+            .ok_or_else(|| anyhow::anyhow!("No object found in tag {}", hash.full_hash()))?;
+        hash = ObjectHash::new(target)?;
+    }
+}
+
+fn collect_tree(
+    base_path: Option<&PathBuf>,
+    tree_hash: &ObjectHash,
+    seen: &mut HashSet<ObjectHash>,
+    objects: &mut Vec<ObjectHash>,
+) -> Result<(), anyhow::Error> {
+    if !seen.insert(tree_hash.clone()) {
+        return Ok(());
+    }
+    objects.push(tree_hash.clone());
+
+    let entries = match ObjectFile::new(base_path, tree_hash)? {
+        ObjectFile::Tree(contents) => contents.contents,
+        ObjectFile::Other(_) => return Err(anyhow::anyhow!("Expected tree object")),
+    };
+
+    for entry in entries {
+        match entry.object_type {
+            ObjectType::Tree => collect_tree(base_path, &entry.hash, seen, objects)?,
+            // Submodules point to commits in another repository.
+            ObjectType::Commit => {}
+            _ => {
+                if seen.insert(entry.hash.clone()) {
+                    objects.push(entry.hash);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the object is a commit we have.
+pub fn is_commit(base_path: Option<&PathBuf>, hash: &ObjectHash) -> bool {
+    matches!(
+        ObjectFile::new(base_path, hash),
+        Ok(ObjectFile::Other(contents)) if contents.object_type == ObjectType::Commit
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_1: &str = "0123456789abcdef0123456789abcdef01234567";
+    const HASH_2: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn parse_commit_without_blank_line() {
+        let contents = format!(
+            "tree {}\nparent {}\nauthor A <a@a.com>\ncommitter C <c@c.com>\nMessage\n",
+            HASH_1, HASH_2
+        );
+        let commit = parse_commit(&ObjectHash::new(HASH_1).unwrap(), &contents).unwrap();
+
+        assert_eq!(commit.tree.full_hash(), HASH_1);
+        assert_eq!(commit.parents.len(), 1);
+        assert_eq!(commit.author.unwrap(), "A <a@a.com>");
+        assert_eq!(commit.message, "Message");
+    }
+
+    #[test]
+    fn parse_commit_with_blank_line_and_merge_parents() {
+        let contents = format!(
+            "tree {}\nparent {}\nparent {}\nauthor A <a@a.com> 0 +0000\n\nLine 1\n\nLine 2\n",
+            HASH_1, HASH_1, HASH_2
+        );
+        let commit = parse_commit(&ObjectHash::new(HASH_1).unwrap(), &contents).unwrap();
+
+        assert_eq!(commit.parents.len(), 2);
+        assert_eq!(commit.committer, None);
+        assert_eq!(commit.message, "Line 1\n\nLine 2");
+    }
+
+    #[test]
+    fn parse_commit_errors_without_tree() {
+        let got = parse_commit(&ObjectHash::new(HASH_1).unwrap(), "Message\n");
+        assert!(got.is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use std::sync::Mutex;
+
+use anyhow::Context;
+use bytes::Bytes;
+
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use reqwest::{StatusCode, Url};
+
+use crate::config::Config;
+use crate::credential::{self, Credential, CredentialSources};
+use crate::dumb_http;
+use crate::http_config::{BodyReader, HttpSettings};
+use crate::protocol::{self, ProtocolVersion, RefAdvertisement};
+
+pub const UPLOAD_PACK: &str = "git-upload-pack";
+pub const GIT_PROTOCOL_HEADER: &str = "Git-Protocol";
+
+// https://www.git-scm.com/docs/http-protocol
+// We use blocking calls for simplicity/ease of use. The async versions for running many
+// clones at once without a thread each are in `nonblocking`.
+
+/// The HTTP client for talking to a remote. It remembers the credentials that worked so
+/// they are only asked for once, even though every request needs them.
+pub struct HttpClient {
+    client: Client,
+    settings: HttpSettings,
+    sources: CredentialSources,
+    credential: Mutex<Option<Credential>>,
+}
+
+impl HttpClient {
+    pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
+        let settings = HttpSettings::from_config(config)?;
+        Ok(HttpClient {
+            client: settings.build_client()?,
+            settings,
+            sources: CredentialSources::from_config(config),
+            credential: Mutex::new(None),
+        })
+    }
+
+    /// Send the request built for the URL, authenticating if the server asks us to with a
+    /// 401. Credentials in the URL are used from the start. Otherwise we ask for them and
+    /// retry once, telling the helpers whether they worked. Only `retryable` requests are
+    /// retried when they fail.
+    fn send<F>(&self, url: &str, retryable: bool, build: F) -> Result<Response, anyhow::Error>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+    {
+        let (url, url_credential) = split_credentials(url)?;
+        let mut credential = self.credential.lock().unwrap();
+        if credential.is_none() {
+            *credential = url_credential;
+        }
+
+        let response = self.settings.send_with_retries(retryable, || {
+            authorize(build(&self.client, &url), credential.as_ref()).send()
+        })?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let authentication_failed = || anyhow::anyhow!("Authentication failed for '{}'", url);
+
+        // Credentials that were already complete were wrong, so there's nothing to retry with.
+        let mut attempt = match credential.take() {
+            Some(rejected) if rejected.is_complete() => {
+                self.sources.reject(&rejected);
+                return Err(authentication_failed());
+            }
+            Some(partial) => partial,
+            None => credential_for_url(&url)?,
+        };
+        attempt.wwwauth = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|header| header.to_str().ok().map(String::from))
+            .collect();
+        self.sources.fill(&mut attempt)?;
+
+        let response = self.settings.send_with_retries(retryable, || {
+            authorize(build(&self.client, &url), Some(&attempt)).send()
+        })?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.sources.reject(&attempt);
+            return Err(authentication_failed());
+        }
+
+        if response.status().is_success() {
+            self.sources.approve(&attempt);
+        }
+        *credential = Some(attempt);
+
+        Ok(response)
+    }
+}
+
+/// Remove the username and password from a URL, returning them as a credential.
+pub fn split_credentials(url: &str) -> Result<(String, Option<Credential>), anyhow::Error> {
+    let mut parsed = Url::parse(url).context(format!("Invalid URL {}", url))?;
+    if parsed.username().is_empty() && parsed.password().is_none() {
+        return Ok((url.to_string(), None));
+    }
+
+    let mut credential = credential_for_url(url)?;
+    credential.username = Some(credential::percent_decode(parsed.username()));
+    credential.password = parsed.password().map(credential::percent_decode);
+    credential.check()?;
+
+    let _ = parsed.set_username("");
+    let _ = parsed.set_password(None);
+    // Url adds a trailing slash to bare hosts, which would break the paths we append.
+    let stripped = parsed.to_string();
+    let stripped = match url.ends_with('/') {
+        true => stripped,
+        false => stripped.trim_end_matches('/').to_string(),
+    };
+
+    Ok((stripped, Some(credential)))
+}
+
+pub fn credential_for_url(url: &str) -> Result<Credential, anyhow::Error> {
+    let parsed = Url::parse(url).context(format!("Invalid URL {}", url))?;
+    let host = match parsed.port() {
+        Some(port) => format!("{}:{}", parsed.host_str().unwrap_or_default(), port),
+        None => parsed.host_str().unwrap_or_default().to_string(),
+    };
+
+    Ok(Credential::new(parsed.scheme(), &host))
+}
+
+fn authorize(request: RequestBuilder, credential: Option<&Credential>) -> RequestBuilder {
+    let credential = match credential {
+        Some(credential) => credential,
+        None => return request,
+    };
+
+    match (&credential.authtype, &credential.credential) {
+        (Some(authtype), Some(token)) => {
+            request.header(AUTHORIZATION, format!("{} {}", authtype, token))
+        }
+        _ => match &credential.username {
+            Some(username) => request.basic_auth(username, credential.password.as_ref()),
+            None => request,
+        },
+    }
+}
+
+/// Request the refs and capabilities of the server. We always ask for protocol v2, and servers
+/// that don't support it ignore the header and answer with a v0 advertisement.
+pub fn discover_references(
+    client: &HttpClient,
+    url: &str,
+    service_name: &str,
+) -> Result<RefAdvertisement, anyhow::Error> {
+    let resp = client.send(url, true, |client, url| {
+        let request_url = format!("{}/info/refs?service={}", url, service_name);
+        with_protocol_header(client.get(request_url), ProtocolVersion::V2)
+    })?;
+
+    let status = resp.status().as_u16();
+    if status != 200 && status != 304 {
+        return Err(anyhow::anyhow!(format!(
+            "Failed to get refs: Status code must be either 200 or 304, received {}",
+            status
+        )));
+    }
+
+    let want_content_type = format!("application/x-{}-advertisement", service_name);
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    // Without the smart content type, the server is only serving the files in the
+    // repository, which we can still fetch from but not push to.
+    if content_type != want_content_type {
+        if service_name != UPLOAD_PACK {
+            return Err(anyhow::anyhow!(format!(
+                "Content-Type must equal {}, received {}",
+                want_content_type, content_type
+            )));
+        }
+
+        let bytes = client.settings.read_body(resp)?;
+        return dumb_http::discover_references(client, url, &bytes).context(format!(
+            "Content-Type was {} instead of {}, so we tried the dumb protocol",
+            content_type, want_content_type
+        ));
+    }
+
+    let bytes = client.settings.read_body(resp)?;
+    protocol::parse_advertisement(&bytes, service_name)
+}
+
+/// Download a file from the repository on the server, such as a loose object or a packfile,
+/// as the dumb protocol does. Returns `None` if the server doesn't have it.
+pub fn get_file(
+    client: &HttpClient,
+    url: &str,
+    path: &str,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let resp = client.send(url, true, |client, url| {
+        client.get(format!("{}/{}", url, path))
+    })?;
+
+    match resp.status() {
+        StatusCode::OK => Ok(Some(client.settings.read_body(resp)?)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(anyhow::anyhow!(
+            "Failed to get {}: received status code {}",
+            path,
+            status.as_u16()
+        )),
+    }
+}
+
+/// Get a file like `get_file`, but read it as it arrives, for files such as packfiles that
+/// can be too large to keep in memory.
+pub fn stream_file(
+    client: &HttpClient,
+    url: &str,
+    path: &str,
+) -> Result<Option<BodyReader>, anyhow::Error> {
+    let resp = client.send(url, true, |client, url| {
+        client.get(format!("{}/{}", url, path))
+    })?;
+
+    match resp.status() {
+        StatusCode::OK => Ok(Some(client.settings.body_reader(resp))),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(anyhow::anyhow!(
+            "Failed to get {}: received status code {}",
+            path,
+            status.as_u16()
+        )),
+    }
+}
+
+pub fn post_service_request(
+    client: &HttpClient,
+    url: &str,
+    version: ProtocolVersion,
+    service_name: &str,
+    body: Vec<u8>,
+) -> Result<Bytes, anyhow::Error> {
+    let resp = send_service_request(client, url, version, service_name, body)?;
+    let bytes = client.settings.read_body(resp)?;
+    Ok(Bytes::from(bytes))
+}
+
+/// Post the request like `post_service_request`, but read the response as it arrives, for
+/// responses such as packfiles that can be too large to keep in memory.
+pub fn stream_service_request(
+    client: &HttpClient,
+    url: &str,
+    version: ProtocolVersion,
+    service_name: &str,
+    body: Vec<u8>,
+) -> Result<BodyReader, anyhow::Error> {
+    let resp = send_service_request(client, url, version, service_name, body)?;
+    Ok(client.settings.body_reader(resp))
+}
+
+fn send_service_request(
+    client: &HttpClient,
+    url: &str,
+    version: ProtocolVersion,
+    service_name: &str,
+    body: Vec<u8>,
+) -> Result<Response, anyhow::Error> {
+    let want_content_type = format!("application/x-{}-result", service_name);
+
+    // Fetching can be asked for again, but a push may already have updated refs.
+    let retryable = service_name == UPLOAD_PACK;
+    let resp = client.send(url, retryable, |client, url| {
+        let request = client
+            .post(format!("{}/{}", url, service_name))
+            .body(body.clone())
+            .header(
+                CONTENT_TYPE,
+                format!("application/x-{}-request", service_name),
+            )
+            .header(ACCEPT, &want_content_type);
+        with_protocol_header(request, version)
+    })?;
+
+    let status = resp.status().as_u16();
+    if status != 200 && status != 304 {
+        return Err(anyhow::anyhow!(format!(
+            "Status code must be either 200 or 304, received {}",
+            status
+        )));
+    }
+
+    let headers = resp.headers();
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .ok_or_else(|| anyhow::anyhow!("Content-Type must equal {}", want_content_type))?
+        .to_str()?;
+
+    if content_type != want_content_type {
+        return Err(anyhow::anyhow!(
+            "Content-Type must equal {}, received {}",
+            want_content_type,
+            content_type
+        ));
+    }
+
+    Ok(resp)
+}
+
+fn with_protocol_header(request: RequestBuilder, version: ProtocolVersion) -> RequestBuilder {
+    match version {
+        ProtocolVersion::V2 => request.header(GIT_PROTOCOL_HEADER, protocol::PROTOCOL_V2),
+        ProtocolVersion::V0 | ProtocolVersion::Dumb => request,
+    }
+}
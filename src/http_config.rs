@@ -0,0 +1,424 @@
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{Certificate, Proxy, StatusCode};
+
+use crate::config::Config;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_MAX_RETRY_TIME: Duration = Duration::from_secs(300);
+// Without a Retry-After header, we wait this long before the first retry and double it
+// for every one after.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How we talk HTTP, from `http.*` config or the environment variables git uses for the
+/// same settings, which take precedence. Proxies in `HTTPS_PROXY`, `HTTP_PROXY`,
+/// `ALL_PROXY` and `NO_PROXY` are used unless `http.proxy` is set.
+/// CF https://git-scm.com/docs/git-config#Documentation/git-config.txt-http
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpSettings {
+    pub proxy: Option<String>,
+    pub ssl_ca_info: Option<PathBuf>,
+    pub ssl_verify: bool,
+    pub extra_headers: Vec<(String, String)>,
+    pub connect_timeout: Option<Duration>,
+    // A transfer slower than the limit, in bytes per second, for longer than the time is
+    // aborted, as is one that stalls for that long.
+    pub low_speed_limit: u64,
+    pub low_speed_time: Option<Duration>,
+    // Transient errors are retried this many times, waiting at most the retry time.
+    pub max_retries: u32,
+    pub max_retry_time: Duration,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            proxy: None,
+            ssl_ca_info: None,
+            ssl_verify: true,
+            extra_headers: vec![],
+            connect_timeout: None,
+            low_speed_limit: 0,
+            low_speed_time: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_retry_time: DEFAULT_MAX_RETRY_TIME,
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn from_config(config: &Config) -> Result<Self, anyhow::Error> {
+        let defaults = HttpSettings::default();
+
+        let ssl_verify = match setting(config, "http.sslverify", "GIT_SSL_NO_VERIFY") {
+            // Any value of GIT_SSL_NO_VERIFY turns verification off.
+            Some(Source::Env(_)) => false,
+            Some(Source::Config(_)) => config.get_bool("http.sslverify")?.unwrap_or(true),
+            None => true,
+        };
+
+        // Like git, an empty header clears the ones before it.
+        let mut extra_headers = vec![];
+        for header in config.get_all("http.extraheader") {
+            if header.is_empty() {
+                extra_headers.clear();
+                continue;
+            }
+
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Invalid http.extraHeader {}", header))?;
+            extra_headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let seconds = |key: &str, env: &str| -> Result<Option<Duration>, anyhow::Error> {
+            match setting(config, key, env) {
+                Some(value) => Ok(Some(Duration::from_secs(parse_number(
+                    key,
+                    value.as_str(),
+                )?))),
+                None => Ok(None),
+            }
+        };
+
+        let low_speed_limit =
+            match setting(config, "http.lowspeedlimit", "GIT_HTTP_LOW_SPEED_LIMIT") {
+                Some(value) => parse_number("http.lowSpeedLimit", value.as_str())?,
+                None => defaults.low_speed_limit,
+            };
+        let max_retries = match setting(config, "http.maxretries", "GIT_HTTP_MAX_RETRIES") {
+            Some(value) => parse_number("http.maxRetries", value.as_str())? as u32,
+            None => defaults.max_retries,
+        };
+
+        Ok(HttpSettings {
+            proxy: config
+                .get("http.proxy")
+                .filter(|proxy| !proxy.is_empty())
+                .map(String::from),
+            ssl_ca_info: setting(config, "http.sslcainfo", "GIT_SSL_CAINFO")
+                .map(|value| PathBuf::from(value.as_str())),
+            ssl_verify,
+            extra_headers,
+            connect_timeout: seconds("http.connecttimeout", "GIT_HTTP_CONNECT_TIMEOUT")?,
+            low_speed_limit,
+            low_speed_time: seconds("http.lowspeedtime", "GIT_HTTP_LOW_SPEED_TIME")?
+                .filter(|time| !time.is_zero()),
+            max_retries,
+            max_retry_time: seconds("http.maxretrytime", "GIT_HTTP_MAX_RETRY_TIME")?
+                .unwrap_or(defaults.max_retry_time),
+        })
+    }
+
+    pub fn build_client(&self) -> Result<Client, anyhow::Error> {
+        let mut builder = Client::builder()
+            .default_headers(self.default_headers()?)
+            .danger_accept_invalid_certs(!self.ssl_verify);
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy).context(format!("Invalid proxy {}", proxy))?);
+        }
+        for certificate in self.root_certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        // The blocking client applies its timeout to every read of the body, so a transfer
+        // that stalls for the low speed time is aborted.
+        if let Some(time) = self.low_speed_time {
+            builder = builder.timeout(time);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// The same client for async requests. Its timeout would apply to the whole transfer,
+    /// so stalled transfers are caught by reading the body with `LowSpeedCheck::time`.
+    #[cfg(feature = "async")]
+    pub fn build_async_client(&self) -> Result<reqwest::Client, anyhow::Error> {
+        let mut builder = reqwest::Client::builder()
+            .default_headers(self.default_headers()?)
+            .danger_accept_invalid_certs(!self.ssl_verify);
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy).context(format!("Invalid proxy {}", proxy))?);
+        }
+        for certificate in self.root_certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    fn default_headers(&self) -> Result<HeaderMap, anyhow::Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.extra_headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes())
+                    .context(format!("Invalid header name {}", name))?,
+                HeaderValue::from_str(value)
+                    .context(format!("Invalid value for header {}", name))?,
+            );
+        }
+        Ok(headers)
+    }
+
+    fn root_certificates(&self) -> Result<Vec<Certificate>, anyhow::Error> {
+        let path = match &self.ssl_ca_info {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+
+        let pem = fs::read(path).context(format!("Reading CA bundle {:?}", path))?;
+        Certificate::from_pem_bundle(&pem).context(format!("Parsing CA bundle {:?}", path))
+    }
+
+    /// Send a request, retrying connection errors and 5xx responses that might go away.
+    /// We wait as long as the server asks with Retry-After, or back off exponentially.
+    /// Requests that aren't `retryable` are sent once, since like git we don't risk
+    /// repeating what isn't safe to do twice, such as updating refs in a push.
+    pub fn send_with_retries<F>(&self, retryable: bool, send: F) -> Result<Response, anyhow::Error>
+    where
+        F: Fn() -> Result<Response, reqwest::Error>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let result = send();
+            if !retryable {
+                return Ok(result?);
+            }
+            let outcome = result
+                .as_ref()
+                .map(|response| (response.status(), response.headers()));
+            match self.retry_delay(attempt, outcome) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Ok(result?),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// How long to wait before sending a request again after the status and headers of its
+    /// response, or the error sending it, or `None` if it shouldn't be retried.
+    pub fn retry_delay(
+        &self,
+        attempt: u32,
+        outcome: Result<(StatusCode, &HeaderMap), &reqwest::Error>,
+    ) -> Option<Duration> {
+        let delay = INITIAL_RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt));
+        let retry_after = match outcome {
+            Ok((status, headers)) if is_transient_status(status) => {
+                retry_after(headers).unwrap_or(delay)
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => delay,
+            _ => return None,
+        };
+
+        if attempt >= self.max_retries || retry_after > self.max_retry_time {
+            return None;
+        }
+        Some(retry_after)
+    }
+
+    /// Read the whole body, aborting if it arrives slower than the low speed limit.
+    pub fn read_body(&self, response: Response) -> Result<Vec<u8>, anyhow::Error> {
+        let mut body = vec![];
+        self.body_reader(response)
+            .read_to_end(&mut body)
+            .context("Reading response body")?;
+        Ok(body)
+    }
+
+    /// Read the body as it arrives rather than all at once, for bodies too large to keep
+    /// in memory, with the same low speed limit as `read_body`.
+    pub fn body_reader(&self, response: Response) -> BodyReader {
+        BodyReader {
+            response,
+            low_speed_check: self.low_speed_check(),
+        }
+    }
+
+    pub fn low_speed_check(&self) -> LowSpeedCheck {
+        LowSpeedCheck {
+            limit: self.low_speed_limit,
+            time: self.low_speed_time,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+}
+
+/// Measures a transfer as its data arrives, to abort it once it has been slower than the
+/// low speed limit for the low speed time.
+pub struct LowSpeedCheck {
+    limit: u64,
+    time: Option<Duration>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl LowSpeedCheck {
+    /// How long a transfer can stall for.
+    pub fn time(&self) -> Option<Duration> {
+        self.time
+    }
+
+    pub fn record(&mut self, bytes: usize) -> Result<(), anyhow::Error> {
+        let time = match self.time {
+            Some(time) if self.limit > 0 => time,
+            _ => return Ok(()),
+        };
+
+        self.window_bytes += bytes as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < time {
+            return Ok(());
+        }
+
+        if (self.window_bytes as f64 / elapsed.as_secs_f64()) < self.limit as f64 {
+            return Err(anyhow::anyhow!(
+                "Transfer was slower than {} bytes/s for {} seconds",
+                self.limit,
+                time.as_secs()
+            ));
+        }
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        Ok(())
+    }
+}
+
+pub struct BodyReader {
+    response: Response,
+    low_speed_check: LowSpeedCheck,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.response.read(buf)?;
+        self.low_speed_check
+            .record(read)
+            .map_err(|e| io::Error::new(ErrorKind::TimedOut, e.to_string()))?;
+        Ok(read)
+    }
+}
+
+enum Source<'a> {
+    Env(String),
+    Config(&'a str),
+}
+
+impl Source<'_> {
+    fn as_str(&self) -> &str {
+        match self {
+            Source::Env(value) => value,
+            Source::Config(value) => value,
+        }
+    }
+}
+
+// The environment variable overrides the config.
+fn setting<'a>(config: &'a Config, key: &str, env: &str) -> Option<Source<'a>> {
+    match std::env::var(env) {
+        Ok(value) => Some(Source::Env(value)),
+        Err(_) => config.get(key).map(Source::Config),
+    }
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u64, anyhow::Error> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid value {} for {}, expected a number", value, key))
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// Only the delay in seconds form of Retry-After, not an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_config_reads_http_settings() {
+        let mut config = Config::load(Some(&PathBuf::from("does-not-exist"))).unwrap();
+        config.add("http.extraHeader", "X-Dropped: 1").unwrap();
+        config.add("http.extraHeader", "").unwrap();
+        config.add("http.extraHeader", "X-Team: infra").unwrap();
+        config
+            .add("http.extraHeader", "Authorization: Bearer abc")
+            .unwrap();
+        config
+            .set("http.proxy", "http://proxy.internal:3128")
+            .unwrap();
+        config.set("http.connectTimeout", "5").unwrap();
+        config.set("http.lowSpeedLimit", "1000").unwrap();
+        config.set("http.lowSpeedTime", "30").unwrap();
+        config.set("http.maxRetries", "7").unwrap();
+
+        let settings = HttpSettings::from_config(&config).unwrap();
+
+        assert_eq!(
+            settings.extra_headers,
+            vec![
+                ("X-Team".to_string(), "infra".to_string()),
+                ("Authorization".to_string(), "Bearer abc".to_string()),
+            ]
+        );
+        assert_eq!(
+            settings.proxy.as_deref(),
+            Some("http://proxy.internal:3128")
+        );
+        assert_eq!(settings.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(settings.low_speed_limit, 1000);
+        assert_eq!(settings.low_speed_time, Some(Duration::from_secs(30)));
+        assert_eq!(settings.max_retries, 7);
+        assert!(settings.ssl_verify);
+    }
+
+    #[test]
+    fn from_config_rejects_invalid_values() {
+        let mut config = Config::load(Some(&PathBuf::from("does-not-exist"))).unwrap();
+        config.set("http.lowSpeedTime", "soon").unwrap();
+        assert!(HttpSettings::from_config(&config).is_err());
+
+        let mut config = Config::load(Some(&PathBuf::from("does-not-exist"))).unwrap();
+        config.set("http.extraHeader", "no colon").unwrap();
+        assert!(HttpSettings::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn build_client_errors_on_missing_ca_bundle() {
+        let settings = HttpSettings {
+            ssl_ca_info: Some(PathBuf::from("/does/not/exist.pem")),
+            ..Default::default()
+        };
+
+        let got = settings.build_client().unwrap_err();
+        assert!(got.to_string().contains("CA bundle"));
+    }
+}
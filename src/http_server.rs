@@ -0,0 +1,440 @@
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::Config;
+use crate::http::UPLOAD_PACK;
+use crate::local::LocalRepository;
+use crate::pkt_line;
+use crate::push::RECEIVE_PACK;
+use crate::server::{self, MAX_REQUEST_SIZE};
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 8080;
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+// How long a client can go without sending anything before its connection is dropped, so
+// slow clients can't hold connections open.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serves the repositories in a directory over smart HTTP, like `git http-backend` behind a
+/// web server. A repository is at the URL of its path relative to the directory, or at the
+/// root if the directory is a repository itself. Like git, pushing has to be enabled in
+/// each repository with `http.receivepack`.
+/// CF https://git-scm.com/docs/git-http-backend
+pub struct HttpServer {
+    listener: std::net::TcpListener,
+    root: PathBuf,
+    read_timeout: Duration,
+}
+
+struct ServeArgs {
+    address: String,
+    port: u16,
+    root: PathBuf,
+}
+
+pub fn serve_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let args = parse_serve_args(args)?;
+    let server = HttpServer::bind(&format!("{}:{}", args.address, args.port), &args.root)?;
+
+    println!(
+        "Serving {} at http://{}",
+        server.root.display(),
+        server.local_addr()?
+    );
+    server.run()
+}
+
+fn parse_serve_args(args: &[String]) -> Result<ServeArgs, anyhow::Error> {
+    let usage =
+        || anyhow::anyhow!("Usage: serve [--listen=<address>] [--port=<port>] [<directory>]");
+
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut port = DEFAULT_PORT;
+    let mut root = None;
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--listen=") {
+            address = value.to_string();
+        } else if let Some(value) = arg.strip_prefix("--port=") {
+            port = value.parse().map_err(|_| usage())?;
+        } else if arg.starts_with('-') || root.is_some() {
+            return Err(usage());
+        } else {
+            root = Some(PathBuf::from(arg));
+        }
+    }
+
+    Ok(ServeArgs {
+        address,
+        port,
+        root: root.unwrap_or_else(|| PathBuf::from(".")),
+    })
+}
+
+impl HttpServer {
+    /// Listen on the address, which can have port 0 to pick any free port.
+    pub fn bind(address: &str, root: &Path) -> Result<Self, anyhow::Error> {
+        let listener =
+            std::net::TcpListener::bind(address).context(format!("Listening on {}", address))?;
+        listener.set_nonblocking(true)?;
+        let root = root
+            .canonicalize()
+            .context(format!("Serving {}", root.display()))?;
+
+        Ok(HttpServer {
+            listener,
+            root,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        })
+    }
+
+    /// Drop connections that send nothing for this long while their request is read.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answer requests until the process is stopped. Each connection is handled on its own
+    /// task, and the services themselves run on blocking threads since they read files.
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+
+        runtime.block_on(async move {
+            let listener = TcpListener::from_std(self.listener)?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let root = self.root.clone();
+                let read_timeout = self.read_timeout;
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, root, read_timeout).await {
+                        eprintln!("Error: {:#}", e);
+                    }
+                });
+            }
+        })
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(content_type: String, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        HttpResponse {
+            status,
+            content_type: "text/plain".to_string(),
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+}
+
+// We answer one request per connection, which every client copes with.
+async fn handle_connection(
+    stream: TcpStream,
+    root: PathBuf,
+    read_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut stream = BufReader::new(stream);
+    let request = read_request(&mut stream, read_timeout).await?;
+
+    let response = tokio::task::spawn_blocking(move || respond(&root, request)).await?;
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request(
+    stream: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<HttpRequest, anyhow::Error> {
+    let line = read_line(stream, read_timeout).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(anyhow::anyhow!("Invalid request line {:?}", line)),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (target, String::new()),
+    };
+
+    let mut headers = vec![];
+    loop {
+        let line = read_line(stream, read_timeout).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        query,
+        headers,
+        body: vec![],
+    };
+
+    // git sends large requests in chunks rather than working out their length first.
+    if request
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        request.body = read_chunked_body(stream, read_timeout).await?;
+    } else if let Some(length) = request.header("Content-Length") {
+        let length: usize = length.parse().context("Invalid Content-Length")?;
+        if length > MAX_REQUEST_SIZE {
+            return Err(too_large());
+        }
+        let mut body = vec![0; length];
+        read_exact(stream, &mut body, read_timeout).await?;
+        request.body = body;
+    }
+
+    if request.header("Content-Encoding") == Some("gzip") {
+        let mut body = vec![];
+        GzDecoder::new(request.body.as_slice())
+            .take(MAX_REQUEST_SIZE as u64 + 1)
+            .read_to_end(&mut body)
+            .context("Decompressing request")?;
+        if body.len() > MAX_REQUEST_SIZE {
+            return Err(too_large());
+        }
+        request.body = body;
+    }
+
+    Ok(request)
+}
+
+async fn read_chunked_body(
+    stream: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut body = vec![];
+
+    loop {
+        let line = read_line(stream, read_timeout).await?;
+        let size = line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16).context("Invalid chunk size")?;
+        if body.len().saturating_add(size) > MAX_REQUEST_SIZE {
+            return Err(too_large());
+        }
+
+        // The chunk is followed by its CRLF.
+        let mut chunk = vec![0; size + 2];
+        read_exact(stream, &mut chunk, read_timeout).await?;
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend(&chunk[..size]);
+    }
+}
+
+// A line of the request head or a chunk size, which are short.
+async fn read_line(
+    stream: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<String, anyhow::Error> {
+    let mut line = String::new();
+    let mut limited = (&mut *stream).take(MAX_LINE_LENGTH);
+    let length = with_timeout(read_timeout, limited.read_line(&mut line)).await?;
+    if length as u64 == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(anyhow::anyhow!("Request line is too long"));
+    }
+    Ok(line)
+}
+
+// The timeout applies to each read rather than the whole buffer, so a large push on a slow
+// connection isn't cut off while it still makes progress.
+async fn read_exact(
+    stream: &mut BufReader<TcpStream>,
+    buffer: &mut [u8],
+    read_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = with_timeout(read_timeout, stream.read(&mut buffer[filled..])).await?;
+        if read == 0 {
+            return Err(anyhow::anyhow!(
+                "Connection closed before the request ended"
+            ));
+        }
+        filled += read;
+    }
+    Ok(())
+}
+
+async fn with_timeout<T>(
+    read_timeout: Duration,
+    read: impl std::future::Future<Output = std::io::Result<T>>,
+) -> Result<T, anyhow::Error> {
+    match tokio::time::timeout(read_timeout, read).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(anyhow::anyhow!(
+            "Timed out reading request after {:?}",
+            read_timeout
+        )),
+    }
+}
+
+fn too_large() -> anyhow::Error {
+    anyhow::anyhow!("Request is larger than {} bytes", MAX_REQUEST_SIZE)
+}
+
+fn respond(root: &Path, request: HttpRequest) -> HttpResponse {
+    let routes = [
+        ("/info/refs", "GET"),
+        ("/git-upload-pack", "POST"),
+        ("/git-receive-pack", "POST"),
+    ];
+    let Some((repository_path, endpoint, method)) = routes.iter().find_map(|(endpoint, method)| {
+        let path = request.path.strip_suffix(endpoint)?;
+        Some((path, *endpoint, *method))
+    }) else {
+        return HttpResponse::error(404, "Not Found");
+    };
+    if request.method != method {
+        return HttpResponse::error(405, "Method Not Allowed");
+    }
+
+    let service = match endpoint {
+        "/info/refs" => match request.query.strip_prefix("service=") {
+            Some(service) if service == UPLOAD_PACK || service == RECEIVE_PACK => service,
+            // We don't serve the files of the dumb protocol.
+            _ => return HttpResponse::error(404, "Not Found"),
+        },
+        endpoint => &endpoint[1..],
+    };
+
+    let Some(mut repository) = server::open_repository(root, repository_path) else {
+        return HttpResponse::error(404, "Not Found");
+    };
+    if service == RECEIVE_PACK && !receive_pack_enabled(&repository) {
+        return HttpResponse::error(403, "Forbidden");
+    }
+
+    let result = match endpoint {
+        "/info/refs" => advertise(&mut repository, service),
+        _ => run_service(&mut repository, service, &request),
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error: {} {}: {:#}", request.method, request.path, e);
+            HttpResponse::error(500, "Internal Server Error")
+        }
+    }
+}
+
+fn receive_pack_enabled(repository: &LocalRepository) -> bool {
+    Config::load(Some(&repository.path))
+        .and_then(|config| config.get_bool("http.receivepack"))
+        .is_ok_and(|enabled| enabled == Some(true))
+}
+
+fn advertise(
+    repository: &mut LocalRepository,
+    service: &str,
+) -> Result<HttpResponse, anyhow::Error> {
+    let mut body = pkt_line::encode_text(&format!("# service={}", service));
+    body.extend(pkt_line::FLUSH_PKT);
+    body.extend(server::advertise_refs(repository, service)?);
+
+    Ok(HttpResponse::new(
+        format!("application/x-{}-advertisement", service),
+        body,
+    ))
+}
+
+fn run_service(
+    repository: &mut LocalRepository,
+    service: &str,
+    request: &HttpRequest,
+) -> Result<HttpResponse, anyhow::Error> {
+    let content_type = format!("application/x-{}-request", service);
+    if request.header("Content-Type") != Some(content_type.as_str()) {
+        return Ok(HttpResponse::error(400, "Bad Request"));
+    }
+
+    let mut input = request.body.as_slice();
+    let mut output = vec![];
+    let result = match service {
+        UPLOAD_PACK => server::upload_pack(repository, &mut input, &mut output, true),
+        _ => server::receive_pack(repository, &mut input, &mut output),
+    };
+
+    // An error the service already told the client about is sent along with the rest.
+    if let Err(e) = result {
+        if output.is_empty() {
+            return Err(e);
+        }
+        eprintln!("Error: {} {}: {:#}", request.method, request.path, e);
+    }
+
+    Ok(HttpResponse::new(
+        format!("application/x-{}-result", service),
+        output,
+    ))
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::pathspec;
+
+// Untracked files that shouldn't be added: the patterns in the `.gitignore` file of each
+// directory, which apply to the files under it, and in `info/exclude` in the repository,
+// which apply everywhere. Files that are already staged aren't affected.
+// CF https://git-scm.com/docs/gitignore
+const IGNORE_FILE: &str = ".gitignore";
+
+#[derive(Debug, Clone, PartialEq)]
+struct IgnorePattern {
+    pattern: String,
+    // The directory of the file the pattern is in, which anchored patterns are relative to.
+    directory: String,
+    negated: bool,
+    directory_only: bool,
+    // Patterns with a `/` other than at their end match the whole path rather than any
+    // file name.
+    anchored: bool,
+}
+
+impl IgnorePattern {
+    fn parse(line: &str, directory: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        // `\#` and `\!` start patterns with those characters.
+        let line = match line.strip_prefix('\\') {
+            Some(rest) if rest.starts_with(['#', '!']) => rest,
+            _ => line,
+        };
+        let (directory_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        let anchored = line.contains('/');
+        Some(IgnorePattern {
+            pattern: line.trim_start_matches('/').to_string(),
+            directory: directory.to_string(),
+            negated,
+            directory_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &str, is_directory: bool) -> bool {
+        if self.directory_only && !is_directory {
+            return false;
+        }
+
+        let relative = match self.directory.as_str() {
+            "" => path,
+            directory => match path
+                .strip_prefix(directory)
+                .and_then(|path| path.strip_prefix('/'))
+            {
+                Some(relative) => relative,
+                None => return false,
+            },
+        };
+        let text = match self.anchored {
+            true => relative,
+            false => relative.rsplit('/').next().unwrap_or(relative),
+        };
+
+        pathspec::wildmatch(self.pattern.as_bytes(), text.as_bytes(), true)
+    }
+}
+
+/// The ignore rules in effect while walking the work tree. The rules of each directory are
+/// pushed when entering it and popped when leaving it.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    // In increasing precedence, as the last pattern that matches decides.
+    patterns: Vec<IgnorePattern>,
+    // Those of `info/exclude`, which come before any `.gitignore` file.
+    exclude: Vec<IgnorePattern>,
+}
+
+impl IgnoreRules {
+    /// The rules of the repository, without those of any directory yet.
+    pub fn load(base_path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let exclude_path = PathBuf::from("not-git/info/exclude");
+        let exclude_path = match base_path {
+            Some(base_path) => base_path.join(exclude_path),
+            None => exclude_path,
+        };
+
+        Ok(IgnoreRules {
+            patterns: vec![],
+            exclude: read_patterns(&exclude_path, "")?,
+        })
+    }
+
+    /// Add the rules of the `.gitignore` file of a directory, if it has one.
+    pub fn push_directory(
+        &mut self,
+        work_tree: &Path,
+        directory: &str,
+    ) -> Result<(), anyhow::Error> {
+        let path = work_tree.join(directory).join(IGNORE_FILE);
+        self.patterns.extend(read_patterns(&path, directory)?);
+        Ok(())
+    }
+
+    /// Drop the rules of a directory once we've left it.
+    pub fn pop_directory(&mut self, directory: &str) {
+        while self
+            .patterns
+            .last()
+            .is_some_and(|pattern| pattern.directory == directory)
+        {
+            self.patterns.pop();
+        }
+    }
+
+    /// Whether an untracked file or directory, relative to the root of the work tree, is
+    /// ignored. The rules of the directories it's in must have been pushed.
+    pub fn is_ignored(&self, path: &str, is_directory: bool) -> bool {
+        self.patterns
+            .iter()
+            .rev()
+            .chain(self.exclude.iter().rev())
+            .find(|pattern| pattern.matches(path, is_directory))
+            .is_some_and(|pattern| !pattern.negated)
+    }
+}
+
+/// Whether a path in the work tree is ignored, either itself or because a directory it's
+/// in is, e.g. to refuse to add it when it's named.
+pub fn is_path_ignored(
+    base_path: Option<&PathBuf>,
+    path: &str,
+    is_directory: bool,
+) -> Result<bool, anyhow::Error> {
+    let work_tree = base_path.cloned().unwrap_or_default();
+    let mut rules = IgnoreRules::load(base_path)?;
+    rules.push_directory(&work_tree, "")?;
+
+    let components: Vec<&str> = path.split('/').collect();
+    let mut current = String::new();
+    for (position, name) in components.iter().enumerate() {
+        if !current.is_empty() {
+            current.push('/');
+        }
+        current.push_str(name);
+
+        let last = position == components.len() - 1;
+        if rules.is_ignored(&current, !last || is_directory) {
+            return Ok(true);
+        }
+        if !last {
+            rules.push_directory(&work_tree, &current)?;
+        }
+    }
+
+    Ok(false)
+}
+
+fn read_patterns(path: &Path, directory: &str) -> Result<Vec<IgnorePattern>, anyhow::Error> {
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+
+    let contents = fs::read_to_string(path).context(format!("Reading {:?}", path))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| IgnorePattern::parse(line, directory))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(directory: &str, contents: &str) -> IgnoreRules {
+        IgnoreRules {
+            patterns: contents
+                .lines()
+                .filter_map(|line| IgnorePattern::parse(line, directory))
+                .collect(),
+            exclude: vec![],
+        }
+    }
+
+    #[test]
+    fn unanchored_patterns_match_file_names_anywhere() {
+        let rules = rules("", "# Build output\n*.o\n\ntarget/\n");
+
+        assert!(rules.is_ignored("main.o", false));
+        assert!(rules.is_ignored("src/lib/main.o", false));
+        assert!(!rules.is_ignored("main.c", false));
+        assert!(rules.is_ignored("sub/target", true));
+        // Only directories match a pattern ending in `/`.
+        assert!(!rules.is_ignored("target", false));
+    }
+
+    #[test]
+    fn anchored_patterns_are_relative_to_their_directory() {
+        let rules = rules("docs", "/build\nout/*.html\n");
+
+        assert!(rules.is_ignored("docs/build", true));
+        assert!(!rules.is_ignored("docs/sub/build", true));
+        assert!(!rules.is_ignored("build", true));
+        assert!(rules.is_ignored("docs/out/index.html", false));
+        assert!(!rules.is_ignored("docs/out/sub/index.html", false));
+    }
+
+    #[test]
+    fn later_patterns_win_and_can_be_negated() {
+        let rules = rules("", "*.log\n!keep.log\n\\!important\n");
+
+        assert!(rules.is_ignored("debug.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+        assert!(rules.is_ignored("!important", false));
+    }
+
+    #[test]
+    fn popping_a_directory_drops_its_rules() {
+        let mut rules = rules("", "*.o\n");
+        rules.patterns.extend(IgnorePattern::parse("*.txt", "src"));
+        assert!(rules.is_ignored("src/a.txt", false));
+
+        rules.pop_directory("src");
+        assert!(!rules.is_ignored("src/a.txt", false));
+        assert!(rules.is_ignored("src/a.o", false));
+    }
+
+    #[test]
+    fn gitignore_files_take_precedence_over_exclude() {
+        let mut rules = rules("", "!*.log\n");
+        rules.exclude.extend(IgnorePattern::parse("*.log", ""));
+        rules.exclude.extend(IgnorePattern::parse("*.tmp", ""));
+
+        assert!(!rules.is_ignored("debug.log", false));
+        assert!(rules.is_ignored("a.tmp", false));
+    }
+}
@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+
+use crate::merge::{self, TreeEntry};
+use crate::objects::{ObjectHash, ObjectType};
+
+// The index, or staging area, is the tree the next commit will have. Each file in it is
+// listed with the stat data it had when it was added, so a file whose stat data hasn't
+// changed doesn't need to be hashed again to know it hasn't changed.
+// CF https://git-scm.com/docs/index-format
+const INDEX_FILE: &str = "index";
+const SIGNATURE: &[u8; 4] = b"DIRC";
+const VERSION: u32 = 2;
+// Version 3 is only needed for entries with extended flags, so it's only written then.
+const EXTENDED_VERSION: u32 = 3;
+
+// Everything in an entry before its path, with the extended flags if it has them.
+const ENTRY_HEADER_SIZE: usize = 62;
+const EXTENDED_ENTRY_HEADER_SIZE: usize = 64;
+const CHECKSUM_SIZE: usize = 20;
+
+const ASSUME_VALID_FLAG: u16 = 0x8000;
+const EXTENDED_FLAG: u16 = 0x4000;
+const STAGE_MASK: u16 = 0x3000;
+const STAGE_SHIFT: u16 = 12;
+// Longer paths are stored with this length, and read up to their NUL instead.
+const NAME_MASK: u16 = 0x0fff;
+// In the extended flags: the file will be added, but its contents aren't staged yet.
+const INTENT_TO_ADD_FLAG: u16 = 0x2000;
+
+// An intent-to-add entry has the hash of the empty blob, which isn't written.
+const EMPTY_BLOB_HASH: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+
+/// A file in the index: the stat data it had when it was added, its mode and its blob.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub ctime_seconds: u32,
+    pub ctime_nanoseconds: u32,
+    pub mtime_seconds: u32,
+    pub mtime_nanoseconds: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub hash: ObjectHash,
+    // The assume-valid bit and the merge stage. The length of the path is worked out when
+    // the entry is written.
+    pub flags: u16,
+    // The intent-to-add bit, only stored by index version 3.
+    pub extended_flags: u16,
+    // Relative to the root of the work tree, with `/` between directories.
+    pub path: String,
+}
+
+impl IndexEntry {
+    /// An entry for a file whose blob has been written, with the file's stat data.
+    pub fn new(path: &str, hash: ObjectHash, metadata: &Metadata) -> Self {
+        let mut entry = IndexEntry {
+            ctime_seconds: 0,
+            ctime_nanoseconds: 0,
+            mtime_seconds: 0,
+            mtime_nanoseconds: 0,
+            dev: 0,
+            ino: 0,
+            mode: file_mode(metadata),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            hash,
+            flags: 0,
+            extended_flags: 0,
+            path: path.to_string(),
+        };
+        entry.update_stat(metadata);
+        entry
+    }
+
+    /// An entry for a file of a tree, without stat data, e.g. for a file that isn't in the
+    /// work tree.
+    pub fn from_tree_entry(path: &str, (object_type, hash): &TreeEntry) -> Self {
+        IndexEntry {
+            ctime_seconds: 0,
+            ctime_nanoseconds: 0,
+            mtime_seconds: 0,
+            mtime_nanoseconds: 0,
+            dev: 0,
+            ino: 0,
+            mode: u32::from_str_radix(object_type.to_mode(), 8).unwrap_or_default(),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            hash: hash.clone(),
+            flags: 0,
+            extended_flags: 0,
+            path: path.to_string(),
+        }
+    }
+
+    /// An entry for a new file that will be added, e.g. so that it shows in diffs, but
+    /// whose contents aren't staged. It has no stat data, so the file always looks changed.
+    pub fn intent_to_add(path: &str, metadata: &Metadata) -> Result<Self, anyhow::Error> {
+        let mut entry = IndexEntry::new(path, ObjectHash::new(EMPTY_BLOB_HASH)?, metadata);
+        entry.ctime_seconds = 0;
+        entry.ctime_nanoseconds = 0;
+        entry.mtime_seconds = 0;
+        entry.mtime_nanoseconds = 0;
+        entry.dev = 0;
+        entry.ino = 0;
+        entry.uid = 0;
+        entry.gid = 0;
+        entry.size = 0;
+        entry.extended_flags = INTENT_TO_ADD_FLAG;
+        Ok(entry)
+    }
+
+    // Like git, only the lower 32 bits are kept. They're only compared for equality.
+    pub fn update_stat(&mut self, metadata: &Metadata) {
+        self.ctime_seconds = metadata.ctime() as u32;
+        self.ctime_nanoseconds = metadata.ctime_nsec() as u32;
+        self.mtime_seconds = metadata.mtime() as u32;
+        self.mtime_nanoseconds = metadata.mtime_nsec() as u32;
+        self.dev = metadata.dev() as u32;
+        self.ino = metadata.ino() as u32;
+        self.uid = metadata.uid();
+        self.gid = metadata.gid();
+        self.size = metadata.size() as u32;
+    }
+
+    /// Whether the file still has the stat data it had when it was added, in which case
+    /// we take it as unchanged without hashing it.
+    pub fn matches_stat(&self, metadata: &Metadata) -> bool {
+        self.mode == file_mode(metadata)
+            && self.mtime_seconds == metadata.mtime() as u32
+            && self.mtime_nanoseconds == metadata.mtime_nsec() as u32
+            && self.ctime_seconds == metadata.ctime() as u32
+            && self.ctime_nanoseconds == metadata.ctime_nsec() as u32
+            && self.ino == metadata.ino() as u32
+            && self.size == metadata.size() as u32
+    }
+
+    pub fn object_type(&self) -> Result<ObjectType, anyhow::Error> {
+        ObjectType::from_mode(&format!("{:06o}", self.mode))
+    }
+
+    /// 0 for a normal entry, or 1 to 3 for the base, ours and theirs of a conflict.
+    pub fn stage(&self) -> u16 {
+        (self.flags & STAGE_MASK) >> STAGE_SHIFT
+    }
+
+    pub fn assume_valid(&self) -> bool {
+        self.flags & ASSUME_VALID_FLAG != 0
+    }
+
+    pub fn is_intent_to_add(&self) -> bool {
+        self.extended_flags & INTENT_TO_ADD_FLAG != 0
+    }
+
+    /// Whether the staged file is the file of a tree, i.e. whether it has no staged changes.
+    pub fn matches_tree_entry(&self, tree_entry: Option<&TreeEntry>) -> bool {
+        match tree_entry {
+            Some((object_type, hash)) => {
+                !self.is_intent_to_add()
+                    && self.hash == *hash
+                    && self.object_type().is_ok_and(|t| t == *object_type)
+            }
+            None => false,
+        }
+    }
+
+    fn parse(data: &[u8], version: u32) -> Result<(Self, usize), anyhow::Error> {
+        let truncated = || anyhow::anyhow!("Index entry is truncated");
+        if data.len() < ENTRY_HEADER_SIZE {
+            return Err(truncated());
+        }
+        let word = |index: usize| {
+            let start = index * 4;
+            u32::from_be_bytes([
+                data[start],
+                data[start + 1],
+                data[start + 2],
+                data[start + 3],
+            ])
+        };
+
+        let hash = ObjectHash::from_bytes(&data[40..60])?;
+        let flags = u16::from_be_bytes([data[60], data[61]]);
+        let (header_size, extended_flags) = match flags & EXTENDED_FLAG != 0 {
+            true if version < EXTENDED_VERSION => {
+                return Err(anyhow::anyhow!(
+                    "Index version {} entries cannot have extended flags",
+                    version
+                ))
+            }
+            true if data.len() < EXTENDED_ENTRY_HEADER_SIZE => return Err(truncated()),
+            true => (
+                EXTENDED_ENTRY_HEADER_SIZE,
+                u16::from_be_bytes([data[62], data[63]]),
+            ),
+            false => (ENTRY_HEADER_SIZE, 0),
+        };
+
+        let name_end = data[header_size..]
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(truncated)?;
+        let path = String::from_utf8(data[header_size..header_size + name_end].to_vec())
+            .context("Index entry path is not utf-8")?;
+
+        let length = entry_length(header_size, path.len());
+        if data.len() < length {
+            return Err(truncated());
+        }
+
+        let entry = IndexEntry {
+            ctime_seconds: word(0),
+            ctime_nanoseconds: word(1),
+            mtime_seconds: word(2),
+            mtime_nanoseconds: word(3),
+            dev: word(4),
+            ino: word(5),
+            mode: word(6),
+            uid: word(7),
+            gid: word(8),
+            size: word(9),
+            hash,
+            flags: flags & !NAME_MASK & !EXTENDED_FLAG,
+            extended_flags,
+            path,
+        };
+        Ok((entry, length))
+    }
+
+    fn write_to(&self, data: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        let start = data.len();
+        for word in [
+            self.ctime_seconds,
+            self.ctime_nanoseconds,
+            self.mtime_seconds,
+            self.mtime_nanoseconds,
+            self.dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.size,
+        ] {
+            data.extend(word.to_be_bytes());
+        }
+        data.extend(hex::decode(self.hash.full_hash())?);
+
+        let name_length = self.path.len().min(NAME_MASK as usize) as u16;
+        let mut flags = (self.flags & !NAME_MASK & !EXTENDED_FLAG) | name_length;
+        let header_size = match self.extended_flags {
+            0 => ENTRY_HEADER_SIZE,
+            _ => {
+                flags |= EXTENDED_FLAG;
+                EXTENDED_ENTRY_HEADER_SIZE
+            }
+        };
+        data.extend(flags.to_be_bytes());
+        if self.extended_flags != 0 {
+            data.extend(self.extended_flags.to_be_bytes());
+        }
+        data.extend(self.path.as_bytes());
+
+        // The path is followed by 1 to 8 NUL bytes to keep entries a multiple of 8 bytes.
+        data.resize(start + entry_length(header_size, self.path.len()), 0);
+        Ok(())
+    }
+}
+
+/// The entries of the index, sorted by path.
+#[derive(Debug, Default, PartialEq)]
+pub struct Index {
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Read the index of the repository. A repository that doesn't have one yet has
+    /// nothing staged.
+    pub fn read(base_path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let path = index_path(base_path);
+        if !path.is_file() {
+            return Ok(Index::default());
+        }
+
+        let data = fs::read(&path).context(format!("Reading {:?}", path))?;
+        Index::parse(&data).context(format!("Reading {:?}", path))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, anyhow::Error> {
+        if data.len() < 12 + CHECKSUM_SIZE || &data[..4] != SIGNATURE {
+            return Err(anyhow::anyhow!("Index file has no signature"));
+        }
+
+        let (contents, checksum) = data.split_at(data.len() - CHECKSUM_SIZE);
+        if Sha1::digest(contents).as_slice() != checksum {
+            return Err(anyhow::anyhow!(
+                "Index file checksum does not match its contents"
+            ));
+        }
+
+        let version = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        if version != VERSION && version != EXTENDED_VERSION {
+            return Err(anyhow::anyhow!("Unsupported index version {}", version));
+        }
+        let count = u32::from_be_bytes([data[8], data[9], data[10], data[11]]) as usize;
+
+        let mut position = 12;
+        // The count isn't trusted to size the entries up front, since a corrupt one could
+        // be far more than the file holds. Every entry takes at least 64 bytes.
+        let mut entries =
+            Vec::with_capacity(count.min(contents.len() / entry_length(ENTRY_HEADER_SIZE, 1)));
+        for _ in 0..count {
+            let (entry, length) = IndexEntry::parse(&contents[position..], version)?;
+            entries.push(entry);
+            position += length;
+        }
+
+        read_extensions(&contents[position..])?;
+
+        Ok(Index { entries })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let mut data = vec![];
+        data.extend(SIGNATURE);
+        let version = match self.entries.iter().any(|entry| entry.extended_flags != 0) {
+            true => EXTENDED_VERSION,
+            false => VERSION,
+        };
+        data.extend(version.to_be_bytes());
+        data.extend((self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            entry.write_to(&mut data)?;
+        }
+
+        let checksum = Sha1::digest(&data);
+        data.extend(checksum.as_slice());
+        Ok(data)
+    }
+
+    /// Replace the index of the repository. It's written to `index.lock` first, so that
+    /// the index is never left half written. Like git, creating the lock fails if it's
+    /// already there, so that two writers can't overwrite each other's changes.
+    pub fn write(&self, base_path: Option<&PathBuf>) -> Result<(), anyhow::Error> {
+        let path = index_path(base_path);
+        let lock_path = path.with_extension("lock");
+        let mut lock = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(lock) => lock,
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(anyhow::anyhow!(
+                    "{} exists: another process seems to be using the index",
+                    lock_path.display()
+                ))
+            }
+            Err(error) => {
+                return Err(error).context(format!("Creating {:?}", lock_path));
+            }
+        };
+
+        let mut write_and_rename = || -> Result<(), anyhow::Error> {
+            lock.write_all(&self.to_bytes()?)?;
+            fs::rename(&lock_path, &path)?;
+            Ok(())
+        };
+        let result = write_and_rename();
+        if result.is_err() {
+            let _ = fs::remove_file(&lock_path);
+        }
+        result.context(format!("Writing {:?}", path))
+    }
+
+    /// The index of the files of a tree, e.g. after checking it out. The stat data of the
+    /// files in the work tree is recorded, so they don't look changed.
+    pub fn from_tree(
+        base_path: Option<&PathBuf>,
+        tree_hash: &ObjectHash,
+    ) -> Result<Self, anyhow::Error> {
+        let work_tree = base_path.cloned().unwrap_or_default();
+        let mut entries = vec![];
+        for (path, tree_entry) in merge::flatten_tree(base_path, tree_hash)? {
+            let mut entry = IndexEntry::from_tree_entry(&path, &tree_entry);
+            if let Ok(metadata) = fs::symlink_metadata(work_tree.join(&path)) {
+                entry.update_stat(&metadata);
+            }
+            entries.push(entry);
+        }
+
+        Ok(Index { entries })
+    }
+
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.find(path).ok().map(|position| &self.entries[position])
+    }
+
+    /// Stage the entry in place of the one with the same path. A file replaces a directory
+    /// of the same name and the other way around, as they can't both be in a tree.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let directory = format!("{}/", entry.path);
+        self.entries.retain(|existing| {
+            !existing.path.starts_with(&directory)
+                && !entry.path.starts_with(&format!("{}/", existing.path))
+        });
+
+        match self.find(&entry.path) {
+            Ok(position) => self.entries[position] = entry,
+            Err(position) => self.entries.insert(position, entry),
+        }
+    }
+
+    /// Unstage the file, returning whether it was staged.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.path != path);
+        self.entries.len() != before
+    }
+
+    /// Write the tree objects for the staged files, returning the root tree. Files that are
+    /// only intended to be added aren't in it.
+    pub fn write_tree(&self, base_path: Option<&PathBuf>) -> Result<ObjectHash, anyhow::Error> {
+        let mut files = BTreeMap::new();
+        for entry in &self.entries {
+            if entry.stage() != 0 {
+                return Err(anyhow::anyhow!("{} is unmerged", entry.path));
+            }
+            if entry.is_intent_to_add() {
+                continue;
+            }
+            files.insert(
+                entry.path.clone(),
+                (entry.object_type()?, entry.hash.clone()),
+            );
+        }
+
+        merge::write_flattened_tree(base_path, &files)
+    }
+
+    fn find(&self, path: &str) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|entry| entry.path.as_str().cmp(path))
+    }
+}
+
+pub fn index_path(base_path: Option<&PathBuf>) -> PathBuf {
+    let path = PathBuf::from("not-git").join(INDEX_FILE);
+    match base_path {
+        Some(base_path) => base_path.join(path),
+        None => path,
+    }
+}
+
+/// The mode git records for a file: a symlink, an executable or a regular file.
+pub fn file_mode(metadata: &Metadata) -> u32 {
+    if metadata.file_type().is_symlink() {
+        0o120000
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+// The entry's header and path, followed by at least one NUL, rounded up to 8 bytes.
+fn entry_length(header_size: usize, path_length: usize) -> usize {
+    (header_size + path_length + 8) & !7
+}
+
+/// Extensions follow the entries, each a signature, a length and data. We don't use any
+/// yet: the optional ones, whose signature starts with an uppercase letter, are caches we
+/// can drop, but we can't make sense of the index without the others.
+fn read_extensions(mut data: &[u8]) -> Result<(), anyhow::Error> {
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err(anyhow::anyhow!("Index extension is truncated"));
+        }
+        let signature = &data[..4];
+        let length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        if data.len() < 8 + length {
+            return Err(anyhow::anyhow!("Index extension is truncated"));
+        }
+
+        match signature {
+            signature if signature[0].is_ascii_uppercase() => {}
+            signature => {
+                return Err(anyhow::anyhow!(
+                    "Index uses the {} extension, which is not supported",
+                    String::from_utf8_lossy(signature)
+                ))
+            }
+        }
+
+        data = &data[8 + length..];
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, hash: &str) -> IndexEntry {
+        let hash = ObjectHash::new(&hash.repeat(40)).unwrap();
+        IndexEntry::from_tree_entry(path, &(ObjectType::Blob, hash))
+    }
+
+    #[test]
+    fn entries_are_padded_to_eight_bytes() {
+        assert_eq!(entry_length(ENTRY_HEADER_SIZE, 1), 64);
+        // A path that would end exactly on a multiple of 8 still needs its NUL.
+        assert_eq!(entry_length(ENTRY_HEADER_SIZE, 2), 72);
+        assert_eq!(entry_length(ENTRY_HEADER_SIZE, 9), 72);
+        assert_eq!(entry_length(EXTENDED_ENTRY_HEADER_SIZE, 7), 72);
+    }
+
+    #[test]
+    fn parse_errors_on_a_count_larger_than_the_file() {
+        let mut index = Index::default();
+        index.add(entry("a.txt", "a"));
+        let mut data = index.to_bytes().unwrap();
+
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let contents_length = data.len() - CHECKSUM_SIZE;
+        let checksum = Sha1::digest(&data[..contents_length]);
+        data[contents_length..].copy_from_slice(&checksum);
+
+        assert!(Index::parse(&data).is_err());
+    }
+
+    #[test]
+    fn index_round_trips() {
+        let mut index = Index::default();
+        index.add(entry("src/main.rs", "b"));
+        index.add(entry("README.md", "a"));
+        let mut executable = entry("run.sh", "c");
+        executable.mode = 0o100755;
+        executable.mtime_seconds = 1_700_000_000;
+        index.add(executable);
+
+        let data = index.to_bytes().unwrap();
+        assert_eq!(&data[..4], b"DIRC");
+        let parsed = Index::parse(&data).unwrap();
+
+        assert_eq!(parsed, index);
+        let paths: Vec<&str> = parsed.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["README.md", "run.sh", "src/main.rs"]);
+        assert_eq!(
+            parsed.get("run.sh").unwrap().object_type().unwrap(),
+            ObjectType::Executable
+        );
+    }
+
+    #[test]
+    fn intent_to_add_entries_need_version_3() {
+        let mut index = Index::default();
+        index.add(entry("a.txt", "a"));
+        let data = index.to_bytes().unwrap();
+        assert_eq!(&data[4..8], &VERSION.to_be_bytes());
+
+        let mut new_file = entry("b.txt", "b");
+        new_file.extended_flags = INTENT_TO_ADD_FLAG;
+        index.add(new_file);
+        let data = index.to_bytes().unwrap();
+        assert_eq!(&data[4..8], &EXTENDED_VERSION.to_be_bytes());
+
+        let parsed = Index::parse(&data).unwrap();
+        assert_eq!(parsed, index);
+        assert!(parsed.get("b.txt").unwrap().is_intent_to_add());
+        assert!(!parsed.get("a.txt").unwrap().is_intent_to_add());
+    }
+
+    #[test]
+    fn parse_skips_optional_extensions_and_refuses_others() {
+        let mut index = Index::default();
+        index.add(entry("a.txt", "a"));
+        let data = index.to_bytes().unwrap();
+        let contents = &data[..data.len() - CHECKSUM_SIZE];
+
+        let with_extension = |signature: &[u8]| {
+            let mut data = contents.to_vec();
+            data.extend(signature);
+            data.extend(3u32.to_be_bytes());
+            data.extend(b"xyz");
+            let checksum = Sha1::digest(&data);
+            data.extend(checksum.as_slice());
+            data
+        };
+
+        assert_eq!(Index::parse(&with_extension(b"TREE")).unwrap(), index);
+        assert!(Index::parse(&with_extension(b"link")).is_err());
+    }
+
+    #[test]
+    fn parse_checks_the_checksum() {
+        let mut data = Index::default().to_bytes().unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+
+        let got = Index::parse(&data).unwrap_err();
+        assert_eq!(
+            got.to_string(),
+            "Index file checksum does not match its contents"
+        );
+    }
+
+    #[test]
+    fn add_replaces_files_and_directories_of_the_same_name() {
+        let mut index = Index::default();
+        index.add(entry("src/main.rs", "a"));
+        index.add(entry("src/lib.rs", "b"));
+        index.add(entry("src", "c"));
+        assert_eq!(index.entries.len(), 1);
+
+        index.add(entry("src/main.rs", "d"));
+        let paths: Vec<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["src/main.rs"]);
+    }
+}
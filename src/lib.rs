@@ -1,12 +1,46 @@
+pub mod add;
 pub mod branch;
+pub mod bundle;
 pub mod checkout;
 pub mod clone;
 pub mod commit;
 pub mod commit_tree;
+pub mod config;
+pub mod connection;
+pub mod credential;
+pub mod daemon;
+pub mod dumb_http;
+pub mod fetch;
 pub mod hash_object;
+pub mod history;
+pub mod http;
+pub mod http_config;
+pub mod http_server;
+pub mod ignore;
+pub mod index;
 pub mod init;
+pub mod local;
+pub mod merge;
+#[cfg(feature = "async")]
+pub mod nonblocking;
 pub mod objects;
 pub mod packfile;
+pub mod pathspec;
+pub mod pkt_line;
+pub mod progress;
+pub mod promisor;
+pub mod protocol;
+pub mod pull;
+pub mod push;
+pub mod refs;
+pub mod refspec;
+pub mod remote;
+pub mod rm;
+pub mod server;
+pub mod shallow;
+pub mod sideband;
+pub mod ssh;
+pub mod transport;
 pub mod update_refs;
 pub mod utils;
 pub mod write_tree;
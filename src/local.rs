@@ -0,0 +1,276 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::fetch;
+use crate::objects::{ObjectFile, ObjectHash};
+use crate::protocol::{
+    FetchOptions, GitRef, RefStatus, RefUpdateCommand, ReportStatus, ShallowInfo,
+};
+use crate::transport::Transport;
+use crate::{history, packfile, refs};
+
+const FILE_URL_PREFIX: &str = "file://";
+const REPOSITORY_DIR: &str = "not-git";
+
+/// A repository on this machine that we clone or fetch from by reading its files directly,
+/// without a server in between.
+#[derive(Debug)]
+pub struct LocalRepository {
+    // The directory containing `not-git`.
+    pub path: PathBuf,
+}
+
+impl LocalRepository {
+    /// Open the repository a URL points to if it's a `file://` URL or a path. Like git, a
+    /// URL with any other scheme or that looks like `host:path` is for a remote machine.
+    pub fn open_url(url: &str) -> Result<Option<Self>, anyhow::Error> {
+        let path = match url.strip_prefix(FILE_URL_PREFIX) {
+            Some(path) => path,
+            None if url.contains("://") => return Ok(None),
+            // The scp-like syntax has a colon before the first slash.
+            None if url.split('/').next().unwrap_or_default().contains(':') => return Ok(None),
+            None => url,
+        };
+
+        LocalRepository::open(Path::new(path)).map(Some)
+    }
+
+    /// Open the repository at the path, which can be either the working directory or the
+    /// `not-git` directory inside it.
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let not_a_repository = || {
+            anyhow::anyhow!(
+                "'{}' does not appear to be a not-git repository",
+                path.display()
+            )
+        };
+
+        let path = fs::canonicalize(path).map_err(|_| not_a_repository())?;
+        if path.join(REPOSITORY_DIR).is_dir() {
+            return Ok(LocalRepository { path });
+        }
+
+        match (path.file_name(), path.parent()) {
+            (Some(name), Some(parent)) if name == REPOSITORY_DIR => Ok(LocalRepository {
+                path: parent.to_path_buf(),
+            }),
+            _ => Err(not_a_repository()),
+        }
+    }
+
+    /// Hardlink every object reachable from the wants into the repository at the base
+    /// path, which is much faster than packing and unpacking them. Objects are copied
+    /// instead when they're on another filesystem. Returns how many objects there were.
+    pub fn link_objects(
+        &self,
+        base_path: &Path,
+        wants: &[&ObjectHash],
+    ) -> Result<usize, anyhow::Error> {
+        let objects = self.list_objects(wants, &[])?;
+
+        for hash in &objects {
+            let source = self.path.join(hash.path());
+            let destination = base_path.join(hash.path());
+            if destination.exists() {
+                continue;
+            }
+
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::hard_link(&source, &destination).is_err() {
+                fs::copy(&source, &destination)
+                    .context(format!("Copying object {}", hash.full_hash()))?;
+            }
+        }
+
+        Ok(objects.len())
+    }
+
+    fn list_objects(
+        &self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+    ) -> Result<Vec<ObjectHash>, anyhow::Error> {
+        let wants: Vec<ObjectHash> = wants.iter().map(|want| (*want).clone()).collect();
+        history::list_objects(Some(&self.path), &wants, haves)
+    }
+}
+
+/// Whether every object reachable from the tip is present, stopping at what the existing
+/// refs already reach. A pack can leave out trees, blobs or parents the tip needs.
+fn is_connected(base_path: Option<&PathBuf>, tip: &ObjectHash, existing: &[ObjectHash]) -> bool {
+    // Reading a commit or tree that isn't present fails the walk.
+    match history::list_objects(base_path, std::slice::from_ref(tip), existing) {
+        Ok(objects) => objects
+            .iter()
+            .all(|hash| ObjectFile::exists(base_path, hash)),
+        Err(_) => false,
+    }
+}
+
+impl Transport for LocalRepository {
+    /// The refs of the repository as a server would advertise them, with annotated tags
+    /// peeled and the ref HEAD points to marked.
+    fn list_refs(&mut self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error> {
+        let base_path = Some(&self.path);
+        let head_target = refs::read_symbolic_ref(base_path, "HEAD")?;
+        // A detached HEAD is matched to a branch at the same commit, like `mark_head` does.
+        let mut detached_head = match head_target {
+            Some(_) => None,
+            None => refs::read_ref(base_path, "HEAD")?,
+        };
+
+        let mut git_refs = vec![];
+        for (name, hash) in refs::list_refs(base_path, "refs/")? {
+            let is_head = match &head_target {
+                Some(target) => *target == name,
+                None if detached_head.as_ref() == Some(&hash) => detached_head.take().is_some(),
+                None => false,
+            };
+            if !ref_prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+                continue;
+            }
+
+            let peeled = history::peel_tag(base_path, &hash)?;
+            let mut git_ref = GitRef::new(hash.clone(), name, is_head);
+            git_ref.peeled = Some(peeled).filter(|peeled| *peeled != hash);
+            git_refs.push(git_ref);
+        }
+
+        Ok(git_refs)
+    }
+
+    /// Do what `upload-pack` would in this process: create a packfile of the objects
+    /// reachable from the wants that aren't reachable from the haves.
+    fn fetch_pack(
+        &mut self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+        options: &FetchOptions,
+        progress: &mut dyn Write,
+        pack: &mut dyn Write,
+    ) -> Result<ShallowInfo, anyhow::Error> {
+        if options.is_shallow() {
+            return Err(anyhow::anyhow!(
+                "Shallow clones and fetches of local repositories are not supported"
+            ));
+        }
+        // Like a server that doesn't support filters, we send everything.
+        if options.filter.is_some() {
+            writeln!(progress, "{}", fetch::FILTER_UNSUPPORTED_WARNING)?;
+        }
+
+        let objects = self.list_objects(wants, haves)?;
+        pack.write_all(&packfile::create_packfile(Some(&self.path), &objects)?)?;
+        Ok(ShallowInfo::default())
+    }
+
+    /// Do what `receive-pack` would in this process: unpack the objects, then update each
+    /// ref that still points where the command expects and whose objects we now have. Like
+    /// git, we refuse to change the branch that is checked out, since its files would no
+    /// longer match.
+    fn send_pack(
+        &mut self,
+        commands: &[RefUpdateCommand],
+        pack: Option<&[u8]>,
+        _progress: &mut dyn Write,
+    ) -> Result<ReportStatus, anyhow::Error> {
+        if let Some(pack) = pack {
+            if let Err(e) = packfile::unpack_objects(&self.path, pack) {
+                // None of the refs are updated without their objects.
+                let refs = commands
+                    .iter()
+                    .map(|command| RefStatus {
+                        name: command.name.clone(),
+                        error: Some("unpacker error".to_string()),
+                    })
+                    .collect();
+                return Ok(ReportStatus {
+                    unpack_error: Some(format!("{:#}", e)),
+                    refs,
+                });
+            }
+        }
+
+        let base_path = Some(&self.path);
+        let head_target = refs::read_symbolic_ref(base_path, "HEAD")?;
+        let existing: Vec<ObjectHash> = refs::list_refs(base_path, "refs/")?
+            .into_iter()
+            .map(|(_, hash)| hash)
+            .collect();
+
+        let mut statuses = vec![];
+        for command in commands {
+            if !refs::is_valid_ref_name(&command.name) {
+                statuses.push(RefStatus {
+                    name: command.name.clone(),
+                    error: Some("funny refname".to_string()),
+                });
+                continue;
+            }
+
+            let current = refs::read_ref(base_path, &command.name)?;
+            let missing = match &command.new_hash {
+                Some(hash) => !is_connected(base_path, hash, &existing),
+                None => false,
+            };
+            let error = if current != command.old_hash {
+                Some("stale info")
+            } else if missing {
+                Some("missing necessary objects")
+            } else if head_target.as_deref() == Some(command.name.as_str()) {
+                match command.new_hash {
+                    Some(_) => Some("branch is currently checked out"),
+                    None => Some("deletion of the current branch prohibited"),
+                }
+            } else {
+                match &command.new_hash {
+                    Some(hash) => refs::write_ref(base_path, &command.name, hash)?,
+                    None => {
+                        refs::delete_ref(base_path, &command.name)?;
+                    }
+                }
+                None
+            };
+
+            statuses.push(RefStatus {
+                name: command.name.clone(),
+                error: error.map(String::from),
+            });
+        }
+
+        Ok(ReportStatus {
+            unpack_error: None,
+            refs: statuses,
+        })
+    }
+
+    fn local_repository(&self) -> Option<&LocalRepository> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_url_leaves_remote_urls_alone() {
+        assert!(LocalRepository::open_url("https://example.com/repo.git")
+            .unwrap()
+            .is_none());
+        assert!(LocalRepository::open_url("ssh://example.com/repo.git")
+            .unwrap()
+            .is_none());
+        assert!(LocalRepository::open_url("git@example.com:org/repo.git")
+            .unwrap()
+            .is_none());
+
+        assert!(LocalRepository::open_url("file:///does/not/exist").is_err());
+        assert!(LocalRepository::open_url("does/not/exist").is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use std::env;
 
-use not_git::{branch, clone, commit, hash_object, init, write_tree};
+use not_git::{
+    add, branch, bundle, clone, commit, daemon, fetch, hash_object, http_server, init, pull, push,
+    remote, rm, server, write_tree,
+};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -14,9 +17,20 @@ fn main() {
     let result = match command.as_str() {
         "init" => init::create_directories(init::InitConfig::new("main", None)),
         "hash-object" => hash_object::hash_object_command(&args[2..]),
+        "add" => add::add_command(&args[2..]),
+        "rm" => rm::rm_command(&args[2..]),
         "branch" => branch::branch_command(&args[2..]),
+        "bundle" => bundle::bundle_command(&args[2..]),
         "commit" => commit::commit_command(&args[2..]),
         "clone" => clone::clone_command(&args[2..]),
+        "daemon" => daemon::daemon_command(&args[2..]),
+        "fetch" => fetch::fetch_command(&args[2..]),
+        "pull" => pull::pull_command(&args[2..]),
+        "push" => push::push_command(&args[2..]),
+        "remote" => remote::remote_command(&args[2..]),
+        "upload-pack" => server::upload_pack_command(&args[2..]),
+        "receive-pack" => server::receive_pack_command(&args[2..]),
+        "serve" => http_server::serve_command(&args[2..]),
         "write-tree" => write_tree::write_tree_command(&args[2..]),
         _ => Err(anyhow::anyhow!(format!("Unknown command {}", command))),
     };
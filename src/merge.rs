@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::hash_object;
+use crate::objects::{self, ObjectFile, ObjectHash, ObjectType};
+
+/// A file in a flattened tree: its type (which decides its mode) and its blob.
+pub type TreeEntry = (ObjectType, ObjectHash);
+
+#[derive(Debug, Error, PartialEq)]
+#[error("Automatic merge failed, conflicting changes in: {}", .paths.join(", "))]
+pub struct MergeConflict {
+    pub paths: Vec<String>,
+}
+
+enum TreeNode {
+    File(TreeEntry),
+    Directory(BTreeMap<String, TreeNode>),
+}
+
+/// Every file reachable from the tree keyed by its path relative to the root of the tree,
+/// e.g. `src/main.rs`.
+pub fn flatten_tree(
+    base_path: Option<&PathBuf>,
+    tree_hash: &ObjectHash,
+) -> Result<BTreeMap<String, TreeEntry>, anyhow::Error> {
+    let mut files = BTreeMap::new();
+    collect_files(base_path, tree_hash, "", &mut files)?;
+    Ok(files)
+}
+
+/// Merge the changes both sides made since `base_tree` and write the resulting tree. A path
+/// that both sides changed in different ways is a conflict, which we refuse rather than
+/// writing conflict markers to the work tree.
+pub fn merge_trees(
+    base_path: Option<&PathBuf>,
+    base_tree: &ObjectHash,
+    our_tree: &ObjectHash,
+    their_tree: &ObjectHash,
+) -> Result<ObjectHash, anyhow::Error> {
+    let base = flatten_tree(base_path, base_tree)?;
+    let ours = flatten_tree(base_path, our_tree)?;
+    let theirs = flatten_tree(base_path, their_tree)?;
+
+    let paths: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    let mut merged = BTreeMap::new();
+    let mut conflicts = vec![];
+    for path in paths {
+        let (base, ours, theirs) = (base.get(path), ours.get(path), theirs.get(path));
+
+        let entry = if ours == theirs || theirs == base {
+            ours
+        } else if ours == base {
+            theirs
+        } else {
+            conflicts.push(path.clone());
+            continue;
+        };
+
+        if let Some(entry) = entry {
+            merged.insert(path.clone(), entry.clone());
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(MergeConflict { paths: conflicts }.into());
+    }
+
+    write_flattened_tree(base_path, &merged)
+}
+
+/// Write the tree objects for the files in a flattened tree, returning the root tree.
+pub fn write_flattened_tree(
+    base_path: Option<&PathBuf>,
+    files: &BTreeMap<String, TreeEntry>,
+) -> Result<ObjectHash, anyhow::Error> {
+    let mut root = BTreeMap::new();
+    let mut conflicts = vec![];
+
+    for (path, entry) in files {
+        if !insert_file(&mut root, path, entry) {
+            conflicts.push(path.clone());
+        }
+    }
+
+    // One side replaced a directory with a file, or the other way around.
+    if !conflicts.is_empty() {
+        return Err(MergeConflict { paths: conflicts }.into());
+    }
+
+    write_tree_node(base_path, &root)
+}
+
+fn collect_files(
+    base_path: Option<&PathBuf>,
+    tree_hash: &ObjectHash,
+    prefix: &str,
+    files: &mut BTreeMap<String, TreeEntry>,
+) -> Result<(), anyhow::Error> {
+    let entries = match ObjectFile::new(base_path, tree_hash)? {
+        ObjectFile::Tree(contents) => contents.contents,
+        ObjectFile::Other(_) => return Err(anyhow::anyhow!("Expected tree object")),
+    };
+
+    for entry in entries {
+        let path = format!("{}{}", prefix, entry.file_name);
+        match entry.object_type {
+            ObjectType::Tree => {
+                collect_files(base_path, &entry.hash, &format!("{}/", path), files)?
+            }
+            object_type => {
+                files.insert(path, (object_type, entry.hash));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn insert_file(directory: &mut BTreeMap<String, TreeNode>, path: &str, entry: &TreeEntry) -> bool {
+    match path.split_once('/') {
+        None => {
+            if directory.contains_key(path) {
+                return false;
+            }
+            directory.insert(path.to_string(), TreeNode::File(entry.clone()));
+            true
+        }
+        Some((name, rest)) => {
+            let node = directory
+                .entry(name.to_string())
+                .or_insert_with(|| TreeNode::Directory(BTreeMap::new()));
+
+            match node {
+                TreeNode::Directory(children) => insert_file(children, rest, entry),
+                TreeNode::File(_) => false,
+            }
+        }
+    }
+}
+
+fn write_tree_node(
+    base_path: Option<&PathBuf>,
+    directory: &BTreeMap<String, TreeNode>,
+) -> Result<ObjectHash, anyhow::Error> {
+    let mut entries = vec![];
+    for (name, node) in directory {
+        let (object_type, hash) = match node {
+            TreeNode::File((object_type, hash)) => (object_type.clone(), hash.clone()),
+            TreeNode::Directory(children) => {
+                (ObjectType::Tree, write_tree_node(base_path, children)?)
+            }
+        };
+        entries.push((name, object_type, hash));
+    }
+    // The map is ordered by name alone, which puts a directory `a` before a file `a.b`.
+    entries.sort_by(|(name, object_type, _), (other_name, other_type, _)| {
+        objects::compare_tree_entries(name, object_type, other_name, other_type)
+    });
+
+    let mut tree_content = vec![];
+    for (name, object_type, hash) in entries {
+        tree_content.extend(format!("{} {}\0", object_type.to_mode(), name).as_bytes());
+        tree_content.extend(hex::decode(hash.full_hash())?);
+    }
+
+    hash_object::hash_and_write_object(base_path, &ObjectType::Tree, &tree_content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(hash: &str) -> TreeEntry {
+        (ObjectType::Blob, ObjectHash::new(&hash.repeat(40)).unwrap())
+    }
+
+    #[test]
+    fn insert_file_nests_directories() {
+        let mut root = BTreeMap::new();
+        assert!(insert_file(&mut root, "a.txt", &entry("a")));
+        assert!(insert_file(&mut root, "src/lib.rs", &entry("b")));
+        assert!(insert_file(&mut root, "src/bin/main.rs", &entry("c")));
+
+        assert!(matches!(root.get("a.txt"), Some(TreeNode::File(_))));
+        match root.get("src") {
+            Some(TreeNode::Directory(children)) => {
+                assert!(matches!(children.get("lib.rs"), Some(TreeNode::File(_))));
+                assert!(matches!(children.get("bin"), Some(TreeNode::Directory(_))));
+            }
+            _ => panic!("Expected src to be a directory"),
+        }
+    }
+
+    #[test]
+    fn insert_file_refuses_file_directory_collisions() {
+        let mut root = BTreeMap::new();
+        assert!(insert_file(&mut root, "src", &entry("a")));
+        assert!(!insert_file(&mut root, "src/lib.rs", &entry("b")));
+
+        let mut root = BTreeMap::new();
+        assert!(insert_file(&mut root, "src/lib.rs", &entry("b")));
+        assert!(!insert_file(&mut root, "src", &entry("a")));
+    }
+}
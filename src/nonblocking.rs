@@ -0,0 +1,602 @@
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use bytes::Bytes;
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::clone::{self, CloneConfig, GitRef, StagingDir};
+use crate::config::Config;
+use crate::credential::{Credential, CredentialSources};
+use crate::fetch::{self, FetchConfig, FetchResult, Negotiation};
+use crate::http::{self, GIT_PROTOCOL_HEADER, UPLOAD_PACK};
+use crate::http_config::{HttpSettings, LowSpeedCheck};
+use crate::objects::ObjectHash;
+use crate::packfile::PackSpool;
+use crate::progress::{CountingWriter, Progress, RECEIVING_OBJECTS};
+use crate::protocol::{
+    self, FetchOptions, ProtocolVersion, RefAdvertisement, RefUpdateCommand, ReportStatus,
+    ShallowInfo,
+};
+use crate::push::{self, PushConfig, PushResult, RECEIVE_PACK};
+use crate::sideband::{Demultiplexer, Sideband};
+use crate::transport;
+
+// Async versions of clone, fetch and push, for running many of them at once on a tokio
+// runtime without a thread each. Only smart HTTP is supported: the other transports run a
+// command or read files, so they would block anyway.
+// The work on the repository's own files, such as unpacking objects and checking out, and
+// the credential helpers would hold up the runtime's other tasks, so they run on tokio's
+// blocking threads, the way `http_server` answers requests.
+
+/// Clone into the path given, or a directory named after the repository, relative to the
+/// base path, like `clone::perform_clone`.
+pub async fn clone(
+    base_path: Option<&PathBuf>,
+    mut config: CloneConfig<'_>,
+) -> Result<(GitRef, usize), anyhow::Error> {
+    let dest_dir = clone::destination(base_path, &config)?;
+    let staging_dir = StagingDir::create(&dest_dir)?;
+    let base_path = &staging_dir.path;
+
+    let client_config = clone::client_config(base_path, &config)?;
+    let transport = HttpTransport::connect(&client_config, &config.url, UPLOAD_PACK).await?;
+
+    let refs = transport.list_refs(&clone::REF_PREFIXES).await?;
+    let plan = clone::plan_clone(refs, &config)?;
+    clone::start_clone(base_path, &config, &config.url, &plan)?;
+
+    let progress = config.progress.as_mut();
+    let mut spool = PackSpool::create(Some(base_path))?;
+    let mut receiving = CountingWriter::new(&mut spool, progress, RECEIVING_OBJECTS);
+    let shallow_info = transport
+        .fetch_pack(
+            &plan.wants(),
+            &[],
+            &plan.options,
+            &mut std::io::stderr(),
+            &mut receiving,
+        )
+        .await
+        .context("Failed to get commit")?;
+    receiving.finish();
+
+    let work_path = base_path.clone();
+    let filter = config.filter.clone();
+    let no_checkout = config.no_checkout;
+    let result = run_blocking(progress, move |progress| {
+        let objects = fetch::store_pack(Some(&work_path), spool, &shallow_info, progress)?;
+        clone::finish_clone(
+            &work_path,
+            filter.as_ref(),
+            no_checkout,
+            plan,
+            objects,
+            progress,
+        )
+    })
+    .await?;
+    staging_dir.persist(&dest_dir)?;
+
+    Ok(result)
+}
+
+/// Fetch from the remote, like `fetch::fetch`.
+pub async fn fetch(
+    base_path: Option<&PathBuf>,
+    mut config: FetchConfig<'_>,
+) -> Result<FetchResult, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    let url = fetch::remote_url(&repo_config, config.remote)?;
+    let transport = HttpTransport::connect(&repo_config, &url, UPLOAD_PACK).await?;
+
+    let refspecs = fetch::fetch_refspecs(base_path, &config)?;
+    let remote_refs = transport.list_refs(&fetch::ref_prefixes(&refspecs)).await?;
+    let plan = fetch::plan_fetch(base_path, &config, refspecs, remote_refs)?;
+
+    let mut objects = 0;
+    if !plan.wants.is_empty() {
+        let progress = config.progress.as_mut();
+        let mut spool = PackSpool::create(base_path)?;
+        let mut receiving = CountingWriter::new(&mut spool, progress, RECEIVING_OBJECTS);
+        let shallow_info = transport
+            .fetch_pack(
+                &plan.wants(),
+                &plan.haves,
+                &plan.options,
+                &mut std::io::stderr(),
+                &mut receiving,
+            )
+            .await?;
+        receiving.finish();
+
+        let work_path = base_path.cloned();
+        objects = run_blocking(progress, move |progress| {
+            fetch::store_pack(work_path.as_ref(), spool, &shallow_info, progress)
+        })
+        .await?;
+    }
+
+    fetch::finish_fetch(base_path, &config, plan, objects)
+}
+
+/// Push to the remote, like `push::push`.
+pub async fn push(
+    base_path: Option<&PathBuf>,
+    mut config: PushConfig<'_>,
+) -> Result<PushResult, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    let url = push::push_url(&repo_config, config.remote)?;
+    let transport = HttpTransport::connect(&repo_config, &url, RECEIVE_PACK).await?;
+
+    let remote_refs = transport.list_refs(&["refs/"]).await?;
+    let plan = push::plan_push(base_path, &config, &remote_refs)?;
+
+    let report = match plan.commands.is_empty() {
+        true => None,
+        false => {
+            plan.start_writing(config.progress.as_mut());
+            let report = transport
+                .send_pack(&plan.commands, plan.pack.as_deref(), &mut std::io::stderr())
+                .await
+                .context("Failed to push")?;
+            plan.finish_writing(config.progress.as_mut());
+            Some(report)
+        }
+    };
+
+    push::finish_push(base_path, plan, report)
+}
+
+/// Run work on the repository's files on a blocking thread, passing on the progress it
+/// reports as it goes.
+async fn run_blocking<T, F>(progress: &mut dyn Progress, work: F) -> Result<T, anyhow::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn Progress) -> Result<T, anyhow::Error> + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let task = tokio::task::spawn_blocking(move || work(&mut ProgressSender(sender)));
+
+    // The channel closes when the work is done, as the sender is dropped with it.
+    while let Some(event) = receiver.recv().await {
+        match event {
+            ProgressEvent::Start(phase, total) => progress.start(&phase, total),
+            ProgressEvent::Update(done, bytes) => progress.update(done, bytes),
+            ProgressEvent::Finish => progress.finish(),
+        }
+    }
+    task.await?
+}
+
+enum ProgressEvent {
+    Start(String, Option<usize>),
+    Update(usize, Option<u64>),
+    Finish,
+}
+
+/// Progress reported on a blocking thread, sent back to the task that's waiting on it.
+struct ProgressSender(mpsc::UnboundedSender<ProgressEvent>);
+
+impl Progress for ProgressSender {
+    fn start(&mut self, phase: &str, total: Option<usize>) {
+        let _ = self.0.send(ProgressEvent::Start(phase.to_string(), total));
+    }
+
+    fn update(&mut self, done: usize, bytes: Option<u64>) {
+        let _ = self.0.send(ProgressEvent::Update(done, bytes));
+    }
+
+    fn finish(&mut self) {
+        let _ = self.0.send(ProgressEvent::Finish);
+    }
+}
+
+/// The HTTP client for talking to a remote, like `http::HttpClient`. Credential helpers are
+/// only run when the server asks for credentials.
+pub struct HttpClient {
+    client: Client,
+    settings: HttpSettings,
+    sources: CredentialSources,
+    credential: Mutex<Option<Credential>>,
+}
+
+impl HttpClient {
+    pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
+        let settings = HttpSettings::from_config(config)?;
+        Ok(HttpClient {
+            client: settings.build_async_client()?,
+            settings,
+            sources: CredentialSources::from_config(config),
+            credential: Mutex::new(None),
+        })
+    }
+
+    /// Send the request built for the URL, authenticating if the server asks us to with a
+    /// 401, the same way as the blocking client.
+    async fn send<F>(&self, url: &str, retryable: bool, build: F) -> Result<Response, anyhow::Error>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+    {
+        let (url, url_credential) = http::split_credentials(url)?;
+        let mut credential = self.credential.lock().await;
+        if credential.is_none() {
+            *credential = url_credential;
+        }
+
+        let response = self
+            .send_with_retries(retryable, || {
+                authorize(build(&self.client, &url), credential.as_ref())
+            })
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let authentication_failed = || anyhow::anyhow!("Authentication failed for '{}'", url);
+
+        // Credentials that were already complete were wrong, so there's nothing to retry with.
+        let mut attempt = match credential.take() {
+            Some(rejected) if rejected.is_complete() => {
+                self.run_helpers(move |sources| sources.reject(&rejected))
+                    .await?;
+                return Err(authentication_failed());
+            }
+            Some(partial) => partial,
+            None => http::credential_for_url(&url)?,
+        };
+        attempt.wwwauth = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|header| header.to_str().ok().map(String::from))
+            .collect();
+        let attempt = self
+            .run_helpers(move |sources| sources.fill(&mut attempt).map(|_| attempt))
+            .await??;
+
+        let response = self
+            .send_with_retries(retryable, || {
+                authorize(build(&self.client, &url), Some(&attempt))
+            })
+            .await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.run_helpers(move |sources| sources.reject(&attempt))
+                .await?;
+            return Err(authentication_failed());
+        }
+
+        if response.status().is_success() {
+            let approved = attempt.clone();
+            self.run_helpers(move |sources| sources.approve(&approved))
+                .await?;
+        }
+        *credential = Some(attempt);
+
+        Ok(response)
+    }
+
+    /// Run the credential helpers, which are commands that may wait on the user, on a
+    /// blocking thread.
+    async fn run_helpers<T, F>(&self, work: F) -> Result<T, anyhow::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&CredentialSources) -> T + Send + 'static,
+    {
+        let sources = self.sources.clone();
+        Ok(tokio::task::spawn_blocking(move || work(&sources)).await?)
+    }
+
+    async fn send_with_retries<F>(
+        &self,
+        retryable: bool,
+        build: F,
+    ) -> Result<Response, anyhow::Error>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let result = build().send().await;
+            if !retryable {
+                return Ok(result?);
+            }
+            let outcome = result
+                .as_ref()
+                .map(|response| (response.status(), response.headers()));
+            match self.settings.retry_delay(attempt, outcome) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Ok(result?),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// The next chunk of the body as it arrives, aborting if the transfer is too slow.
+    async fn read_chunk(
+        &self,
+        response: &mut Response,
+        low_speed_check: &mut LowSpeedCheck,
+    ) -> Result<Option<Bytes>, anyhow::Error> {
+        let chunk = match low_speed_check.time() {
+            Some(time) => tokio::time::timeout(time, response.chunk())
+                .await
+                .map_err(|_| anyhow::anyhow!("Transfer stalled for {} seconds", time.as_secs()))?,
+            None => response.chunk().await,
+        };
+        let chunk = chunk.context("Reading response body")?;
+
+        if let Some(chunk) = &chunk {
+            low_speed_check.record(chunk.len())?;
+        }
+        Ok(chunk)
+    }
+
+    /// Read the whole body, for the responses that are small enough to keep in memory.
+    async fn read_body(&self, mut response: Response) -> Result<Vec<u8>, anyhow::Error> {
+        let mut body = vec![];
+        let mut low_speed_check = self.settings.low_speed_check();
+        while let Some(chunk) = self.read_chunk(&mut response, &mut low_speed_check).await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+fn authorize(request: RequestBuilder, credential: Option<&Credential>) -> RequestBuilder {
+    let credential = match credential {
+        Some(credential) => credential,
+        None => return request,
+    };
+
+    match (&credential.authtype, &credential.credential) {
+        (Some(authtype), Some(token)) => {
+            request.header(AUTHORIZATION, format!("{} {}", authtype, token))
+        }
+        _ => match &credential.username {
+            Some(username) => request.basic_auth(username, credential.password.as_ref()),
+            None => request,
+        },
+    }
+}
+
+/// Smart HTTP to the service of a remote, like `transport::HttpTransport`. Its responses
+/// are read as they arrive, so a packfile is handed on without waiting for all of it.
+pub struct HttpTransport {
+    service: String,
+    client: HttpClient,
+    url: String,
+    advertisement: RefAdvertisement,
+}
+
+impl HttpTransport {
+    /// Ask the server for the refs and capabilities of the service.
+    pub async fn connect(config: &Config, url: &str, service: &str) -> Result<Self, anyhow::Error> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(anyhow::anyhow!(
+                "Only HTTP(S) remotes can be used asynchronously, not {}",
+                url
+            ));
+        }
+
+        let client = HttpClient::new(config)?;
+        let advertisement = discover_references(&client, url, service).await?;
+        Ok(HttpTransport {
+            service: service.to_string(),
+            client,
+            url: url.to_string(),
+            advertisement,
+        })
+    }
+
+    /// The refs of the remote matching any of the prefixes, like `Transport::list_refs`.
+    pub async fn list_refs(&self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error> {
+        if self.advertisement.version != ProtocolVersion::V2 {
+            return Ok(self.advertisement.refs.clone());
+        }
+
+        let body = protocol::create_ls_refs_request(&self.advertisement, ref_prefixes);
+        let response = self
+            .post(ProtocolVersion::V2, UPLOAD_PACK, body)
+            .await
+            .context("Failed to list refs")?;
+        let response = self.client.read_body(response).await?;
+        protocol::parse_ls_refs_response(&response)
+    }
+
+    /// Negotiate a packfile of the wanted objects, like `Transport::fetch_pack`, writing it
+    /// to `pack` as it arrives. Returns the new boundary of a shallow history.
+    pub async fn fetch_pack<W: Write, P: Write>(
+        &self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+        options: &FetchOptions,
+        progress: &mut W,
+        pack: &mut P,
+    ) -> Result<ShallowInfo, anyhow::Error> {
+        transport::check_service(&self.service, UPLOAD_PACK)?;
+        let mut negotiation =
+            Negotiation::new(&self.advertisement, wants, haves, options, progress)?;
+
+        loop {
+            let body = negotiation.next_request();
+            let mut response = self
+                .post(self.advertisement.version, UPLOAD_PACK, body)
+                .await
+                .context("Failed to fetch packfile")?;
+            let mut low_speed_check = self.client.settings.low_speed_check();
+
+            // We can't tell whether the acknowledgments have all arrived until they can be
+            // read, so we keep what we have and read it again when more arrives. It's only
+            // the packfile that's large.
+            let mut head = vec![];
+            let (shallow_info, position) = loop {
+                let chunk = self
+                    .client
+                    .read_chunk(&mut response, &mut low_speed_check)
+                    .await?;
+                let ended = chunk.is_none();
+                head.extend_from_slice(&chunk.unwrap_or_default());
+
+                let mut cursor = Cursor::new(head.as_slice());
+                match negotiation.read_response_head(&mut cursor) {
+                    Ok(Some(shallow_info)) => {
+                        break (Some(shallow_info), cursor.position() as usize)
+                    }
+                    _ if !ended => continue,
+                    Ok(None) => break (None, 0),
+                    Err(e) => return Err(e),
+                }
+            };
+            let shallow_info = match shallow_info {
+                Some(shallow_info) => shallow_info,
+                None => continue,
+            };
+
+            let rest = &head[position..];
+            match negotiation.sideband() {
+                Sideband::Disabled => {
+                    pack.write_all(rest)?;
+                    while let Some(chunk) = self
+                        .client
+                        .read_chunk(&mut response, &mut low_speed_check)
+                        .await?
+                    {
+                        pack.write_all(&chunk)?;
+                    }
+                }
+                _ => {
+                    let mut demultiplexer = Demultiplexer::new(progress);
+                    demultiplexer.write(rest, pack)?;
+                    while let Some(chunk) = self
+                        .client
+                        .read_chunk(&mut response, &mut low_speed_check)
+                        .await?
+                    {
+                        demultiplexer.write(&chunk, pack)?;
+                    }
+                    demultiplexer.finish()?;
+                }
+            }
+
+            return Ok(shallow_info);
+        }
+    }
+
+    /// Send the ref updates along with a packfile of the objects they need, like
+    /// `Transport::send_pack`.
+    pub async fn send_pack<W: Write>(
+        &self,
+        commands: &[RefUpdateCommand],
+        pack: Option<&[u8]>,
+        progress: &mut W,
+    ) -> Result<ReportStatus, anyhow::Error> {
+        transport::check_service(&self.service, RECEIVE_PACK)?;
+        let body = transport::receive_pack_request(&self.advertisement, commands, pack)?;
+        let response = self.post(ProtocolVersion::V0, RECEIVE_PACK, body).await?;
+        let response = self.client.read_body(response).await?;
+        transport::read_receive_pack_response(&self.advertisement, &response, progress)
+    }
+
+    /// Post the request to the service, like `http::post_service_request`, returning the
+    /// response for its body to be read.
+    async fn post(
+        &self,
+        version: ProtocolVersion,
+        service_name: &str,
+        body: Vec<u8>,
+    ) -> Result<Response, anyhow::Error> {
+        let want_content_type = format!("application/x-{}-result", service_name);
+
+        // Fetching can be asked for again, but a push may already have updated refs.
+        let retryable = service_name == UPLOAD_PACK;
+        let resp = self
+            .client
+            .send(&self.url, retryable, |client, url| {
+                let request = client
+                    .post(format!("{}/{}", url, service_name))
+                    .body(body.clone())
+                    .header(
+                        CONTENT_TYPE,
+                        format!("application/x-{}-request", service_name),
+                    )
+                    .header(ACCEPT, &want_content_type);
+                with_protocol_header(request, version)
+            })
+            .await?;
+
+        let status = resp.status().as_u16();
+        if status != 200 && status != 304 {
+            return Err(anyhow::anyhow!(format!(
+                "Status code must be either 200 or 304, received {}",
+                status
+            )));
+        }
+
+        let content_type = content_type(&resp);
+        if content_type != want_content_type {
+            return Err(anyhow::anyhow!(
+                "Content-Type must equal {}, received {}",
+                want_content_type,
+                content_type
+            ));
+        }
+
+        Ok(resp)
+    }
+}
+
+/// Request the refs and capabilities of the server, like `http::discover_references`.
+/// Servers that only serve files can't be used, since fetching from them takes a request
+/// per object.
+async fn discover_references(
+    client: &HttpClient,
+    url: &str,
+    service_name: &str,
+) -> Result<RefAdvertisement, anyhow::Error> {
+    let resp = client
+        .send(url, true, |client, url| {
+            let request_url = format!("{}/info/refs?service={}", url, service_name);
+            with_protocol_header(client.get(request_url), ProtocolVersion::V2)
+        })
+        .await?;
+
+    let status = resp.status().as_u16();
+    if status != 200 && status != 304 {
+        return Err(anyhow::anyhow!(format!(
+            "Failed to get refs: Status code must be either 200 or 304, received {}",
+            status
+        )));
+    }
+
+    let want_content_type = format!("application/x-{}-advertisement", service_name);
+    let content_type = content_type(&resp);
+    if content_type != want_content_type {
+        return Err(anyhow::anyhow!(format!(
+            "Content-Type must equal {}, received {}",
+            want_content_type, content_type
+        )));
+    }
+
+    let bytes = client.read_body(resp).await?;
+    protocol::parse_advertisement(&bytes, service_name)
+}
+
+fn content_type(response: &Response) -> String {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn with_protocol_header(request: RequestBuilder, version: ProtocolVersion) -> RequestBuilder {
+    match version {
+        ProtocolVersion::V2 => request.header(GIT_PROTOCOL_HEADER, protocol::PROTOCOL_V2),
+        ProtocolVersion::V0 | ProtocolVersion::Dumb => request,
+    }
+}
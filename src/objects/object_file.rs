@@ -4,6 +4,7 @@ use std::str::FromStr;
 use anyhow::Context;
 
 use super::{ObjectHash, ObjectType, TreeObject};
+use crate::promisor;
 use crate::utils::{decode_file, split_header_from_contents};
 
 /// A representation of a git object file. Trees are unique in that the contents
@@ -49,6 +50,12 @@ impl ObjectFile {
             None => hash.into(),
         };
 
+        // A partial clone leaves objects out, which are fetched from the promisor remote
+        // the first time they're needed.
+        if !path.is_file() && promisor::is_partial_clone(base_path) {
+            promisor::fetch_missing(base_path, std::slice::from_ref(hash))?;
+        }
+
         let contents = decode_file(path).context("Decoding file from hash")?;
 
         let (header, body) =
@@ -75,6 +82,18 @@ impl ObjectFile {
     }
 }
 
+impl ObjectFile {
+    /// Whether the object is present in the repository.
+    pub fn exists(base_path: Option<&PathBuf>, hash: &ObjectHash) -> bool {
+        let path: PathBuf = match base_path {
+            Some(base_path) => base_path.join(hash.path()),
+            None => hash.into(),
+        };
+
+        path.is_file()
+    }
+}
+
 impl TryFrom<&ObjectHash> for ObjectFile {
     type Error = anyhow::Error;
 
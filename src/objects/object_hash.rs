@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use hex::ToHex;
 
 // TODO: Remove clone derive when tree objects take a reference to the hash.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ObjectHash {
     prefix: String,
     hash: String,
@@ -36,6 +36,11 @@ impl ObjectHash {
         self.prefix.to_string() + &self.hash.to_string()
     }
 
+    /// The first seven characters of the hash, which is how git abbreviates them.
+    pub fn short_hash(&self) -> String {
+        self.full_hash()[..7].to_string()
+    }
+
     pub fn path(&self) -> PathBuf {
         self.into()
     }
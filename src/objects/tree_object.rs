@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::io::{BufRead, Cursor, ErrorKind, Read};
 
 use super::{ObjectHash, ObjectType};
@@ -37,6 +38,20 @@ impl TreeObject {
     }
 }
 
+/// The order git keeps tree entries in: by name, with directories compared as though their
+/// names ended in `/`. So a file `a.b` comes before a directory `a`, which git's fsck checks.
+pub fn compare_tree_entries(
+    name: &str,
+    object_type: &ObjectType,
+    other_name: &str,
+    other_type: &ObjectType,
+) -> Ordering {
+    let suffix = |object_type: &ObjectType| (*object_type == ObjectType::Tree).then_some(b'/');
+    name.bytes()
+        .chain(suffix(object_type))
+        .cmp(other_name.bytes().chain(suffix(other_type)))
+}
+
 fn read_next_tree_file(cursor: &mut Cursor<&[u8]>) -> Result<Option<TreeObject>, anyhow::Error> {
     let mut mode_file_name = vec![];
 
@@ -71,6 +86,25 @@ fn read_next_tree_file(cursor: &mut Cursor<&[u8]>) -> Result<Option<TreeObject>,
 mod tests {
     use super::*;
 
+    #[test]
+    fn compare_tree_entries_sorts_directories_with_a_slash() {
+        let blob = ObjectType::Blob;
+        let tree = ObjectType::Tree;
+
+        assert_eq!(
+            compare_tree_entries("a.b", &blob, "a", &tree),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_tree_entries("a", &blob, "a.b", &blob),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_tree_entries("a", &tree, "a0", &blob),
+            Ordering::Less
+        );
+    }
+
     #[test]
     fn from_object_success() {
         let mut file_contents: Vec<u8> = vec![];
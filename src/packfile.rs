@@ -1,11 +1,24 @@
-use std::io::{Cursor, ErrorKind, Read};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{
+    self, BufRead, BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write,
+};
 use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::Context;
 
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use sha1::{Digest, Sha1};
+
 use crate::hash_object;
-use crate::objects::{ObjectHash, ObjectType};
-use crate::utils::read_next_zlib_data;
+use crate::objects::{ObjectFile, ObjectHash, ObjectType};
+use crate::progress::{NoProgress, Progress};
+use crate::utils::{decode_file, split_header_from_contents};
 
 const VARINT_ENCODING_BITS: u8 = 7;
 
@@ -22,6 +35,18 @@ const MAX_OFFSET_BYTES: usize = 4;
 // Maximum number of mgihts that be specified in size.
 const MAX_SIZE_BYTES: usize = 3;
 
+// PACK + 4 bytes for the version number + 4 bytes for the number of objects.
+pub const PACKFILE_HEADER_SIZE: usize = 12;
+
+// The SHA-1 of everything before it that ends a packfile.
+const CHECKSUM_SIZE: usize = 20;
+
+// Where packfiles are spooled while they're received, like git's `index-pack`.
+const PACK_DIR: [&str; 3] = ["not-git", "objects", "pack"];
+
+// Tells apart the spools of packfiles received at the same time by this process.
+static SPOOL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
 pub struct PackfileHeader {
     pub signature: String,
@@ -29,11 +54,13 @@ pub struct PackfileHeader {
     pub num_objects: u32,
 }
 
+/// An object that was unpacked: where it was in the packfile and the object it became.
+/// Its contents are only in the object store, so unpacking a large packfile doesn't keep
+/// them all in memory.
 #[derive(Debug)]
 pub struct PackfileObject {
     pub object_type: PackfileObjectType,
     pub size: usize,
-    pub data: Vec<u8>,
     pub position: usize,
     pub file_hash: ObjectHash,
     pub file_type: ObjectType,
@@ -61,16 +88,16 @@ pub struct InsertInstruction {
 }
 
 impl PackfileHeader {
+    /// Parse the 12 byte header at the start of a packfile: `PACK`, the version number
+    /// and the number of objects. Any negotiation lines must already have been removed.
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, anyhow::Error> {
-        let head = String::from_utf8(bytes[0..8].to_vec())?;
-
-        if head != "0008NAK\n" {
+        if bytes.len() != PACKFILE_HEADER_SIZE {
             return Err(anyhow::anyhow!("Invalid packfile header"));
         }
 
-        let signature = String::from_utf8(bytes[8..12].to_vec())?;
-        let version_number = u32::from_be_bytes(bytes[12..16].try_into()?);
-        let num_objects = u32::from_be_bytes(bytes[16..].try_into()?);
+        let signature = String::from_utf8(bytes[0..4].to_vec())?;
+        let version_number = u32::from_be_bytes(bytes[4..8].try_into()?);
+        let num_objects = u32::from_be_bytes(bytes[8..].try_into()?);
 
         if signature != "PACK" {
             return Err(anyhow::anyhow!("Invalid packfile signature"));
@@ -134,87 +161,477 @@ impl PackfileObjectType {
     }
 }
 
-pub fn decode_undeltified_data(
+/// Write every object in the packfile to the object store, resolving deltas against
+/// objects earlier or later in the same packfile, or already in the store.
+pub fn unpack_objects(
+    base_path: &PathBuf,
+    pack: &[u8],
+) -> Result<Vec<PackfileObject>, anyhow::Error> {
+    let mut spool = PackSpool::create(Some(base_path))?;
+    spool.write_all(pack)?;
+    spool.unpack(base_path, &mut NoProgress)
+}
+
+/// A packfile being received, written to `objects/pack/tmp_pack_*` as it arrives rather
+/// than kept in memory. What's written is hashed along the way, so the checksum at the end
+/// is checked without reading it all again. The file is removed when dropped.
+pub struct PackSpool {
+    path: PathBuf,
+    file: BufWriter<File>,
+    checksum: PackChecksum,
+}
+
+impl PackSpool {
+    pub fn create(base_path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let path = temporary_pack_path(base_path)?;
+        let file = File::create(&path).context(format!("Creating {:?}", path))?;
+
+        Ok(PackSpool {
+            path,
+            file: BufWriter::new(file),
+            checksum: PackChecksum::new(),
+        })
+    }
+
+    /// Check that the whole packfile arrived, then write its objects to the object store.
+    /// The packfile is read back from the spool, so only one object is in memory at a time.
+    pub fn unpack(
+        mut self,
+        base_path: &PathBuf,
+        progress: &mut dyn Progress,
+    ) -> Result<Vec<PackfileObject>, anyhow::Error> {
+        self.file.flush()?;
+        std::mem::take(&mut self.checksum).verify()?;
+
+        let file = File::open(&self.path).context(format!("Reading {:?}", self.path))?;
+        unpack_file(base_path, file, progress)
+    }
+}
+
+impl Write for PackSpool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write_all(buf)?;
+        self.checksum.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for PackSpool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A new path for a packfile that's being put together or received, in `objects/pack` like
+/// git's temporary packfiles. Nothing else uses it while this process is running.
+pub fn temporary_pack_path(base_path: Option<&PathBuf>) -> Result<PathBuf, anyhow::Error> {
+    let dir: PathBuf = PACK_DIR.iter().collect();
+    let dir = match base_path {
+        Some(base_path) => base_path.join(dir),
+        None => dir,
+    };
+    fs::create_dir_all(&dir).context(format!("Creating {:?} directory", dir))?;
+
+    let name = format!(
+        "tmp_pack_{}_{}",
+        process::id(),
+        SPOOL_COUNT.fetch_add(1, Ordering::Relaxed)
+    );
+    Ok(dir.join(name))
+}
+
+/// Checks the checksum at the end of a packfile against the rest of it, as it's written
+/// through in pieces.
+#[derive(Default)]
+pub struct PackChecksum {
+    hasher: Sha1,
+    // The last bytes written aren't hashed yet, since they're the checksum if nothing else
+    // follows.
+    tail: Vec<u8>,
+}
+
+impl PackChecksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn verify(self) -> Result<(), anyhow::Error> {
+        if self.tail.len() < CHECKSUM_SIZE {
+            return Err(anyhow::anyhow!("Packfile is too short to contain a header"));
+        }
+        if self.hasher.finalize().as_slice() != self.tail.as_slice() {
+            return Err(anyhow::anyhow!(
+                "Packfile checksum does not match its contents"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Write for PackChecksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tail.extend_from_slice(buf);
+        if self.tail.len() > CHECKSUM_SIZE {
+            let hashed = self.tail.len() - CHECKSUM_SIZE;
+            self.hasher.update(&self.tail[..hashed]);
+            self.tail.drain(..hashed);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads a packfile in order, keeping count of where it is for offset deltas.
+struct PackReader<R: BufRead> {
+    reader: R,
+    position: usize,
+}
+
+impl<R: BufRead> Read for PackReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for PackReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.position += amt;
+    }
+}
+
+/// What a delta is applied to: the object at a position earlier in the packfile, or the
+/// object with a hash, which can be anywhere in the packfile or already in the store.
+#[derive(Debug)]
+enum DeltaBase {
+    Position(usize),
+    Hash(ObjectHash),
+}
+
+/// A delta whose base hadn't been unpacked yet when we got to it. Its data is read again
+/// from the spool once the base has been.
+struct PendingDelta {
+    object_type: PackfileObjectType,
+    position: usize,
+    base: DeltaBase,
+    data_position: usize,
+}
+
+fn unpack_file(
+    base_path: &PathBuf,
+    file: File,
+    progress: &mut dyn Progress,
+) -> Result<Vec<PackfileObject>, anyhow::Error> {
+    let mut reader = PackReader {
+        reader: BufReader::new(file),
+        position: 0,
+    };
+
+    let mut header = vec![0; PACKFILE_HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .context("Packfile is too short to contain a header")?;
+    let header = PackfileHeader::from_bytes(header)?;
+
+    let mut objects: Vec<PackfileObject> = vec![];
+    // The hashes of the objects by their position, to find the bases of offset deltas.
+    let mut hashes: HashMap<usize, ObjectHash> = HashMap::new();
+    let mut pending = vec![];
+
+    progress.start("Unpacking objects", Some(header.num_objects as usize));
+    for index in 0..header.num_objects as usize {
+        progress.update(index, None);
+        let position = reader.position;
+        let object_type = read_type_and_length(&mut reader)?;
+
+        let file_type = match object_type {
+            PackfileObjectType::Commit(_) => ObjectType::Commit,
+            PackfileObjectType::Tree(_) => ObjectType::Tree,
+            PackfileObjectType::Blob(_) => ObjectType::Blob,
+            PackfileObjectType::Tag(_) => ObjectType::Tag,
+            PackfileObjectType::OfsDelta(_) | PackfileObjectType::RefDelta(_) => {
+                let base = read_delta_base(&mut reader, position, &object_type)?;
+                let data_position = reader.position;
+
+                match find_base(base_path, &hashes, &base)? {
+                    Some(base) => {
+                        let (file_hash, file_type) = apply_delta(base_path, base, &mut reader)?;
+                        hashes.insert(position, file_hash.clone());
+                        objects.push(PackfileObject {
+                            size: object_type.length(),
+                            object_type,
+                            position,
+                            file_hash,
+                            file_type,
+                        });
+                    }
+                    None => {
+                        // We still have to read the data to know where the next object is.
+                        io::copy(&mut ZlibDecoder::new(&mut reader), &mut io::sink())?;
+                        pending.push(PendingDelta {
+                            object_type,
+                            position,
+                            base,
+                            data_position,
+                        });
+                    }
+                }
+                continue;
+            }
+        };
+
+        let (_, file_hash, file_type) = decode_undeltified_data(base_path, file_type, &mut reader)?;
+        hashes.insert(position, file_hash.clone());
+        objects.push(PackfileObject {
+            size: object_type.length(),
+            object_type,
+            position,
+            file_hash,
+            file_type,
+        });
+    }
+
+    progress.update(header.num_objects as usize, None);
+    progress.finish();
+
+    // Each pass resolves the deltas whose bases were resolved by the one before.
+    let mut file = reader.reader.into_inner();
+    let deltas = pending.len();
+    let mut resolved = 0;
+    if deltas > 0 {
+        progress.start("Resolving deltas", Some(deltas));
+    }
+    while !pending.is_empty() {
+        let count = pending.len();
+        let mut unresolved = vec![];
+
+        for delta in pending {
+            let base = match find_base(base_path, &hashes, &delta.base)? {
+                Some(base) => base,
+                None => {
+                    unresolved.push(delta);
+                    continue;
+                }
+            };
+
+            file.seek(SeekFrom::Start(delta.data_position as u64))?;
+            let (file_hash, file_type) =
+                apply_delta(base_path, base, &mut BufReader::new(&mut file))?;
+            hashes.insert(delta.position, file_hash.clone());
+            objects.push(PackfileObject {
+                size: delta.object_type.length(),
+                object_type: delta.object_type,
+                position: delta.position,
+                file_hash,
+                file_type,
+            });
+            resolved += 1;
+            progress.update(resolved, None);
+        }
+
+        if unresolved.len() == count {
+            return Err(anyhow::anyhow!(
+                "Unable to find the base of the delta at position {} in packfile: {:?}",
+                unresolved[0].position,
+                unresolved[0].base
+            ));
+        }
+        pending = unresolved;
+    }
+    if deltas > 0 {
+        progress.finish();
+    }
+
+    Ok(objects)
+}
+
+pub fn decode_undeltified_data<R: BufRead>(
     base_path: &PathBuf,
     file_type: ObjectType,
-    cursor: &mut Cursor<&[u8]>,
+    reader: &mut R,
 ) -> Result<(Vec<u8>, ObjectHash, ObjectType), anyhow::Error> {
-    let data = read_next_zlib_data(cursor)?;
-    let hash = hash_object::hash_and_write_object(Some(base_path), &file_type, &mut data.clone())?;
+    let data = read_zlib_data(reader)?;
+    let hash = hash_object::hash_and_write_object(Some(base_path), &file_type, &data)?;
     Ok((data, hash, file_type))
 }
 
-pub fn read_obj_offset_data(
+// Read zlib data up to where it ends, and not a byte further, since the next object
+// follows right after it.
+fn read_zlib_data<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, anyhow::Error> {
+    let mut data = vec![];
+    ZlibDecoder::new(reader).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn read_delta_base<R: Read>(
+    reader: &mut R,
+    position: usize,
+    object_type: &PackfileObjectType,
+) -> Result<DeltaBase, anyhow::Error> {
+    if let PackfileObjectType::RefDelta(_) = object_type {
+        let mut ref_sha: [u8; 20] = [0; 20];
+        reader.read_exact(&mut ref_sha)?;
+        return Ok(DeltaBase::Hash(ObjectHash::from_bytes(&ref_sha)?));
+    }
+
+    // The offset is back from the start of this object, so we are guaranteed to have seen
+    // the base before now.
+    let offset = read_offset(reader)?;
+    let base_position = position.checked_sub(offset).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Invalid offset {} for the delta at position {} in packfile",
+            offset,
+            position
+        )
+    })?;
+    Ok(DeltaBase::Position(base_position))
+}
+
+/// Read the offset of an offset delta's base. Unlike other variable-length numbers, it's in
+/// big-endian order, and each byte after the first adds one so that no two encodings
+/// have the same value.
+/// CF https://git-scm.com/docs/pack-format#_original_version_1_pack_idx_files_have_the_following_format
+pub fn read_offset<R: Read>(reader: &mut R) -> Result<usize, anyhow::Error> {
+    let (byte, mut more_bytes) = read_varint_byte(reader)?;
+    let mut offset = byte as usize;
+
+    while more_bytes {
+        let (byte, more) = read_varint_byte(reader)?;
+        offset = ((offset + 1) << VARINT_ENCODING_BITS) | byte as usize;
+        more_bytes = more;
+    }
+
+    Ok(offset)
+}
+
+/// The type and contents of the base of a delta, read back from the object store, or
+/// `None` if it hasn't been unpacked yet.
+fn find_base(
     base_path: &PathBuf,
-    objects: &[PackfileObject],
-    cursor: &mut Cursor<&[u8]>,
-) -> Result<(Vec<u8>, ObjectHash, ObjectType), anyhow::Error> {
-    // We need to read the offset from the packfile. The offset is variable-sized
-    // but negative so we are guaranteed to have seen it before now.
-    // Then we need to find the object that starts at that position.
-    // Once we have that file, then we can get the data delta.
-    let current_position = cursor.position();
-
-    // This will move the cursor ahead by the number of bytes in the varint
-    let negative_offset = read_varint_bytes_le(cursor)?;
-    let position = current_position - negative_offset as u64;
-    let object = objects
-        .iter()
-        .find(|object| object.position as u64 == position)
-        .ok_or_else(|| {
-            anyhow::anyhow!(format!(
-                "Unable to find object with position {} in packfile",
-                position
-            ))
-        })?;
-
-    compile_file_from_deltas(base_path, cursor, object)
-}
-
-pub fn read_obj_ref_data(
+    hashes: &HashMap<usize, ObjectHash>,
+    base: &DeltaBase,
+) -> Result<Option<(ObjectType, Vec<u8>)>, anyhow::Error> {
+    let hash = match base {
+        DeltaBase::Position(position) => match hashes.get(position) {
+            Some(hash) => hash,
+            None => return Ok(None),
+        },
+        DeltaBase::Hash(hash) if ObjectFile::exists(Some(base_path), hash) => hash,
+        DeltaBase::Hash(_) => return Ok(None),
+    };
+
+    let contents = decode_file(base_path.join(hash.path()))
+        .context(format!("Reading object {}", hash.full_hash()))?;
+    let (header, body) = split_header_from_contents(&contents)?;
+    let header = String::from_utf8(header.to_vec())?;
+    let object_type = header
+        .split_once(' ')
+        .map(|(object_type, _)| object_type)
+        .ok_or_else(|| anyhow::anyhow!("Invalid object header {}", header))?;
+
+    Ok(Some((ObjectType::from_str(object_type)?, body.to_vec())))
+}
+
+/// Read the delta's data and write the object it makes of its base to the store.
+fn apply_delta<R: BufRead>(
     base_path: &PathBuf,
-    objects: &[PackfileObject],
-    cursor: &mut Cursor<&[u8]>,
-) -> Result<(Vec<u8>, ObjectHash, ObjectType), anyhow::Error> {
-    let mut ref_sha: [u8; 20] = [0; 20];
-    cursor.read_exact(&mut ref_sha)?;
+    (file_type, base): (ObjectType, Vec<u8>),
+    reader: &mut R,
+) -> Result<(ObjectHash, ObjectType), anyhow::Error> {
+    let delta_data = read_zlib_data(reader)?;
+    let file_contents = apply_deltas(&base, delta_data)?;
+
+    let hash = hash_object::hash_and_write_object(Some(base_path), &file_type, &file_contents)?;
+    Ok((hash, file_type))
+}
 
-    let hash = hex::encode(ref_sha);
-    let hash = ObjectHash::new(&hash)?;
+/// Create a packfile containing the objects. Every object is stored whole since we
+/// don't compute deltas, so the packfile is only as small as zlib makes it.
+/// CF https://git-scm.com/docs/pack-format
+pub fn create_packfile(
+    base_path: Option<&PathBuf>,
+    hashes: &[ObjectHash],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut pack = vec![];
+    pack.extend(b"PACK");
+    pack.extend(2u32.to_be_bytes());
+    pack.extend((hashes.len() as u32).to_be_bytes());
+
+    for hash in hashes {
+        let path: PathBuf = match base_path {
+            Some(base_path) => base_path.join(hash.path()),
+            None => hash.into(),
+        };
 
-    let object = objects
-        .iter()
-        .find(|object| object.file_hash.full_hash() == hash.full_hash())
-        .ok_or_else(|| {
-            anyhow::anyhow!(format!(
-                "Unable to find object with hash {} in packfile",
-                hash.full_hash()
-            ))
-        })?;
+        let contents = decode_file(path).context(format!("Reading object {}", hash.full_hash()))?;
+        let (header, body) = split_header_from_contents(&contents)?;
+        let header = String::from_utf8(header.to_vec())?;
+        let object_type = header
+            .split_once(' ')
+            .map(|(object_type, _)| object_type)
+            .ok_or_else(|| anyhow::anyhow!("Invalid object header {}", header))?;
+
+        pack.extend(encode_object(object_type, body)?);
+    }
 
-    println!("OBJECT: {:?}", object);
+    // The packfile ends with a checksum of everything before it.
+    let mut hasher = Sha1::new();
+    hasher.update(&pack);
+    pack.extend(hasher.finalize());
 
-    compile_file_from_deltas(base_path, cursor, object)
+    Ok(pack)
 }
 
-fn compile_file_from_deltas(
-    base_path: &PathBuf,
-    cursor: &mut Cursor<&[u8]>,
-    object: &PackfileObject,
-) -> Result<(Vec<u8>, ObjectHash, ObjectType), anyhow::Error> {
-    let delta_data = read_next_zlib_data(cursor)?;
-    let file_contents = apply_deltas(object, delta_data)?;
+/// Encode an object the way a packfile stores it whole: its type and length, then its
+/// contents compressed with zlib.
+pub fn encode_object(object_type: &str, body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let type_bits = match object_type {
+        "commit" => 1,
+        "tree" => 2,
+        "blob" => 3,
+        "tag" => 4,
+        _ => return Err(anyhow::anyhow!("Invalid object type {}", object_type)),
+    };
 
-    let hash = hash_object::hash_and_write_object(
-        Some(base_path),
-        &object.file_type,
-        &mut file_contents.clone(),
-    )?;
+    let mut encoded = encode_type_and_length(type_bits, body.len());
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(body)?;
+    encoded.extend(encoder.finish()?);
 
-    Ok((file_contents, hash, object.file_type.clone()))
+    Ok(encoded)
 }
 
-fn apply_deltas(target: &PackfileObject, delta_data: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+/// The reverse of `read_type_and_length`: the first byte holds the type and the lowest
+/// four bits of the size, then the rest of the size follows in groups of seven bits.
+pub fn encode_type_and_length(type_bits: u8, size: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut byte = (type_bits << TYPE_BYTE_SIZE_BITS) | keep_bits(size, TYPE_BYTE_SIZE_BITS) as u8;
+    let mut remaining = size >> TYPE_BYTE_SIZE_BITS;
+
+    while remaining > 0 {
+        bytes.push(byte | MSB_MASK);
+        byte = keep_bits(remaining, VARINT_ENCODING_BITS) as u8;
+        remaining >>= VARINT_ENCODING_BITS;
+    }
+
+    bytes.push(byte);
+    bytes
+}
+
+fn apply_deltas(base: &[u8], delta_data: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
     let mut cursor = Cursor::new(delta_data.as_slice());
 
     let source_length = read_varint_bytes_le(&mut cursor)?;
@@ -222,10 +639,11 @@ fn apply_deltas(target: &PackfileObject, delta_data: Vec<u8>) -> Result<Vec<u8>,
 
     let mut data = Vec::with_capacity(final_length);
 
-    if source_length != target.size {
+    if source_length != base.len() {
         eprintln!(
             "Warning: source length {} does not match target length {} in deltafied object",
-            source_length, target.size
+            source_length,
+            base.len()
         )
     }
 
@@ -236,7 +654,7 @@ fn apply_deltas(target: &PackfileObject, delta_data: Vec<u8>) -> Result<Vec<u8>,
                 apply_insert_instruction(&mut cursor, instruction.size as usize)?
             }
             DeltaInstruction::Copy(instruction) => {
-                apply_copy_instruction(target, instruction.offset, instruction.size)?
+                apply_copy_instruction(base, instruction.offset, instruction.size)?
             }
             DeltaInstruction::End => break,
         };
@@ -322,11 +740,11 @@ fn get_copy_instruction_data(
 }
 
 fn apply_copy_instruction(
-    target: &PackfileObject,
+    base: &[u8],
     offset: usize,
     size: usize,
 ) -> Result<Vec<u8>, anyhow::Error> {
-    let data = target.data.get(offset..offset + size).ok_or_else(|| {
+    let data = base.get(offset..offset + size).ok_or_else(|| {
         anyhow::anyhow!(format!(
             "Unable to get data from offset {} to {} in target data",
             offset,
@@ -348,9 +766,7 @@ fn apply_insert_instruction(
 }
 
 /// We need to read the packfile in little-endian order. The first three bits are the object type.
-pub fn read_type_and_length(
-    cursor: &mut Cursor<&[u8]>,
-) -> Result<PackfileObjectType, anyhow::Error> {
+pub fn read_type_and_length<R: Read>(cursor: &mut R) -> Result<PackfileObjectType, anyhow::Error> {
     // Using a `usize` type limits us to files that are 2^61 bytes in size.
     // I hope whatever future person is passing around files that are 2 exabytes
     // in their git repo doesn't use this code.
@@ -416,7 +832,7 @@ fn keep_bits(value: usize, bits: u8) -> usize {
 /// We receive a variable-sized encoded value from the packfile. We want to get all the bytes
 /// that represent the type and length of the object. Using a cursor allows us to advance
 /// that distance without keeping track of the current position in the buffer.
-pub fn read_varint_bytes_le(packfile_reader: &mut Cursor<&[u8]>) -> Result<usize, anyhow::Error> {
+pub fn read_varint_bytes_le<R: Read>(packfile_reader: &mut R) -> Result<usize, anyhow::Error> {
     let mut value = 0;
     let mut length = 0;
 
@@ -442,7 +858,7 @@ pub fn read_varint_bytes_le(packfile_reader: &mut Cursor<&[u8]>) -> Result<usize
 
 // We read a single byte from the cursor. We divide it into two parts: the 7-bit value and
 // the flag for whether there are more bytes to read.
-pub fn read_varint_byte(packfile_reader: &mut Cursor<&[u8]>) -> Result<(u8, bool), anyhow::Error> {
+pub fn read_varint_byte<R: Read>(packfile_reader: &mut R) -> Result<(u8, bool), anyhow::Error> {
     let mut bytes: [u8; 1] = [0];
 
     packfile_reader
@@ -475,9 +891,7 @@ pub fn read_byte(cursor: &mut Cursor<&[u8]>) -> Result<u8, std::io::Error> {
 mod tests {
     use std::{io::Cursor, vec};
 
-    use crate::objects::{ObjectHash, ObjectType};
-
-    use super::{apply_copy_instruction, PackfileObject};
+    use super::apply_copy_instruction;
 
     #[test]
     fn read_varint_byte_reads_separates_msb_data_and_value() {
@@ -549,16 +963,8 @@ mod tests {
             0b1111_1010,
             0b1111_1001,
         ];
-        let object = PackfileObject {
-            object_type: super::PackfileObjectType::Blob(7),
-            size: 7,
-            data,
-            position: 0,
-            file_hash: ObjectHash::new("0123456789abcdef0123456789abcdef01234567").unwrap(),
-            file_type: ObjectType::Blob,
-        };
 
-        let got = apply_copy_instruction(&object, offset, size).unwrap();
+        let got = apply_copy_instruction(&data, offset, size).unwrap();
         assert_eq!(got, vec![0b1111_1101, 0b1111_1100, 0b1111_1011]);
     }
 
@@ -567,16 +973,32 @@ mod tests {
         let offset = 2;
         let size = 3;
         let data = vec![0b1111_1111];
-        let object = PackfileObject {
-            object_type: super::PackfileObjectType::Blob(7),
-            size: 7,
-            data,
-            position: 0,
-            file_hash: ObjectHash::new("0123456789abcdef0123456789abcdef01234567").unwrap(),
-            file_type: ObjectType::Blob,
-        };
 
-        let got = apply_copy_instruction(&object, offset, size);
+        let got = apply_copy_instruction(&data, offset, size);
         assert!(got.is_err());
     }
+
+    #[test]
+    fn read_offset_adds_one_for_each_byte_after_the_first() {
+        let bytes = vec![0b0000_0001];
+        let mut cursor = Cursor::new(bytes.as_slice());
+        assert_eq!(super::read_offset(&mut cursor).unwrap(), 1);
+
+        // (0 + 1) << 7 | 0, which a little-endian varint would read as 0.
+        let bytes = vec![0b1000_0000, 0b0000_0000];
+        let mut cursor = Cursor::new(bytes.as_slice());
+        assert_eq!(super::read_offset(&mut cursor).unwrap(), 128);
+    }
+
+    #[test]
+    fn encode_type_and_length_round_trips() {
+        for size in [0, 15, 16, 1000, 1 << 20] {
+            let bytes = super::encode_type_and_length(3, size);
+            let mut cursor = Cursor::new(bytes.as_slice());
+
+            let got = super::read_type_and_length(&mut cursor).unwrap();
+            assert!(matches!(got, super::PackfileObjectType::Blob(got_size) if got_size == size));
+            assert_eq!(cursor.position() as usize, bytes.len());
+        }
+    }
 }
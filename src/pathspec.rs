@@ -0,0 +1,224 @@
+// Which files a command applies to, given as paths or glob patterns relative to the root
+// of the work tree.
+// CF https://git-scm.com/docs/gitglossary#Documentation/gitglossary.txt-aiddefpathspecapathspec
+
+use std::collections::HashSet;
+
+/// A path or glob pattern. A path matches itself and everything under it, and a pattern
+/// matches paths as a whole, with `*` matching across directories, e.g. `*.rs` matches
+/// `src/lib.rs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pathspec {
+    pub pattern: String,
+}
+
+impl Pathspec {
+    pub fn new(pattern: &str) -> Self {
+        Pathspec {
+            pattern: normalize_path(pattern).to_string(),
+        }
+    }
+
+    pub fn is_glob(&self) -> bool {
+        self.pattern.contains(['*', '?', '['])
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        if self.pattern.is_empty() || path == self.pattern {
+            return true;
+        }
+        if path.starts_with(&self.pattern) && path[self.pattern.len()..].starts_with('/') {
+            return true;
+        }
+
+        self.is_glob() && wildmatch(self.pattern.as_bytes(), path.as_bytes(), false)
+    }
+}
+
+/// A path as the index has it: `.` is the root of the work tree, and directories don't end
+/// in `/`.
+pub fn normalize_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "." => "",
+        path => path.strip_prefix("./").unwrap_or(path),
+    }
+}
+
+/// Match a text against a glob pattern the way git does. `*` matches any run of characters,
+/// `?` any one and `[...]` one in a class, and `\` escapes the next character. In pathname
+/// mode, none of them match `/`, but `**` between slashes matches any number of
+/// directories.
+pub fn wildmatch(pattern: &[u8], text: &[u8], pathname: bool) -> bool {
+    Wildmatch {
+        pathname,
+        failed: HashSet::new(),
+    }
+    .matches(pattern, text)
+}
+
+struct Wildmatch {
+    pathname: bool,
+    // What's left of the pattern and the text after a `*` that are known not to match,
+    // by their lengths. Without this, each `*` retries the rest of the pattern at every
+    // offset, which is exponential for patterns like `*a*a*a*b`.
+    failed: HashSet<(usize, usize)>,
+}
+
+impl Wildmatch {
+    fn matches(&mut self, pattern: &[u8], text: &[u8]) -> bool {
+        let pathname = self.pathname;
+        let Some((&first, rest)) = pattern.split_first() else {
+            return text.is_empty();
+        };
+
+        match first {
+            b'*' if pathname && rest.first() == Some(&b'*') => {
+                let rest = &rest[1..];
+                match rest.split_first() {
+                    // `**` at the end matches everything left.
+                    None => true,
+                    // `**/` matches no directories at all, or any number of them.
+                    Some((b'/', after)) => {
+                        self.matches_after_star(after, text)
+                            || (0..text.len())
+                                .filter(|&index| text[index] == b'/')
+                                .any(|index| self.matches_after_star(after, &text[index + 1..]))
+                    }
+                    // Anywhere else it's just a `*`.
+                    Some(_) => self.matches(&pattern[1..], text),
+                }
+            }
+            b'*' => (0..=text.len())
+                .take_while(|&index| !pathname || index == 0 || text[index - 1] != b'/')
+                .any(|index| self.matches_after_star(rest, &text[index..])),
+            b'?' => match text.split_first() {
+                Some((&byte, text)) if !(pathname && byte == b'/') => self.matches(rest, text),
+                _ => false,
+            },
+            b'[' => match (text.split_first(), match_class(rest)) {
+                (Some((&byte, text)), Some((class, negated, after)))
+                    if !(pathname && byte == b'/') =>
+                {
+                    class_contains(class, byte) != negated && self.matches(after, text)
+                }
+                // A `[` that doesn't start a class is matched as it is.
+                (Some((b'[', text)), None) => self.matches(rest, text),
+                _ => false,
+            },
+            b'\\' if !rest.is_empty() => match text.split_first() {
+                Some((&byte, text)) if byte == rest[0] => self.matches(&rest[1..], text),
+                _ => false,
+            },
+            first => match text.split_first() {
+                Some((&byte, text)) if byte == first => self.matches(rest, text),
+                _ => false,
+            },
+        }
+    }
+
+    // The pattern and the text are always what's left of the ones we started with, so
+    // their lengths tell where we are in each.
+    fn matches_after_star(&mut self, pattern: &[u8], text: &[u8]) -> bool {
+        let key = (pattern.len(), text.len());
+        if self.failed.contains(&key) {
+            return false;
+        }
+
+        let matched = self.matches(pattern, text);
+        if !matched {
+            self.failed.insert(key);
+        }
+        matched
+    }
+}
+
+// The contents of a class after its `[`, whether it's negated and the pattern after it.
+fn match_class(pattern: &[u8]) -> Option<(&[u8], bool, &[u8])> {
+    let (negated, pattern) = match pattern.first() {
+        Some(b'!' | b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+
+    // A `]` right at the start is part of the class rather than its end.
+    let end = pattern
+        .iter()
+        .skip(1)
+        .position(|&byte| byte == b']')
+        .map(|position| position + 1)?;
+    Some((&pattern[..end], negated, &pattern[end + 1..]))
+}
+
+fn class_contains(class: &[u8], byte: u8) -> bool {
+    let mut index = 0;
+    while index < class.len() {
+        if index + 2 < class.len() && class[index + 1] == b'-' {
+            if (class[index]..=class[index + 2]).contains(&byte) {
+                return true;
+            }
+            index += 3;
+        } else {
+            if class[index] == byte {
+                return true;
+            }
+            index += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str, pathname: bool) -> bool {
+        wildmatch(pattern.as_bytes(), text.as_bytes(), pathname)
+    }
+
+    #[test]
+    fn wildmatch_stars_only_cross_directories_without_pathname() {
+        assert!(matches("*.rs", "src/lib.rs", false));
+        assert!(!matches("*.rs", "src/lib.rs", true));
+        assert!(matches("src/*.rs", "src/lib.rs", true));
+        assert!(!matches("src/*.rs", "src/bin/main.rs", true));
+    }
+
+    #[test]
+    fn wildmatch_double_star_matches_any_number_of_directories() {
+        assert!(matches("**/target", "target", true));
+        assert!(matches("**/target", "a/b/target", true));
+        assert!(matches("docs/**", "docs/a/b.md", true));
+        assert!(matches("a/**/b", "a/b", true));
+        assert!(matches("a/**/b", "a/x/y/b", true));
+        assert!(!matches("a/**/b", "a/x/c", true));
+    }
+
+    #[test]
+    fn wildmatch_classes_and_escapes() {
+        assert!(matches("file[0-9].txt", "file7.txt", true));
+        assert!(!matches("file[!0-9].txt", "file7.txt", true));
+        assert!(matches("[]]", "]", true));
+        assert!(matches("what\\?", "what?", true));
+        assert!(!matches("what\\?", "whatx", true));
+        assert!(matches("?.txt", "a.txt", true));
+    }
+
+    #[test]
+    fn wildmatch_does_not_retry_what_already_failed() {
+        // Trying every way the stars could split the text would take far too long.
+        let pattern = "*a".repeat(20) + "*b";
+        let text = "a".repeat(200);
+        assert!(!matches(&pattern, &text, false));
+        assert!(!matches(&pattern, &text, true));
+        assert!(matches(&pattern, &(text + "b"), true));
+    }
+
+    #[test]
+    fn pathspec_matches_directories_and_globs() {
+        assert!(Pathspec::new("src").matches("src/lib.rs"));
+        assert!(Pathspec::new("src/").matches("src/lib.rs"));
+        assert!(!Pathspec::new("src").matches("srcs/lib.rs"));
+        assert!(Pathspec::new(".").matches("a.txt"));
+        assert!(Pathspec::new("*.rs").matches("src/bin/main.rs"));
+        assert!(!Pathspec::new("*.rs").matches("README.md"));
+    }
+}
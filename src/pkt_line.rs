@@ -0,0 +1,141 @@
+use std::io::{ErrorKind, Read};
+
+use anyhow::Context;
+
+/// A pkt-line is the framing used by every git wire protocol. Each line is prefixed
+/// by four hex digits which give the length of the line including the prefix itself.
+/// The lengths `0000`, `0001` and `0002` are reserved for special packets.
+/// CF https://git-scm.com/docs/protocol-common#_pkt_line_format
+pub const FLUSH_PKT: &[u8] = b"0000";
+pub const DELIMITER_PKT: &[u8] = b"0001";
+pub const RESPONSE_END_PKT: &[u8] = b"0002";
+
+// The four hex digits of the length prefix count towards the length.
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+// 65520 bytes is the largest pkt-line git will send or accept, prefix included.
+pub const MAX_PKT_LINE_SIZE: usize = 65520;
+
+#[derive(Debug, PartialEq)]
+pub enum PktLine {
+    Flush,
+    Delimiter,
+    ResponseEnd,
+    Data(Vec<u8>),
+}
+
+impl PktLine {
+    /// The contents of a data line as a string with the trailing newline removed.
+    /// Special packets and lines that are not utf-8 return `None`.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            PktLine::Data(data) => {
+                let text = std::str::from_utf8(data).ok()?;
+                Some(text.strip_suffix('\n').unwrap_or(text))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Prefix the data with its length so it can be sent as a single pkt-line.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut line = format!("{:04x}", data.len() + LENGTH_PREFIX_SIZE).into_bytes();
+    line.extend(data);
+    line
+}
+
+/// Encode a line of text, adding the trailing newline that git expects on text lines.
+pub fn encode_text(text: &str) -> Vec<u8> {
+    encode(format!("{}\n", text).as_bytes())
+}
+
+/// Read the next pkt-line from the reader. An empty reader is an error since every
+/// conversation is expected to be terminated by a special packet.
+pub fn read_pkt_line<R: Read>(reader: &mut R) -> Result<PktLine, anyhow::Error> {
+    let mut length_bytes = [0; LENGTH_PREFIX_SIZE];
+    reader
+        .read_exact(&mut length_bytes)
+        .context("Reading pkt-line length")?;
+
+    let length = std::str::from_utf8(&length_bytes)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid pkt-line length {:?}",
+                String::from_utf8_lossy(&length_bytes)
+            )
+        })?;
+
+    match length {
+        0 => Ok(PktLine::Flush),
+        1 => Ok(PktLine::Delimiter),
+        2 => Ok(PktLine::ResponseEnd),
+        3 => Err(anyhow::anyhow!("Invalid pkt-line length 3")),
+        length if length > MAX_PKT_LINE_SIZE => Err(anyhow::anyhow!(
+            "pkt-line length {} exceeds the maximum of {}",
+            length,
+            MAX_PKT_LINE_SIZE
+        )),
+        length => {
+            let mut data = vec![0; length - LENGTH_PREFIX_SIZE];
+            reader
+                .read_exact(&mut data)
+                .context("Reading pkt-line data")?;
+            Ok(PktLine::Data(data))
+        }
+    }
+}
+
+/// Read the next pkt-line, returning `None` if the reader has been exhausted.
+pub fn try_read_pkt_line<R: Read>(reader: &mut R) -> Result<Option<PktLine>, anyhow::Error> {
+    match read_pkt_line(reader) {
+        Ok(line) => Ok(Some(line)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn encode_prefixes_length_including_prefix() {
+        assert_eq!(encode(b"a\n"), b"0006a\n");
+        assert_eq!(encode_text("done"), b"0009done\n");
+    }
+
+    #[test]
+    fn read_pkt_line_reads_special_and_data_packets() {
+        let data = b"0000000100020009done\n".to_vec();
+        let mut cursor = Cursor::new(data.as_slice());
+
+        assert_eq!(read_pkt_line(&mut cursor).unwrap(), PktLine::Flush);
+        assert_eq!(read_pkt_line(&mut cursor).unwrap(), PktLine::Delimiter);
+        assert_eq!(read_pkt_line(&mut cursor).unwrap(), PktLine::ResponseEnd);
+
+        let line = read_pkt_line(&mut cursor).unwrap();
+        assert_eq!(line.as_text(), Some("done"));
+    }
+
+    #[test]
+    fn read_pkt_line_errors_on_invalid_length() {
+        let mut cursor = Cursor::new(b"zzzzdone".as_slice());
+        assert!(read_pkt_line(&mut cursor).is_err());
+
+        let mut cursor = Cursor::new(b"0003".as_slice());
+        assert!(read_pkt_line(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn try_read_pkt_line_returns_none_at_end() {
+        let mut cursor = Cursor::new(b"".as_slice());
+        assert!(try_read_pkt_line(&mut cursor).unwrap().is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+// How often a progress line is redrawn at most, so that updating it doesn't slow down what
+// it is reporting on.
+const THROTTLE: Duration = Duration::from_millis(100);
+
+// The phase of downloading a packfile, named as git does.
+pub const RECEIVING_OBJECTS: &str = "Receiving objects";
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+/// Told how far along a long-running operation is, such as receiving objects or updating
+/// files. It goes through phases one after the other, each counting items, bytes or both.
+/// Library callers implement it to show progress their own way.
+pub trait Progress: Send {
+    /// A phase has started, with the number of items it goes through if that's known.
+    fn start(&mut self, phase: &str, total: Option<usize>);
+
+    /// How far into the current phase we are: the items done and, for phases that transfer
+    /// data, the bytes so far.
+    fn update(&mut self, done: usize, bytes: Option<u64>);
+
+    /// The current phase is over.
+    fn finish(&mut self);
+}
+
+/// Reports nothing, for callers that don't want progress.
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn start(&mut self, _phase: &str, _total: Option<usize>) {}
+
+    fn update(&mut self, _done: usize, _bytes: Option<u64>) {}
+
+    fn finish(&mut self) {}
+}
+
+// So that callers can lend out progress they want back afterwards, e.g. to look at what
+// it recorded.
+impl<P: Progress + ?Sized> Progress for &mut P {
+    fn start(&mut self, phase: &str, total: Option<usize>) {
+        (**self).start(phase, total)
+    }
+
+    fn update(&mut self, done: usize, bytes: Option<u64>) {
+        (**self).update(done, bytes)
+    }
+
+    fn finish(&mut self) {
+        (**self).finish()
+    }
+}
+
+/// Where the commands report progress: a line on stderr if it's a terminal, as git does, or
+/// nowhere if the output is going somewhere else.
+pub fn stderr() -> Box<dyn Progress> {
+    match io::stderr().is_terminal() {
+        true => Box::new(ProgressLine::new(io::stderr())),
+        false => Box::new(NoProgress),
+    }
+}
+
+/// Renders progress the way git does on a terminal: a line for each phase, redrawn in place
+/// as it goes, e.g. `Receiving objects:  45% (45/100), 1.20 MiB`, and ending in `, done.`
+/// Redrawing is throttled, except at the end of a phase.
+pub struct ProgressLine<W: Write> {
+    writer: W,
+    phase: Option<String>,
+    total: Option<usize>,
+    done: usize,
+    bytes: Option<u64>,
+    throttle: Duration,
+    last_drawn: Option<Instant>,
+}
+
+impl<W: Write> ProgressLine<W> {
+    pub fn new(writer: W) -> Self {
+        ProgressLine {
+            writer,
+            phase: None,
+            total: None,
+            done: 0,
+            bytes: None,
+            throttle: THROTTLE,
+            last_drawn: None,
+        }
+    }
+
+    /// Redraw at most once per `throttle` rather than the default.
+    pub fn with_throttle(mut self, throttle: Duration) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// The line for the current phase as it stands.
+    pub fn render(&self) -> String {
+        let mut line = format!("{}:", self.phase.as_deref().unwrap_or_default());
+
+        let mut counts = vec![];
+        match self.total {
+            Some(total) => {
+                let percent = match total {
+                    0 => 100,
+                    total => self.done * 100 / total,
+                };
+                counts.push(format!("{:3}% ({}/{})", percent, self.done, total));
+            }
+            None if self.done > 0 => counts.push(self.done.to_string()),
+            None => {}
+        }
+        if let Some(bytes) = self.bytes {
+            counts.push(format_bytes(bytes));
+        }
+
+        if !counts.is_empty() {
+            line.push(' ');
+            line.push_str(&counts.join(", "));
+        }
+        line
+    }
+
+    // Progress is only shown, so failing to show it isn't worth failing what it's about.
+    fn draw(&mut self, end: &str) {
+        let line = self.render();
+        let _ = write!(self.writer, "\r{}{}", line, end);
+        let _ = self.writer.flush();
+        self.last_drawn = Some(Instant::now());
+    }
+}
+
+impl<W: Write + Send> Progress for ProgressLine<W> {
+    fn start(&mut self, phase: &str, total: Option<usize>) {
+        if self.phase.is_some() {
+            self.finish();
+        }
+
+        self.phase = Some(phase.to_string());
+        self.total = total;
+        self.done = 0;
+        self.bytes = None;
+        self.draw("");
+    }
+
+    fn update(&mut self, done: usize, bytes: Option<u64>) {
+        if self.phase.is_none() {
+            return;
+        }
+
+        self.done = done;
+        self.bytes = bytes;
+        let due = match self.last_drawn {
+            Some(last_drawn) => last_drawn.elapsed() >= self.throttle,
+            None => true,
+        };
+        if due {
+            self.draw("");
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.phase.is_none() {
+            return;
+        }
+
+        self.draw(", done.\n");
+        self.phase = None;
+    }
+}
+
+/// Passes data on to another writer, reporting the bytes that went through as a phase of
+/// progress, such as receiving a packfile.
+pub struct CountingWriter<'a, W: Write> {
+    writer: W,
+    progress: &'a mut dyn Progress,
+    bytes: u64,
+}
+
+impl<'a, W: Write> CountingWriter<'a, W> {
+    pub fn new(writer: W, progress: &'a mut dyn Progress, phase: &str) -> Self {
+        progress.start(phase, None);
+        CountingWriter {
+            writer,
+            progress,
+            bytes: 0,
+        }
+    }
+
+    pub fn finish(self) {
+        self.progress.finish();
+    }
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.bytes += written as u64;
+        self.progress.update(0, Some(self.bytes));
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Sizes the way git shows them, e.g. `512 bytes` or `1.20 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        bytes if bytes >= GIB => format!("{:.2} GiB", bytes as f64 / GIB as f64),
+        bytes if bytes >= MIB => format!("{:.2} MiB", bytes as f64 / MIB as f64),
+        bytes if bytes >= KIB => format!("{:.2} KiB", bytes as f64 / KIB as f64),
+        bytes => format!("{} bytes", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_shows_percentage_and_bytes() {
+        let mut line = ProgressLine::new(vec![]);
+        line.start("Receiving objects", Some(200));
+        line.update(90, Some(3 * MIB / 2));
+
+        assert_eq!(line.render(), "Receiving objects:  45% (90/200), 1.50 MiB");
+    }
+
+    #[test]
+    fn render_shows_count_without_total() {
+        let mut line = ProgressLine::new(vec![]);
+        line.start("Updating files", None);
+        assert_eq!(line.render(), "Updating files:");
+
+        line.update(7, None);
+        assert_eq!(line.render(), "Updating files: 7");
+    }
+
+    #[test]
+    fn updates_are_throttled_until_the_phase_finishes() {
+        // However slow the machine, the updates come sooner than this.
+        let mut line = ProgressLine::new(vec![]).with_throttle(Duration::MAX);
+        line.start("Resolving deltas", Some(3));
+        line.update(1, None);
+        line.update(2, None);
+        line.update(3, None);
+        line.start("Updating files", Some(1));
+        line.update(1, None);
+        line.finish();
+
+        let output = String::from_utf8(line.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "\rResolving deltas:   0% (0/3)\rResolving deltas: 100% (3/3), done.\n\
+             \rUpdating files:   0% (0/1)\rUpdating files: 100% (1/1), done.\n"
+        );
+    }
+
+    #[test]
+    fn updates_are_drawn_once_the_throttle_has_passed() {
+        let mut line = ProgressLine::new(vec![]).with_throttle(Duration::ZERO);
+        line.start("Resolving deltas", Some(2));
+        line.update(1, None);
+        line.finish();
+
+        let output = String::from_utf8(line.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "\rResolving deltas:   0% (0/2)\rResolving deltas:  50% (1/2)\
+             \rResolving deltas:  50% (1/2), done.\n"
+        );
+    }
+
+    #[test]
+    fn format_bytes_uses_binary_units() {
+        assert_eq!(format_bytes(512), "512 bytes");
+        assert_eq!(format_bytes(2048), "2.00 KiB");
+        assert_eq!(format_bytes(5 * GIB / 4), "1.25 GiB");
+    }
+}
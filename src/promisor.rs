@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::fetch;
+use crate::http::UPLOAD_PACK;
+use crate::objects::ObjectHash;
+use crate::packfile::PackSpool;
+use crate::progress::NoProgress;
+use crate::protocol::{FetchOptions, ObjectFilter};
+use crate::transport;
+
+/// The remote a partial clone promises to fetch missing objects from, if this is one.
+/// CF https://git-scm.com/docs/partial-clone
+pub fn promisor_remote(base_path: Option<&PathBuf>) -> Result<Option<String>, anyhow::Error> {
+    let config = Config::load(base_path)?;
+    Ok(config
+        .get("extensions.partialclone")
+        .map(|remote| remote.to_string()))
+}
+
+pub fn is_partial_clone(base_path: Option<&PathBuf>) -> bool {
+    matches!(promisor_remote(base_path), Ok(Some(_)))
+}
+
+/// Record that the remote was fetched from with the filter, so objects missing because
+/// of it can be fetched later and later fetches use the same filter.
+pub fn configure_promisor(
+    base_path: Option<&PathBuf>,
+    remote: &str,
+    filter: &ObjectFilter,
+) -> Result<(), anyhow::Error> {
+    let mut config = Config::load(base_path)?;
+
+    config.set(&format!("remote.{}.promisor", remote), "true")?;
+    config.set(
+        &format!("remote.{}.partialclonefilter", remote),
+        &filter.to_string(),
+    )?;
+    if config.get("extensions.partialclone").is_none() {
+        config.set("extensions.partialclone", remote)?;
+    }
+
+    config.save()
+}
+
+/// The filter a promisor remote was set up with, which fetches from it keep using.
+pub fn remote_filter(config: &Config, remote: &str) -> Result<Option<ObjectFilter>, anyhow::Error> {
+    if config.get_bool(&format!("remote.{}.promisor", remote))? != Some(true) {
+        return Ok(None);
+    }
+
+    config
+        .get(&format!("remote.{}.partialclonefilter", remote))
+        .map(|filter| filter.parse())
+        .transpose()
+}
+
+/// Fetch objects a partial clone left out from the promisor remote, in a single request.
+/// Everything reachable from them is fetched along with them.
+pub fn fetch_missing(
+    base_path: Option<&PathBuf>,
+    hashes: &[ObjectHash],
+) -> Result<usize, anyhow::Error> {
+    let remote = promisor_remote(base_path)?
+        .ok_or_else(|| anyhow::anyhow!("No promisor remote to fetch missing objects from"))?;
+    let config = Config::load(base_path)?;
+    let url = fetch::remote_url(&config, &remote)?;
+
+    let mut transport = transport::connect(base_path, &config, &url, UPLOAD_PACK)?;
+
+    let wants: Vec<&ObjectHash> = hashes.iter().collect();
+    let mut spool = PackSpool::create(base_path)?;
+    transport
+        .fetch_pack(
+            &wants,
+            &[],
+            &FetchOptions::default(),
+            &mut std::io::stderr(),
+            &mut spool,
+        )
+        .context(format!("Fetching missing objects from {}", remote))?;
+
+    let pack_base_path = base_path.cloned().unwrap_or_default();
+    Ok(spool.unpack(&pack_base_path, &mut NoProgress)?.len())
+}
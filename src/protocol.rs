@@ -0,0 +1,1036 @@
+use std::fmt;
+use std::io::{BufRead, Cursor, Write};
+use std::str::FromStr;
+
+use crate::objects::ObjectHash;
+use crate::pkt_line::{self, PktLine};
+use crate::sideband::{self, RemoteError, Sideband};
+
+/// The value of the `Git-Protocol` header (or environment variable for other transports)
+/// that asks the server to speak protocol v2.
+/// CF https://git-scm.com/docs/protocol-v2
+pub const PROTOCOL_V2: &str = "version=2";
+
+pub const AGENT: &str = concat!("not-git/", env!("CARGO_PKG_VERSION"));
+
+// Empty repositories advertise their capabilities on a fake ref with this name.
+const CAPABILITIES_REF: &str = "capabilities^{}";
+const PEELED_SUFFIX: &str = "^{}";
+
+// Stands in for the old or new hash of a ref that doesn't exist.
+pub const ZERO_HASH: &str = "0000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolVersion {
+    V0,
+    V2,
+    // A plain file server without git on it, whose refs come from `info/refs`.
+    Dumb,
+}
+
+#[derive(Debug, Clone)]
+pub struct GitRef {
+    pub commit_hash: ObjectHash,
+    pub branch: String,
+    pub is_head: bool,
+    // The object an annotated tag points to, if the server sent it.
+    pub peeled: Option<ObjectHash>,
+    // The ref a symbolic ref such as HEAD points to, if the server sent it.
+    pub symref_target: Option<String>,
+}
+
+impl GitRef {
+    pub fn new(commit_hash: ObjectHash, branch: String, is_head: bool) -> Self {
+        GitRef {
+            commit_hash,
+            branch,
+            is_head,
+            peeled: None,
+            symref_target: None,
+        }
+    }
+}
+
+/// The result of the initial request to a server. In protocol v0, the server sends all of its
+/// refs along with its capabilities. In protocol v2, only the capabilities are sent and the
+/// refs need to be requested with the `ls-refs` command.
+#[derive(Debug)]
+pub struct RefAdvertisement {
+    pub version: ProtocolVersion,
+    pub capabilities: Vec<String>,
+    pub refs: Vec<GitRef>,
+}
+
+impl RefAdvertisement {
+    /// Whether the server advertised the capability, either on its own or with a value
+    /// such as `fetch=shallow filter`.
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|capability| capability == name || capability.starts_with(&format!("{}=", name)))
+    }
+
+    pub fn capability_value(&self, name: &str) -> Option<&str> {
+        self.capabilities
+            .iter()
+            .find_map(|capability| capability.strip_prefix(&format!("{}=", name)))
+    }
+
+    /// In protocol v2, commands such as `fetch` advertise their features as a
+    /// space-separated value.
+    pub fn has_command_feature(&self, command: &str, feature: &str) -> bool {
+        self.capability_value(command)
+            .map(|features| features.split(' ').any(|f| f == feature))
+            .unwrap_or(false)
+    }
+}
+
+/// A protocol v0 advertisement as a server sends it, without the `# service=` preamble HTTP
+/// adds: HEAD first if there is one, then the refs with annotated tags followed by what they
+/// peel to. The capabilities go after the first ref, or after a placeholder without refs.
+pub fn create_advertisement(
+    head: Option<&ObjectHash>,
+    refs: &[GitRef],
+    capabilities: &[String],
+) -> Vec<u8> {
+    let mut lines = vec![];
+    if let Some(head) = head {
+        lines.push(format!("{} HEAD", head.full_hash()));
+    }
+    for git_ref in refs {
+        lines.push(format!(
+            "{} {}",
+            git_ref.commit_hash.full_hash(),
+            git_ref.branch
+        ));
+        if let Some(peeled) = &git_ref.peeled {
+            lines.push(format!(
+                "{} {}{}",
+                peeled.full_hash(),
+                git_ref.branch,
+                PEELED_SUFFIX
+            ));
+        }
+    }
+    if lines.is_empty() {
+        lines.push(format!("{} {}", ZERO_HASH, CAPABILITIES_REF));
+    }
+
+    let mut advertisement = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        match index {
+            0 => advertisement.extend(pkt_line::encode(
+                format!("{}\0{}\n", line, capabilities.join(" ")).as_bytes(),
+            )),
+            _ => advertisement.extend(pkt_line::encode_text(line)),
+        }
+    }
+    advertisement.extend(pkt_line::FLUSH_PKT);
+    advertisement
+}
+
+/// Parse the response to `info/refs?service=<service_name>`. The `# service=` preamble is
+/// required for v0 over HTTP but optional for v2, and is absent for other transports.
+pub fn parse_advertisement(
+    data: &[u8],
+    service_name: &str,
+) -> Result<RefAdvertisement, anyhow::Error> {
+    let mut cursor = Cursor::new(data);
+    let mut line = pkt_line::read_pkt_line(&mut cursor)?;
+
+    if let Some(service) = line.as_text().and_then(|t| t.strip_prefix("# service=")) {
+        if service != service_name {
+            return Err(anyhow::anyhow!(
+                "Invalid packet format: expected service={}, received service={}",
+                service_name,
+                service
+            ));
+        }
+
+        if pkt_line::read_pkt_line(&mut cursor)? != PktLine::Flush {
+            return Err(anyhow::anyhow!(
+                "Invalid packet format: service line must be followed by a flush packet"
+            ));
+        }
+
+        line = match pkt_line::try_read_pkt_line(&mut cursor)? {
+            Some(line) => line,
+            None => return Err(anyhow::anyhow!("No refs or capabilities in advertisement")),
+        };
+    }
+
+    // Protocol v1 is identical to v0 except for this line.
+    if line.as_text() == Some("version 1") {
+        line = pkt_line::read_pkt_line(&mut cursor)?;
+    }
+
+    if line.as_text() == Some("version 2") {
+        let mut capabilities = vec![];
+        loop {
+            match pkt_line::read_pkt_line(&mut cursor)? {
+                PktLine::Flush => break,
+                line => {
+                    let capability = line
+                        .as_text()
+                        .ok_or_else(|| anyhow::anyhow!("Invalid capability line"))?;
+                    capabilities.push(capability.to_string());
+                }
+            }
+        }
+
+        return Ok(RefAdvertisement {
+            version: ProtocolVersion::V2,
+            capabilities,
+            refs: vec![],
+        });
+    }
+
+    parse_v0_advertisement(line, &mut cursor)
+}
+
+fn parse_v0_advertisement(
+    first_line: PktLine,
+    cursor: &mut Cursor<&[u8]>,
+) -> Result<RefAdvertisement, anyhow::Error> {
+    // An empty repository sends a flush packet without any refs or capabilities.
+    if first_line == PktLine::Flush {
+        return Ok(RefAdvertisement {
+            version: ProtocolVersion::V0,
+            capabilities: vec![],
+            refs: vec![],
+        });
+    }
+
+    // The first line reads {sha} {ref_name}\0{capabilities}
+    let first_line = match first_line {
+        PktLine::Data(data) => data,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Invalid packet format: expected a ref line"
+            ))
+        }
+    };
+    let mut split_line = first_line.splitn(2, |&b| b == 0);
+    let ref_line = split_line.next().unwrap_or_default();
+    let capabilities = split_line
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Invalid packet format: no capabilities in first ref"))?;
+
+    let capabilities: Vec<String> = String::from_utf8(capabilities.to_vec())?
+        .split_ascii_whitespace()
+        .map(|c| c.to_string())
+        .collect();
+
+    let mut refs: Vec<GitRef> = vec![];
+    push_v0_ref(&mut refs, std::str::from_utf8(ref_line)?)?;
+
+    loop {
+        let line = pkt_line::read_pkt_line(cursor)?;
+        let text = match line {
+            PktLine::Flush => break,
+            ref line => line
+                .as_text()
+                .ok_or_else(|| anyhow::anyhow!("Invalid packet format: ref line is not utf-8"))?,
+        };
+
+        // Shallow lines are only sent in response to a shallow request.
+        if text.starts_with("shallow ") {
+            continue;
+        }
+
+        push_v0_ref(&mut refs, text)?;
+    }
+
+    // The symref capability looks like symref=HEAD:refs/heads/main
+    let head_target = capabilities.iter().find_map(|capability| {
+        capability
+            .strip_prefix("symref=HEAD:")
+            .map(|target| target.to_string())
+    });
+
+    if let Some(head) = refs.iter_mut().find(|r| r.branch == "HEAD") {
+        head.symref_target = head_target;
+    }
+
+    Ok(RefAdvertisement {
+        version: ProtocolVersion::V0,
+        capabilities,
+        refs: mark_head(refs),
+    })
+}
+
+/// Parse the `info/refs` file of a dumb server, which lists `{sha}\t{ref_name}` lines the
+/// way v0 advertises refs, without capabilities. HEAD isn't listed, so the ref it points to
+/// is read from the `HEAD` file and passed in.
+/// CF https://git-scm.com/docs/http-protocol#_discovering_references
+pub fn parse_info_refs(
+    data: &[u8],
+    head_target: Option<&str>,
+) -> Result<RefAdvertisement, anyhow::Error> {
+    let text =
+        std::str::from_utf8(data).map_err(|_| anyhow::anyhow!("Invalid info/refs: not utf-8"))?;
+
+    let mut refs: Vec<GitRef> = vec![];
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        let (hash, name) = line
+            .split_once('\t')
+            .ok_or_else(|| anyhow::anyhow!("Invalid info/refs line {}", line))?;
+        push_v0_ref(&mut refs, &format!("{} {}", hash, name))?;
+    }
+
+    if let Some(head) = refs
+        .iter_mut()
+        .find(|r| Some(r.branch.as_str()) == head_target)
+    {
+        head.is_head = true;
+    }
+
+    Ok(RefAdvertisement {
+        version: ProtocolVersion::Dumb,
+        capabilities: vec![],
+        refs,
+    })
+}
+
+fn push_v0_ref(refs: &mut Vec<GitRef>, line: &str) -> Result<(), anyhow::Error> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let (hash, name) = line
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("Invalid ref line {}", line))?;
+
+    if name == CAPABILITIES_REF {
+        return Ok(());
+    }
+
+    let hash = ObjectHash::new(hash)?;
+
+    // Annotated tags are followed by a line with the commit they point to.
+    if let Some(name) = name.strip_suffix(PEELED_SUFFIX) {
+        if let Some(tag) = refs.iter_mut().rev().find(|r| r.branch == name) {
+            tag.peeled = Some(hash);
+        }
+        return Ok(());
+    }
+
+    refs.push(GitRef::new(hash, name.to_string(), false));
+    Ok(())
+}
+
+/// Remove the HEAD ref from the list and mark the ref it points to. If the server told us
+/// which ref HEAD points to, we use it, otherwise we guess based on the commit hash.
+fn mark_head(mut refs: Vec<GitRef>) -> Vec<GitRef> {
+    let head_index = match refs.iter().position(|r| r.branch == "HEAD") {
+        Some(index) => index,
+        None => return refs,
+    };
+    let head = refs.remove(head_index);
+
+    let target_index = match &head.symref_target {
+        Some(target) => refs.iter().position(|r| &r.branch == target),
+        None => refs
+            .iter()
+            .position(|r| r.commit_hash.full_hash() == head.commit_hash.full_hash()),
+    };
+
+    if let Some(index) = target_index {
+        refs[index].is_head = true;
+    }
+
+    refs
+}
+
+/// Begin a protocol v2 command request. Capabilities are only sent if the server advertised them.
+fn create_command_request(command: &str, advertisement: &RefAdvertisement) -> Vec<u8> {
+    let mut request = pkt_line::encode_text(&format!("command={}", command));
+
+    if advertisement.has_capability("agent") {
+        request.extend(pkt_line::encode_text(&format!("agent={}", AGENT)));
+    }
+
+    if let Some(object_format) = advertisement.capability_value("object-format") {
+        request.extend(pkt_line::encode_text(&format!(
+            "object-format={}",
+            object_format
+        )));
+    }
+
+    request.extend(pkt_line::DELIMITER_PKT);
+    request
+}
+
+/// Create an `ls-refs` request that asks for the refs matching any of the prefixes along with
+/// where symbolic refs point and what annotated tags peel to.
+pub fn create_ls_refs_request(advertisement: &RefAdvertisement, ref_prefixes: &[&str]) -> Vec<u8> {
+    let mut request = create_command_request("ls-refs", advertisement);
+
+    request.extend(pkt_line::encode_text("peel"));
+    request.extend(pkt_line::encode_text("symrefs"));
+    for prefix in ref_prefixes {
+        request.extend(pkt_line::encode_text(&format!("ref-prefix {}", prefix)));
+    }
+
+    request.extend(pkt_line::FLUSH_PKT);
+    request
+}
+
+/// Parse the response to an `ls-refs` request. Each line reads
+/// `{sha} {ref_name}[ symref-target:{ref}][ peeled:{sha}]`.
+pub fn parse_ls_refs_response(data: &[u8]) -> Result<Vec<GitRef>, anyhow::Error> {
+    let mut cursor = Cursor::new(data);
+    let mut refs = vec![];
+
+    loop {
+        let line = pkt_line::read_pkt_line(&mut cursor)?;
+        let text = match line {
+            PktLine::Flush => break,
+            ref line => line
+                .as_text()
+                .ok_or_else(|| anyhow::anyhow!("Invalid ls-refs line"))?,
+        };
+
+        let mut parts = text.split(' ');
+        let hash = parts.next().unwrap_or_default();
+        let name = parts
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Invalid ls-refs line {}", text))?;
+
+        // Only sent when the unborn feature is requested, which we don't do.
+        if hash == "unborn" {
+            continue;
+        }
+
+        let mut git_ref = GitRef::new(ObjectHash::new(hash)?, name.to_string(), false);
+        for attribute in parts {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                git_ref.symref_target = Some(target.to_string());
+            } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                git_ref.peeled = Some(ObjectHash::new(peeled)?);
+            }
+        }
+
+        refs.push(git_ref);
+    }
+
+    Ok(mark_head(refs))
+}
+
+// Asking for this depth means asking for the whole history, which is how `--unshallow`
+// is sent to the server.
+const INFINITE_DEPTH: u32 = 0x7fffffff;
+
+/// How far back the server should send history for a shallow fetch.
+/// CF https://git-scm.com/docs/shallow
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deepen {
+    // At most this many commits from each wanted commit.
+    Depth(u32),
+    // Commits newer than the unix timestamp.
+    Since(i64),
+    // Everything our shallow commits are missing.
+    Unshallow,
+}
+
+/// Which objects the server should leave out of the packfile for a partial clone. The
+/// objects left out are fetched from the promisor remote when they're needed.
+/// CF https://git-scm.com/docs/partial-clone
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectFilter {
+    // blob:none
+    NoBlobs,
+    // blob:limit=<n>, leaving out blobs of at least n bytes.
+    BlobLimit(u64),
+    // tree:<depth>, leaving out trees (and their blobs) deeper than the depth.
+    TreeDepth(u32),
+}
+
+impl FromStr for ObjectFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid filter-spec {}", s);
+
+        if s == "blob:none" {
+            return Ok(ObjectFilter::NoBlobs);
+        }
+        if let Some(limit) = s.strip_prefix("blob:limit=") {
+            // The limit can have a k, m or g suffix.
+            let (number, multiplier) = match limit.char_indices().last() {
+                Some((index, 'k')) => (&limit[..index], 1 << 10),
+                Some((index, 'm')) => (&limit[..index], 1 << 20),
+                Some((index, 'g')) => (&limit[..index], 1 << 30),
+                _ => (limit, 1),
+            };
+            let number: u64 = number.parse().map_err(|_| invalid())?;
+            return Ok(ObjectFilter::BlobLimit(number * multiplier));
+        }
+        if let Some(depth) = s.strip_prefix("tree:") {
+            return Ok(ObjectFilter::TreeDepth(
+                depth.parse().map_err(|_| invalid())?,
+            ));
+        }
+
+        Err(invalid())
+    }
+}
+
+impl fmt::Display for ObjectFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectFilter::NoBlobs => write!(f, "blob:none"),
+            ObjectFilter::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+            ObjectFilter::TreeDepth(depth) => write!(f, "tree:{}", depth),
+        }
+    }
+}
+
+/// The optional parts of a fetch request: the commits our history is cut off at, so the
+/// server doesn't assume we have their parents, how far to deepen it, and which objects to
+/// leave out of the packfile.
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    pub shallow: Vec<ObjectHash>,
+    pub deepen: Option<Deepen>,
+    pub filter: Option<ObjectFilter>,
+}
+
+impl FetchOptions {
+    pub fn new(shallow: Vec<ObjectHash>, deepen: Option<Deepen>) -> Self {
+        Self {
+            shallow,
+            deepen,
+            filter: None,
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Option<ObjectFilter>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Whether the request involves shallow history, which the server needs to support.
+    pub fn is_shallow(&self) -> bool {
+        !self.shallow.is_empty() || self.deepen.is_some()
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .shallow
+            .iter()
+            .map(|hash| format!("shallow {}", hash.full_hash()))
+            .collect();
+
+        match &self.deepen {
+            Some(Deepen::Depth(depth)) => lines.push(format!("deepen {}", depth)),
+            Some(Deepen::Since(timestamp)) => lines.push(format!("deepen-since {}", timestamp)),
+            Some(Deepen::Unshallow) => lines.push(format!("deepen {}", INFINITE_DEPTH)),
+            None => {}
+        }
+
+        if let Some(filter) = &self.filter {
+            lines.push(format!("filter {}", filter));
+        }
+
+        lines
+    }
+}
+
+/// The server's answer to a shallow request: the commits that are now the boundary of our
+/// history and the previously shallow commits whose parents it sent.
+#[derive(Debug, Default, PartialEq)]
+pub struct ShallowInfo {
+    pub shallow: Vec<ObjectHash>,
+    pub unshallow: Vec<ObjectHash>,
+}
+
+impl ShallowInfo {
+    /// Record a `shallow <sha>` or `unshallow <sha>` line, returning whether it was one.
+    fn read_line(&mut self, text: &str) -> Result<bool, anyhow::Error> {
+        if let Some(hash) = text.strip_prefix("shallow ") {
+            self.shallow.push(ObjectHash::new(hash)?);
+        } else if let Some(hash) = text.strip_prefix("unshallow ") {
+            self.unshallow.push(ObjectHash::new(hash)?);
+        } else {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+/// The parts of a `fetch` response we use. Until the server is `ready`, it only
+/// acknowledges which of our haves it has in common with us and doesn't send a packfile.
+#[derive(Debug)]
+pub struct FetchResponse {
+    pub acknowledgments: Vec<ObjectHash>,
+    pub ready: bool,
+    pub shallow_info: ShallowInfo,
+    pub pack: Option<Vec<u8>>,
+}
+
+/// Create a protocol v2 `fetch` request for the wanted commits, telling the server which
+/// commits we already have, where our history is cut off and what to leave out. Sending
+/// `done` asks for the packfile whether or not the server has found enough common commits.
+pub fn create_fetch_request(
+    advertisement: &RefAdvertisement,
+    wants: &[&ObjectHash],
+    haves: &[&ObjectHash],
+    options: &FetchOptions,
+    done: bool,
+) -> Vec<u8> {
+    let mut request = create_command_request("fetch", advertisement);
+
+    for want in wants {
+        request.extend(pkt_line::encode_text(&format!("want {}", want.full_hash())));
+    }
+    for have in haves {
+        request.extend(pkt_line::encode_text(&format!("have {}", have.full_hash())));
+    }
+    for line in options.lines() {
+        request.extend(pkt_line::encode_text(&line));
+    }
+    if done {
+        request.extend(pkt_line::encode_text("done"));
+    }
+
+    request.extend(pkt_line::FLUSH_PKT);
+    request
+}
+
+/// Parse the sections of a protocol v2 `fetch` response. The packfile section is always
+/// multiplexed on side-band channels.
+pub fn parse_fetch_response<W: Write>(
+    data: &[u8],
+    progress: &mut W,
+) -> Result<FetchResponse, anyhow::Error> {
+    let mut cursor = Cursor::new(data);
+    let mut response = parse_fetch_response_head(&mut cursor)?;
+
+    if response.pack.is_some() {
+        let mut pack = vec![];
+        sideband::demultiplex(&mut cursor, &mut pack, progress)?;
+        response.pack = Some(pack);
+    }
+
+    Ok(response)
+}
+
+/// Parse a `fetch` response up to the data of its packfile section, which is left in the
+/// reader for the caller to read. A response with a packfile section has an empty pack.
+pub fn parse_fetch_response_head<R: BufRead>(
+    reader: &mut R,
+) -> Result<FetchResponse, anyhow::Error> {
+    let mut response = FetchResponse {
+        acknowledgments: vec![],
+        ready: false,
+        shallow_info: ShallowInfo::default(),
+        pack: None,
+    };
+
+    loop {
+        let line = match pkt_line::try_read_pkt_line(reader)? {
+            Some(line) => line,
+            None => break,
+        };
+
+        match line.as_text() {
+            Some("packfile") => {
+                response.pack = Some(vec![]);
+                break;
+            }
+            Some("ready") => response.ready = true,
+            Some(text) if text.starts_with("ACK ") => {
+                response.acknowledgments.push(ObjectHash::new(&text[4..])?)
+            }
+            Some(text) if text.starts_with("ERR ") => {
+                return Err(RemoteError::Err(text[4..].to_string()).into())
+            }
+            Some(text) if response.shallow_info.read_line(text)? => continue,
+            // The other sections (wanted-refs) and NAK are not needed yet.
+            Some(_) => continue,
+            None if line == PktLine::Delimiter => continue,
+            // Without a packfile the response ends after the acknowledgments.
+            None => break,
+        }
+    }
+
+    Ok(response)
+}
+
+/// The capabilities we ask for in a protocol v0 upload-pack request, based on what the
+/// server advertised.
+pub fn upload_pack_capabilities(advertisement: &RefAdvertisement) -> Vec<String> {
+    let mut capabilities = vec![];
+
+    if let Some(sideband) = Sideband::negotiate(advertisement).capability() {
+        capabilities.push(sideband.to_string());
+    }
+
+    if advertisement.has_capability("agent") {
+        capabilities.push(format!("agent={}", AGENT));
+    }
+
+    capabilities
+}
+
+/// Create a protocol v0 upload-pack request. The capabilities we want to use are appended
+/// to the first want line. The haves are sent in the same request since we don't ask for
+/// multi-ack, so the server acknowledges at most one of them.
+pub fn create_upload_pack_request(
+    wants: &[&ObjectHash],
+    haves: &[&ObjectHash],
+    options: &FetchOptions,
+    capabilities: &[String],
+) -> Vec<u8> {
+    let mut request = vec![];
+
+    for (index, want) in wants.iter().enumerate() {
+        let line = if index == 0 && !capabilities.is_empty() {
+            format!("want {} {}", want.full_hash(), capabilities.join(" "))
+        } else {
+            format!("want {}", want.full_hash())
+        };
+        request.extend(pkt_line::encode_text(&line));
+    }
+    for line in options.lines() {
+        request.extend(pkt_line::encode_text(&line));
+    }
+
+    // 0000 is the termination code
+    // 0009done is added to indicate that this is the final request in negotiation
+    request.extend(pkt_line::FLUSH_PKT);
+    for have in haves {
+        request.extend(pkt_line::encode_text(&format!("have {}", have.full_hash())));
+    }
+    request.extend(pkt_line::encode_text("done"));
+    request
+}
+
+/// Parse a protocol v0 upload-pack response. A shallow request is answered with `shallow`
+/// and `unshallow` lines ending in a flush packet. Then the server sends `NAK` (or `ACK`s) as
+/// pkt-lines then the packfile, either multiplexed over side-band packets or as raw bytes.
+pub fn parse_upload_pack_response<W: Write>(
+    data: &[u8],
+    sideband: Sideband,
+    progress: &mut W,
+) -> Result<FetchResponse, anyhow::Error> {
+    let mut cursor = Cursor::new(data);
+    let mut response = parse_upload_pack_response_head(&mut cursor, sideband)?;
+
+    let position = cursor.position() as usize;
+    let pack = match sideband {
+        Sideband::Disabled => data[position..].to_vec(),
+        _ => {
+            let mut pack = vec![];
+            sideband::demultiplex(&mut cursor, &mut pack, progress)?;
+            pack
+        }
+    };
+    response.pack = Some(pack);
+    Ok(response)
+}
+
+/// Parse an upload-pack response up to its packfile, which is left in the reader for the
+/// caller to read.
+pub fn parse_upload_pack_response_head<R: BufRead>(
+    reader: &mut R,
+    sideband: Sideband,
+) -> Result<FetchResponse, anyhow::Error> {
+    let mut response = FetchResponse {
+        acknowledgments: vec![],
+        ready: true,
+        shallow_info: ShallowInfo::default(),
+        pack: None,
+    };
+
+    loop {
+        // A pkt-line starts with its length in hex, so a P can only be the start of PACK.
+        if sideband == Sideband::Disabled && reader.fill_buf()?.first() == Some(&b'P') {
+            return Ok(response);
+        }
+
+        let line = pkt_line::read_pkt_line(reader)?;
+        match line.as_text() {
+            Some(text) if text == "NAK" || text.starts_with("ACK ") => {
+                if let Some(hash) = text.strip_prefix("ACK ") {
+                    let hash = hash.split(' ').next().unwrap_or_default();
+                    response.acknowledgments.push(ObjectHash::new(hash)?);
+                }
+
+                // The last acknowledgment is followed by the packfile.
+                if sideband != Sideband::Disabled && !text.ends_with(" continue") {
+                    return Ok(response);
+                }
+            }
+            Some(text) if response.shallow_info.read_line(text)? => {}
+            Some(text) if text.starts_with("ERR ") => {
+                return Err(RemoteError::Err(text[4..].to_string()).into())
+            }
+            // The end of the shallow lines.
+            None if line == PktLine::Flush => {}
+            _ => return Err(anyhow::anyhow!("Invalid upload-pack response")),
+        }
+    }
+}
+
+/// A ref update sent to `git-receive-pack`. A missing old hash means the ref is created
+/// and a missing new hash means it is deleted.
+#[derive(Debug)]
+pub struct RefUpdateCommand {
+    pub old_hash: Option<ObjectHash>,
+    pub new_hash: Option<ObjectHash>,
+    pub name: String,
+}
+
+/// The result of a push as reported by the server with the `report-status` capability.
+/// An error in unpacking means none of the refs were updated.
+/// CF https://git-scm.com/docs/pack-protocol#_report_status
+#[derive(Debug, PartialEq)]
+pub struct ReportStatus {
+    pub unpack_error: Option<String>,
+    pub refs: Vec<RefStatus>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RefStatus {
+    pub name: String,
+    // The reason the server refused to update the ref, if it did.
+    pub error: Option<String>,
+}
+
+/// The capabilities we ask for in a receive-pack request, based on what the server advertised.
+pub fn receive_pack_capabilities(advertisement: &RefAdvertisement) -> Vec<String> {
+    let mut capabilities = vec![];
+
+    for capability in ["report-status", "delete-refs"] {
+        if advertisement.has_capability(capability) {
+            capabilities.push(capability.to_string());
+        }
+    }
+
+    if let Some(sideband) = Sideband::negotiate(advertisement).capability() {
+        capabilities.push(sideband.to_string());
+    }
+
+    if advertisement.has_capability("agent") {
+        capabilities.push(format!("agent={}", AGENT));
+    }
+
+    capabilities
+}
+
+/// Create a receive-pack request: the ref update commands, with our capabilities after the
+/// first one, followed by the packfile. Only deleting refs doesn't need a packfile.
+pub fn create_receive_pack_request(
+    commands: &[RefUpdateCommand],
+    capabilities: &[String],
+    pack: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut request = vec![];
+    let format_hash = |hash: &Option<ObjectHash>| match hash {
+        Some(hash) => hash.full_hash(),
+        None => ZERO_HASH.to_string(),
+    };
+
+    for (index, command) in commands.iter().enumerate() {
+        let line = format!(
+            "{} {} {}",
+            format_hash(&command.old_hash),
+            format_hash(&command.new_hash),
+            command.name
+        );
+
+        if index == 0 && !capabilities.is_empty() {
+            request.extend(pkt_line::encode(
+                format!("{}\0{}\n", line, capabilities.join(" ")).as_bytes(),
+            ));
+        } else {
+            request.extend(pkt_line::encode_text(&line));
+        }
+    }
+
+    request.extend(pkt_line::FLUSH_PKT);
+    if let Some(pack) = pack {
+        request.extend(pack);
+    }
+    request
+}
+
+/// Create the report-status a server sends back after a receive-pack request, the other
+/// way around from `parse_receive_pack_response`.
+pub fn create_report_status(report: &ReportStatus) -> Vec<u8> {
+    let mut data = match &report.unpack_error {
+        Some(error) => pkt_line::encode_text(&format!("unpack {}", error)),
+        None => pkt_line::encode_text("unpack ok"),
+    };
+    for status in &report.refs {
+        let line = match &status.error {
+            Some(error) => format!("ng {} {}", status.name, error),
+            None => format!("ok {}", status.name),
+        };
+        data.extend(pkt_line::encode_text(&line));
+    }
+    data.extend(pkt_line::FLUSH_PKT);
+    data
+}
+
+/// Parse the response to a receive-pack request. With side-band, the report is sent as
+/// pkt-lines inside the pack channel.
+pub fn parse_receive_pack_response<W: Write>(
+    data: &[u8],
+    sideband: Sideband,
+    progress: &mut W,
+) -> Result<ReportStatus, anyhow::Error> {
+    let report = match sideband {
+        Sideband::Disabled => data.to_vec(),
+        _ => {
+            let mut report = vec![];
+            sideband::demultiplex(&mut Cursor::new(data), &mut report, progress)?;
+            report
+        }
+    };
+
+    let mut cursor = Cursor::new(report.as_slice());
+    let unpack = pkt_line::read_pkt_line(&mut cursor)?;
+    let unpack_error = match unpack.as_text() {
+        Some("unpack ok") => None,
+        Some(text) if text.starts_with("unpack ") => Some(text[7..].to_string()),
+        Some(text) if text.starts_with("ERR ") => {
+            return Err(RemoteError::Err(text[4..].to_string()).into())
+        }
+        _ => return Err(anyhow::anyhow!("Invalid report-status: no unpack status")),
+    };
+
+    let mut refs = vec![];
+    loop {
+        let line = match pkt_line::try_read_pkt_line(&mut cursor)? {
+            Some(PktLine::Data(data)) => PktLine::Data(data),
+            _ => break,
+        };
+        let text = line
+            .as_text()
+            .ok_or_else(|| anyhow::anyhow!("Invalid report-status line"))?;
+
+        if let Some(name) = text.strip_prefix("ok ") {
+            refs.push(RefStatus {
+                name: name.to_string(),
+                error: None,
+            });
+        } else if let Some(rest) = text.strip_prefix("ng ") {
+            let (name, reason) = rest.split_once(' ').unwrap_or((rest, "failed"));
+            refs.push(RefStatus {
+                name: name.to_string(),
+                error: Some(reason.to_string()),
+            });
+        } else {
+            return Err(anyhow::anyhow!("Invalid report-status line {}", text));
+        }
+    }
+
+    Ok(ReportStatus { unpack_error, refs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_1: &str = "0123456789abcdef0123456789abcdef01234567";
+    const HASH_2: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn parse_info_refs_reads_refs_and_peeled_tags() {
+        let data = format!(
+            "{}\trefs/heads/main\n{}\trefs/tags/v1\n{}\trefs/tags/v1^{{}}\n",
+            HASH_1, HASH_2, HASH_1
+        );
+
+        let advertisement = parse_info_refs(data.as_bytes(), Some("refs/heads/main")).unwrap();
+
+        assert_eq!(advertisement.version, ProtocolVersion::Dumb);
+        assert_eq!(advertisement.refs.len(), 2);
+        assert_eq!(advertisement.refs[0].branch, "refs/heads/main");
+        assert!(advertisement.refs[0].is_head);
+        assert_eq!(advertisement.refs[1].branch, "refs/tags/v1");
+        assert_eq!(
+            advertisement.refs[1].peeled.as_ref().unwrap().full_hash(),
+            HASH_1
+        );
+
+        assert!(parse_info_refs(b"hello", None).is_err());
+    }
+
+    #[test]
+    fn create_advertisement_is_read_back_by_parse_advertisement() {
+        let hash_1 = ObjectHash::new(HASH_1).unwrap();
+        let mut tag = GitRef::new(
+            ObjectHash::new(HASH_2).unwrap(),
+            "refs/tags/v1".to_string(),
+            false,
+        );
+        tag.peeled = Some(hash_1.clone());
+        let refs = vec![
+            GitRef::new(hash_1.clone(), "refs/heads/main".to_string(), false),
+            tag,
+        ];
+        let capabilities = vec!["symref=HEAD:refs/heads/main".to_string()];
+
+        let data = create_advertisement(Some(&hash_1), &refs, &capabilities);
+        let advertisement = parse_advertisement(&data, "git-upload-pack").unwrap();
+
+        assert_eq!(advertisement.version, ProtocolVersion::V0);
+        assert_eq!(advertisement.capabilities, capabilities);
+        assert_eq!(advertisement.refs.len(), 2);
+        assert!(advertisement.refs[0].is_head);
+        assert_eq!(advertisement.refs[1].peeled, Some(hash_1));
+
+        let empty = create_advertisement(None, &[], &capabilities);
+        let advertisement = parse_advertisement(&empty, "git-receive-pack").unwrap();
+        assert!(advertisement.refs.is_empty());
+        assert_eq!(advertisement.capabilities, capabilities);
+    }
+
+    #[test]
+    fn mark_head_prefers_symref_target() {
+        let mut head = GitRef::new(ObjectHash::new(HASH_1).unwrap(), "HEAD".to_string(), false);
+        head.symref_target = Some("refs/heads/main".to_string());
+
+        let refs = vec![
+            head,
+            GitRef::new(
+                ObjectHash::new(HASH_1).unwrap(),
+                "refs/heads/a".to_string(),
+                false,
+            ),
+            GitRef::new(
+                ObjectHash::new(HASH_1).unwrap(),
+                "refs/heads/main".to_string(),
+                false,
+            ),
+        ];
+
+        let refs = mark_head(refs);
+        assert_eq!(refs.len(), 2);
+        assert!(!refs[0].is_head);
+        assert!(refs[1].is_head);
+    }
+
+    #[test]
+    fn mark_head_falls_back_to_hash() {
+        let refs = vec![
+            GitRef::new(ObjectHash::new(HASH_2).unwrap(), "HEAD".to_string(), false),
+            GitRef::new(
+                ObjectHash::new(HASH_1).unwrap(),
+                "refs/heads/a".to_string(),
+                false,
+            ),
+            GitRef::new(
+                ObjectHash::new(HASH_2).unwrap(),
+                "refs/heads/b".to_string(),
+                false,
+            ),
+        ];
+
+        let refs = mark_head(refs);
+        assert!(!refs[0].is_head);
+        assert!(refs[1].is_head);
+    }
+}
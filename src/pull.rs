@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::PathBuf;
+
+use crate::add;
+use crate::commit_tree::{self, CommitTreeConfig};
+use crate::config::Config;
+use crate::fetch::{self, FetchConfig, FetchResult};
+use crate::index::{Index, IndexEntry};
+use crate::merge::{self, TreeEntry};
+use crate::objects::{ObjectFile, ObjectHash, ObjectType};
+use crate::update_refs::{self, UpdateRefsConfig};
+use crate::{history, refs, refspec, utils};
+
+pub struct PullConfig<'a> {
+    // The remote and branch to pull from instead of the current branch's upstream.
+    pub remote: Option<&'a str>,
+    pub branch: Option<&'a str>,
+    // Refuse to create a merge commit when the histories have diverged.
+    pub ff_only: bool,
+}
+
+impl<'a> PullConfig<'a> {
+    pub fn new(remote: Option<&'a str>, branch: Option<&'a str>, ff_only: bool) -> Self {
+        Self {
+            remote,
+            branch,
+            ff_only,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PullOutcome {
+    UpToDate,
+    FastForward {
+        from: Option<ObjectHash>,
+        to: ObjectHash,
+    },
+    Merged(ObjectHash),
+}
+
+#[derive(Debug)]
+pub struct PullResult {
+    pub fetch: FetchResult,
+    pub outcome: PullOutcome,
+}
+
+pub fn pull_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let mut ff_only = false;
+    let mut positional = vec![];
+
+    for arg in args {
+        match arg.as_str() {
+            "--ff-only" => ff_only = true,
+            arg if arg.starts_with('-') || positional.len() == 2 => {
+                return Err(anyhow::anyhow!(
+                    "Usage: pull [--ff-only] [<remote> [<branch>]]"
+                ))
+            }
+            arg => positional.push(arg),
+        }
+    }
+
+    let config = PullConfig::new(
+        positional.first().copied(),
+        positional.get(1).copied(),
+        ff_only,
+    );
+    let result = pull(None, config)?;
+
+    fetch::print_updates(&result.fetch);
+    match result.outcome {
+        PullOutcome::UpToDate => println!("Already up to date."),
+        PullOutcome::FastForward { from, to } => {
+            if let Some(from) = from {
+                println!("Updating {}..{}", from.short_hash(), to.short_hash());
+            }
+            println!("Fast-forward");
+        }
+        PullOutcome::Merged(_) => println!("Merge made by the 'tree' strategy."),
+    }
+
+    Ok(())
+}
+
+/// Fetch the upstream of the current branch and bring the branch and the work tree up to
+/// date with it, merging if both sides have new commits.
+pub fn pull(base_path: Option<&PathBuf>, config: PullConfig) -> Result<PullResult, anyhow::Error> {
+    let branch = utils::get_head_ref(base_path)?;
+    let repo_config = Config::load(base_path)?;
+
+    let remote = match config.remote {
+        Some(remote) => remote.to_string(),
+        None => repo_config
+            .get(&format!("branch.{}.remote", branch))
+            .map(|remote| remote.to_string())
+            .ok_or_else(|| no_tracking_information(&branch))?,
+    };
+    let merge_ref = match config.branch {
+        Some(merge_branch) => refspec::expand_ref_name(merge_branch),
+        None => repo_config
+            .get(&format!("branch.{}.merge", branch))
+            .map(|merge_ref| merge_ref.to_string())
+            .ok_or_else(|| no_tracking_information(&branch))?,
+    };
+
+    let refspecs = vec![merge_ref.parse()?];
+    let fetch_result = fetch::fetch(base_path, FetchConfig::new(&remote, refspecs, false))?;
+
+    let theirs = fetch_result
+        .refs
+        .iter()
+        .find(|r| r.branch == merge_ref)
+        .map(|r| r.commit_hash.clone())
+        .ok_or_else(|| anyhow::anyhow!("Couldn't find remote ref {} on {}", merge_ref, remote))?;
+
+    let ours = refs::read_ref(base_path, &format!("refs/heads/{}", branch))?;
+
+    let outcome = match &ours {
+        Some(ours) if history::is_ancestor(base_path, &theirs, ours)? => PullOutcome::UpToDate,
+        Some(ours) if !history::is_ancestor(base_path, ours, &theirs)? => {
+            if config.ff_only {
+                return Err(anyhow::anyhow!(
+                    "Not possible to fast-forward, aborting. The histories of {} and {} have diverged",
+                    branch,
+                    refs::short_name(&merge_ref)
+                ));
+            }
+
+            let message = format!(
+                "Merge branch '{}' of {}",
+                refs::short_name(&merge_ref),
+                fetch_result.url
+            );
+            let merge_commit = merge_commits(base_path, ours, &theirs, message)?;
+            update_branch(base_path, &branch, Some(ours), &merge_commit)?;
+            PullOutcome::Merged(merge_commit)
+        }
+        _ => {
+            update_branch(base_path, &branch, ours.as_ref(), &theirs)?;
+            PullOutcome::FastForward {
+                from: ours.clone(),
+                to: theirs,
+            }
+        }
+    };
+
+    Ok(PullResult {
+        fetch: fetch_result,
+        outcome,
+    })
+}
+
+fn no_tracking_information(branch: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "There is no tracking information for the current branch. Set branch.{}.remote and branch.{}.merge, or pass the remote and branch to pull",
+        branch,
+        branch
+    )
+}
+
+fn merge_commits(
+    base_path: Option<&PathBuf>,
+    ours: &ObjectHash,
+    theirs: &ObjectHash,
+    message: String,
+) -> Result<ObjectHash, anyhow::Error> {
+    let merge_base = history::merge_base(base_path, ours, theirs)?
+        .ok_or_else(|| anyhow::anyhow!("Refusing to merge unrelated histories"))?;
+
+    let tree = merge::merge_trees(
+        base_path,
+        &history::read_commit(base_path, &merge_base)?.tree,
+        &history::read_commit(base_path, ours)?.tree,
+        &history::read_commit(base_path, theirs)?.tree,
+    )?;
+
+    let config =
+        CommitTreeConfig::new(&tree, message, Some(ours.clone())).with_merge_parent(theirs.clone());
+    commit_tree::create_commit(base_path, config)
+}
+
+/// Move the branch from `old` to `new` and update the work tree and the index to match,
+/// refusing before anything is changed if that would lose changes that aren't committed.
+/// Only the files that differ between `old` and `new` are touched, so local changes to
+/// other files are kept.
+fn update_branch(
+    base_path: Option<&PathBuf>,
+    branch: &str,
+    old: Option<&ObjectHash>,
+    new: &ObjectHash,
+) -> Result<(), anyhow::Error> {
+    let old_files = match old {
+        Some(old) => merge::flatten_tree(base_path, &history::read_commit(base_path, old)?.tree)?,
+        None => BTreeMap::new(),
+    };
+    let new_files = merge::flatten_tree(base_path, &history::read_commit(base_path, new)?.tree)?;
+
+    let mut index = Index::read(base_path)?;
+    check_staged_changes(&index, &old_files)?;
+    check_local_changes(base_path, &index, &old_files, &new_files)?;
+
+    update_refs::update_refs(
+        base_path,
+        UpdateRefsConfig::new(new, &PathBuf::from(branch)),
+    )?;
+
+    let work_tree = base_path.cloned().unwrap_or_default();
+    for path in old_files
+        .keys()
+        .filter(|path| !new_files.contains_key(*path))
+    {
+        utils::remove_work_file(&work_tree, path)?;
+        index.remove(path);
+    }
+    for (path, entry) in &new_files {
+        if old_files.get(path) == Some(entry) {
+            continue;
+        }
+
+        write_work_file(base_path, path, entry)?;
+        let mut index_entry = IndexEntry::from_tree_entry(path, entry);
+        index_entry.update_stat(&fs::symlink_metadata(work_tree.join(path))?);
+        index.add(index_entry);
+    }
+    index.write(base_path)?;
+
+    Ok(())
+}
+
+// Staged changes would be lost when the index is updated, so, like git, there mustn't be
+// any: the index has to be the tree of `old`.
+fn check_staged_changes(
+    index: &Index,
+    old_files: &BTreeMap<String, TreeEntry>,
+) -> Result<(), anyhow::Error> {
+    let mut staged: Vec<&str> = index
+        .entries
+        .iter()
+        .filter(|entry| !entry.matches_tree_entry(old_files.get(&entry.path)))
+        .map(|entry| entry.path.as_str())
+        .collect();
+    staged.extend(
+        old_files
+            .keys()
+            .filter(|path| index.get(path).is_none())
+            .map(|path| path.as_str()),
+    );
+
+    if !staged.is_empty() {
+        staged.sort();
+        return Err(anyhow::anyhow!(
+            "Your index contains uncommitted changes to the following files: {}",
+            staged.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+// A file that differs between `old` and `new` is overwritten, so its contents in the work
+// tree must be those of its index entry, which is `old`, or already those of `new`.
+// Untracked files in the way of new files are only overwritten if they're the same too.
+fn check_local_changes(
+    base_path: Option<&PathBuf>,
+    index: &Index,
+    old_files: &BTreeMap<String, TreeEntry>,
+    new_files: &BTreeMap<String, TreeEntry>,
+) -> Result<(), anyhow::Error> {
+    let work_tree = base_path.cloned().unwrap_or_default();
+    let changed = old_files
+        .keys()
+        .chain(new_files.keys())
+        .filter(|path| old_files.get(*path) != new_files.get(*path))
+        .collect::<BTreeSet<_>>();
+
+    let mut overwritten = vec![];
+    for path in changed {
+        let Ok(metadata) = fs::symlink_metadata(work_tree.join(path)) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            continue;
+        }
+        let tracked = index.get(path);
+        if tracked.is_some_and(|entry| entry.matches_stat(&metadata)) {
+            continue;
+        }
+
+        let work_file = add::stage_file(base_path, path, true)?;
+        if !tracked
+            .is_some_and(|entry| work_file.hash == entry.hash && work_file.mode == entry.mode)
+            && !work_file.matches_tree_entry(new_files.get(path))
+        {
+            overwritten.push(path.as_str());
+        }
+    }
+
+    if !overwritten.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Your local changes to the following files would be overwritten: {}",
+            overwritten.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+// Write the file of a tree to the work tree, in place of whatever is there.
+fn write_work_file(
+    base_path: Option<&PathBuf>,
+    path: &str,
+    (object_type, hash): &TreeEntry,
+) -> Result<(), anyhow::Error> {
+    let file = base_path.cloned().unwrap_or_default().join(path);
+    let contents = match ObjectFile::new(base_path, hash)? {
+        ObjectFile::Other(object) => object.contents,
+        ObjectFile::Tree(_) => return Err(anyhow::anyhow!("Expected a blob for {}", path)),
+    };
+
+    if let Ok(metadata) = fs::symlink_metadata(&file) {
+        match metadata.is_dir() {
+            true => fs::remove_dir_all(&file)?,
+            false => fs::remove_file(&file)?,
+        }
+    }
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match object_type {
+        ObjectType::Symlink => symlink(OsString::from_vec(contents), &file)?,
+        ObjectType::Executable => {
+            fs::write(&file, contents)?;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o755))?;
+        }
+        _ => fs::write(&file, contents)?,
+    }
+
+    Ok(())
+}
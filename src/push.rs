@@ -0,0 +1,599 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::fetch;
+use crate::objects::{ObjectFile, ObjectHash};
+use crate::progress::{self, NoProgress, Progress};
+use crate::protocol::{GitRef, RefUpdateCommand, ReportStatus};
+use crate::refspec::{expand_ref_name, Refspec};
+use crate::transport::{self, Transport};
+use crate::{history, packfile, refs};
+
+pub const RECEIVE_PACK: &str = "git-receive-pack";
+
+const WRITING_OBJECTS: &str = "Writing objects";
+
+/// `--force-with-lease` only overwrites a remote ref if it still points where we expect.
+/// Without an explicit value, we expect it to match our remote-tracking ref.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lease {
+    // --force-with-lease
+    All,
+    // --force-with-lease=<ref>
+    Ref(String),
+    // --force-with-lease=<ref>:<expect>
+    Expect(String, ObjectHash),
+}
+
+pub struct PushConfig<'a> {
+    pub remote: &'a str,
+    pub refspecs: Vec<Refspec>,
+    pub force: bool,
+    pub leases: Vec<Lease>,
+    pub progress: Box<dyn Progress + 'a>,
+}
+
+impl<'a> PushConfig<'a> {
+    pub fn new(remote: &'a str, refspecs: Vec<Refspec>, force: bool, leases: Vec<Lease>) -> Self {
+        Self {
+            remote,
+            refspecs,
+            force,
+            leases,
+            progress: Box::new(NoProgress),
+        }
+    }
+
+    pub fn with_progress(mut self, progress: Box<dyn Progress + 'a>) -> Self {
+        self.progress = progress;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushStatus {
+    UpToDate,
+    New,
+    FastForward,
+    Forced,
+    Deleted,
+    // We didn't send the update, e.g. because it isn't a fast-forward.
+    Rejected(String),
+    // The server refused the update.
+    RemoteRejected(String),
+}
+
+#[derive(Debug)]
+pub struct PushUpdate {
+    // The local ref that was pushed. Deletions don't have one.
+    pub source: Option<String>,
+    pub destination: String,
+    pub old_hash: Option<ObjectHash>,
+    pub new_hash: Option<ObjectHash>,
+    pub status: PushStatus,
+}
+
+impl PushUpdate {
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self.status,
+            PushStatus::Rejected(_) | PushStatus::RemoteRejected(_)
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct PushResult {
+    pub url: String,
+    pub updates: Vec<PushUpdate>,
+    pub objects: usize,
+}
+
+pub fn push_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let (remote, refspecs, force, leases) = parse_push_args(args)?;
+    let remote = match remote {
+        Some(remote) => remote,
+        None => fetch::default_remote(None)?,
+    };
+
+    let config =
+        PushConfig::new(&remote, refspecs, force, leases).with_progress(progress::stderr());
+    let result = push(None, config)?;
+
+    print_updates(&result);
+
+    if result.updates.iter().any(|update| update.is_error()) {
+        return Err(anyhow::anyhow!(
+            "Failed to push some refs to {}",
+            result.url
+        ));
+    }
+
+    Ok(())
+}
+
+type PushArgs = (Option<String>, Vec<Refspec>, bool, Vec<Lease>);
+
+fn parse_push_args(args: &[String]) -> Result<PushArgs, anyhow::Error> {
+    let usage = "Usage: push [--force | --force-with-lease[=<ref>[:<expect>]]] [--delete] [<remote>] [<refspec>...]";
+
+    let mut force = false;
+    let mut delete = false;
+    let mut leases = vec![];
+    let mut positional = vec![];
+
+    for arg in args {
+        match arg.as_str() {
+            "--force" | "-f" => force = true,
+            "--delete" | "-d" => delete = true,
+            "--force-with-lease" => leases.push(Lease::All),
+            arg if arg.starts_with("--force-with-lease=") => {
+                let lease = &arg["--force-with-lease=".len()..];
+                leases.push(match lease.split_once(':') {
+                    Some((name, expect)) => {
+                        Lease::Expect(expand_ref_name(name), ObjectHash::new(expect)?)
+                    }
+                    None => Lease::Ref(expand_ref_name(lease)),
+                });
+            }
+            arg if arg.starts_with('-') => return Err(anyhow::anyhow!(usage)),
+            arg => positional.push(arg),
+        }
+    }
+
+    let remote = positional.first().map(|remote| remote.to_string());
+    let refspecs = positional
+        .iter()
+        .skip(1)
+        .map(|refspec| match delete {
+            // --delete <branch> is the same as :<branch>
+            true => format!(":{}", refspec).parse(),
+            false => refspec.parse(),
+        })
+        .collect::<Result<Vec<Refspec>, _>>()?;
+
+    if delete && refspecs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--delete doesn't make sense without any refs"
+        ));
+    }
+
+    Ok((remote, refspecs, force, leases))
+}
+
+/// The URL we push to, which can be set separately from the one we fetch from.
+pub fn push_url(config: &Config, remote: &str) -> Result<String, anyhow::Error> {
+    match config.get(&format!("remote.{}.pushurl", remote)) {
+        Some(url) => Ok(url.to_string()),
+        None => fetch::remote_url(config, remote),
+    }
+}
+
+pub fn push(base_path: Option<&PathBuf>, config: PushConfig) -> Result<PushResult, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    let url = push_url(&repo_config, config.remote)?;
+    let mut transport = transport::connect(base_path, &repo_config, &url, RECEIVE_PACK)?;
+
+    push_to(base_path, config, transport.as_mut())
+}
+
+/// Push to the remote over a transport that is already open.
+pub fn push_to(
+    base_path: Option<&PathBuf>,
+    mut config: PushConfig,
+    transport: &mut dyn Transport,
+) -> Result<PushResult, anyhow::Error> {
+    let remote_refs = transport.list_refs(&["refs/"])?;
+    let plan = plan_push(base_path, &config, &remote_refs)?;
+
+    let report = match plan.commands.is_empty() {
+        true => None,
+        false => {
+            plan.start_writing(config.progress.as_mut());
+            let report = transport
+                .send_pack(&plan.commands, plan.pack.as_deref(), &mut std::io::stderr())
+                .context("Failed to push")?;
+            plan.finish_writing(config.progress.as_mut());
+            Some(report)
+        }
+    };
+
+    finish_push(base_path, plan, report)
+}
+
+/// What a push sends, worked out from the refs the remote has: the commands for the updates
+/// that were accepted, and a packfile of the objects the remote is missing.
+pub struct PushPlan {
+    url: String,
+    tracking_refspecs: Vec<Refspec>,
+    updates: Vec<PushUpdate>,
+    // Nothing is sent without any commands.
+    pub commands: Vec<RefUpdateCommand>,
+    pub pack: Option<Vec<u8>>,
+    objects: usize,
+}
+
+impl PushPlan {
+    // The pack is sent in one go, so all there is to report is that it's being written
+    // and, once it has been, how big it was.
+    pub fn start_writing(&self, progress: &mut dyn Progress) {
+        progress.start(WRITING_OBJECTS, Some(self.objects));
+    }
+
+    pub fn finish_writing(&self, progress: &mut dyn Progress) {
+        let bytes = self.pack.as_ref().map(|pack| pack.len() as u64);
+        progress.update(self.objects, bytes);
+        progress.finish();
+    }
+}
+
+pub fn plan_push(
+    base_path: Option<&PathBuf>,
+    config: &PushConfig,
+    remote_refs: &[GitRef],
+) -> Result<PushPlan, anyhow::Error> {
+    let repo_config = Config::load(base_path)?;
+    let url = push_url(&repo_config, config.remote)?;
+    let refspecs = match config.refspecs.is_empty() {
+        true => vec![current_branch_refspec(base_path, &repo_config)?],
+        false => config.refspecs.clone(),
+    };
+
+    let tracking_refspecs = fetch_refspecs(&repo_config, config.remote)?;
+    let mut updates = vec![];
+    for (source, destination, force) in resolve_updates(base_path, &refspecs)? {
+        let old_hash = remote_refs
+            .iter()
+            .find(|r| r.branch == destination)
+            .map(|r| r.commit_hash.clone());
+
+        let lease = find_lease(base_path, &config.leases, &destination, &tracking_refspecs)?;
+        let force = force || config.force;
+
+        let new_hash = match &source {
+            Some(source) => Some(
+                refs::read_ref(base_path, source)?
+                    .ok_or_else(|| anyhow::anyhow!("src refspec {} does not match any", source))?,
+            ),
+            None => None,
+        };
+
+        let status = check_update(base_path, &old_hash, &new_hash, force, lease)?;
+        updates.push(PushUpdate {
+            source,
+            destination,
+            old_hash,
+            new_hash,
+            status,
+        });
+    }
+
+    let pending: Vec<&PushUpdate> = updates
+        .iter()
+        .filter(|update| !update.is_error() && update.status != PushStatus::UpToDate)
+        .collect();
+
+    let commands: Vec<RefUpdateCommand> = pending
+        .iter()
+        .map(|update| RefUpdateCommand {
+            old_hash: update.old_hash.clone(),
+            new_hash: update.new_hash.clone(),
+            name: update.destination.clone(),
+        })
+        .collect();
+
+    let wants: Vec<ObjectHash> = pending.iter().filter_map(|u| u.new_hash.clone()).collect();
+    let objects = match wants.is_empty() {
+        true => vec![],
+        false => {
+            let remote_hashes = remote_commits(remote_refs);
+            history::list_objects(base_path, &wants, &remote_hashes)?
+        }
+    };
+    let pack = match wants.is_empty() {
+        true => None,
+        false => Some(packfile::create_packfile(base_path, &objects)?),
+    };
+
+    Ok(PushPlan {
+        url,
+        tracking_refspecs,
+        updates,
+        commands,
+        pack,
+        objects: objects.len(),
+    })
+}
+
+/// Record what the remote reported back about the updates sent, if any were, and update
+/// the remote-tracking refs of the ones it accepted.
+pub fn finish_push(
+    base_path: Option<&PathBuf>,
+    mut plan: PushPlan,
+    report: Option<ReportStatus>,
+) -> Result<PushResult, anyhow::Error> {
+    if let Some(report) = report {
+        if let Some(error) = report.unpack_error {
+            return Err(anyhow::anyhow!(
+                "Remote failed to unpack objects: {}",
+                error
+            ));
+        }
+
+        for update in plan.updates.iter_mut() {
+            if let Some(status) = report.refs.iter().find(|s| s.name == update.destination) {
+                if let Some(error) = &status.error {
+                    update.status = PushStatus::RemoteRejected(error.clone());
+                }
+            }
+        }
+    }
+
+    for update in &plan.updates {
+        update_tracking_ref(base_path, &plan.tracking_refspecs, update)?;
+    }
+
+    Ok(PushResult {
+        url: plan.url,
+        updates: plan.updates,
+        objects: plan.objects,
+    })
+}
+
+/// With no refspecs, we push the current branch to its upstream branch, or to the branch
+/// with the same name if it doesn't have one.
+fn current_branch_refspec(
+    base_path: Option<&PathBuf>,
+    config: &Config,
+) -> Result<Refspec, anyhow::Error> {
+    let head = refs::read_symbolic_ref(base_path, "HEAD")?
+        .ok_or_else(|| anyhow::anyhow!("You are not currently on a branch"))?;
+    let branch = head.strip_prefix("refs/heads/").unwrap_or(&head);
+
+    let destination = config
+        .get(&format!("branch.{}.merge", branch))
+        .unwrap_or(&head);
+
+    format!("{}:{}", head, destination).parse()
+}
+
+fn fetch_refspecs(config: &Config, remote: &str) -> Result<Vec<Refspec>, anyhow::Error> {
+    config
+        .get_all(&format!("remote.{}.fetch", remote))
+        .into_iter()
+        .map(|refspec| refspec.parse())
+        .collect()
+}
+
+/// Turn the refspecs into the local ref to push and the remote ref to update, which
+/// defaults to the same name. Patterns push every matching local ref.
+fn resolve_updates(
+    base_path: Option<&PathBuf>,
+    refspecs: &[Refspec],
+) -> Result<Vec<(Option<String>, String, bool)>, anyhow::Error> {
+    let mut updates = vec![];
+
+    for refspec in refspecs {
+        if refspec.source.is_empty() {
+            let destination = refspec
+                .destination
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("Invalid refspec {}", refspec))?;
+            updates.push((None, destination, refspec.force));
+            continue;
+        }
+
+        if refspec.is_pattern() {
+            let mapping = match &refspec.destination {
+                Some(_) => refspec.clone(),
+                None => Refspec {
+                    destination: Some(refspec.source.clone()),
+                    ..refspec.clone()
+                },
+            };
+
+            for (name, _) in refs::list_refs(base_path, refspec.source_prefix())? {
+                if let Some(destination) = mapping.map_source(&name) {
+                    updates.push((Some(name), destination, refspec.force));
+                }
+            }
+            continue;
+        }
+
+        let source = match refspec.source.as_str() {
+            "HEAD" => refs::read_symbolic_ref(base_path, "HEAD")?
+                .ok_or_else(|| anyhow::anyhow!("You are not currently on a branch"))?,
+            source => source.to_string(),
+        };
+        let destination = refspec
+            .destination
+            .clone()
+            .unwrap_or_else(|| source.clone());
+        updates.push((Some(source), destination, refspec.force));
+    }
+
+    Ok(updates)
+}
+
+/// The value a remote ref is expected to have if a lease applies to it. `Some(None)` means
+/// the ref is expected not to exist.
+fn find_lease(
+    base_path: Option<&PathBuf>,
+    leases: &[Lease],
+    destination: &str,
+    tracking_refspecs: &[Refspec],
+) -> Result<Option<Option<ObjectHash>>, anyhow::Error> {
+    for lease in leases {
+        match lease {
+            Lease::Expect(name, hash) if name == destination => {
+                return Ok(Some(Some(hash.clone())))
+            }
+            Lease::Ref(name) if name == destination => {}
+            Lease::All => {}
+            _ => continue,
+        }
+
+        let tracking_ref = tracking_refspecs
+            .iter()
+            .find_map(|refspec| refspec.map_source(destination));
+        let expected = match tracking_ref {
+            Some(tracking_ref) => refs::read_ref(base_path, &tracking_ref)?,
+            None => None,
+        };
+        return Ok(Some(expected));
+    }
+
+    Ok(None)
+}
+
+fn check_update(
+    base_path: Option<&PathBuf>,
+    old_hash: &Option<ObjectHash>,
+    new_hash: &Option<ObjectHash>,
+    force: bool,
+    lease: Option<Option<ObjectHash>>,
+) -> Result<PushStatus, anyhow::Error> {
+    if old_hash == new_hash {
+        return Ok(match old_hash {
+            Some(_) => PushStatus::UpToDate,
+            None => PushStatus::Rejected("remote ref does not exist".to_string()),
+        });
+    }
+
+    // A lease replaces the fast-forward check with a check that nobody else has pushed.
+    if let Some(expected) = lease {
+        if expected != *old_hash {
+            return Ok(PushStatus::Rejected("stale info".to_string()));
+        }
+        return Ok(match (old_hash, new_hash) {
+            (_, None) => PushStatus::Deleted,
+            (None, _) => PushStatus::New,
+            _ => PushStatus::Forced,
+        });
+    }
+
+    let (old_hash, new_hash) = match (old_hash, new_hash) {
+        (_, None) => return Ok(PushStatus::Deleted),
+        (None, _) => return Ok(PushStatus::New),
+        (Some(old_hash), Some(new_hash)) => (old_hash, new_hash),
+    };
+
+    if !ObjectFile::exists(base_path, old_hash) {
+        return Ok(match force {
+            true => PushStatus::Forced,
+            false => PushStatus::Rejected("fetch first".to_string()),
+        });
+    }
+
+    if history::is_ancestor(base_path, old_hash, new_hash)? {
+        return Ok(PushStatus::FastForward);
+    }
+
+    Ok(match force {
+        true => PushStatus::Forced,
+        false => PushStatus::Rejected("non-fast-forward".to_string()),
+    })
+}
+
+/// The commits the server already has, which we don't need to send.
+fn remote_commits(refs: &[GitRef]) -> Vec<ObjectHash> {
+    let mut hashes = vec![];
+    for git_ref in refs {
+        let hash = git_ref.peeled.as_ref().unwrap_or(&git_ref.commit_hash);
+        if !hashes.contains(hash) {
+            hashes.push(hash.clone());
+        }
+    }
+    hashes
+}
+
+/// After a successful push, the remote-tracking ref is updated to match so that we don't
+/// need to fetch to see what we just pushed.
+fn update_tracking_ref(
+    base_path: Option<&PathBuf>,
+    tracking_refspecs: &[Refspec],
+    update: &PushUpdate,
+) -> Result<(), anyhow::Error> {
+    if update.is_error() || update.status == PushStatus::UpToDate {
+        return Ok(());
+    }
+
+    let tracking_ref = match tracking_refspecs
+        .iter()
+        .find_map(|refspec| refspec.map_source(&update.destination))
+    {
+        Some(tracking_ref) => tracking_ref,
+        None => return Ok(()),
+    };
+
+    match &update.new_hash {
+        Some(hash) => refs::write_ref(base_path, &tracking_ref, hash),
+        None => refs::delete_ref(base_path, &tracking_ref).map(|_| ()),
+    }
+}
+
+fn print_updates(result: &PushResult) {
+    if result
+        .updates
+        .iter()
+        .all(|u| u.status == PushStatus::UpToDate)
+    {
+        println!("Everything up-to-date");
+        return;
+    }
+
+    println!("To {}", result.url);
+    for update in &result.updates {
+        let source = update.source.as_deref().map(refs::short_name).unwrap_or("");
+        let destination = refs::short_name(&update.destination);
+        let refs = match update.source {
+            Some(_) => format!("{} -> {}", source, destination),
+            None => destination.to_string(),
+        };
+
+        let (flag, summary, note) = match &update.status {
+            PushStatus::UpToDate => continue,
+            PushStatus::New => {
+                let kind = match update.destination.starts_with("refs/tags/") {
+                    true => "[new tag]",
+                    false => "[new branch]",
+                };
+                ('*', kind.to_string(), String::new())
+            }
+            PushStatus::FastForward => (' ', update_range(update, ".."), String::new()),
+            PushStatus::Forced => (
+                '+',
+                update_range(update, "..."),
+                " (forced update)".to_string(),
+            ),
+            PushStatus::Deleted => ('-', "[deleted]".to_string(), String::new()),
+            PushStatus::Rejected(reason) => {
+                ('!', "[rejected]".to_string(), format!(" ({})", reason))
+            }
+            PushStatus::RemoteRejected(reason) => (
+                '!',
+                "[remote rejected]".to_string(),
+                format!(" ({})", reason),
+            ),
+        };
+
+        println!(" {} {:<17} {}{}", flag, summary, refs, note);
+    }
+}
+
+fn update_range(update: &PushUpdate, separator: &str) -> String {
+    let abbreviate = |hash: &Option<ObjectHash>| {
+        hash.as_ref()
+            .map(|hash| hash.short_hash())
+            .unwrap_or_default()
+    };
+
+    format!(
+        "{}{}{}",
+        abbreviate(&update.old_hash),
+        separator,
+        abbreviate(&update.new_hash)
+    )
+}
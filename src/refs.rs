@@ -0,0 +1,248 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::objects::ObjectHash;
+
+const PACKED_REFS: &str = "packed-refs";
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted\n";
+const SYMBOLIC_REF_PREFIX: &str = "ref: ";
+
+// Symbolic refs pointing at other symbolic refs are followed at most this many times.
+const MAX_SYMBOLIC_REF_DEPTH: usize = 5;
+
+/// The path of a ref, such as `refs/remotes/origin/main`, inside the repository.
+pub fn ref_path(base_path: Option<&PathBuf>, name: &str) -> PathBuf {
+    let path = Path::new("not-git").join(name);
+    match base_path {
+        Some(base_path) => base_path.join(path),
+        None => path,
+    }
+}
+
+/// Read the commit a ref points to, following symbolic refs. Loose refs take
+/// precedence over `packed-refs`. Refs that don't exist return `None`.
+pub fn read_ref(
+    base_path: Option<&PathBuf>,
+    name: &str,
+) -> Result<Option<ObjectHash>, anyhow::Error> {
+    let mut name = name.to_string();
+
+    for _ in 0..MAX_SYMBOLIC_REF_DEPTH {
+        let path = ref_path(base_path, &name);
+        if !path.is_file() {
+            return Ok(read_packed_refs(base_path)?
+                .into_iter()
+                .find(|(packed_name, _)| *packed_name == name)
+                .map(|(_, hash)| hash));
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let contents = contents.trim();
+        match contents.strip_prefix(SYMBOLIC_REF_PREFIX) {
+            Some(target) => name = target.to_string(),
+            None => return Ok(Some(ObjectHash::new(contents)?)),
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "Symbolic ref {} is nested too deeply",
+        name
+    ))
+}
+
+/// Read the ref a symbolic ref points to without following it any further.
+pub fn read_symbolic_ref(
+    base_path: Option<&PathBuf>,
+    name: &str,
+) -> Result<Option<String>, anyhow::Error> {
+    let path = ref_path(base_path, name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .trim()
+        .strip_prefix(SYMBOLIC_REF_PREFIX)
+        .map(|target| target.to_string()))
+}
+
+/// Whether a ref sent by a client is one we can safely write: a name under `refs/` that
+/// git would accept and that can't point outside the refs directory.
+/// CF https://git-scm.com/docs/git-check-ref-format
+pub fn is_valid_ref_name(name: &str) -> bool {
+    if !name.starts_with("refs/") || name.ends_with('/') || name.ends_with(".lock") {
+        return false;
+    }
+    if name.contains("..") || name.contains("@{") || name.contains("//") {
+        return false;
+    }
+
+    let forbidden = |c: char| c.is_ascii_control() || " ~^:?*[\\".contains(c);
+    name.split('/')
+        .all(|component| !component.starts_with('.') && !component.contains(forbidden))
+}
+
+pub fn write_ref(
+    base_path: Option<&PathBuf>,
+    name: &str,
+    hash: &ObjectHash,
+) -> Result<(), anyhow::Error> {
+    let path = ref_path(base_path, name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, hash.full_hash())?;
+    Ok(())
+}
+
+pub fn write_symbolic_ref(
+    base_path: Option<&PathBuf>,
+    name: &str,
+    target: &str,
+) -> Result<(), anyhow::Error> {
+    let path = ref_path(base_path, name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, format!("{}{}\n", SYMBOLIC_REF_PREFIX, target))?;
+    Ok(())
+}
+
+/// Delete a ref, whether it is loose or packed. Empty directories left behind are removed.
+/// Returns whether the ref existed.
+pub fn delete_ref(base_path: Option<&PathBuf>, name: &str) -> Result<bool, anyhow::Error> {
+    let path = ref_path(base_path, name);
+    let mut deleted = false;
+
+    if path.is_file() {
+        fs::remove_file(&path)?;
+        deleted = true;
+        remove_empty_parents(base_path, &path);
+    }
+
+    let packed_refs = read_packed_refs(base_path)?;
+    if packed_refs
+        .iter()
+        .any(|(packed_name, _)| packed_name == name)
+    {
+        let remaining: Vec<(String, ObjectHash)> = packed_refs
+            .into_iter()
+            .filter(|(packed_name, _)| packed_name != name)
+            .collect();
+        write_packed_refs(base_path, &remaining)?;
+        deleted = true;
+    }
+
+    Ok(deleted)
+}
+
+/// List every ref whose name starts with the prefix, e.g. `refs/remotes/origin/`, sorted
+/// by name. Symbolic refs such as `refs/remotes/origin/HEAD` are skipped.
+pub fn list_refs(
+    base_path: Option<&PathBuf>,
+    prefix: &str,
+) -> Result<Vec<(String, ObjectHash)>, anyhow::Error> {
+    let mut refs: Vec<(String, ObjectHash)> = read_packed_refs(base_path)?
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(prefix))
+        .collect();
+
+    let refs_dir = ref_path(base_path, "refs");
+    if refs_dir.is_dir() {
+        let mut loose_refs = vec![];
+        collect_loose_refs(&refs_dir, "refs", &mut loose_refs)?;
+
+        for (name, contents) in loose_refs {
+            if !name.starts_with(prefix) || contents.starts_with(SYMBOLIC_REF_PREFIX) {
+                continue;
+            }
+
+            let hash = ObjectHash::new(contents.trim())?;
+            refs.retain(|(existing, _)| *existing != name);
+            refs.push((name, hash));
+        }
+    }
+
+    refs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(refs)
+}
+
+/// The name of a ref as git shows it, e.g. `main` for `refs/heads/main`
+/// and `origin/main` for `refs/remotes/origin/main`.
+pub fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+fn collect_loose_refs(
+    dir: &Path,
+    name: &str,
+    refs: &mut Vec<(String, String)>,
+) -> Result<(), anyhow::Error> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let entry_name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+
+        if entry.file_type()?.is_dir() {
+            collect_loose_refs(&entry.path(), &entry_name, refs)?;
+        } else {
+            refs.push((entry_name, fs::read_to_string(entry.path())?));
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_empty_parents(base_path: Option<&PathBuf>, path: &Path) {
+    let refs_dir = ref_path(base_path, "refs");
+    let mut parent = path.parent();
+
+    while let Some(dir) = parent {
+        if dir == refs_dir || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+/// Read `packed-refs`, skipping the header and the peeled (`^{sha}`) lines.
+pub fn read_packed_refs(
+    base_path: Option<&PathBuf>,
+) -> Result<Vec<(String, ObjectHash)>, anyhow::Error> {
+    let path = ref_path(base_path, PACKED_REFS);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut refs = vec![];
+    for line in fs::read_to_string(path)?.lines() {
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+
+        let (hash, name) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Invalid packed-refs line {}", line))?;
+        refs.push((name.to_string(), ObjectHash::new(hash)?));
+    }
+
+    Ok(refs)
+}
+
+fn write_packed_refs(
+    base_path: Option<&PathBuf>,
+    refs: &[(String, ObjectHash)],
+) -> Result<(), anyhow::Error> {
+    let mut contents = PACKED_REFS_HEADER.to_string();
+    for (name, hash) in refs {
+        contents.push_str(&format!("{} {}\n", hash.full_hash(), name));
+    }
+
+    fs::write(ref_path(base_path, PACKED_REFS), contents)?;
+    Ok(())
+}
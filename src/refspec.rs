@@ -0,0 +1,160 @@
+use std::str::FromStr;
+
+/// A refspec maps refs on one side of a transfer to refs on the other, e.g.
+/// `+refs/heads/*:refs/remotes/origin/*`. The `+` allows non-fast-forward updates and
+/// a `*` in both the source and the destination matches any part of a ref name.
+/// CF https://git-scm.com/book/en/v2/Git-Internals-The-Refspec
+#[derive(Debug, Clone, PartialEq)]
+pub struct Refspec {
+    pub force: bool,
+    pub source: String,
+    pub destination: Option<String>,
+}
+
+impl Refspec {
+    /// The refspec git configures for a remote when it is added or cloned.
+    pub fn default_fetch(remote: &str) -> Self {
+        Self {
+            force: true,
+            source: "refs/heads/*".to_string(),
+            destination: Some(format!("refs/remotes/{}/*", remote)),
+        }
+    }
+
+    pub fn is_pattern(&self) -> bool {
+        self.source.contains('*')
+    }
+
+    /// If the ref matches the source, the name it maps to in the destination.
+    pub fn map_source(&self, name: &str) -> Option<String> {
+        let destination = self.destination.as_ref()?;
+        map_pattern(&self.source, destination, name)
+    }
+
+    /// If the ref matches the destination, the name it was mapped from in the source.
+    pub fn map_destination(&self, name: &str) -> Option<String> {
+        let destination = self.destination.as_ref()?;
+        map_pattern(destination, &self.source, name)
+    }
+
+    pub fn matches_source(&self, name: &str) -> bool {
+        match_pattern(&self.source, name).is_some()
+    }
+
+    /// The part of the source before any wildcard, which can be sent as an `ls-refs` prefix.
+    pub fn source_prefix(&self) -> &str {
+        match self.source.split_once('*') {
+            Some((prefix, _)) => prefix,
+            None => &self.source,
+        }
+    }
+}
+
+impl FromStr for Refspec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (force, refspec) = match s.strip_prefix('+') {
+            Some(refspec) => (true, refspec),
+            None => (false, s),
+        };
+
+        let (source, destination) = match refspec.split_once(':') {
+            Some((source, destination)) => (source, Some(destination)),
+            None => (refspec, None),
+        };
+
+        if source.is_empty() && destination.is_none() {
+            return Err(anyhow::anyhow!("Invalid refspec {}", s));
+        }
+
+        let source = expand_ref_name(source);
+        let destination = destination
+            .filter(|destination| !destination.is_empty())
+            .map(expand_ref_name);
+
+        let source_wildcards = source.matches('*').count();
+        let destination_wildcards = destination.as_ref().map(|d| d.matches('*').count());
+        if source_wildcards > 1
+            || destination_wildcards.is_some_and(|count| count != source_wildcards)
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid refspec {}: wildcards must appear once on both sides",
+                s
+            ));
+        }
+
+        Ok(Self {
+            force,
+            source,
+            destination,
+        })
+    }
+}
+
+impl std::fmt::Display for Refspec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.force {
+            write!(f, "+")?;
+        }
+
+        write!(f, "{}", self.source)?;
+        if let Some(destination) = &self.destination {
+            write!(f, ":{}", destination)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Short branch names such as `main` refer to `refs/heads/main`.
+pub fn expand_ref_name(name: &str) -> String {
+    if name.is_empty() || name == "HEAD" || name.starts_with("refs/") {
+        name.to_string()
+    } else {
+        format!("refs/heads/{}", name)
+    }
+}
+
+/// If the name matches the pattern, the part of the name the wildcard matched.
+fn match_pattern<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(matched)
+        }
+        None if pattern == name => Some(""),
+        None => None,
+    }
+}
+
+fn map_pattern(from: &str, to: &str, name: &str) -> Option<String> {
+    let matched = match_pattern(from, name)?;
+    Some(to.replacen('*', matched, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_pattern_matches_wildcards_and_exact_names() {
+        assert_eq!(match_pattern("refs/heads/*", "refs/heads/a/b"), Some("a/b"));
+        assert_eq!(match_pattern("refs/heads/*", "refs/tags/a"), None);
+        assert_eq!(
+            match_pattern("refs/heads/main", "refs/heads/main"),
+            Some("")
+        );
+        assert_eq!(
+            match_pattern("refs/heads/main", "refs/heads/mainline"),
+            None
+        );
+    }
+
+    #[test]
+    fn expand_ref_name_expands_short_branch_names() {
+        assert_eq!(expand_ref_name("main"), "refs/heads/main");
+        assert_eq!(expand_ref_name("HEAD"), "HEAD");
+        assert_eq!(expand_ref_name("refs/tags/v1"), "refs/tags/v1");
+    }
+}
@@ -0,0 +1,428 @@
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::refs;
+use crate::refspec::Refspec;
+
+pub enum RemoteConfig {
+    // remote [-v]
+    List(bool),
+    // remote add [-t <branch>]... <name> <url>
+    Add {
+        name: String,
+        url: String,
+        branches: Vec<String>,
+    },
+    Remove(String),
+    Rename(String, String),
+    // remote set-url [--push] [--add] <name> <url>
+    SetUrl {
+        name: String,
+        url: String,
+        push: bool,
+        add: bool,
+    },
+    Show(String),
+}
+
+/// A remote as it is configured in `remote.<name>.*`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remote {
+    pub name: String,
+    pub urls: Vec<String>,
+    // Pushes go to the fetch URLs unless push URLs are set.
+    pub push_urls: Vec<String>,
+    pub fetch: Vec<Refspec>,
+}
+
+impl Remote {
+    fn read(config: &Config, name: &str) -> Result<Self, anyhow::Error> {
+        let key = |key: &str| format!("remote.{}.{}", name, key);
+        let to_strings = |values: Vec<&str>| values.into_iter().map(String::from).collect();
+
+        Ok(Remote {
+            name: name.to_string(),
+            urls: to_strings(config.get_all(&key("url"))),
+            push_urls: to_strings(config.get_all(&key("pushurl"))),
+            fetch: config
+                .get_all(&key("fetch"))
+                .into_iter()
+                .map(|refspec| refspec.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// The URLs pushes go to.
+    pub fn effective_push_urls(&self) -> &[String] {
+        if self.push_urls.is_empty() {
+            &self.urls
+        } else {
+            &self.push_urls
+        }
+    }
+}
+
+/// What `remote show` reports about a remote, from what we know locally.
+#[derive(Debug)]
+pub struct RemoteDetails {
+    pub remote: Remote,
+    // The branch `refs/remotes/<name>/HEAD` points to.
+    pub head_branch: Option<String>,
+    pub tracking_branches: Vec<String>,
+    // Local branches and the remote branch each one pulls from.
+    pub upstreams: Vec<(String, String)>,
+}
+
+pub fn remote_command(args: &[String]) -> Result<(), anyhow::Error> {
+    match parse_remote_config(args)? {
+        RemoteConfig::List(verbose) => {
+            for remote in list_remotes(None)? {
+                print_remote(&remote, verbose);
+            }
+            Ok(())
+        }
+        RemoteConfig::Add {
+            name,
+            url,
+            branches,
+        } => add_remote(None, &name, &url, &branches),
+        RemoteConfig::Remove(name) => remove_remote(None, &name),
+        RemoteConfig::Rename(old, new) => rename_remote(None, &old, &new),
+        RemoteConfig::SetUrl {
+            name,
+            url,
+            push,
+            add,
+        } => set_url(None, &name, &url, push, add),
+        RemoteConfig::Show(name) => {
+            let details = show_remote(None, &name)?;
+            print_remote_details(&details);
+            Ok(())
+        }
+    }
+}
+
+pub fn list_remotes(base_path: Option<&PathBuf>) -> Result<Vec<Remote>, anyhow::Error> {
+    let config = Config::load(base_path)?;
+    config
+        .subsections("remote")
+        .into_iter()
+        .map(|name| Remote::read(&config, name))
+        .collect()
+}
+
+pub fn get_remote(base_path: Option<&PathBuf>, name: &str) -> Result<Remote, anyhow::Error> {
+    let config = Config::load(base_path)?;
+    if !remote_exists(&config, name) {
+        return Err(anyhow::anyhow!("No such remote '{}'", name));
+    }
+
+    Remote::read(&config, name)
+}
+
+/// Add a remote that fetches every branch, or only the branches given, into
+/// `refs/remotes/<name>/*`.
+pub fn add_remote(
+    base_path: Option<&PathBuf>,
+    name: &str,
+    url: &str,
+    branches: &[String],
+) -> Result<(), anyhow::Error> {
+    validate_remote_name(name)?;
+
+    let mut config = Config::load(base_path)?;
+    if remote_exists(&config, name) {
+        return Err(anyhow::anyhow!("Remote {} already exists", name));
+    }
+
+    let key = format!("remote.{}.fetch", name);
+    config.set(&format!("remote.{}.url", name), url)?;
+    if branches.is_empty() {
+        config.add(&key, &Refspec::default_fetch(name).to_string())?;
+    }
+    for branch in branches {
+        let refspec = format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, name);
+        config.add(&key, &refspec)?;
+    }
+
+    config.save()
+}
+
+/// Remove a remote along with its remote-tracking refs and the upstream of every branch
+/// that pulled from it.
+pub fn remove_remote(base_path: Option<&PathBuf>, name: &str) -> Result<(), anyhow::Error> {
+    let mut config = Config::load(base_path)?;
+    if !config.remove_section(&format!("remote.{}", name)) {
+        return Err(anyhow::anyhow!("No such remote '{}'", name));
+    }
+
+    for branch in branches_tracking(&config, name) {
+        config.unset_all(&format!("branch.{}.remote", branch))?;
+        config.unset_all(&format!("branch.{}.merge", branch))?;
+    }
+    if config.get("extensions.partialclone") == Some(name) {
+        config.unset_all("extensions.partialclone")?;
+    }
+    config.save()?;
+
+    let prefix = format!("refs/remotes/{}/", name);
+    for (ref_name, _) in refs::list_refs(base_path, &prefix)? {
+        refs::delete_ref(base_path, &ref_name)?;
+    }
+    refs::delete_ref(base_path, &format!("{}HEAD", prefix))?;
+
+    Ok(())
+}
+
+/// Rename a remote, moving its remote-tracking refs and rewriting the refspecs and
+/// upstreams that refer to it.
+pub fn rename_remote(
+    base_path: Option<&PathBuf>,
+    old: &str,
+    new: &str,
+) -> Result<(), anyhow::Error> {
+    validate_remote_name(new)?;
+
+    let mut config = Config::load(base_path)?;
+    if !remote_exists(&config, old) {
+        return Err(anyhow::anyhow!("No such remote '{}'", old));
+    }
+    if remote_exists(&config, new) {
+        return Err(anyhow::anyhow!("Remote {} already exists", new));
+    }
+
+    let old_prefix = format!("refs/remotes/{}/", old);
+    let new_prefix = format!("refs/remotes/{}/", new);
+    let rename_ref = |name: &str| match name.strip_prefix(&old_prefix) {
+        Some(rest) => format!("{}{}", new_prefix, rest),
+        None => name.to_string(),
+    };
+
+    // Only the refspecs that store into the remote's own namespace are rewritten.
+    let fetch_key = format!("remote.{}.fetch", old);
+    let refspecs: Vec<String> = config
+        .get_all(&fetch_key)
+        .into_iter()
+        .map(|refspec| {
+            let mut refspec: Refspec = refspec.parse()?;
+            refspec.destination = refspec.destination.as_deref().map(rename_ref);
+            Ok(refspec.to_string())
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+    config.unset_all(&fetch_key)?;
+    for refspec in refspecs {
+        config.add(&fetch_key, &refspec)?;
+    }
+    config.rename_section(&format!("remote.{}", old), &format!("remote.{}", new));
+
+    for branch in branches_tracking(&config, old) {
+        config.set(&format!("branch.{}.remote", branch), new)?;
+    }
+    if config.get("extensions.partialclone") == Some(old) {
+        config.set("extensions.partialclone", new)?;
+    }
+    config.save()?;
+
+    for (ref_name, hash) in refs::list_refs(base_path, &old_prefix)? {
+        refs::write_ref(base_path, &rename_ref(&ref_name), &hash)?;
+        refs::delete_ref(base_path, &ref_name)?;
+    }
+
+    let old_head = format!("{}HEAD", old_prefix);
+    if let Some(target) = refs::read_symbolic_ref(base_path, &old_head)? {
+        let new_head = format!("{}HEAD", new_prefix);
+        refs::write_symbolic_ref(base_path, &new_head, &rename_ref(&target))?;
+        refs::delete_ref(base_path, &old_head)?;
+    }
+
+    Ok(())
+}
+
+/// Change the URL of a remote, or of where it is pushed to with `push`. With `add`, the
+/// URL is added to the existing ones rather than replacing them.
+pub fn set_url(
+    base_path: Option<&PathBuf>,
+    name: &str,
+    url: &str,
+    push: bool,
+    add: bool,
+) -> Result<(), anyhow::Error> {
+    let mut config = Config::load(base_path)?;
+    if !remote_exists(&config, name) {
+        return Err(anyhow::anyhow!("No such remote '{}'", name));
+    }
+
+    let key = match push {
+        true => format!("remote.{}.pushurl", name),
+        false => format!("remote.{}.url", name),
+    };
+    match add {
+        true => config.add(&key, url)?,
+        false => config.set(&key, url)?,
+    }
+
+    config.save()
+}
+
+pub fn show_remote(
+    base_path: Option<&PathBuf>,
+    name: &str,
+) -> Result<RemoteDetails, anyhow::Error> {
+    let config = Config::load(base_path)?;
+    if !remote_exists(&config, name) {
+        return Err(anyhow::anyhow!("No such remote '{}'", name));
+    }
+    let remote = Remote::read(&config, name)?;
+
+    let prefix = format!("refs/remotes/{}/", name);
+    let head_branch = refs::read_symbolic_ref(base_path, &format!("{}HEAD", prefix))?
+        .and_then(|target| target.strip_prefix(&prefix).map(String::from));
+    let tracking_branches = refs::list_refs(base_path, &prefix)?
+        .into_iter()
+        .filter_map(|(ref_name, _)| ref_name.strip_prefix(&prefix).map(String::from))
+        .collect();
+
+    let mut upstreams: Vec<(String, String)> = branches_tracking(&config, name)
+        .into_iter()
+        .filter_map(|branch| {
+            let merge = config.get(&format!("branch.{}.merge", branch))?;
+            Some((branch, refs::short_name(merge).to_string()))
+        })
+        .collect();
+    upstreams.sort();
+
+    Ok(RemoteDetails {
+        remote,
+        head_branch,
+        tracking_branches,
+        upstreams,
+    })
+}
+
+fn remote_exists(config: &Config, name: &str) -> bool {
+    config.subsections("remote").contains(&name)
+}
+
+// The local branches whose upstream is on the remote.
+fn branches_tracking(config: &Config, remote: &str) -> Vec<String> {
+    config
+        .subsections("branch")
+        .into_iter()
+        .filter(|branch| config.get(&format!("branch.{}.remote", branch)) == Some(remote))
+        .map(String::from)
+        .collect()
+}
+
+// Remote names end up in config sections and ref names, so they follow the rules of both.
+fn validate_remote_name(name: &str) -> Result<(), anyhow::Error> {
+    let is_valid = !name.is_empty()
+        && !name.starts_with('-')
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.contains("..")
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "\"\\:?*[~^".contains(c));
+
+    if !is_valid {
+        return Err(anyhow::anyhow!("'{}' is not a valid remote name", name));
+    }
+
+    Ok(())
+}
+
+fn print_remote(remote: &Remote, verbose: bool) {
+    if !verbose {
+        println!("{}", remote.name);
+        return;
+    }
+
+    for url in &remote.urls {
+        println!("{}\t{} (fetch)", remote.name, url);
+    }
+    for url in remote.effective_push_urls() {
+        println!("{}\t{} (push)", remote.name, url);
+    }
+}
+
+fn print_remote_details(details: &RemoteDetails) {
+    let remote = &details.remote;
+    println!("* remote {}", remote.name);
+    for url in &remote.urls {
+        println!("  Fetch URL: {}", url);
+    }
+    for url in remote.effective_push_urls() {
+        println!("  Push  URL: {}", url);
+    }
+    println!(
+        "  HEAD branch: {}",
+        details.head_branch.as_deref().unwrap_or("(unknown)")
+    );
+
+    if !details.tracking_branches.is_empty() {
+        println!("  Remote branches:");
+        for branch in &details.tracking_branches {
+            println!("    {}", branch);
+        }
+    }
+
+    if !details.upstreams.is_empty() {
+        println!("  Local branches configured for 'pull':");
+        for (branch, upstream) in &details.upstreams {
+            println!("    {} merges with remote {}", branch, upstream);
+        }
+    }
+}
+
+fn parse_remote_config(args: &[String]) -> Result<RemoteConfig, anyhow::Error> {
+    let usage = || {
+        anyhow::anyhow!(
+            "Usage: remote [-v] | add [-t <branch>] <name> <url> | remove <name> | rename <old> <new> | set-url [--push] [--add] <name> <url> | show <name>"
+        )
+    };
+
+    let (command, rest) = match args.split_first() {
+        None => return Ok(RemoteConfig::List(false)),
+        Some((command, rest)) => (command.as_str(), rest),
+    };
+
+    let mut positional: Vec<&str> = vec![];
+    let mut branches = vec![];
+    let mut push = false;
+    let mut add = false;
+
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-t" | "--track" if command == "add" => {
+                let branch = rest.next().ok_or_else(usage)?;
+                branches.push(branch.to_string());
+            }
+            "--push" if command == "set-url" => push = true,
+            "--add" if command == "set-url" => add = true,
+            arg if arg.starts_with('-') => return Err(usage()),
+            arg => positional.push(arg),
+        }
+    }
+
+    let config = match (command, positional.as_slice()) {
+        ("-v" | "--verbose", []) => RemoteConfig::List(true),
+        ("add", [name, url]) => RemoteConfig::Add {
+            name: name.to_string(),
+            url: url.to_string(),
+            branches,
+        },
+        ("remove" | "rm", [name]) => RemoteConfig::Remove(name.to_string()),
+        ("rename", [old, new]) => RemoteConfig::Rename(old.to_string(), new.to_string()),
+        ("set-url", [name, url]) => RemoteConfig::SetUrl {
+            name: name.to_string(),
+            url: url.to_string(),
+            push,
+            add,
+        },
+        ("show", [name]) => RemoteConfig::Show(name.to_string()),
+        _ => return Err(usage()),
+    };
+
+    Ok(config)
+}
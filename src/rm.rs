@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::add;
+use crate::history;
+use crate::index::Index;
+use crate::merge;
+use crate::pathspec;
+use crate::refs;
+use crate::utils;
+
+pub struct RmConfig {
+    pub paths: Vec<String>,
+    // Only unstage the files, leaving them in the work tree.
+    pub cached: bool,
+    pub recursive: bool,
+    // Remove files from the work tree even if they have changes that aren't committed.
+    pub force: bool,
+}
+
+impl RmConfig {
+    pub fn new(paths: Vec<String>) -> Self {
+        Self {
+            paths,
+            cached: false,
+            recursive: false,
+            force: false,
+        }
+    }
+
+    pub fn with_cached(mut self, cached: bool) -> Self {
+        self.cached = cached;
+        self
+    }
+
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+pub fn rm_command(args: &[String]) -> Result<(), anyhow::Error> {
+    let config = parse_rm_config(args)?;
+    for path in rm(None, config)? {
+        println!("rm '{}'", path);
+    }
+
+    Ok(())
+}
+
+/// Unstage the files at the paths, and remove them from the work tree too unless `cached`
+/// is set. A directory removes every file under it, but only if `recursive` is set. Like
+/// git, files whose staged or work tree contents differ from HEAD are only removed from the
+/// work tree if `force` is set, as their changes would be lost. Nothing is removed if any
+/// path doesn't match. Returns the paths of the files removed.
+pub fn rm(base_path: Option<&PathBuf>, config: RmConfig) -> Result<Vec<String>, anyhow::Error> {
+    let mut index = Index::read(base_path)?;
+
+    let mut removed: Vec<String> = vec![];
+    for path in &config.paths {
+        let path = pathspec::normalize_path(path);
+        let directory = format!("{}/", path);
+
+        let mut matched = false;
+        for entry in &index.entries {
+            let in_directory = path.is_empty() || entry.path.starts_with(&directory);
+            if entry.path != path && !in_directory {
+                continue;
+            }
+            if in_directory && !config.recursive {
+                return Err(anyhow::anyhow!(
+                    "not removing '{}' recursively without -r",
+                    path
+                ));
+            }
+
+            matched = true;
+            if !removed.contains(&entry.path) {
+                removed.push(entry.path.clone());
+            }
+        }
+
+        if !matched {
+            return Err(anyhow::anyhow!(
+                "pathspec '{}' did not match any files",
+                path
+            ));
+        }
+    }
+
+    if !config.cached && !config.force {
+        check_committed(base_path, &index, &removed)?;
+    }
+
+    let work_tree = base_path.cloned().unwrap_or_default();
+    for path in &removed {
+        index.remove(path);
+        if !config.cached {
+            utils::remove_work_file(&work_tree, path)?;
+        }
+    }
+    index.write(base_path)?;
+
+    Ok(removed)
+}
+
+// Refuse to remove files from the work tree if that would lose changes: either staged ones,
+// which differ from HEAD, or ones in the work tree, which differ from what's staged.
+fn check_committed(
+    base_path: Option<&PathBuf>,
+    index: &Index,
+    paths: &[String],
+) -> Result<(), anyhow::Error> {
+    let head_files = match refs::read_ref(base_path, "HEAD")? {
+        Some(head) => {
+            merge::flatten_tree(base_path, &history::read_commit(base_path, &head)?.tree)?
+        }
+        None => BTreeMap::new(),
+    };
+    let work_tree = base_path.cloned().unwrap_or_default();
+
+    for path in paths {
+        let Some(entry) = index.get(path) else {
+            continue;
+        };
+        if !entry.matches_tree_entry(head_files.get(path)) {
+            return Err(refuse(path, "has changes staged in the index"));
+        }
+
+        let Ok(metadata) = fs::symlink_metadata(work_tree.join(path)) else {
+            continue;
+        };
+        if metadata.is_dir() || entry.matches_stat(&metadata) {
+            continue;
+        }
+        let work_file = add::stage_file(base_path, path, true)?;
+        if work_file.hash != entry.hash || work_file.mode != entry.mode {
+            return Err(refuse(path, "has local modifications"));
+        }
+    }
+
+    Ok(())
+}
+
+fn refuse(path: &str, reason: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "the following file {}:\n    {}\n(use --cached to keep the file, or -f to force removal)",
+        reason,
+        path
+    )
+}
+
+fn parse_rm_config(args: &[String]) -> Result<RmConfig, anyhow::Error> {
+    let usage = || anyhow::anyhow!("Usage: rm [--cached] [-f] [-r] <path>...");
+
+    let mut cached = false;
+    let mut recursive = false;
+    let mut force = false;
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--cached" => cached = true,
+            "-r" => recursive = true,
+            "-f" | "--force" => force = true,
+            arg if arg.starts_with('-') => return Err(usage()),
+            arg => paths.push(arg.to_string()),
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(usage());
+    }
+
+    Ok(RmConfig::new(paths)
+        .with_cached(cached)
+        .with_recursive(recursive)
+        .with_force(force))
+}
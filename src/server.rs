@@ -0,0 +1,375 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::http::UPLOAD_PACK;
+use crate::local::LocalRepository;
+use crate::objects::{ObjectFile, ObjectHash};
+use crate::pkt_line::{self, PktLine};
+use crate::protocol::{self, RefUpdateCommand, AGENT, ZERO_HASH};
+use crate::push::RECEIVE_PACK;
+use crate::sideband::{self, Sideband};
+use crate::transport::Transport;
+use crate::{history, packfile, refs};
+
+// We don't do multi-ack, shallow or filters, so clients fall back to what we can do.
+const UPLOAD_PACK_CAPABILITIES: [&str; 3] = ["side-band", "side-band-64k", "no-progress"];
+const RECEIVE_PACK_CAPABILITIES: [&str; 3] = ["report-status", "delete-refs", "side-band-64k"];
+/// Requests are read whole before they're answered, so their size has to be capped. Pushes
+/// are the largest, with the whole pack in them.
+pub const MAX_REQUEST_SIZE: usize = 256 * 1024 * 1024;
+
+/// The server side of fetching and pushing, for the repository in a directory. Without
+/// `--stateless-rpc`, the conversation is on stdin and stdout from start to end, as over
+/// ssh. With it, we only answer one request, as for smart HTTP, where `--advertise-refs`
+/// is used for the advertisement before any request.
+/// CF https://git-scm.com/docs/git-upload-pack
+struct ServiceArgs {
+    stateless_rpc: bool,
+    advertise_refs: bool,
+    directory: PathBuf,
+}
+
+pub fn upload_pack_command(args: &[String]) -> Result<(), anyhow::Error> {
+    run_service(UPLOAD_PACK, args)
+}
+
+pub fn receive_pack_command(args: &[String]) -> Result<(), anyhow::Error> {
+    run_service(RECEIVE_PACK, args)
+}
+
+fn run_service(service: &str, args: &[String]) -> Result<(), anyhow::Error> {
+    let args = parse_service_args(service, args)?;
+    let mut repository = LocalRepository::open(&args.directory)?;
+    let mut input = std::io::stdin().lock();
+    let mut output = std::io::stdout().lock();
+
+    if args.advertise_refs || !args.stateless_rpc {
+        output.write_all(&advertise_refs(&mut repository, service)?)?;
+        output.flush()?;
+    }
+    if args.advertise_refs {
+        return Ok(());
+    }
+
+    match service {
+        UPLOAD_PACK => upload_pack(&mut repository, &mut input, &mut output, args.stateless_rpc),
+        _ => receive_pack(&mut repository, &mut input, &mut output),
+    }
+}
+
+fn parse_service_args(service: &str, args: &[String]) -> Result<ServiceArgs, anyhow::Error> {
+    let usage = || {
+        anyhow::anyhow!(
+            "Usage: {} [--stateless-rpc] [--advertise-refs] <directory>",
+            service.trim_start_matches("git-")
+        )
+    };
+
+    let mut stateless_rpc = false;
+    let mut advertise_refs = false;
+    let mut directory = None;
+    for arg in args {
+        match arg.as_str() {
+            "--stateless-rpc" => stateless_rpc = true,
+            "--advertise-refs" => advertise_refs = true,
+            arg if arg.starts_with('-') => return Err(usage()),
+            arg if directory.is_none() => directory = Some(PathBuf::from(arg)),
+            _ => return Err(usage()),
+        }
+    }
+
+    Ok(ServiceArgs {
+        stateless_rpc,
+        advertise_refs,
+        directory: directory.ok_or_else(usage)?,
+    })
+}
+
+/// The repository a server serves at the path, relative to the directory it serves. Paths
+/// that would leave the directory aren't served.
+pub fn open_repository(root: &Path, path: &str) -> Option<LocalRepository> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let repository = LocalRepository::open(&root.join(relative)).ok()?;
+    repository.path.starts_with(root).then_some(repository)
+}
+
+/// The protocol v0 advertisement of the service, without the `# service=` preamble HTTP adds.
+/// Clients asking for protocol v2 get this too, which they understand as us not speaking it.
+pub fn advertise_refs(
+    repository: &mut LocalRepository,
+    service: &str,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let base_path = Some(repository.path.clone());
+    let mut refs = repository.list_refs(&["refs/"])?;
+
+    if service == RECEIVE_PACK {
+        // Pushing only needs to know where the refs are.
+        for git_ref in &mut refs {
+            git_ref.peeled = None;
+        }
+
+        let mut capabilities: Vec<String> = RECEIVE_PACK_CAPABILITIES
+            .iter()
+            .map(|capability| capability.to_string())
+            .collect();
+        capabilities.push(format!("agent={}", AGENT));
+        return Ok(protocol::create_advertisement(None, &refs, &capabilities));
+    }
+
+    let head = refs::read_ref(base_path.as_ref(), "HEAD")?;
+    let mut capabilities: Vec<String> = UPLOAD_PACK_CAPABILITIES
+        .iter()
+        .map(|capability| capability.to_string())
+        .collect();
+    if let (Some(_), Some(target)) = (&head, refs::read_symbolic_ref(base_path.as_ref(), "HEAD")?) {
+        capabilities.push(format!("symref=HEAD:{}", target));
+    }
+    capabilities.push(format!("agent={}", AGENT));
+
+    Ok(protocol::create_advertisement(
+        head.as_ref(),
+        &refs,
+        &capabilities,
+    ))
+}
+
+/// Answer an upload-pack request: read the wants, which must be advertised or reachable from
+/// what is, acknowledge the first have we also have, and once the client is done, send a
+/// packfile of what it's missing. Without multi-ack, we only ever acknowledge one have, and
+/// send `NAK` at each flush until we've found one.
+/// CF https://git-scm.com/docs/pack-protocol#_packfile_negotiation
+pub fn upload_pack<R: Read, W: Write>(
+    repository: &mut LocalRepository,
+    input: &mut R,
+    output: &mut W,
+    stateless_rpc: bool,
+) -> Result<(), anyhow::Error> {
+    let base_path = Some(repository.path.clone());
+    let base_path = base_path.as_ref();
+
+    // Only what we advertise can be fetched, along with the history behind it, as the
+    // repository may have objects no ref points to.
+    let mut tips: Vec<ObjectHash> = refs::read_ref(base_path, "HEAD")?.into_iter().collect();
+    for git_ref in repository.list_refs(&["refs/"])? {
+        tips.extend(git_ref.peeled);
+        tips.push(git_ref.commit_hash);
+    }
+    let mut reachable: Option<HashSet<ObjectHash>> = None;
+
+    let mut wants: Vec<ObjectHash> = vec![];
+    let mut capabilities: Vec<String> = vec![];
+    loop {
+        let text = match pkt_line::try_read_pkt_line(input)? {
+            // The client hung up, or only wanted to know about the refs.
+            None => return Ok(()),
+            Some(PktLine::Flush) => break,
+            Some(line) => line_text(&line)?,
+        };
+
+        let mut parts = text
+            .strip_prefix("want ")
+            .ok_or_else(|| protocol_error(UPLOAD_PACK, "want", &text))?
+            .split(' ');
+        let hash = ObjectHash::new(parts.next().unwrap_or_default())?;
+        if wants.is_empty() {
+            capabilities = parts.map(String::from).collect();
+        }
+
+        if !tips.contains(&hash) && reachable.is_none() {
+            // Commits the refs have moved on from, which a stateless client may want after
+            // they were advertised, are found by walking the history, once.
+            let commits = history::walk_commits(base_path, &tips, None)?;
+            reachable = Some(commits.into_iter().collect());
+        }
+        let ours = tips.contains(&hash) || reachable.as_ref().is_some_and(|r| r.contains(&hash));
+        if !ours {
+            let message = format!("upload-pack: not our ref {}", hash.full_hash());
+            output.write_all(&pkt_line::encode_text(&format!("ERR {}", message)))?;
+            output.flush()?;
+            return Err(anyhow::anyhow!(message));
+        }
+        wants.push(hash);
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+
+    let capabilities: Vec<&str> = capabilities.iter().map(String::as_str).collect();
+    let sideband = Sideband::requested(&capabilities);
+
+    let mut common: Vec<ObjectHash> = vec![];
+    loop {
+        let line = match pkt_line::try_read_pkt_line(input)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        if line == PktLine::Flush {
+            if common.is_empty() {
+                output.write_all(&pkt_line::encode_text("NAK"))?;
+            }
+            output.flush()?;
+            // The client sends everything again in its next request.
+            if stateless_rpc {
+                return Ok(());
+            }
+            continue;
+        }
+
+        let text = line_text(&line)?;
+        if text == "done" {
+            break;
+        }
+
+        let hash = text
+            .strip_prefix("have ")
+            .ok_or_else(|| protocol_error(UPLOAD_PACK, "have", &text))?;
+        let hash = ObjectHash::new(hash)?;
+        if ObjectFile::exists(base_path, &hash) && !common.contains(&hash) {
+            if common.is_empty() {
+                output.write_all(&pkt_line::encode_text(&format!("ACK {}", hash.full_hash())))?;
+            }
+            common.push(hash);
+        }
+    }
+    if common.is_empty() {
+        output.write_all(&pkt_line::encode_text("NAK"))?;
+    }
+
+    let objects = history::list_objects(base_path, &wants, &common)?;
+    let pack = packfile::create_packfile(base_path, &objects)?;
+
+    if sideband != Sideband::Disabled && !capabilities.contains(&"no-progress") {
+        let message = format!("Enumerating objects: {}, done.\n", objects.len());
+        output.write_all(&sideband::progress_packet(&message))?;
+    }
+    output.write_all(&sideband::multiplex_pack(&pack, sideband))?;
+    if sideband != Sideband::Disabled {
+        output.write_all(pkt_line::FLUSH_PKT)?;
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Answer a receive-pack request: read the ref update commands and the packfile after them,
+/// store the objects, update the refs and report back how it went.
+/// CF https://git-scm.com/docs/pack-protocol#_reference_update_request_and_packfile_transfer
+pub fn receive_pack<R: Read, W: Write>(
+    repository: &mut LocalRepository,
+    input: &mut R,
+    output: &mut W,
+) -> Result<(), anyhow::Error> {
+    let mut commands = vec![];
+    let mut capabilities: Vec<String> = vec![];
+    loop {
+        let text = match pkt_line::try_read_pkt_line(input)? {
+            None => return Ok(()),
+            Some(PktLine::Flush) => break,
+            Some(line) => line_text(&line)?,
+        };
+
+        let command = match text.split_once('\0') {
+            Some((command, requested)) => {
+                capabilities = requested.split(' ').map(String::from).collect();
+                command
+            }
+            None => text.as_str(),
+        };
+        commands.push(parse_command(command)?);
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    // The packfile is the rest of the request. Only deleting refs doesn't send one.
+    let mut pack = vec![];
+    if commands.iter().any(|command| command.new_hash.is_some()) {
+        input
+            .take(MAX_REQUEST_SIZE as u64 + 1)
+            .read_to_end(&mut pack)?;
+        if pack.len() > MAX_REQUEST_SIZE {
+            return Err(anyhow::anyhow!(
+                "Pack is larger than {} bytes",
+                MAX_REQUEST_SIZE
+            ));
+        }
+    }
+    let pack = Some(pack.as_slice()).filter(|pack| !pack.is_empty());
+
+    let report = repository.send_pack(&commands, pack, &mut std::io::sink())?;
+
+    let capabilities: Vec<&str> = capabilities.iter().map(String::as_str).collect();
+    if !capabilities.contains(&"report-status") {
+        return Ok(());
+    }
+
+    let report = protocol::create_report_status(&report);
+    let sideband = Sideband::requested(&capabilities);
+    output.write_all(&sideband::multiplex_pack(&report, sideband))?;
+    if sideband != Sideband::Disabled {
+        output.write_all(pkt_line::FLUSH_PKT)?;
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+/// A command is `<old hash> <new hash> <ref name>`, with zeros for a ref that doesn't exist.
+fn parse_command(command: &str) -> Result<RefUpdateCommand, anyhow::Error> {
+    let parts: Vec<&str> = command.splitn(3, ' ').collect();
+    let [old_hash, new_hash, name] = parts[..] else {
+        return Err(protocol_error(RECEIVE_PACK, "a command", command));
+    };
+
+    let parse_hash = |hash: &str| match hash {
+        ZERO_HASH => Ok(None),
+        hash => ObjectHash::new(hash).map(Some),
+    };
+
+    Ok(RefUpdateCommand {
+        old_hash: parse_hash(old_hash)?,
+        new_hash: parse_hash(new_hash)?,
+        name: name.to_string(),
+    })
+}
+
+fn line_text(line: &PktLine) -> Result<String, anyhow::Error> {
+    line.as_text()
+        .map(String::from)
+        .ok_or_else(|| anyhow::anyhow!("Invalid pkt-line {:?}", line))
+}
+
+fn protocol_error(service: &str, expected: &str, received: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{}: protocol error, expected {}, received '{}'",
+        service.trim_start_matches("git-"),
+        expected,
+        received
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_reads_zero_hashes_as_missing() {
+        let new = "0123456789abcdef0123456789abcdef01234567";
+        let command = parse_command(&format!("{} {} refs/heads/main", ZERO_HASH, new)).unwrap();
+
+        assert_eq!(command.old_hash, None);
+        assert_eq!(command.new_hash.unwrap().full_hash(), new);
+        assert_eq!(command.name, "refs/heads/main");
+
+        assert!(parse_command("refs/heads/main").is_err());
+    }
+}
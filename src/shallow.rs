@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::objects::ObjectHash;
+use crate::protocol::{Deepen, ShallowInfo};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// The `shallow` file lists the commits whose parents we don't have, one per line.
+/// CF https://git-scm.com/docs/shallow
+fn shallow_path(base_path: Option<&PathBuf>) -> PathBuf {
+    let path = PathBuf::from("not-git").join("shallow");
+    match base_path {
+        Some(base_path) => base_path.join(path),
+        None => path,
+    }
+}
+
+/// The commits our history is cut off at. A complete repository doesn't have any.
+pub fn read_shallow(base_path: Option<&PathBuf>) -> Result<HashSet<ObjectHash>, anyhow::Error> {
+    let path = shallow_path(base_path);
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(ObjectHash::new)
+        .collect()
+}
+
+pub fn is_shallow(base_path: Option<&PathBuf>) -> Result<bool, anyhow::Error> {
+    Ok(!read_shallow(base_path)?.is_empty())
+}
+
+/// Write the shallow commits, removing the file once the repository is complete again.
+pub fn write_shallow(
+    base_path: Option<&PathBuf>,
+    shallow: &HashSet<ObjectHash>,
+) -> Result<(), anyhow::Error> {
+    let path = shallow_path(base_path);
+    if shallow.is_empty() {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+
+    let mut lines: Vec<String> = shallow.iter().map(|hash| hash.full_hash()).collect();
+    lines.sort();
+    fs::write(path, format!("{}\n", lines.join("\n")))?;
+
+    Ok(())
+}
+
+/// Apply the server's answer to a shallow fetch to the `shallow` file.
+pub fn update_shallow(
+    base_path: Option<&PathBuf>,
+    info: &ShallowInfo,
+) -> Result<(), anyhow::Error> {
+    if info.shallow.is_empty() && info.unshallow.is_empty() {
+        return Ok(());
+    }
+
+    let mut shallow = read_shallow(base_path)?;
+    shallow.extend(info.shallow.iter().cloned());
+    for hash in &info.unshallow {
+        shallow.remove(hash);
+    }
+
+    write_shallow(base_path, &shallow)
+}
+
+/// Parse `--depth`, `--shallow-since` and `--unshallow`, with the value of the first two
+/// either after `=` or in the next argument. Other arguments aren't consumed.
+pub fn parse_deepen_arg<'a, I>(arg: &str, rest: &mut I) -> Result<Option<Deepen>, anyhow::Error>
+where
+    I: Iterator<Item = &'a String>,
+{
+    if arg == "--unshallow" {
+        return Ok(Some(Deepen::Unshallow));
+    }
+
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_string())),
+        None => (arg, None),
+    };
+    if name != "--depth" && name != "--shallow-since" {
+        return Ok(None);
+    }
+
+    let value = match value {
+        Some(value) => value,
+        None => rest
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("{} requires a value", name))?
+            .to_string(),
+    };
+
+    if name == "--depth" {
+        match value.parse::<u32>() {
+            Ok(depth) if depth > 0 => Ok(Some(Deepen::Depth(depth))),
+            _ => Err(anyhow::anyhow!("Depth {} is not a positive number", value)),
+        }
+    } else {
+        Ok(Some(Deepen::Since(parse_since(&value)?)))
+    }
+}
+
+/// Parse the date given to `--shallow-since`, either a unix timestamp or a `YYYY-MM-DD`
+/// date, which is taken as midnight UTC.
+pub fn parse_since(value: &str) -> Result<i64, anyhow::Error> {
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(timestamp);
+    }
+
+    let invalid = || anyhow::anyhow!("Invalid date {}, expected YYYY-MM-DD", value);
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+
+    let year: i64 = parts[0].parse().map_err(|_| invalid())?;
+    let month: i64 = parts[1].parse().map_err(|_| invalid())?;
+    let day: i64 = parts[2].parse().map_err(|_| invalid())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    Ok(days_from_civil(year, month, day) * SECONDS_PER_DAY)
+}
+
+// The number of days since 1970-01-01 in the proleptic Gregorian calendar.
+// CF https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_since_reads_timestamps_and_dates() {
+        assert_eq!(parse_since("1700000000").unwrap(), 1700000000);
+        assert_eq!(parse_since("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_since("2000-03-01").unwrap(), 951868800);
+        assert_eq!(parse_since("2024-02-29").unwrap(), 1709164800);
+        assert!(parse_since("2024-13-01").is_err());
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[test]
+    fn parse_deepen_arg_reads_inline_and_separate_values() {
+        let rest = ["5".to_string()];
+
+        let got = parse_deepen_arg("--depth=3", &mut rest.iter()).unwrap();
+        assert_eq!(got, Some(Deepen::Depth(3)));
+
+        let got = parse_deepen_arg("--depth", &mut rest.iter()).unwrap();
+        assert_eq!(got, Some(Deepen::Depth(5)));
+
+        let got = parse_deepen_arg("--shallow-since=1970-01-02", &mut rest.iter()).unwrap();
+        assert_eq!(got, Some(Deepen::Since(SECONDS_PER_DAY)));
+
+        let got = parse_deepen_arg("--unshallow", &mut rest.iter()).unwrap();
+        assert_eq!(got, Some(Deepen::Unshallow));
+
+        let got = parse_deepen_arg("--prune", &mut rest.iter()).unwrap();
+        assert_eq!(got, None);
+
+        assert!(parse_deepen_arg("--depth=0", &mut rest.iter()).is_err());
+        assert!(parse_deepen_arg("--depth", &mut [].iter()).is_err());
+    }
+}
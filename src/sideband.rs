@@ -0,0 +1,322 @@
+use std::io::{Cursor, Read, Write};
+
+use crate::pkt_line::{self, PktLine};
+use crate::protocol::RefAdvertisement;
+
+// Every side-band packet starts with a byte that says which channel it belongs to.
+const PACK_CHANNEL: u8 = 1;
+const PROGRESS_CHANNEL: u8 = 2;
+const ERROR_CHANNEL: u8 = 3;
+
+const PROGRESS_PREFIX: &[u8] = b"remote: ";
+
+/// Errors that the remote reported to us, as opposed to errors talking to it. They are
+/// surfaced through `anyhow` so callers can `downcast_ref::<RemoteError>()` to tell them apart.
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum RemoteError {
+    /// A message sent on the error channel of a multiplexed response.
+    #[error("remote error: {0}")]
+    Fatal(String),
+    /// An `ERR <message>` packet, which a server can send in place of any pkt-line.
+    #[error("remote error: {0}")]
+    Err(String),
+    #[error("invalid side-band channel {0}")]
+    InvalidChannel(u8),
+}
+
+/// Which kind of multiplexing the server will use. `side-band` packets may hold up to
+/// 1000 bytes and `side-band-64k` packets up to 65520 bytes, but the framing is the same.
+/// CF https://git-scm.com/docs/protocol-capabilities#_side_band_side_band_64k
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sideband {
+    Disabled,
+    Sideband,
+    Sideband64k,
+}
+
+impl Sideband {
+    /// Pick the best side-band mode the server advertised for protocol v0.
+    pub fn negotiate(advertisement: &RefAdvertisement) -> Self {
+        if advertisement.has_capability("side-band-64k") {
+            Sideband::Sideband64k
+        } else if advertisement.has_capability("side-band") {
+            Sideband::Sideband
+        } else {
+            Sideband::Disabled
+        }
+    }
+
+    /// The side-band mode a client asked for in its request, for the server side.
+    pub fn requested(capabilities: &[&str]) -> Self {
+        if capabilities.contains(&"side-band-64k") {
+            Sideband::Sideband64k
+        } else if capabilities.contains(&"side-band") {
+            Sideband::Sideband
+        } else {
+            Sideband::Disabled
+        }
+    }
+
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            Sideband::Disabled => None,
+            Sideband::Sideband => Some("side-band"),
+            Sideband::Sideband64k => Some("side-band-64k"),
+        }
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        match self {
+            Sideband::Disabled => pkt_line::MAX_PKT_LINE_SIZE,
+            Sideband::Sideband => 1000,
+            Sideband::Sideband64k => pkt_line::MAX_PKT_LINE_SIZE,
+        }
+    }
+}
+
+/// Read multiplexed pkt-lines until a flush packet, appending the pack data to `pack`
+/// and forwarding progress messages to `progress` prefixed with `remote: ` like git does.
+pub fn demultiplex<R: Read, P: Write, W: Write>(
+    reader: &mut R,
+    pack: &mut P,
+    progress: &mut W,
+) -> Result<(), anyhow::Error> {
+    let mut progress_writer = ProgressWriter::new(progress);
+
+    loop {
+        let data = match pkt_line::try_read_pkt_line(reader)? {
+            Some(PktLine::Data(data)) => data,
+            // Older servers close the connection without a flush packet.
+            Some(_) | None => break,
+        };
+        read_packet(&data, pack, &mut progress_writer)?;
+    }
+
+    progress_writer.finish()?;
+    Ok(())
+}
+
+/// Demultiplexes side-band packets as they arrive, for responses that come in chunks we're
+/// handed rather than from a reader. Packets can be split across chunks, so the end of a
+/// chunk is kept until the rest of its packet arrives.
+pub struct Demultiplexer<'a, W: Write> {
+    buffer: Vec<u8>,
+    progress: ProgressWriter<'a, W>,
+    // Whether we've seen the flush packet that ends the response.
+    done: bool,
+}
+
+impl<'a, W: Write> Demultiplexer<'a, W> {
+    pub fn new(progress: &'a mut W) -> Self {
+        Demultiplexer {
+            buffer: vec![],
+            progress: ProgressWriter::new(progress),
+            done: false,
+        }
+    }
+
+    /// Read the packets completed by the chunk, writing their pack data to `pack`.
+    pub fn write<P: Write>(&mut self, chunk: &[u8], pack: &mut P) -> Result<(), anyhow::Error> {
+        if self.done {
+            return Ok(());
+        }
+        self.buffer.extend_from_slice(chunk);
+
+        let mut cursor = Cursor::new(self.buffer.as_slice());
+        let mut consumed = 0;
+        loop {
+            match pkt_line::try_read_pkt_line(&mut cursor)? {
+                Some(PktLine::Data(data)) => read_packet(&data, pack, &mut self.progress)?,
+                Some(_) => {
+                    self.done = true;
+                    break;
+                }
+                // The rest of the packet is in a later chunk.
+                None => break,
+            }
+            consumed = cursor.position() as usize;
+        }
+
+        self.buffer.drain(..consumed);
+        Ok(())
+    }
+
+    /// Call once the response has ended.
+    pub fn finish(mut self) -> Result<(), anyhow::Error> {
+        self.progress.finish()?;
+        Ok(())
+    }
+}
+
+fn read_packet<P: Write, W: Write>(
+    data: &[u8],
+    pack: &mut P,
+    progress: &mut ProgressWriter<W>,
+) -> Result<(), anyhow::Error> {
+    if let Some(message) = data.strip_prefix(b"ERR ") {
+        return Err(RemoteError::Err(String::from_utf8_lossy(message).trim().to_string()).into());
+    }
+
+    match data.split_first() {
+        Some((&PACK_CHANNEL, pack_data)) => pack.write_all(pack_data)?,
+        Some((&PROGRESS_CHANNEL, message)) => progress.write(message)?,
+        Some((&ERROR_CHANNEL, message)) => {
+            progress.finish()?;
+            let message = String::from_utf8_lossy(message).trim().to_string();
+            return Err(RemoteError::Fatal(message).into());
+        }
+        Some((&channel, _)) => return Err(RemoteError::InvalidChannel(channel).into()),
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Split data over as many side-band packets of the pack channel as it takes, the other
+/// way around from `demultiplex`. Without side-band, the data is sent as it is.
+pub fn multiplex_pack(data: &[u8], sideband: Sideband) -> Vec<u8> {
+    if sideband == Sideband::Disabled {
+        return data.to_vec();
+    }
+
+    // Each packet has its length prefix and channel byte on top of the data.
+    let mut packets = vec![];
+    for chunk in data.chunks(sideband.max_packet_size() - 5) {
+        let mut packet = vec![PACK_CHANNEL];
+        packet.extend(chunk);
+        packets.extend(pkt_line::encode(&packet));
+    }
+    packets
+}
+
+/// A message for the progress channel, which the client shows prefixed with `remote: `.
+pub fn progress_packet(message: &str) -> Vec<u8> {
+    let mut packet = vec![PROGRESS_CHANNEL];
+    packet.extend(message.as_bytes());
+    pkt_line::encode(&packet)
+}
+
+/// Progress messages can be split across packets and use `\r` to redraw the current line,
+/// so we only prefix a message once we've seen where it begins.
+struct ProgressWriter<'a, W: Write> {
+    output: &'a mut W,
+    at_line_start: bool,
+}
+
+impl<'a, W: Write> ProgressWriter<'a, W> {
+    fn new(output: &'a mut W) -> Self {
+        Self {
+            output,
+            at_line_start: true,
+        }
+    }
+
+    fn write(&mut self, message: &[u8]) -> Result<(), std::io::Error> {
+        // Each line, with the `\r` or `\n` that ends it, is written whole.
+        for line in message.split_inclusive(|&byte| byte == b'\r' || byte == b'\n') {
+            if self.at_line_start {
+                self.output.write_all(PROGRESS_PREFIX)?;
+            }
+
+            self.output.write_all(line)?;
+            self.at_line_start = line.ends_with(b"\r") || line.ends_with(b"\n");
+        }
+
+        self.output.flush()
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        if !self.at_line_start {
+            self.output.write_all(b"\n")?;
+            self.at_line_start = true;
+        }
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiplex_pack_round_trips_through_demultiplex() {
+        let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let mut packets = multiplex_pack(&data, Sideband::Sideband);
+        packets.extend(progress_packet("done\n"));
+        packets.extend(pkt_line::FLUSH_PKT);
+
+        let mut pack = vec![];
+        let mut progress = vec![];
+        demultiplex(&mut packets.as_slice(), &mut pack, &mut progress).unwrap();
+
+        assert_eq!(pack, data);
+        assert_eq!(progress, b"remote: done\n");
+    }
+
+    #[test]
+    fn demultiplexer_reads_packets_split_across_chunks() {
+        let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let mut packets = progress_packet("done\n");
+        packets.extend(multiplex_pack(&data, Sideband::Sideband));
+        packets.extend(pkt_line::FLUSH_PKT);
+        packets.extend(b"ignored");
+
+        let mut pack = vec![];
+        let mut progress = vec![];
+        let mut demultiplexer = Demultiplexer::new(&mut progress);
+        for chunk in packets.chunks(7) {
+            demultiplexer.write(chunk, &mut pack).unwrap();
+        }
+        demultiplexer.finish().unwrap();
+
+        assert_eq!(pack, data);
+        assert_eq!(progress, b"remote: done\n");
+    }
+
+    #[test]
+    fn progress_writer_prefixes_each_line() {
+        let mut output = vec![];
+        let mut writer = ProgressWriter::new(&mut output);
+
+        writer.write(b"Counting: 1\rCounting: 2\rCount").unwrap();
+        writer.write(b"ing: done\n").unwrap();
+        writer.write(b"Compressing").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "remote: Counting: 1\rremote: Counting: 2\rremote: Counting: done\nremote: Compressing\n"
+        );
+    }
+
+    #[test]
+    fn progress_writer_writes_whole_lines() {
+        // Records each write separately.
+        struct Writes(Vec<Vec<u8>>);
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut output = Writes(vec![]);
+        ProgressWriter::new(&mut output)
+            .write(b"Counting: 1\rCounting: 2\n")
+            .unwrap();
+
+        let writes: Vec<&[u8]> = output.0.iter().map(|write| write.as_slice()).collect();
+        assert_eq!(
+            writes,
+            vec![
+                &b"remote: "[..],
+                b"Counting: 1\r",
+                b"remote: ",
+                b"Counting: 2\n"
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::Config;
+
+const SSH_SCHEMES: [&str; 3] = ["ssh://", "git+ssh://", "ssh+git://"];
+const EXT_PREFIX: &str = "ext::";
+
+// The command to run instead of ssh, as a shell snippet or a program.
+const SSH_COMMAND_ENV: &str = "GIT_SSH_COMMAND";
+const SSH_PROGRAM_ENV: &str = "GIT_SSH";
+
+/// Where a repository is on a machine we reach over ssh.
+#[derive(Debug, PartialEq)]
+pub struct SshUrl {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+impl SshUrl {
+    /// Parse `ssh://[user@]host[:port]/path` or the scp-like `[user@]host:path`.
+    /// Returns `None` for any other URL.
+    pub fn parse(url: &str) -> Option<Self> {
+        if let Some(rest) = SSH_SCHEMES
+            .iter()
+            .find_map(|scheme| url.strip_prefix(scheme))
+        {
+            let (authority, path) = rest.split_once('/')?;
+            let (user, host) = split_user(authority);
+            let (host, port) = match host.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port.parse().ok()?)),
+                None => (host, None),
+            };
+
+            // `ssh://host/~user/repo` is relative to a home directory.
+            let path = match path.starts_with('~') {
+                true => path.to_string(),
+                false => format!("/{}", path),
+            };
+
+            return Some(SshUrl {
+                user,
+                host: host.to_string(),
+                port,
+                path,
+            });
+        }
+
+        if url.contains("://") || url.starts_with(EXT_PREFIX) {
+            return None;
+        }
+
+        // The scp-like syntax has a colon before the first slash.
+        let (authority, path) = url.split_once(':')?;
+        if authority.is_empty() || authority.contains('/') || path.is_empty() {
+            return None;
+        }
+
+        let (user, host) = split_user(authority);
+        Some(SshUrl {
+            user,
+            host: host.to_string(),
+            port: None,
+            path: path.to_string(),
+        })
+    }
+
+    /// The command that runs the service on the remote machine, e.g.
+    /// `ssh -p 2222 git@example.com "git-upload-pack 'org/repo.git'"`. The command used
+    /// instead of ssh comes from `GIT_SSH_COMMAND`, `core.sshCommand` or `GIT_SSH`.
+    pub fn command(&self, config: &Config, service: &str) -> Result<Command, anyhow::Error> {
+        // ssh would take a host or user starting with `-` as an option, e.g.
+        // `-oProxyCommand=...`, which runs any command it's given.
+        if self.host.starts_with('-') {
+            return Err(anyhow::anyhow!("strange hostname '{}' blocked", self.host));
+        }
+        if let Some(user) = self.user.as_ref().filter(|user| user.starts_with('-')) {
+            return Err(anyhow::anyhow!("strange username '{}' blocked", user));
+        }
+
+        let (mut command, program) = match std::env::var(SSH_COMMAND_ENV)
+            .ok()
+            .or_else(|| config.get("core.sshcommand").map(String::from))
+        {
+            Some(snippet) => {
+                let program = snippet
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                (shell(&format!("{} \"$@\"", snippet)), program)
+            }
+            None => {
+                let program = std::env::var(SSH_PROGRAM_ENV).unwrap_or_else(|_| "ssh".to_string());
+                (Command::new(&program), program)
+            }
+        };
+
+        // OpenSSH only passes the protocol version on if it's told to.
+        if Path::new(&program)
+            .file_name()
+            .is_some_and(|name| name == "ssh")
+        {
+            command.args(["-o", "SendEnv=GIT_PROTOCOL"]);
+        }
+        if let Some(port) = self.port {
+            command.args(["-p", &port.to_string()]);
+        }
+
+        let destination = match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        };
+        command.arg(destination);
+        command.arg(format!("{} {}", service, shell_quote(&self.path)));
+
+        Ok(command)
+    }
+}
+
+fn split_user(authority: &str) -> (Option<String>, &str) {
+    match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, authority),
+    }
+}
+
+/// Whether the URL is for a server we run a command to talk to.
+pub fn is_command_url(url: &str) -> bool {
+    url.starts_with(EXT_PREFIX) || SshUrl::parse(url).is_some()
+}
+
+/// The command that runs the service for the URL, if it's an ssh URL or an `ext::` URL.
+/// CF https://git-scm.com/docs/git-remote-ext
+pub fn remote_command(
+    config: &Config,
+    url: &str,
+    service: &str,
+) -> Result<Option<Command>, anyhow::Error> {
+    if let Some(ext_command) = url.strip_prefix(EXT_PREFIX) {
+        check_ext_allowed(config)?;
+        return Ok(Some(shell(&expand_ext_command(ext_command, service))));
+    }
+
+    SshUrl::parse(url)
+        .map(|url| url.command(config, service))
+        .transpose()
+}
+
+/// An `ext::` URL runs whatever command it contains, so like git, it has to be allowed
+/// explicitly with `protocol.ext.allow`.
+fn check_ext_allowed(config: &Config) -> Result<(), anyhow::Error> {
+    let allow = config
+        .get("protocol.ext.allow")
+        .or_else(|| config.get("protocol.allow"));
+
+    match allow {
+        // Every command we run was asked for by the user.
+        Some("always" | "user") => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "Transport 'ext' not allowed, set protocol.ext.allow to always to use ext:: URLs"
+        )),
+    }
+}
+
+/// Replace the placeholders of an `ext::` command: `%S` is the service, `%s` is the
+/// service without the `git-` prefix and `%%` is a percent sign. Unlike git, which splits
+/// the command on spaces itself, we hand it to the shell.
+fn expand_ext_command(command: &str, service: &str) -> String {
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('S') => expanded.push_str(service),
+            Some('s') => expanded.push_str(service.strip_prefix("git-").unwrap_or(service)),
+            Some(other) => expanded.push(other),
+            None => expanded.push('%'),
+        }
+    }
+
+    expanded
+}
+
+fn shell(snippet: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(snippet).arg(snippet);
+    command
+}
+
+/// Quote an argument for the remote shell, which runs the command ssh is given.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_ssh_and_scp_like_urls() {
+        assert_eq!(
+            SshUrl::parse("ssh://git@example.com:2222/org/repo.git"),
+            Some(SshUrl {
+                user: Some("git".to_string()),
+                host: "example.com".to_string(),
+                port: Some(2222),
+                path: "/org/repo.git".to_string(),
+            })
+        );
+        assert_eq!(
+            SshUrl::parse("git@example.com:org/repo.git"),
+            Some(SshUrl {
+                user: Some("git".to_string()),
+                host: "example.com".to_string(),
+                port: None,
+                path: "org/repo.git".to_string(),
+            })
+        );
+        assert_eq!(
+            SshUrl::parse("ssh://example.com/~alice/repo").unwrap().path,
+            "~alice/repo"
+        );
+
+        assert_eq!(SshUrl::parse("https://example.com/repo.git"), None);
+        assert_eq!(SshUrl::parse("ext::ssh -T host %S repo"), None);
+        assert_eq!(SshUrl::parse("./relative/path:with-colon"), None);
+    }
+
+    #[test]
+    fn command_blocks_hosts_and_users_that_look_like_options() {
+        // A repository without a config file has an empty configuration.
+        let config = Config::load(Some(&std::path::PathBuf::from("not-a-repository"))).unwrap();
+
+        let url = SshUrl::parse("ssh://-oProxyCommand=touch%20pwned/repo").unwrap();
+        let got = url.command(&config, "git-upload-pack").unwrap_err();
+        assert_eq!(
+            got.to_string(),
+            "strange hostname '-oProxyCommand=touch%20pwned' blocked"
+        );
+
+        let url = SshUrl::parse("-oProxyCommand=sh@example.com:repo").unwrap();
+        let got = url.command(&config, "git-upload-pack").unwrap_err();
+        assert_eq!(
+            got.to_string(),
+            "strange username '-oProxyCommand=sh' blocked"
+        );
+
+        let url = SshUrl::parse("git@example.com:repo").unwrap();
+        assert!(url.command(&config, "git-upload-pack").is_ok());
+    }
+
+    #[test]
+    fn expand_ext_command_replaces_placeholders() {
+        assert_eq!(
+            expand_ext_command("ssh host %S 100%% %s", "git-upload-pack"),
+            "ssh host git-upload-pack 100% upload-pack"
+        );
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("it's/repo"), "'it'\\''s/repo'");
+    }
+}
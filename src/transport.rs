@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use bytes::Bytes;
+
+use crate::bundle::Bundle;
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::dumb_http;
+use crate::fetch::{self, Negotiation};
+use crate::http::{self, HttpClient, UPLOAD_PACK};
+use crate::local::LocalRepository;
+use crate::objects::ObjectHash;
+use crate::protocol::{
+    self, FetchOptions, GitRef, ProtocolVersion, RefAdvertisement, RefUpdateCommand, ReportStatus,
+    ShallowInfo,
+};
+use crate::push::RECEIVE_PACK;
+use crate::sideband::Sideband;
+
+/// How we talk to a remote repository, whatever is in between: HTTP, ssh or another
+/// command, a git daemon, or nothing at all for a repository on this machine. A transport
+/// is opened for one service, so fetching and pushing each open their own.
+pub trait Transport {
+    /// The refs of the remote matching any of the prefixes. Servers that send every ref
+    /// up front may return refs that don't match.
+    fn list_refs(&mut self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error>;
+
+    /// Download a packfile of the wanted objects, leaving out what's reachable from the
+    /// haves the remote recognizes. The packfile is written to `pack` as it arrives rather
+    /// than kept in memory. Returns the new boundary of a shallow history.
+    fn fetch_pack(
+        &mut self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+        options: &FetchOptions,
+        progress: &mut dyn Write,
+        pack: &mut dyn Write,
+    ) -> Result<ShallowInfo, anyhow::Error>;
+
+    /// Send the ref updates along with a packfile of the objects they need, and return what
+    /// the remote reported back. A remote that doesn't report anything returns no refs.
+    fn send_pack(
+        &mut self,
+        commands: &[RefUpdateCommand],
+        pack: Option<&[u8]>,
+        progress: &mut dyn Write,
+    ) -> Result<ReportStatus, anyhow::Error>;
+
+    /// The repository, if it's on this machine and can be read directly.
+    fn local_repository(&self) -> Option<&LocalRepository> {
+        None
+    }
+
+    /// The bundle, if the remote is a bundle file rather than a repository.
+    fn bundle(&self) -> Option<&Bundle> {
+        None
+    }
+}
+
+/// Open a transport to the service of the repository at the URL: read it directly if it's
+/// local or a bundle file, run ssh or an `ext::` command for it, ask a git daemon for it,
+/// or otherwise ask for it over HTTP. `base_path` is the repository we're fetching into or
+/// pushing from.
+pub fn connect(
+    base_path: Option<&PathBuf>,
+    config: &Config,
+    url: &str,
+    service: &str,
+) -> Result<Box<dyn Transport>, anyhow::Error> {
+    if let Some(bundle) = Bundle::open_url(url)? {
+        return Ok(Box::new(bundle));
+    }
+    if let Some(repository) = LocalRepository::open_url(url)? {
+        return Ok(Box::new(repository));
+    }
+
+    if let Some((connection, advertisement)) = Connection::open(config, url, service)? {
+        return Ok(Box::new(ConnectionTransport {
+            service: service.to_string(),
+            connection,
+            advertisement,
+        }));
+    }
+
+    let client = HttpClient::new(config)?;
+    let advertisement = http::discover_references(&client, url, service)?;
+    Ok(Box::new(HttpTransport {
+        base_path: base_path.cloned(),
+        service: service.to_string(),
+        client,
+        url: url.to_string(),
+        advertisement,
+    }))
+}
+
+/// Smart HTTP, where every request is a POST of its own, or the dumb protocol when the
+/// server only serves files.
+pub struct HttpTransport {
+    // A dumb server leaves it to us to work out which objects we already have.
+    base_path: Option<PathBuf>,
+    service: String,
+    client: HttpClient,
+    url: String,
+    advertisement: RefAdvertisement,
+}
+
+impl Transport for HttpTransport {
+    fn list_refs(&mut self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error> {
+        let (client, url) = (&self.client, &self.url);
+        list_refs(&self.advertisement, ref_prefixes, |body| {
+            http::post_service_request(client, url, ProtocolVersion::V2, UPLOAD_PACK, body)
+        })
+    }
+
+    /// Negotiate the packfile with a smart server, leaving out the history beyond the
+    /// requested depth of a shallow request and the objects the filter excludes. A dumb
+    /// server can't do either, so its objects are downloaded one at a time instead.
+    fn fetch_pack(
+        &mut self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+        options: &FetchOptions,
+        mut progress: &mut dyn Write,
+        mut pack: &mut dyn Write,
+    ) -> Result<ShallowInfo, anyhow::Error> {
+        check_service(&self.service, UPLOAD_PACK)?;
+        let (client, url) = (&self.client, &self.url);
+
+        if self.advertisement.version == ProtocolVersion::Dumb {
+            if options.is_shallow() {
+                return Err(fetch::shallow_unsupported());
+            }
+            if options.filter.is_some() {
+                writeln!(progress, "{}", fetch::FILTER_UNSUPPORTED_WARNING)?;
+            }
+
+            dumb_http::fetch_pack(self.base_path.as_ref(), client, url, wants, haves, pack)
+                .context("Failed to fetch objects from dumb server")?;
+            return Ok(ShallowInfo::default());
+        }
+
+        fetch::negotiate_pack(
+            &self.advertisement,
+            wants,
+            haves,
+            options,
+            &mut progress,
+            &mut pack,
+            |version, body| http::stream_service_request(client, url, version, UPLOAD_PACK, body),
+        )
+    }
+
+    fn send_pack(
+        &mut self,
+        commands: &[RefUpdateCommand],
+        pack: Option<&[u8]>,
+        progress: &mut dyn Write,
+    ) -> Result<ReportStatus, anyhow::Error> {
+        check_service(&self.service, RECEIVE_PACK)?;
+        let (client, url) = (&self.client, &self.url);
+        send_pack(&self.advertisement, commands, pack, progress, |body| {
+            http::post_service_request(client, url, ProtocolVersion::V0, RECEIVE_PACK, body)
+        })
+    }
+}
+
+/// A transport over a `Connection`: to the service over ssh, an `ext::` command or the git
+/// protocol.
+pub struct ConnectionTransport {
+    service: String,
+    connection: Connection,
+    advertisement: RefAdvertisement,
+}
+
+impl Transport for ConnectionTransport {
+    fn list_refs(&mut self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error> {
+        let connection = &mut self.connection;
+        list_refs(&self.advertisement, ref_prefixes, |body| {
+            connection.request(ProtocolVersion::V2, body)
+        })
+    }
+
+    fn fetch_pack(
+        &mut self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+        options: &FetchOptions,
+        mut progress: &mut dyn Write,
+        mut pack: &mut dyn Write,
+    ) -> Result<ShallowInfo, anyhow::Error> {
+        check_service(&self.service, UPLOAD_PACK)?;
+        let version = self.advertisement.version;
+        let mut negotiation =
+            Negotiation::new(&self.advertisement, wants, haves, options, &mut progress)?;
+
+        // Each response is read from the connection itself, which a closure can't lend
+        // out, so we go through the rounds here rather than with `fetch::negotiate_pack`.
+        loop {
+            let body = negotiation.next_request();
+            let response = self
+                .connection
+                .stream(version, body)
+                .context("Failed to fetch packfile")?;
+
+            if let Some(shallow_info) =
+                negotiation.read_response(response, &mut pack, &mut progress)?
+            {
+                if version == ProtocolVersion::V0 {
+                    self.connection.finish()?;
+                }
+                return Ok(shallow_info);
+            }
+        }
+    }
+
+    fn send_pack(
+        &mut self,
+        commands: &[RefUpdateCommand],
+        pack: Option<&[u8]>,
+        progress: &mut dyn Write,
+    ) -> Result<ReportStatus, anyhow::Error> {
+        check_service(&self.service, RECEIVE_PACK)?;
+        let connection = &mut self.connection;
+        send_pack(&self.advertisement, commands, pack, progress, |body| {
+            connection.request(ProtocolVersion::V0, body)
+        })
+    }
+}
+
+/// Replays a recorded conversation with a server: the advertisement it starts with and its
+/// responses to each of our requests, in order. The requests we sent are kept so they can
+/// be checked afterwards.
+pub struct ScriptedTransport {
+    service: String,
+    advertisement: RefAdvertisement,
+    responses: VecDeque<Vec<u8>>,
+    pub requests: Vec<Vec<u8>>,
+}
+
+impl ScriptedTransport {
+    pub fn new(
+        service: &str,
+        advertisement: &[u8],
+        responses: Vec<Vec<u8>>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(ScriptedTransport {
+            service: service.to_string(),
+            advertisement: protocol::parse_advertisement(advertisement, service)?,
+            responses: responses.into(),
+            requests: vec![],
+        })
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn list_refs(&mut self, ref_prefixes: &[&str]) -> Result<Vec<GitRef>, anyhow::Error> {
+        let (responses, requests) = (&mut self.responses, &mut self.requests);
+        list_refs(&self.advertisement, ref_prefixes, |body| {
+            replay(responses, requests, body)
+        })
+    }
+
+    fn fetch_pack(
+        &mut self,
+        wants: &[&ObjectHash],
+        haves: &[ObjectHash],
+        options: &FetchOptions,
+        mut progress: &mut dyn Write,
+        mut pack: &mut dyn Write,
+    ) -> Result<ShallowInfo, anyhow::Error> {
+        check_service(&self.service, UPLOAD_PACK)?;
+        let (responses, requests) = (&mut self.responses, &mut self.requests);
+        fetch::negotiate_pack(
+            &self.advertisement,
+            wants,
+            haves,
+            options,
+            &mut progress,
+            &mut pack,
+            |_, body| replay(responses, requests, body).map(Cursor::new),
+        )
+    }
+
+    fn send_pack(
+        &mut self,
+        commands: &[RefUpdateCommand],
+        pack: Option<&[u8]>,
+        progress: &mut dyn Write,
+    ) -> Result<ReportStatus, anyhow::Error> {
+        check_service(&self.service, RECEIVE_PACK)?;
+        let (responses, requests) = (&mut self.responses, &mut self.requests);
+        send_pack(&self.advertisement, commands, pack, progress, |body| {
+            replay(responses, requests, body)
+        })
+    }
+}
+
+fn replay(
+    responses: &mut VecDeque<Vec<u8>>,
+    requests: &mut Vec<Vec<u8>>,
+    body: Vec<u8>,
+) -> Result<Bytes, anyhow::Error> {
+    requests.push(body);
+    responses
+        .pop_front()
+        .map(Bytes::from)
+        .ok_or_else(|| anyhow::anyhow!("The script has no response to request {}", requests.len()))
+}
+
+// A transport only talks to the service it was opened for.
+pub fn check_service(service: &str, expected: &str) -> Result<(), anyhow::Error> {
+    if service != expected {
+        return Err(anyhow::anyhow!(
+            "Expected a connection to {}, not {}",
+            expected,
+            service
+        ));
+    }
+    Ok(())
+}
+
+/// The refs matching any of the prefixes. Protocol v2 servers are asked with the `ls-refs`
+/// command, which `send` sends, while protocol v0 and dumb servers already sent every ref
+/// in the advertisement.
+fn list_refs<F>(
+    advertisement: &RefAdvertisement,
+    ref_prefixes: &[&str],
+    send: F,
+) -> Result<Vec<GitRef>, anyhow::Error>
+where
+    F: FnOnce(Vec<u8>) -> Result<Bytes, anyhow::Error>,
+{
+    if advertisement.version != ProtocolVersion::V2 {
+        return Ok(advertisement.refs.clone());
+    }
+
+    let body = protocol::create_ls_refs_request(advertisement, ref_prefixes);
+    let response = send(body).context("Failed to list refs")?;
+    protocol::parse_ls_refs_response(&response)
+}
+
+/// The part of pushing that doesn't depend on how we talk to the server, which `send`
+/// takes care of.
+fn send_pack<F>(
+    advertisement: &RefAdvertisement,
+    commands: &[RefUpdateCommand],
+    pack: Option<&[u8]>,
+    progress: &mut dyn Write,
+    send: F,
+) -> Result<ReportStatus, anyhow::Error>
+where
+    F: FnOnce(Vec<u8>) -> Result<Bytes, anyhow::Error>,
+{
+    let body = receive_pack_request(advertisement, commands, pack)?;
+    let response = send(body)?;
+    read_receive_pack_response(advertisement, &response, progress)
+}
+
+/// The body of the request that pushes the ref updates and packfile.
+pub fn receive_pack_request(
+    advertisement: &RefAdvertisement,
+    commands: &[RefUpdateCommand],
+    pack: Option<&[u8]>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    if advertisement.version != ProtocolVersion::V0 {
+        return Err(anyhow::anyhow!(
+            "Expected a protocol v0 advertisement from {}",
+            RECEIVE_PACK
+        ));
+    }
+
+    let deletes = commands.iter().any(|command| command.new_hash.is_none());
+    if deletes && !advertisement.has_capability("delete-refs") {
+        return Err(anyhow::anyhow!("The remote does not support deleting refs"));
+    }
+
+    Ok(protocol::create_receive_pack_request(
+        commands,
+        &protocol::receive_pack_capabilities(advertisement),
+        pack,
+    ))
+}
+
+/// What the remote reported back in its response to a push.
+pub fn read_receive_pack_response(
+    advertisement: &RefAdvertisement,
+    response: &[u8],
+    mut progress: &mut dyn Write,
+) -> Result<ReportStatus, anyhow::Error> {
+    if !advertisement.has_capability("report-status") {
+        return Ok(ReportStatus {
+            unpack_error: None,
+            refs: vec![],
+        });
+    }
+
+    let sideband = Sideband::negotiate(advertisement);
+    protocol::parse_receive_pack_response(response, sideband, &mut progress)
+}
@@ -1,6 +1,6 @@
 use std::fs;
 use std::io::{Cursor, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use flate2::read::ZlibDecoder;
@@ -80,3 +80,22 @@ pub fn read_next_zlib_data(mut cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>, an
 
     Ok(data)
 }
+
+/// Remove a file from the work tree, along with the directories it leaves empty.
+pub fn remove_work_file(work_tree: &Path, path: &str) -> Result<(), anyhow::Error> {
+    let file = work_tree.join(path);
+    if file.is_file() {
+        fs::remove_file(&file)?;
+    }
+
+    // Remove the directories the file leaves empty, but never the root of the work tree.
+    let mut directory = file.parent();
+    while let Some(dir) = directory {
+        if dir == work_tree || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        directory = dir.parent();
+    }
+
+    Ok(())
+}
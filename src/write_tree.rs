@@ -2,7 +2,8 @@ use std::path::PathBuf;
 use std::{env, fs};
 
 use crate::hash_object;
-use crate::objects::{ObjectHash, ObjectType};
+use crate::objects::{self, ObjectHash, ObjectType};
+use crate::progress::{self, Progress};
 
 enum TreeFileType {
     Tree(Vec<TreeFile>),
@@ -27,20 +28,26 @@ impl TreeFile {
 }
 
 pub fn write_tree_command(_: &[String]) -> Result<(), anyhow::Error> {
-    let hash = create_tree(None)?;
+    let hash = create_tree(None, progress::stderr().as_mut())?;
     println!("{}", hash.full_hash());
 
     Ok(())
 }
 
-pub fn create_tree(path: Option<&str>) -> Result<ObjectHash, anyhow::Error> {
+pub fn create_tree(
+    path: Option<&str>,
+    progress: &mut dyn Progress,
+) -> Result<ObjectHash, anyhow::Error> {
     let path = match path {
         None => env::current_dir()?,
         Some(path) => PathBuf::from(path),
     };
 
     let base_path = &PathBuf::from(&path);
-    let mut root_tree = build_tree_from_path(base_path, path)?;
+    let mut files_hashed = 0;
+    progress.start("Hashing files", None);
+    let mut root_tree = build_tree_from_path(base_path, path, progress, &mut files_hashed)?;
+    progress.finish();
 
     let hash = hash_tree(base_path, &mut root_tree)?;
     let hash = ObjectHash::new(&hash)?;
@@ -51,6 +58,8 @@ pub fn create_tree(path: Option<&str>) -> Result<ObjectHash, anyhow::Error> {
 fn build_tree_from_path(
     base_path: &PathBuf,
     path: PathBuf,
+    progress: &mut dyn Progress,
+    files_hashed: &mut usize,
 ) -> Result<Vec<TreeFile>, anyhow::Error> {
     let mut tree_files: Vec<TreeFile> = Vec::new();
 
@@ -74,22 +83,26 @@ fn build_tree_from_path(
         }
 
         let tree_file_type = match object_type {
-            ObjectType::Tree => match build_tree_from_path(base_path, entry.path()) {
-                Ok(tree_file) => TreeFileType::Tree(tree_file),
-                Err(e) => TreeFileType::Error(e),
-            },
+            ObjectType::Tree => {
+                match build_tree_from_path(base_path, entry.path(), progress, files_hashed) {
+                    Ok(tree_file) => TreeFileType::Tree(tree_file),
+                    Err(e) => TreeFileType::Error(e),
+                }
+            }
             _ => TreeFileType::Other(object_type.clone(), entry.path()),
         };
 
         let hash = match tree_file_type {
             TreeFileType::Error(e) => return Err(e),
             TreeFileType::Other(object_type, path) => {
-                let mut file_contents = fs::read(path)?;
+                let file_contents = fs::read(path)?;
                 let hash = hash_object::hash_and_write_object(
                     Some(base_path),
                     &object_type,
-                    &mut file_contents,
+                    &file_contents,
                 )?;
+                *files_hashed += 1;
+                progress.update(*files_hashed, None);
                 hash.full_hash()
             }
             TreeFileType::Tree(mut tree_files) => hash_tree(base_path, &mut tree_files)?,
@@ -103,7 +116,9 @@ fn build_tree_from_path(
 }
 
 fn hash_tree(base_path: &PathBuf, tree_files: &mut Vec<TreeFile>) -> Result<String, anyhow::Error> {
-    tree_files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    tree_files.sort_by(|a, b| {
+        objects::compare_tree_entries(&a.file_name, &a.object_type, &b.file_name, &b.object_type)
+    });
 
     let mut tree_content = Vec::new();
     for tree_file in tree_files {
@@ -149,6 +164,6 @@ fn hash_tree(base_path: &PathBuf, tree_files: &mut Vec<TreeFile>) -> Result<Stri
     }
 
     let hash =
-        hash_object::hash_and_write_object(Some(base_path), &ObjectType::Tree, &mut tree_content)?;
+        hash_object::hash_and_write_object(Some(base_path), &ObjectType::Tree, &tree_content)?;
     Ok(hash.full_hash())
 }
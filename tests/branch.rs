@@ -1,7 +1,7 @@
 use std::{fs, path::PathBuf};
 
 use not_git::objects::ObjectHash;
-use not_git::{branch, init, update_refs};
+use not_git::{branch, init, refs, update_refs};
 
 mod common;
 
@@ -161,3 +161,49 @@ fn create_branch_creates_new_branch() {
     let ref_contents = String::from_utf8(ref_contents).unwrap();
     assert_eq!(ref_contents, commit_hash.full_hash());
 }
+
+#[test]
+fn list_branches_with_all_lists_remote_tracking_branches() {
+    let path = common::TestPath::new();
+    init::create_directories(init::InitConfig::new("main", path.to_optional_path())).unwrap();
+
+    let tree_hash = common::create_valid_tree_hash(&path);
+    let commit_hash = common::write_commit(&path, &tree_hash, None, "test-commit");
+
+    let main_path = PathBuf::from("main");
+    let config = update_refs::UpdateRefsConfig::new(&commit_hash, &main_path);
+    update_refs::update_refs(path.to_optional_path(), config).unwrap();
+
+    refs::write_ref(
+        path.to_optional_path(),
+        "refs/remotes/origin/main",
+        &commit_hash,
+    )
+    .unwrap();
+    refs::write_ref(
+        path.to_optional_path(),
+        "refs/remotes/origin/feature",
+        &commit_hash,
+    )
+    .unwrap();
+    refs::write_symbolic_ref(
+        path.to_optional_path(),
+        "refs/remotes/origin/HEAD",
+        "refs/remotes/origin/main",
+    )
+    .unwrap();
+
+    let branch_options = branch::list_branches(path.to_optional_path(), false).unwrap();
+    assert_eq!(branch_options.branches, vec!["main"]);
+
+    let branch_options = branch::list_branches(path.to_optional_path(), true).unwrap();
+    assert_eq!(
+        branch_options.branches,
+        vec![
+            "main",
+            "remotes/origin/HEAD -> origin/main",
+            "remotes/origin/feature",
+            "remotes/origin/main",
+        ]
+    );
+}
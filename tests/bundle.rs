@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::PathBuf;
+
+use not_git::bundle::{self, Bundle};
+use not_git::config::Config;
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::objects::ObjectFile;
+use not_git::progress::NoProgress;
+use not_git::{clone, init, refs};
+
+mod common;
+
+fn first_commit() -> common::TestRemoteRepository {
+    common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First")
+}
+
+// A second commit on `main` of the source, returning it.
+fn add_second_commit(
+    source: &PathBuf,
+    first: &common::TestRemoteRepository,
+) -> common::TestRemoteRepository {
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+    second.write_to(source);
+    refs::write_ref(Some(source), "refs/heads/main", &second.commit_hash).unwrap();
+    second
+}
+
+fn revisions(revisions: &[&str]) -> Vec<String> {
+    revisions
+        .iter()
+        .map(|revision| revision.to_string())
+        .collect()
+}
+
+#[test]
+fn create_bundle_records_refs_and_verifies() {
+    let path = common::TestPath::new();
+    let first = first_commit();
+    let source = common::setup_source(&path, &first);
+
+    let file = path.join(&"repo.bundle");
+    let objects = bundle::create_bundle(Some(&source), &file, &revisions(&["--all"]), 2).unwrap();
+
+    let bundle = Bundle::open(&file).unwrap();
+    assert_eq!(objects, 3);
+    assert_eq!(bundle.version, 2);
+    assert!(bundle.prerequisites.is_empty());
+    assert_eq!(
+        bundle.refs,
+        vec![
+            ("HEAD".to_string(), first.commit_hash.clone()),
+            ("refs/heads/main".to_string(), first.commit_hash.clone()),
+        ]
+    );
+    bundle.verify(Some(&source)).unwrap();
+}
+
+#[test]
+fn create_bundle_writes_v3_capabilities() {
+    let path = common::TestPath::new();
+    let source = common::setup_source(&path, &first_commit());
+
+    let file = path.join(&"repo.bundle");
+    bundle::create_bundle(Some(&source), &file, &revisions(&["main"]), 3).unwrap();
+
+    let contents = fs::read(&file).unwrap();
+    assert!(contents.starts_with(b"# v3 git bundle\n@object-format=sha1\n"));
+    assert_eq!(Bundle::open(&file).unwrap().version, 3);
+}
+
+#[test]
+fn create_bundle_refuses_to_create_empty_bundle() {
+    let path = common::TestPath::new();
+    let first = first_commit();
+    let source = common::setup_source(&path, &first);
+
+    let file = path.join(&"repo.bundle");
+    let got = bundle::create_bundle(
+        Some(&source),
+        &file,
+        &revisions(&[&first.commit_hash.full_hash()]),
+        2,
+    )
+    .unwrap_err();
+
+    assert_eq!(got.to_string(), "Refusing to create empty bundle");
+    assert!(!file.exists());
+}
+
+#[test]
+fn create_bundle_leaves_other_lock_files_and_cleans_up_its_own() {
+    let path = common::TestPath::new();
+    let source = common::setup_source(&path, &first_commit());
+
+    // Another writer's lock is left as it is.
+    let file = path.join(&"repo.bundle");
+    let lock = path.join(&"repo.bundle.lock");
+    fs::write(&lock, b"theirs").unwrap();
+    let got = bundle::create_bundle(Some(&source), &file, &revisions(&["main"]), 2);
+    assert!(got.unwrap_err().to_string().contains("exists"));
+    assert_eq!(fs::read(&lock).unwrap(), b"theirs");
+
+    // A directory in the way makes the rename fail once the lock is written.
+    let file = path.join(&"taken");
+    fs::create_dir_all(file.join("inside")).unwrap();
+    let got = bundle::create_bundle(Some(&source), &file, &revisions(&["main"]), 2);
+    assert!(got.is_err());
+    assert!(!path.join(&"taken.lock").exists());
+}
+
+#[test]
+fn incremental_bundle_requires_its_base() {
+    let path = common::TestPath::new();
+    let first = first_commit();
+    let source = common::setup_source(&path, &first);
+    let full = path.join(&"full.bundle");
+    bundle::create_bundle(Some(&source), &full, &revisions(&["main"]), 2).unwrap();
+
+    let second = add_second_commit(&source, &first);
+    let incremental = path.join(&"incremental.bundle");
+    let range = format!("{}..main", first.commit_hash.full_hash());
+    let objects =
+        bundle::create_bundle(Some(&source), &incremental, &revisions(&[&range]), 2).unwrap();
+
+    // The new commit, its tree and the new blob.
+    assert_eq!(objects, 3);
+    let incremental = Bundle::open(&incremental).unwrap();
+    assert_eq!(incremental.prerequisites, vec![first.commit_hash.clone()]);
+
+    let repo = path.join(&"repo");
+    init::create_directories(init::InitConfig::new("main", Some(&repo))).unwrap();
+    let got = incremental
+        .unbundle(Some(&repo), &mut NoProgress)
+        .unwrap_err();
+    assert!(got
+        .to_string()
+        .contains("Repository lacks these prerequisite commits"));
+
+    Bundle::open(&full)
+        .unwrap()
+        .unbundle(Some(&repo), &mut NoProgress)
+        .unwrap();
+    incremental.verify(Some(&repo)).unwrap();
+    incremental.unbundle(Some(&repo), &mut NoProgress).unwrap();
+    assert!(ObjectFile::exists(Some(&repo), &second.commit_hash));
+}
+
+#[test]
+fn verify_fails_if_pack_is_corrupt() {
+    let path = common::TestPath::new();
+    let source = common::setup_source(&path, &first_commit());
+
+    let file = path.join(&"repo.bundle");
+    bundle::create_bundle(Some(&source), &file, &revisions(&["main"]), 2).unwrap();
+    let mut contents = fs::read(&file).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    fs::write(&file, contents).unwrap();
+
+    let got = Bundle::open(&file)
+        .unwrap()
+        .verify(Some(&source))
+        .unwrap_err();
+    assert_eq!(
+        got.to_string(),
+        "Packfile checksum does not match its contents"
+    );
+}
+
+#[test]
+fn clone_and_fetch_from_bundle() {
+    let path = common::TestPath::new();
+    let first = first_commit();
+    let source = common::setup_source(&path, &first);
+
+    let file = path.join(&"repo.bundle");
+    bundle::create_bundle(Some(&source), &file, &revisions(&["--all"]), 2).unwrap();
+
+    let config = clone::CloneConfig::new(file.to_str().unwrap().to_string(), None);
+    let (head_ref, objects) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    // The directory is named after the bundle.
+    let repo = path.join(&"repo");
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 3);
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(
+        config.get("remote.origin.url"),
+        fs::canonicalize(&file).unwrap().to_str()
+    );
+
+    // A new bundle in the same place builds on what we have.
+    let second = add_second_commit(&source, &first);
+    let range = format!("{}..main", first.commit_hash.full_hash());
+    bundle::create_bundle(Some(&source), &file, &revisions(&[&range]), 2).unwrap();
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates.len(), 1);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(second.commit_hash)
+    );
+}
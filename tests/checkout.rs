@@ -3,6 +3,7 @@ use std::{
     path::PathBuf,
 };
 
+use not_git::progress::NoProgress;
 use not_git::{checkout, init, update_refs};
 
 mod common;
@@ -22,7 +23,7 @@ fn checkout_creates_files_in_new_branch() {
     update_refs::update_refs(path.to_optional_path(), update_refs_config).unwrap();
 
     let checkout_config = checkout::CheckoutConfig::new(branch_name.to_str().unwrap().to_string());
-    checkout::checkout_branch(path.to_optional_path(), &checkout_config).unwrap();
+    checkout::checkout_branch(path.to_optional_path(), &checkout_config, &mut NoProgress).unwrap();
 
     let mut entries: Vec<DirEntry> = path
         .0
@@ -0,0 +1,459 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use not_git::clone;
+use not_git::config::Config;
+use not_git::pkt_line;
+use not_git::progress::Progress;
+use not_git::sideband::RemoteError;
+
+mod common;
+use common::server::{self, TestResponse, TestServer};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+const RESULT: &str = "application/x-git-upload-pack-result";
+
+fn test_remote() -> common::TestRemoteRepository {
+    common::TestRemoteRepository::new(
+        &[
+            ("hello.txt", b"Hello, world!"),
+            ("other.txt", b"Other file"),
+        ],
+        None,
+        "Initial commit",
+    )
+}
+
+fn assert_cloned(path: &common::TestPath, remote: &common::TestRemoteRepository) {
+    let repo = path.join(&"repo");
+    for (name, contents) in &remote.files {
+        assert_eq!(&fs::read(repo.join(name)).unwrap(), contents);
+    }
+
+    let head = fs::read_to_string(repo.join("not-git/HEAD")).unwrap();
+    assert_eq!(head, "ref: refs/heads/main\n");
+
+    let main = fs::read_to_string(repo.join("not-git/refs/heads/main")).unwrap();
+    assert_eq!(main, remote.commit_hash.full_hash());
+}
+
+#[test]
+fn clone_uses_protocol_v2_when_supported() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let commit_hash = remote.commit_hash.clone();
+    let pack = remote.pack();
+    let commands = Arc::new(Mutex::new(vec![]));
+    let server_commands = commands.clone();
+
+    let server = TestServer::start(move |request| {
+        assert_eq!(request.header("Git-Protocol"), Some("version=2"));
+        let refs = [("refs/heads/main", &commit_hash)];
+
+        match request.method.as_str() {
+            "GET" => TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v2_advertisement(&["agent=git/2.45.0", "ls-refs=unborn", "fetch"]),
+            ),
+            _ => {
+                let body = String::from_utf8_lossy(&request.body).to_string();
+                if body.contains("command=ls-refs") {
+                    assert!(body.contains("ref-prefix refs/heads/"));
+                    server_commands.lock().unwrap().push("ls-refs");
+                    TestResponse::new(200, RESULT, server::v2_ls_refs_response(&refs))
+                } else {
+                    assert!(body.contains(&format!("want {}", commit_hash.full_hash())));
+                    server_commands.lock().unwrap().push("fetch");
+                    TestResponse::new(200, RESULT, server::v2_fetch_response(&pack))
+                }
+            }
+        }
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    let (head_ref, objects) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 4);
+    assert_eq!(*commands.lock().unwrap(), vec!["ls-refs", "fetch"]);
+    assert_cloned(&path, &remote);
+}
+
+#[test]
+fn clone_falls_back_to_protocol_v0() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let commit_hash = remote.commit_hash.clone();
+    let pack = remote.pack();
+
+    // A server that doesn't know about protocol v2 ignores the Git-Protocol header.
+    let server = TestServer::start(move |request| {
+        let refs = [("refs/heads/main", &commit_hash)];
+
+        match request.method.as_str() {
+            "GET" => {
+                assert_eq!(request.path, "/info/refs?service=git-upload-pack");
+                TestResponse::new(200, ADVERTISEMENT, server::v0_advertisement(&refs, &[]))
+            }
+            _ => {
+                assert_eq!(request.path, "/git-upload-pack");
+                assert!(request.header("Git-Protocol").is_none());
+                TestResponse::new(200, RESULT, server::v0_upload_pack_response(&pack))
+            }
+        }
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    let (head_ref, _) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_cloned(&path, &remote);
+}
+
+// Records each phase with the count it finished at.
+#[derive(Default)]
+struct RecordingProgress {
+    phases: Vec<(String, Option<usize>, usize)>,
+}
+
+impl Progress for RecordingProgress {
+    fn start(&mut self, phase: &str, total: Option<usize>) {
+        self.phases.push((phase.to_string(), total, 0));
+    }
+
+    fn update(&mut self, done: usize, _bytes: Option<u64>) {
+        self.phases.last_mut().unwrap().2 = done;
+    }
+
+    fn finish(&mut self) {}
+}
+
+#[test]
+fn clone_reports_progress() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let commit_hash = remote.commit_hash.clone();
+    let pack = remote.pack();
+
+    let server = TestServer::start(move |request| {
+        let refs = [("refs/heads/main", &commit_hash)];
+
+        match request.method.as_str() {
+            "GET" => TestResponse::new(200, ADVERTISEMENT, server::v0_advertisement(&refs, &[])),
+            _ => TestResponse::new(200, RESULT, server::v0_upload_pack_response(&pack)),
+        }
+    });
+
+    let mut progress = RecordingProgress::default();
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_progress(Box::new(&mut progress));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(
+        progress.phases,
+        vec![
+            ("Receiving objects".to_string(), None, 0),
+            ("Unpacking objects".to_string(), Some(4), 4),
+            ("Updating files".to_string(), None, 2),
+        ]
+    );
+}
+
+#[test]
+fn clone_fails_and_cleans_up_on_invalid_content_type() {
+    let path = common::TestPath::new();
+
+    let server = TestServer::start(|_| TestResponse::new(200, "text/plain", b"hello".to_vec()));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.is_err());
+    assert!(!path.join(&"repo").exists());
+    assert_eq!(fs::read_dir(&path.0).unwrap().count(), 0);
+}
+
+#[test]
+fn clone_demultiplexes_sideband_when_negotiated() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let commit_hash = remote.commit_hash.clone();
+    let pack = remote.pack();
+
+    let server = TestServer::start(move |request| {
+        let refs = [("refs/heads/main", &commit_hash)];
+
+        match request.method.as_str() {
+            "GET" => TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v0_advertisement(&refs, &["side-band", "side-band-64k", "agent=git/2"]),
+            ),
+            _ => {
+                let body = String::from_utf8_lossy(&request.body).to_string();
+                assert!(body.contains("side-band-64k agent=not-git/"));
+                TestResponse::new(200, RESULT, server::v0_sideband_upload_pack_response(&pack))
+            }
+        }
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_cloned(&path, &remote);
+}
+
+#[test]
+fn clone_surfaces_remote_errors() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+    let commit_hash = remote.commit_hash.clone();
+
+    let server = TestServer::start(move |request| {
+        let refs = [("refs/heads/main", &commit_hash)];
+
+        match request.method.as_str() {
+            "GET" => TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v0_advertisement(&refs, &["side-band-64k"]),
+            ),
+            _ => {
+                let mut body = pkt_line::encode_text("NAK");
+                body.extend(pkt_line::encode(b"\x03pack-objects died"));
+                TestResponse::new(200, RESULT, body)
+            }
+        }
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config).unwrap_err();
+
+    let remote_error = got
+        .chain()
+        .find_map(|e| e.downcast_ref::<RemoteError>())
+        .unwrap();
+    assert_eq!(
+        remote_error,
+        &RemoteError::Fatal("pack-objects died".to_string())
+    );
+    assert!(!path.join(&"repo").exists());
+}
+
+/// A server with `main`, a `feature` branch with different files and a `v1` tag on
+/// `feature`. Every fetch gets the objects of both commits and is recorded.
+fn start_branches_server(
+    fetch_requests: Arc<Mutex<Vec<String>>>,
+) -> (
+    TestServer,
+    common::TestRemoteRepository,
+    common::TestRemoteRepository,
+) {
+    let main = test_remote();
+    let feature =
+        common::TestRemoteRepository::new(&[("feature.txt", b"Feature")], None, "Feature");
+    let pack = common::create_pack(&[main.objects.clone(), feature.objects.clone()].concat());
+
+    let server = server::upload_pack_server(
+        vec![
+            ("refs/heads/main", main.commit_hash.clone()),
+            ("refs/heads/feature", feature.commit_hash.clone()),
+            ("refs/tags/v1", feature.commit_hash.clone()),
+        ],
+        pack,
+        fetch_requests,
+    );
+    (server, main, feature)
+}
+
+fn read_repo_file(path: &common::TestPath, name: &str) -> Option<String> {
+    fs::read_to_string(path.join(&"repo").join(name)).ok()
+}
+
+#[test]
+fn clone_fetches_every_branch_into_remote_tracking_refs() {
+    let path = common::TestPath::new();
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let (server, main, feature) = start_branches_server(fetch_requests.clone());
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert!(fetch_requests[0].contains(&format!("want {}", main.commit_hash.full_hash())));
+    assert!(fetch_requests[0].contains(&format!("want {}", feature.commit_hash.full_hash())));
+
+    assert_cloned(&path, &main);
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/remotes/origin/main"),
+        Some(main.commit_hash.full_hash())
+    );
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/remotes/origin/feature"),
+        Some(feature.commit_hash.full_hash())
+    );
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/tags/v1"),
+        Some(feature.commit_hash.full_hash())
+    );
+    assert_eq!(read_repo_file(&path, "not-git/refs/heads/feature"), None);
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/remotes/origin/HEAD"),
+        Some("ref: refs/remotes/origin/main\n".to_string())
+    );
+
+    let config = Config::load(Some(&path.join(&"repo"))).unwrap();
+    assert_eq!(config.get("remote.origin.url"), Some(server.url.as_str()));
+    assert_eq!(
+        config.get("remote.origin.fetch"),
+        Some("+refs/heads/*:refs/remotes/origin/*")
+    );
+    assert_eq!(config.get("branch.main.remote"), Some("origin"));
+    assert_eq!(config.get("branch.main.merge"), Some("refs/heads/main"));
+}
+
+#[test]
+fn clone_single_branch_starts_on_the_given_branch() {
+    let path = common::TestPath::new();
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let (server, main, feature) = start_branches_server(fetch_requests.clone());
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_branch("feature")
+        .with_single_branch(true);
+    let (head_ref, _) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/feature");
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert!(!fetch_requests[0].contains(&format!("want {}", main.commit_hash.full_hash())));
+
+    assert_eq!(
+        read_repo_file(&path, "not-git/HEAD"),
+        Some("ref: refs/heads/feature\n".to_string())
+    );
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/heads/feature"),
+        Some(feature.commit_hash.full_hash())
+    );
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/remotes/origin/main"),
+        None
+    );
+    assert_eq!(
+        read_repo_file(&path, "feature.txt"),
+        Some("Feature".to_string())
+    );
+    assert_eq!(read_repo_file(&path, "hello.txt"), None);
+
+    let config = Config::load(Some(&path.join(&"repo"))).unwrap();
+    assert_eq!(
+        config.get("remote.origin.fetch"),
+        Some("+refs/heads/feature:refs/remotes/origin/feature")
+    );
+    assert_eq!(
+        config.get("branch.feature.merge"),
+        Some("refs/heads/feature")
+    );
+}
+
+#[test]
+fn clone_of_a_tag_detaches_head() {
+    let path = common::TestPath::new();
+    let (server, _, feature) = start_branches_server(Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo")).with_branch("v1");
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(
+        read_repo_file(&path, "not-git/HEAD"),
+        Some(feature.commit_hash.full_hash())
+    );
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/tags/v1"),
+        Some(feature.commit_hash.full_hash())
+    );
+    assert_eq!(
+        read_repo_file(&path, "feature.txt"),
+        Some("Feature".to_string())
+    );
+}
+
+#[test]
+fn clone_no_checkout_leaves_work_tree_empty() {
+    let path = common::TestPath::new();
+    let (server, main, _) = start_branches_server(Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo")).with_no_checkout(true);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(
+        read_repo_file(&path, "not-git/refs/heads/main"),
+        Some(main.commit_hash.full_hash())
+    );
+    assert_eq!(read_repo_file(&path, "hello.txt"), None);
+}
+
+#[test]
+fn clone_errors_on_unknown_branch() {
+    let path = common::TestPath::new();
+    let (server, _, _) = start_branches_server(Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo")).with_branch("missing");
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.is_err());
+    assert!(!path.join(&"repo").exists());
+}
+
+#[test]
+fn clone_names_directory_after_the_url() {
+    let path = common::TestPath::new();
+    let (server, main, _) = start_branches_server(Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(format!("{}/project.git", server.url), None);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let repo = path.join(&"project");
+    assert_eq!(
+        fs::read_to_string(repo.join("not-git/refs/heads/main")).unwrap(),
+        main.commit_hash.full_hash()
+    );
+    assert_eq!(fs::read_dir(&path.0).unwrap().count(), 1);
+}
+
+#[test]
+fn clone_refuses_non_empty_destination() {
+    let path = common::TestPath::new();
+    let (server, _, _) = start_branches_server(Arc::new(Mutex::new(vec![])));
+
+    fs::create_dir_all(path.join(&"repo")).unwrap();
+    fs::write(path.join(&"repo").join("keep.txt"), b"keep").unwrap();
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.is_err());
+    assert_eq!(
+        fs::read_dir(path.join(&"repo")).unwrap().count(),
+        1,
+        "Destination was modified"
+    );
+    assert_eq!(fs::read_dir(&path.0).unwrap().count(), 1);
+}
+
+#[test]
+fn clone_into_empty_destination() {
+    let path = common::TestPath::new();
+    let (server, main, _) = start_branches_server(Arc::new(Mutex::new(vec![])));
+    fs::create_dir_all(path.join(&"repo")).unwrap();
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_cloned(&path, &main);
+}
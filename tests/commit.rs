@@ -3,7 +3,7 @@ use std::path::PathBuf;
 
 use not_git::objects::{ObjectFile, ObjectHash, ObjectType};
 use not_git::utils::get_head_ref;
-use not_git::{commit, commit_tree, init};
+use not_git::{add, commit, commit_tree, init};
 
 mod common;
 
@@ -121,9 +121,12 @@ fn create_commit_with_files(
     files: Vec<(&str, Vec<u8>)>,
     message: &str,
 ) -> String {
+    let mut file_names = vec![];
     for (file_name, contents) in files {
         fs::write(path.join(&file_name), contents).unwrap();
+        file_names.push(file_name.to_string());
     }
+    add::add(path.to_optional_path(), add::AddConfig::new(file_names)).unwrap();
 
     let commit_config = commit::CommitConfig::new(message.to_string());
     commit::commit(path.to_optional_path(), commit_config).unwrap();
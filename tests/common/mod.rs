@@ -1,14 +1,21 @@
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::thread;
 
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use hex::ToHex;
+use not_git::config::Config;
+use not_git::hash_object;
+use not_git::http_server::HttpServer;
 use not_git::objects::{ObjectHash, ObjectType, TreeObject};
-use not_git::packfile::{PackfileObject, PackfileObjectType};
+use not_git::packfile::PackfileObjectType;
+use not_git::{init, refs};
 use sha1::{Digest, Sha1};
 
+pub mod server;
+
 // TODO: Figure out why some functions are marked as not being used.
 
 pub struct TestPath(pub PathBuf);
@@ -472,14 +479,190 @@ pub fn encode_pack_object(data: Vec<TestPackObject>) -> Vec<u8> {
     header
 }
 
+/// Hash the contents the way git does, header included, without writing anything.
+#[allow(dead_code)]
+pub fn hash_contents(object_type: &ObjectType, contents: &[u8]) -> ObjectHash {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{} {}\0", object_type.as_str(), contents.len()));
+    hasher.update(contents);
+    let hash: String = hasher.finalize().encode_hex();
+    ObjectHash::new(&hash).unwrap()
+}
+
+/// Create a complete, undeltified packfile with a correct object header and trailing checksum.
 #[allow(dead_code)]
-pub fn packfile_file_object() -> PackfileObject {
-    PackfileObject {
-        object_type: PackfileObjectType::Blob(10),
-        size: 10,
-        data: b"Hello, world!".to_vec(),
-        position: 20,
-        file_hash: ObjectHash::new("0123456789abcdef0123456789abcdef01234567").unwrap(),
-        file_type: ObjectType::Blob,
+pub fn create_pack(objects: &[(ObjectType, Vec<u8>)]) -> Vec<u8> {
+    let mut pack = vec![];
+    pack.extend(b"PACK");
+    pack.extend(&[0, 0, 0, 2]);
+    pack.extend(&(objects.len() as u32).to_be_bytes());
+
+    for (object_type, contents) in objects {
+        let type_bits: usize = match object_type {
+            ObjectType::Commit => 1,
+            ObjectType::Tree => 2,
+            ObjectType::Tag => 4,
+            _ => 3,
+        };
+
+        // The first byte holds the type and the lowest 4 bits of the size, then the rest
+        // of the size follows in groups of 7 bits.
+        let size = contents.len();
+        let mut byte = ((type_bits << 4) | (size & 0b1111)) as u8;
+        let mut remaining = size >> 4;
+        while remaining > 0 {
+            pack.push(byte | MSB_CONTINUE_FLAG);
+            byte = (remaining & VARINT_MASK as usize) as u8;
+            remaining >>= 7;
+        }
+        pack.push(byte);
+
+        pack.extend(encode_to_zlib(contents));
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(&pack);
+    pack.extend(hasher.finalize());
+    pack
+}
+
+/// A small repository that only exists as a packfile, to be served by a test server.
+#[allow(dead_code)]
+pub struct TestRemoteRepository {
+    pub commit_hash: ObjectHash,
+    pub tree_hash: ObjectHash,
+    pub files: Vec<(String, Vec<u8>)>,
+    pub objects: Vec<(ObjectType, Vec<u8>)>,
+}
+
+impl TestRemoteRepository {
+    #[allow(dead_code)]
+    pub fn new(files: &[(&str, &[u8])], parent: Option<&ObjectHash>, message: &str) -> Self {
+        let parents: Vec<&ObjectHash> = parent.into_iter().collect();
+        Self::with_parents(files, &parents, message)
+    }
+
+    /// A commit with any number of parents, such as a merge.
+    #[allow(dead_code)]
+    pub fn with_parents(files: &[(&str, &[u8])], parents: &[&ObjectHash], message: &str) -> Self {
+        let mut objects = vec![];
+        let mut tree_contents = vec![];
+
+        let mut sorted_files = files.to_vec();
+        sorted_files.sort_by_key(|(name, _)| name.to_string());
+
+        for (name, contents) in &sorted_files {
+            let hash = hash_contents(&ObjectType::Blob, contents);
+            tree_contents.extend(format!("100644 {}\0", name).as_bytes());
+            tree_contents.extend(hex::decode(hash.full_hash()).unwrap());
+            objects.push((ObjectType::Blob, contents.to_vec()));
+        }
+
+        let tree_hash = hash_contents(&ObjectType::Tree, &tree_contents);
+        objects.push((ObjectType::Tree, tree_contents));
+
+        let mut commit_contents = vec![];
+        writeln!(&mut commit_contents, "tree {}", tree_hash.full_hash()).unwrap();
+        for parent in parents {
+            writeln!(&mut commit_contents, "parent {}", parent.full_hash()).unwrap();
+        }
+        writeln!(
+            &mut commit_contents,
+            "author Ben Horowitz <benyakir.horowitz@gmail.com>"
+        )
+        .unwrap();
+        writeln!(
+            &mut commit_contents,
+            "committer Ben Horowitz <benyakir.horowitz@gmail.com>"
+        )
+        .unwrap();
+        writeln!(&mut commit_contents, "{}", message).unwrap();
+
+        let commit_hash = hash_contents(&ObjectType::Commit, &commit_contents);
+        objects.insert(0, (ObjectType::Commit, commit_contents));
+
+        Self {
+            commit_hash,
+            tree_hash,
+            files: sorted_files
+                .iter()
+                .map(|(name, contents)| (name.to_string(), contents.to_vec()))
+                .collect(),
+            objects,
+        }
     }
+
+    #[allow(dead_code)]
+    pub fn pack(&self) -> Vec<u8> {
+        create_pack(&self.objects)
+    }
+
+    /// Write the objects into a local repository as though they had already been fetched.
+    #[allow(dead_code)]
+    pub fn write_to(&self, path: &PathBuf) {
+        for (object_type, contents) in &self.objects {
+            hash_object::hash_and_write_object(Some(path), object_type, contents).unwrap();
+        }
+    }
+}
+
+/// A remote with a single commit of a single file.
+#[allow(dead_code)]
+pub fn test_remote() -> TestRemoteRepository {
+    TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit")
+}
+
+/// A repository on disk with the commit on `main`, which HEAD points to.
+#[allow(dead_code)]
+pub fn setup_source(path: &TestPath, remote: &TestRemoteRepository) -> PathBuf {
+    let source = path.join(&"source");
+    init::create_directories(init::InitConfig::new("main", Some(&source))).unwrap();
+    remote.write_to(&source);
+    refs::write_ref(Some(&source), "refs/heads/main", &remote.commit_hash).unwrap();
+    source
+}
+
+/// A repository with one commit on main, to be served.
+#[allow(dead_code)]
+pub fn create_served_repo(path: &TestPath) -> (PathBuf, TestRemoteRepository) {
+    let served = path.join(&"served");
+    init::create_directories(init::InitConfig::new("main", Some(&served))).unwrap();
+    let commit = test_remote();
+    commit.write_to(&served);
+    refs::write_ref(Some(&served), "refs/heads/main", &commit.commit_hash).unwrap();
+
+    (served, commit)
+}
+
+/// Point `origin` at the url, fetching its branches into `refs/remotes/origin/` like a
+/// clone sets it up.
+#[allow(dead_code)]
+pub fn configure_origin(repo: &PathBuf, url: &str) {
+    let mut config = Config::load(Some(repo)).unwrap();
+    config.set("remote.origin.url", url).unwrap();
+    config
+        .set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")
+        .unwrap();
+    config.save().unwrap();
+}
+
+/// Create a repository on `main` whose `origin` is the url.
+#[allow(dead_code)]
+pub fn init_with_origin(repo: &PathBuf, url: &str) {
+    init::create_directories(init::InitConfig::new("main", Some(repo))).unwrap();
+    configure_origin(repo, url);
+}
+
+#[allow(dead_code)]
+pub fn read_ref(repo: &PathBuf, name: &str) -> Option<ObjectHash> {
+    refs::read_ref(Some(repo), name).unwrap()
+}
+
+/// Serve the directory on a free port for the rest of the test, and return its URL.
+#[allow(dead_code)]
+pub fn start_server(root: &Path) -> String {
+    let server = HttpServer::bind("127.0.0.1:0", root).unwrap();
+    let url = format!("http://{}", server.local_addr().unwrap());
+    thread::spawn(move || server.run());
+    url
 }
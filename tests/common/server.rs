@@ -0,0 +1,458 @@
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use not_git::objects::ObjectHash;
+use not_git::pkt_line::{self, PktLine};
+use not_git::protocol::ZERO_HASH;
+use not_git::{packfile, refs};
+
+/// A minimal HTTP/1.1 server that hands every request to the handler. It's just enough
+/// to stand in for a git server without needing a network connection.
+#[allow(dead_code)]
+pub struct TestServer {
+    pub url: String,
+    address: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct TestRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    #[allow(dead_code)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    #[allow(dead_code)]
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn not_found() -> Self {
+        Self::new(404, "text/plain", b"Not found".to_vec())
+    }
+}
+
+impl TestServer {
+    #[allow(dead_code)]
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thread_shutdown = shutdown.clone();
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if let Ok(stream) = stream {
+                    handle_connection(stream, &handler);
+                }
+            }
+        });
+
+        Self {
+            url: format!("http://{}", address),
+            address,
+            shutdown,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the listener up so that it notices it should stop.
+        let _ = TcpStream::connect(self.address);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn handle_connection<F>(stream: TcpStream, handler: &F)
+where
+    F: Fn(&TestRequest) -> TestResponse,
+{
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() || request_line.is_empty() {
+        return;
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let content_length = headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.parse::<usize>().unwrap())
+        .unwrap_or(0);
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+
+    let request = TestRequest {
+        method,
+        path,
+        headers,
+        body,
+    };
+    let response = handler(&request);
+
+    let mut stream = reader.into_inner();
+    let mut head = format!("HTTP/1.1 {} Test\r\n", response.status);
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&response.body);
+    let _ = stream.flush();
+}
+
+/// A protocol v0 ref advertisement as sent by `info/refs?service=git-upload-pack`.
+/// The first ref is advertised as HEAD.
+#[allow(dead_code)]
+pub fn v0_advertisement(refs: &[(&str, &ObjectHash)], capabilities: &[&str]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("# service=git-upload-pack");
+    body.extend(pkt_line::FLUSH_PKT);
+
+    let (head_name, head_hash) = refs[0];
+    let mut capabilities = capabilities.to_vec();
+    let symref = format!("symref=HEAD:{}", head_name);
+    capabilities.push(&symref);
+
+    body.extend(pkt_line::encode(
+        format!(
+            "{} HEAD\0{}\n",
+            head_hash.full_hash(),
+            capabilities.join(" ")
+        )
+        .as_bytes(),
+    ));
+    for (name, hash) in refs {
+        body.extend(pkt_line::encode_text(&format!(
+            "{} {}",
+            hash.full_hash(),
+            name
+        )));
+    }
+
+    body.extend(pkt_line::FLUSH_PKT);
+    body
+}
+
+#[allow(dead_code)]
+pub fn v2_advertisement(capabilities: &[&str]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("version 2");
+    for capability in capabilities {
+        body.extend(pkt_line::encode_text(capability));
+    }
+    body.extend(pkt_line::FLUSH_PKT);
+    body
+}
+
+/// The refs are sent as `ls-refs` output. The first ref is advertised as HEAD.
+#[allow(dead_code)]
+pub fn v2_ls_refs_response(refs: &[(&str, &ObjectHash)]) -> Vec<u8> {
+    let (head_name, head_hash) = refs[0];
+    let mut body = pkt_line::encode_text(&format!(
+        "{} HEAD symref-target:{}",
+        head_hash.full_hash(),
+        head_name
+    ));
+
+    for (name, hash) in refs {
+        body.extend(pkt_line::encode_text(&format!(
+            "{} {}",
+            hash.full_hash(),
+            name
+        )));
+    }
+
+    body.extend(pkt_line::FLUSH_PKT);
+    body
+}
+
+/// Split the pack over several side-band packets, preceded by a progress message.
+#[allow(dead_code)]
+pub fn sideband_packets(pack: &[u8]) -> Vec<u8> {
+    let mut body = pkt_line::encode(b"\x02Enumerating objects: done.\n");
+    for chunk in pack.chunks(16) {
+        let mut data = vec![1];
+        data.extend(chunk);
+        body.extend(pkt_line::encode(&data));
+    }
+    body.extend(pkt_line::FLUSH_PKT);
+    body
+}
+
+/// Wrap the pack in a v2 fetch response.
+#[allow(dead_code)]
+pub fn v2_fetch_response(pack: &[u8]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("packfile");
+    body.extend(sideband_packets(pack));
+    body
+}
+
+/// A v2 fetch response for a shallow request, with the shallow-info section before the pack.
+#[allow(dead_code)]
+pub fn v2_shallow_fetch_response(
+    shallow: &[&ObjectHash],
+    unshallow: &[&ObjectHash],
+    pack: &[u8],
+) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("shallow-info");
+    for hash in shallow {
+        body.extend(pkt_line::encode_text(&format!(
+            "shallow {}",
+            hash.full_hash()
+        )));
+    }
+    for hash in unshallow {
+        body.extend(pkt_line::encode_text(&format!(
+            "unshallow {}",
+            hash.full_hash()
+        )));
+    }
+    body.extend(pkt_line::DELIMITER_PKT);
+    body.extend(v2_fetch_response(pack));
+    body
+}
+
+#[allow(dead_code)]
+pub fn v0_upload_pack_response(pack: &[u8]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("NAK");
+    body.extend(pack);
+    body
+}
+
+#[allow(dead_code)]
+pub fn v0_sideband_upload_pack_response(pack: &[u8]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("NAK");
+    body.extend(sideband_packets(pack));
+    body
+}
+
+/// A protocol v0 advertisement as sent by `info/refs?service=git-receive-pack`. An empty
+/// repository advertises its capabilities on a placeholder ref.
+#[allow(dead_code)]
+pub fn receive_pack_advertisement(refs: &[(String, ObjectHash)], capabilities: &[&str]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("# service=git-receive-pack");
+    body.extend(pkt_line::FLUSH_PKT);
+
+    let capabilities = capabilities.join(" ");
+    match refs.split_first() {
+        Some(((name, hash), rest)) => {
+            body.extend(pkt_line::encode(
+                format!("{} {}\0{}\n", hash.full_hash(), name, capabilities).as_bytes(),
+            ));
+            for (name, hash) in rest {
+                body.extend(pkt_line::encode_text(&format!(
+                    "{} {}",
+                    hash.full_hash(),
+                    name
+                )));
+            }
+        }
+        None => body.extend(pkt_line::encode(
+            format!("{} capabilities^{{}}\0{}\n", ZERO_HASH, capabilities).as_bytes(),
+        )),
+    }
+
+    body.extend(pkt_line::FLUSH_PKT);
+    body
+}
+
+/// A ref update command sent by the client: the old hash, the new hash and the ref name.
+#[allow(dead_code)]
+pub type ReceivedCommand = (String, String, String);
+
+/// Split a receive-pack request into its commands, the capabilities after the first
+/// command and the packfile that follows them.
+#[allow(dead_code)]
+pub fn parse_receive_pack_request(body: &[u8]) -> (Vec<ReceivedCommand>, Vec<String>, Vec<u8>) {
+    let mut cursor = Cursor::new(body);
+    let mut commands = vec![];
+    let mut capabilities = vec![];
+
+    while let PktLine::Data(data) = pkt_line::read_pkt_line(&mut cursor).unwrap() {
+        let line = String::from_utf8(data).unwrap();
+        let line = line.trim_end_matches('\n');
+        let command = match line.split_once('\0') {
+            Some((command, caps)) => {
+                capabilities = caps.split(' ').map(|c| c.to_string()).collect();
+                command
+            }
+            None => line,
+        };
+
+        let parts: Vec<&str> = command.split(' ').collect();
+        commands.push((
+            parts[0].to_string(),
+            parts[1].to_string(),
+            parts[2].to_string(),
+        ));
+    }
+
+    let pack = body[cursor.position() as usize..].to_vec();
+    (commands, capabilities, pack)
+}
+
+/// A report-status response. Refs with a reason were rejected.
+#[allow(dead_code)]
+pub fn report_status(refs: &[(&str, Option<&str>)]) -> Vec<u8> {
+    let mut body = pkt_line::encode_text("unpack ok");
+    for (name, reason) in refs {
+        let line = match reason {
+            Some(reason) => format!("ng {} {}", name, reason),
+            None => format!("ok {}", name),
+        };
+        body.extend(pkt_line::encode_text(&line));
+    }
+    body.extend(pkt_line::FLUSH_PKT);
+    body
+}
+
+/// Stands in for `git-receive-pack` serving the repository at the path: it advertises the
+/// refs of the repository, unpacks the packfile it receives and updates the refs.
+#[allow(dead_code)]
+pub fn receive_pack_handler(
+    repo: PathBuf,
+    capabilities: &'static [&'static str],
+) -> impl Fn(&TestRequest) -> TestResponse + Send + Sync + 'static {
+    move |request| {
+        if request.method == "GET" {
+            assert_eq!(request.path, "/info/refs?service=git-receive-pack");
+            let refs = refs::list_refs(Some(&repo), "refs/").unwrap();
+            return TestResponse::new(
+                200,
+                "application/x-git-receive-pack-advertisement",
+                receive_pack_advertisement(&refs, capabilities),
+            );
+        }
+
+        let (commands, _, pack) = parse_receive_pack_request(&request.body);
+        if !pack.is_empty() {
+            packfile::unpack_objects(&repo, &pack).unwrap();
+        }
+
+        let mut results = vec![];
+        for (old, new, name) in &commands {
+            let current = refs::read_ref(Some(&repo), name)
+                .unwrap()
+                .map(|hash| hash.full_hash())
+                .unwrap_or(ZERO_HASH.to_string());
+
+            if current != *old {
+                results.push((name.as_str(), Some("stale info")));
+                continue;
+            }
+
+            if new == ZERO_HASH {
+                refs::delete_ref(Some(&repo), name).unwrap();
+            } else {
+                refs::write_ref(Some(&repo), name, &ObjectHash::new(new).unwrap()).unwrap();
+            }
+            results.push((name.as_str(), None));
+        }
+
+        TestResponse::new(
+            200,
+            "application/x-git-receive-pack-result",
+            report_status(&results),
+        )
+    }
+}
+
+/// A protocol v2 server with the refs, the first of which is HEAD, that sends the pack
+/// for every fetch request. The bodies of the fetch requests are recorded.
+#[allow(dead_code)]
+pub fn upload_pack_server(
+    refs: Vec<(&'static str, ObjectHash)>,
+    pack: Vec<u8>,
+    fetch_requests: Arc<Mutex<Vec<String>>>,
+) -> TestServer {
+    TestServer::start(move |request| {
+        if request.method == "GET" {
+            return TestResponse::new(
+                200,
+                "application/x-git-upload-pack-advertisement",
+                v2_advertisement(&["ls-refs", "fetch"]),
+            );
+        }
+
+        let body = String::from_utf8_lossy(&request.body).to_string();
+        if body.contains("command=ls-refs") {
+            let refs: Vec<(&str, &ObjectHash)> =
+                refs.iter().map(|(name, hash)| (*name, hash)).collect();
+            TestResponse::new(
+                200,
+                "application/x-git-upload-pack-result",
+                v2_ls_refs_response(&refs),
+            )
+        } else {
+            fetch_requests.lock().unwrap().push(body);
+            TestResponse::new(
+                200,
+                "application/x-git-upload-pack-result",
+                v2_fetch_response(&pack),
+            )
+        }
+    })
+}
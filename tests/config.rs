@@ -0,0 +1,62 @@
+use std::fs;
+
+use not_git::config::Config;
+
+mod common;
+
+#[test]
+fn config_reads_sections_subsections_and_multiple_values() {
+    let path = common::TestPath::new();
+    fs::create_dir_all(path.join(&"not-git")).unwrap();
+    fs::write(
+        path.join(&"not-git/config"),
+        "# A comment\n[core]\n\tbare = false\n[remote \"origin\"]\n\turl = https://example.com/repo.git\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n\tfetch = +refs/tags/*:refs/tags/*\n[branch.main]\n\tremote = origin\n",
+    )
+    .unwrap();
+
+    let config = Config::load(path.to_optional_path()).unwrap();
+
+    assert_eq!(config.get_bool("core.bare").unwrap(), Some(false));
+    assert_eq!(
+        config.get("remote.origin.url"),
+        Some("https://example.com/repo.git")
+    );
+    assert_eq!(config.get_all("remote.origin.fetch").len(), 2);
+    assert_eq!(
+        config.get("remote.origin.fetch"),
+        Some("+refs/tags/*:refs/tags/*")
+    );
+    assert_eq!(config.get("branch.main.remote"), Some("origin"));
+    assert_eq!(
+        config.get("REMOTE.origin.URL"),
+        config.get("remote.origin.url")
+    );
+    assert_eq!(config.get("remote.Origin.url"), None);
+}
+
+#[test]
+fn config_saves_and_reloads_changes() {
+    let path = common::TestPath::new();
+
+    let mut config = Config::load(path.to_optional_path()).unwrap();
+    config.set("remote.origin.url", "https://a.com").unwrap();
+    config
+        .add("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")
+        .unwrap();
+    config.set("branch.feature/x.remote", "origin").unwrap();
+    config.save().unwrap();
+
+    let mut config = Config::load(path.to_optional_path()).unwrap();
+    assert_eq!(config.get("remote.origin.url"), Some("https://a.com"));
+    assert_eq!(config.get("branch.feature/x.remote"), Some("origin"));
+    assert_eq!(config.subsections("remote"), vec!["origin"]);
+
+    assert!(config.rename_section("remote.origin", "remote.upstream"));
+    assert!(config.unset_all("branch.feature/x.remote").unwrap());
+    config.save().unwrap();
+
+    let config = Config::load(path.to_optional_path()).unwrap();
+    assert_eq!(config.get("remote.origin.url"), None);
+    assert_eq!(config.get("remote.upstream.url"), Some("https://a.com"));
+    assert_eq!(config.subsections("branch").len(), 0);
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use not_git::clone;
+
+mod common;
+use common::server::{self, TestResponse, TestServer};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+const RESULT: &str = "application/x-git-upload-pack-result";
+
+// alice:secret
+const BASIC_AUTH: &str = "Basic YWxpY2U6c2VjcmV0";
+
+/// A protocol v2 server that answers every request without the expected Authorization
+/// header with a 401. The Authorization headers it receives are recorded.
+fn start_auth_server(
+    remote: &common::TestRemoteRepository,
+    expected: &'static str,
+    authorizations: Arc<Mutex<Vec<Option<String>>>>,
+) -> TestServer {
+    let commit_hash = remote.commit_hash.clone();
+    let pack = remote.pack();
+
+    TestServer::start(move |request| {
+        let authorization = request.header("Authorization").map(String::from);
+        authorizations.lock().unwrap().push(authorization.clone());
+        if authorization.as_deref() != Some(expected) {
+            let mut response = TestResponse::new(401, "text/plain", b"Unauthorized".to_vec());
+            response.headers.push((
+                "WWW-Authenticate".to_string(),
+                "Basic realm=\"test\"".to_string(),
+            ));
+            return response;
+        }
+
+        if request.method == "GET" {
+            return TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v2_advertisement(&["ls-refs", "fetch"]),
+            );
+        }
+
+        let body = String::from_utf8_lossy(&request.body).to_string();
+        if body.contains("command=ls-refs") {
+            let refs = [("refs/heads/main", &commit_hash)];
+            TestResponse::new(200, RESULT, server::v2_ls_refs_response(&refs))
+        } else {
+            TestResponse::new(200, RESULT, server::v2_fetch_response(&pack))
+        }
+    })
+}
+
+/// A helper that logs the actions it's asked to perform and answers `get` with the output.
+fn logging_helper(path: &common::TestPath, output: &str) -> (String, PathBuf) {
+    fs::create_dir_all(&path.0).unwrap();
+    let log = fs::canonicalize(&path.0).unwrap().join("helper.log");
+    let helper = format!(
+        "!f() {{ cat > /dev/null; echo \"$1\" >> {}; if [ \"$1\" = get ]; then printf '{}'; fi; }}; f",
+        log.display(),
+        output
+    );
+    (helper, log)
+}
+
+fn url_with_credentials(url: &str, credentials: &str) -> String {
+    url.replacen("http://", &format!("http://{}@", credentials), 1)
+}
+
+#[test]
+fn clone_uses_credentials_in_url() {
+    let path = common::TestPath::new();
+    let authorizations = Arc::new(Mutex::new(vec![]));
+    let server = start_auth_server(&common::test_remote(), BASIC_AUTH, authorizations.clone());
+
+    let url = url_with_credentials(&server.url, "alice:secret");
+    let config = clone::CloneConfig::new(url, Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(fs::read(path.join(&"repo").join("a.txt")).unwrap(), b"a");
+    let authorizations = authorizations.lock().unwrap();
+    assert!(authorizations
+        .iter()
+        .all(|a| a.as_deref() == Some(BASIC_AUTH)));
+}
+
+#[test]
+fn clone_asks_credential_helper_after_401_and_stores() {
+    let path = common::TestPath::new();
+    let authorizations = Arc::new(Mutex::new(vec![]));
+    let server = start_auth_server(&common::test_remote(), BASIC_AUTH, authorizations.clone());
+    let (helper, log) = logging_helper(&path, "username=alice\\npassword=secret\\n");
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_config("credential.helper", &helper);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(fs::read_to_string(log).unwrap(), "get\nstore\n");
+    let authorizations = authorizations.lock().unwrap();
+    assert_eq!(authorizations[0], None);
+    assert!(authorizations[1..]
+        .iter()
+        .all(|a| a.as_deref() == Some(BASIC_AUTH)));
+}
+
+#[test]
+fn clone_tells_helper_to_erase_rejected_credentials() {
+    let path = common::TestPath::new();
+    let server = start_auth_server(
+        &common::test_remote(),
+        BASIC_AUTH,
+        Arc::new(Mutex::new(vec![])),
+    );
+    let (helper, log) = logging_helper(&path, "username=alice\\npassword=wrong\\n");
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_config("credential.helper", &helper);
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got
+        .unwrap_err()
+        .to_string()
+        .contains("Authentication failed"));
+    assert_eq!(fs::read_to_string(log).unwrap(), "get\nerase\n");
+    assert!(!path.join(&"repo").exists());
+}
+
+#[test]
+fn clone_uses_bearer_token_from_helper() {
+    let path = common::TestPath::new();
+    let authorizations = Arc::new(Mutex::new(vec![]));
+    let server = start_auth_server(
+        &common::test_remote(),
+        "Bearer token",
+        authorizations.clone(),
+    );
+    let (helper, _) = logging_helper(&path, "authtype=Bearer\\ncredential=token\\n");
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_config("credential.helper", &helper);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(
+        authorizations.lock().unwrap().last().unwrap().as_deref(),
+        Some("Bearer token")
+    );
+}
+
+#[test]
+fn clone_asks_askpass_for_missing_password() {
+    let path = common::TestPath::new();
+    let server = start_auth_server(
+        &common::test_remote(),
+        BASIC_AUTH,
+        Arc::new(Mutex::new(vec![])),
+    );
+
+    fs::create_dir_all(&path.0).unwrap();
+    let askpass = fs::canonicalize(&path.0).unwrap().join("askpass.sh");
+    let prompts = askpass.with_file_name("prompts.log");
+    fs::write(
+        &askpass,
+        format!(
+            "#!/bin/sh\necho \"$1\" >> {}\necho secret\n",
+            prompts.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // The username comes from the URL, so only the password is asked for.
+    let url = url_with_credentials(&server.url, "alice");
+    let config = clone::CloneConfig::new(url, Some("repo"))
+        .with_config("core.askPass", askpass.to_str().unwrap());
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let prompts = fs::read_to_string(prompts).unwrap();
+    assert_eq!(prompts.lines().count(), 1);
+    assert!(prompts.starts_with("Password for 'http://alice@127.0.0.1:"));
+}
+
+#[test]
+fn clone_refuses_newlines_in_url_credentials() {
+    let path = common::TestPath::new();
+    let server = start_auth_server(
+        &common::test_remote(),
+        BASIC_AUTH,
+        Arc::new(Mutex::new(vec![])),
+    );
+    let (helper, log) = logging_helper(&path, "username=alice\\npassword=secret\\n");
+
+    // Decoded, the username would add a `host=` line for another server.
+    let url = url_with_credentials(&server.url, "alice%0Ahost=example.com");
+    let config =
+        clone::CloneConfig::new(url, Some("repo")).with_config("credential.helper", &helper);
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.unwrap_err().to_string().contains("newline"));
+    assert!(!log.exists());
+    assert!(!path.join(&"repo").exists());
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use not_git::config::Config;
+use not_git::daemon::Daemon;
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::push::{self, PushConfig};
+use not_git::{clone, refs};
+
+mod common;
+
+/// Run a daemon for the directory on a free port for the rest of the test, and return its
+/// URL.
+fn start_daemon(root: &Path, export_all: bool) -> String {
+    let daemon = Daemon::bind("127.0.0.1:0", root, export_all).unwrap();
+    let url = format!("git://{}", daemon.local_addr().unwrap());
+    thread::spawn(move || daemon.run());
+    url
+}
+
+#[test]
+fn clone_and_fetch_over_git_protocol() {
+    let path = common::TestPath::new();
+    let (served, first) = common::create_served_repo(&path);
+    fs::write(served.join("not-git").join("git-daemon-export-ok"), "").unwrap();
+    let url = format!("{}/served", start_daemon(&path.0, false));
+
+    let config = clone::CloneConfig::new(url.clone(), Some("repo"));
+    let (head_ref, objects) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 3);
+    let repo = path.join(&"repo");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(config.get("remote.origin.url"), Some(url.as_str()));
+
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("c.txt", b"c")],
+        Some(&first.commit_hash),
+        "Second commit",
+    );
+    second.write_to(&served);
+    refs::write_ref(Some(&served), "refs/heads/main", &second.commit_hash).unwrap();
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+}
+
+#[test]
+fn clone_over_git_protocol_needs_exported_repository() {
+    let path = common::TestPath::new();
+    common::create_served_repo(&path);
+    let url = format!("{}/served", start_daemon(&path.0, false));
+
+    let config = clone::CloneConfig::new(url, Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config).unwrap_err();
+
+    assert!(format!("{:#}", got).contains("access denied or repository not exported: /served"));
+}
+
+#[test]
+fn push_over_git_protocol_is_not_served() {
+    let path = common::TestPath::new();
+    let (served, _) = common::create_served_repo(&path);
+    let url = format!("{}/served", start_daemon(&path.0, true));
+
+    let refspecs = vec!["main:refs/heads/dev".parse().unwrap()];
+    let config = PushConfig::new(&url, refspecs, false, vec![]);
+    let got = push::push(Some(&served), config).unwrap_err();
+
+    assert!(format!("{:#}", got).contains("service not enabled: 'git-receive-pack'"));
+    assert_eq!(
+        refs::read_ref(Some(&served), "refs/heads/dev").unwrap(),
+        None
+    );
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use not_git::clone;
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::objects::{ObjectFile, ObjectHash, ObjectType};
+use not_git::refs;
+use sha1::{Digest, Sha1};
+
+mod common;
+use common::server::{TestResponse, TestServer};
+
+type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// A plain file server in front of a bare repository. It answers every request with the
+/// file at the path, ignoring the query, and records the paths it was asked for.
+fn start_file_server(files: Files, requests: Arc<Mutex<Vec<String>>>) -> TestServer {
+    TestServer::start(move |request| {
+        let path = request.path.trim_start_matches('/');
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        requests.lock().unwrap().push(path.to_string());
+
+        match files.lock().unwrap().get(path) {
+            Some(contents) => TestResponse::new(200, "text/plain", contents.clone()),
+            None => TestResponse::not_found(),
+        }
+    })
+}
+
+fn set_main(files: &Files, commit_hash: &ObjectHash) {
+    let mut files = files.lock().unwrap();
+    files.insert("HEAD".to_string(), b"ref: refs/heads/main\n".to_vec());
+    files.insert(
+        "info/refs".to_string(),
+        format!("{}\trefs/heads/main\n", commit_hash.full_hash()).into_bytes(),
+    );
+}
+
+fn loose_object_path(hash: &ObjectHash) -> String {
+    let hash = hash.full_hash();
+    format!("objects/{}/{}", &hash[..2], &hash[2..])
+}
+
+fn add_loose_objects(files: &Files, objects: &[(ObjectType, Vec<u8>)]) {
+    let mut files = files.lock().unwrap();
+    for (object_type, contents) in objects {
+        let hash = common::hash_contents(object_type, contents);
+        let mut object = format!("{} {}\0", object_type.as_str(), contents.len()).into_bytes();
+        object.extend(contents);
+        files.insert(loose_object_path(&hash), common::encode_to_zlib(&object));
+    }
+}
+
+/// Store the objects in a packfile with a version 2 index, listed in `objects/info/packs`.
+fn add_pack(files: &Files, objects: &[(ObjectType, Vec<u8>)]) {
+    let pack = common::create_pack(objects);
+    let name = format!("pack-{}", hex::encode(&pack[pack.len() - 20..]));
+
+    let mut hashes: Vec<Vec<u8>> = objects
+        .iter()
+        .map(|(object_type, contents)| {
+            hex::decode(common::hash_contents(object_type, contents).full_hash()).unwrap()
+        })
+        .collect();
+    hashes.sort();
+
+    let mut index = vec![0xff, b't', b'O', b'c'];
+    index.extend(2u32.to_be_bytes());
+    for byte in 0..=255u8 {
+        let count = hashes.iter().filter(|hash| hash[0] <= byte).count() as u32;
+        index.extend(count.to_be_bytes());
+    }
+    for hash in &hashes {
+        index.extend(hash);
+    }
+    // We don't read the CRCs and offsets, so they're left empty.
+    index.extend(vec![0; hashes.len() * 8]);
+    index.extend(&pack[pack.len() - 20..]);
+    index.extend(Sha1::digest(&index));
+
+    let mut files = files.lock().unwrap();
+    files.insert(
+        "objects/info/packs".to_string(),
+        format!("P {}.pack\n\n", name).into_bytes(),
+    );
+    files.insert(format!("objects/pack/{}.pack", name), pack);
+    files.insert(format!("objects/pack/{}.idx", name), index);
+}
+
+fn read_repo_file(path: &common::TestPath, name: &str) -> Option<String> {
+    fs::read_to_string(path.join(&"repo").join(name)).ok()
+}
+
+#[test]
+fn clone_falls_back_to_dumb_protocol_with_loose_objects() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        None,
+        "Initial commit",
+    );
+
+    let files: Files = Arc::new(Mutex::new(HashMap::new()));
+    add_loose_objects(&files, &remote.objects);
+    set_main(&files, &remote.commit_hash);
+    let server = start_file_server(files, Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let repo = path.join(&"repo");
+    assert_eq!(read_repo_file(&path, "a.txt"), Some("a".to_string()));
+    assert_eq!(read_repo_file(&path, "b.txt"), Some("b".to_string()));
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(remote.commit_hash.clone())
+    );
+}
+
+#[test]
+fn clone_downloads_packs_listed_in_info_packs() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    // The first commit was packed, the second was made after and is still loose.
+    let files: Files = Arc::new(Mutex::new(HashMap::new()));
+    add_pack(&files, &first.objects);
+    add_loose_objects(&files, &second.objects);
+    set_main(&files, &second.commit_hash);
+    let server = start_file_server(files, Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let repo = path.join(&"repo");
+    assert_eq!(read_repo_file(&path, "b.txt"), Some("b".to_string()));
+    assert!(ObjectFile::exists(Some(&repo), &first.commit_hash));
+    assert!(ObjectFile::exists(Some(&repo), &first.tree_hash));
+}
+
+#[test]
+fn clone_fails_when_object_is_missing_from_dumb_server() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+
+    // Only the commit, without its tree or blob.
+    let files: Files = Arc::new(Mutex::new(HashMap::new()));
+    add_loose_objects(&files, &remote.objects[..1]);
+    set_main(&files, &remote.commit_hash);
+    let server = start_file_server(files, Arc::new(Mutex::new(vec![])));
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(format!("{:#}", got.unwrap_err()).contains(&remote.tree_hash.full_hash()));
+    assert!(!path.join(&"repo").exists());
+}
+
+#[test]
+fn fetch_from_dumb_server_stops_at_haves() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+
+    let files: Files = Arc::new(Mutex::new(HashMap::new()));
+    let requests = Arc::new(Mutex::new(vec![]));
+    add_loose_objects(&files, &first.objects);
+    set_main(&files, &first.commit_hash);
+    let server = start_file_server(files.clone(), requests.clone());
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+    add_loose_objects(&files, &second.objects);
+    set_main(&files, &second.commit_hash);
+    requests.lock().unwrap().clear();
+
+    let repo = path.join(&"repo");
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert_eq!(result.updates.len(), 1);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(second.commit_hash.clone())
+    );
+    assert!(ObjectFile::exists(Some(&repo), &second.tree_hash));
+
+    let requests = requests.lock().unwrap();
+    assert!(requests.contains(&loose_object_path(&second.commit_hash)));
+    assert!(!requests.contains(&loose_object_path(&first.commit_hash)));
+    // The blob is in both commits, so we already have it.
+    let unchanged = common::hash_contents(&ObjectType::Blob, b"a");
+    assert!(!requests.contains(&loose_object_path(&unchanged)));
+}
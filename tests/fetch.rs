@@ -0,0 +1,290 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use not_git::config::Config;
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::objects::ObjectFile;
+use not_git::refspec::Refspec;
+use not_git::{init, refs};
+
+mod common;
+use common::server::{self, TestResponse, TestServer};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+const RESULT: &str = "application/x-git-upload-pack-result";
+
+/// Create a repository with `origin` pointing at the url.
+fn setup_repo(path: &common::TestPath, url: &str) -> PathBuf {
+    let repo = path.join(&"repo");
+    common::init_with_origin(&repo, url);
+    repo
+}
+
+#[test]
+fn fetch_creates_remote_tracking_branches() {
+    let path = common::TestPath::new();
+    let main = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Main");
+    let dev = common::TestRemoteRepository::new(&[("b.txt", b"b")], None, "Dev");
+
+    let mut objects = main.objects.clone();
+    objects.extend(dev.objects.clone());
+    let server = server::upload_pack_server(
+        vec![
+            ("refs/heads/main", main.commit_hash.clone()),
+            ("refs/heads/dev", dev.commit_hash.clone()),
+        ],
+        common::create_pack(&objects),
+        Arc::new(Mutex::new(vec![])),
+    );
+    let repo = setup_repo(&path, &server.url);
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert_eq!(result.objects, 6);
+    assert_eq!(result.updates.len(), 2);
+    assert!(result
+        .updates
+        .iter()
+        .all(|update| update.status == RefUpdateStatus::New));
+
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/main"),
+        Some(main.commit_hash.clone())
+    );
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/dev"),
+        Some(dev.commit_hash.clone())
+    );
+    assert!(ObjectFile::exists(Some(&repo), &dev.tree_hash));
+}
+
+#[test]
+fn fetch_sends_haves_and_fast_forwards() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", second.commit_hash.clone())],
+        second.pack(),
+        fetch_requests.clone(),
+    );
+    let repo = setup_repo(&path, &server.url);
+    first.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/remotes/origin/main", &first.commit_hash).unwrap();
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert_eq!(fetch_requests.len(), 1);
+    assert!(fetch_requests[0].contains(&format!("want {}", second.commit_hash.full_hash())));
+    assert!(fetch_requests[0].contains(&format!("have {}", first.commit_hash.full_hash())));
+    assert!(fetch_requests[0].contains("done"));
+
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+    assert_eq!(
+        result.updates[0].old_hash.as_ref(),
+        Some(&first.commit_hash)
+    );
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/main"),
+        Some(second.commit_hash.clone())
+    );
+}
+
+#[test]
+fn fetch_skips_negotiation_when_up_to_date() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", remote.commit_hash.clone())],
+        remote.pack(),
+        fetch_requests.clone(),
+    );
+    let repo = setup_repo(&path, &server.url);
+    remote.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/remotes/origin/main", &remote.commit_hash).unwrap();
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert!(fetch_requests.lock().unwrap().is_empty());
+    assert_eq!(result.objects, 0);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::UpToDate);
+}
+
+#[test]
+fn fetch_rejects_non_fast_forward_without_force() {
+    let path = common::TestPath::new();
+    let local = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Local");
+    let rewritten = common::TestRemoteRepository::new(&[("a.txt", b"b")], None, "Rewritten");
+
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", rewritten.commit_hash.clone())],
+        rewritten.pack(),
+        Arc::new(Mutex::new(vec![])),
+    );
+    let repo = setup_repo(&path, &server.url);
+    let mut config = Config::load(Some(&repo)).unwrap();
+    config
+        .set("remote.origin.fetch", "refs/heads/*:refs/remotes/origin/*")
+        .unwrap();
+    config.save().unwrap();
+    local.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/remotes/origin/main", &local.commit_hash).unwrap();
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert_eq!(result.updates[0].status, RefUpdateStatus::Rejected);
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/main"),
+        Some(local.commit_hash.clone())
+    );
+
+    let refspecs = vec!["+refs/heads/main:refs/remotes/origin/main".parse().unwrap()];
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", refspecs, false)).unwrap();
+
+    assert_eq!(result.updates[0].status, RefUpdateStatus::Forced);
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/main"),
+        Some(rewritten.commit_hash.clone())
+    );
+}
+
+#[test]
+fn fetch_prune_deletes_stale_remote_tracking_branches() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", remote.commit_hash.clone())],
+        remote.pack(),
+        Arc::new(Mutex::new(vec![])),
+    );
+    let repo = setup_repo(&path, &server.url);
+    remote.write_to(&repo);
+    refs::write_ref(
+        Some(&repo),
+        "refs/remotes/origin/old/branch",
+        &remote.commit_hash,
+    )
+    .unwrap();
+
+    fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+    assert!(common::read_ref(&repo, "refs/remotes/origin/old/branch").is_some());
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], true)).unwrap();
+
+    let deleted = result
+        .updates
+        .iter()
+        .find(|update| update.status == RefUpdateStatus::Deleted)
+        .unwrap();
+    assert_eq!(deleted.destination, "refs/remotes/origin/old/branch");
+    assert!(common::read_ref(&repo, "refs/remotes/origin/old/branch").is_none());
+    assert!(!repo.join("not-git/refs/remotes/origin/old").exists());
+    assert!(common::read_ref(&repo, "refs/remotes/origin/main").is_some());
+}
+
+#[test]
+fn fetch_maps_command_line_refspec_through_configured_refspecs_over_v0() {
+    let path = common::TestPath::new();
+    let main = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Main");
+    let dev = common::TestRemoteRepository::new(&[("b.txt", b"b")], None, "Dev");
+
+    let main_hash = main.commit_hash.clone();
+    let dev_hash = dev.commit_hash.clone();
+    let pack = dev.pack();
+    let server = TestServer::start(move |request| {
+        if request.method == "GET" {
+            assert_eq!(request.header("Git-Protocol"), Some("version=2"));
+            let refs = [
+                ("refs/heads/main", &main_hash),
+                ("refs/heads/dev", &dev_hash),
+            ];
+            return TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v0_advertisement(&refs, &["side-band-64k"]),
+            );
+        }
+
+        let body = String::from_utf8_lossy(&request.body).to_string();
+        assert!(body.contains(&format!("want {}", dev_hash.full_hash())));
+        assert!(!body.contains(&format!("want {}", main_hash.full_hash())));
+        TestResponse::new(200, RESULT, server::v0_sideband_upload_pack_response(&pack))
+    });
+    let repo = setup_repo(&path, &server.url);
+
+    let refspecs = vec!["dev".parse().unwrap()];
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", refspecs, false)).unwrap();
+
+    assert_eq!(result.refs.len(), 1);
+    assert_eq!(result.updates.len(), 1);
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/dev"),
+        Some(dev.commit_hash.clone())
+    );
+    assert!(common::read_ref(&repo, "refs/remotes/origin/main").is_none());
+}
+
+#[test]
+fn fetch_refuses_to_update_the_current_branch() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"changed")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", second.commit_hash.clone())],
+        second.pack(),
+        Arc::new(Mutex::new(vec![])),
+    );
+    let repo = setup_repo(&path, &server.url);
+    first.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &first.commit_hash).unwrap();
+
+    let refspecs = vec!["main:main".parse().unwrap()];
+    let got = fetch::fetch(Some(&repo), FetchConfig::new("origin", refspecs, false));
+
+    assert!(got
+        .unwrap_err()
+        .to_string()
+        .contains("Refusing to fetch into current branch refs/heads/main"));
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(first.commit_hash.clone())
+    );
+}
+
+#[test]
+fn ref_prefixes_are_listed_once() {
+    let refspecs: Vec<Refspec> = ["refs/heads/main", "refs/tags/v1", "refs/heads/main"]
+        .iter()
+        .map(|refspec| refspec.parse().unwrap())
+        .collect();
+
+    assert_eq!(
+        fetch::ref_prefixes(&refspecs),
+        vec!["refs/heads/main", "refs/tags/v1"]
+    );
+}
+
+#[test]
+fn fetch_errors_on_unknown_remote() {
+    let path = common::TestPath::new();
+    let repo = path.join(&"repo");
+    init::create_directories(init::InitConfig::new("main", Some(&repo))).unwrap();
+
+    let got = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false));
+    assert!(got.is_err());
+}
@@ -9,12 +9,9 @@ fn hash_object_encodes_file_to_zlib_with_header() {
     let path = common::TestPath::new();
 
     let contents = b"hello world";
-    let got_hash = hash_object::hash_and_write_object(
-        path.to_optional_path(),
-        &ObjectType::Blob,
-        &mut contents.to_vec(),
-    )
-    .unwrap();
+    let got_hash =
+        hash_object::hash_and_write_object(path.to_optional_path(), &ObjectType::Blob, contents)
+            .unwrap();
 
     let file_path = path.join(&got_hash.path());
     assert!(file_path.exists());
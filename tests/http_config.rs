@@ -0,0 +1,155 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use not_git::clone;
+
+mod common;
+use common::server::{self, TestRequest, TestResponse, TestServer};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+const RESULT: &str = "application/x-git-upload-pack-result";
+
+/// A protocol v2 server for the remote. Each request is recorded and given to `before`
+/// first, which can answer in place of the server.
+fn start_server<F>(
+    remote: &common::TestRemoteRepository,
+    requests: Arc<Mutex<Vec<TestRequest>>>,
+    before: F,
+) -> TestServer
+where
+    F: Fn(&TestRequest, usize) -> Option<TestResponse> + Send + Sync + 'static,
+{
+    let commit_hash = remote.commit_hash.clone();
+    let pack = remote.pack();
+
+    TestServer::start(move |request| {
+        let count = {
+            let mut requests = requests.lock().unwrap();
+            requests.push(TestRequest {
+                method: request.method.clone(),
+                path: request.path.clone(),
+                headers: request.headers.clone(),
+                body: request.body.clone(),
+            });
+            requests.len()
+        };
+        if let Some(response) = before(request, count) {
+            return response;
+        }
+
+        if request.method == "GET" {
+            return TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v2_advertisement(&["ls-refs", "fetch"]),
+            );
+        }
+
+        let body = String::from_utf8_lossy(&request.body).to_string();
+        if body.contains("command=ls-refs") {
+            let refs = [("refs/heads/main", &commit_hash)];
+            TestResponse::new(200, RESULT, server::v2_ls_refs_response(&refs))
+        } else {
+            TestResponse::new(200, RESULT, server::v2_fetch_response(&pack))
+        }
+    })
+}
+
+fn unavailable() -> TestResponse {
+    let mut response = TestResponse::new(503, "text/plain", b"Try again".to_vec());
+    response
+        .headers
+        .push(("Retry-After".to_string(), "0".to_string()));
+    response
+}
+
+#[test]
+fn clone_sends_extra_headers() {
+    let path = common::TestPath::new();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(&common::test_remote(), requests.clone(), |_, _| None);
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_config("http.extraHeader", "X-Team: infra")
+        .with_config("http.extraHeader", "X-Trace: 1");
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    for request in requests.iter() {
+        assert_eq!(request.header("X-Team"), Some("infra"));
+        assert_eq!(request.header("X-Trace"), Some("1"));
+    }
+}
+
+#[test]
+fn clone_retries_transient_server_errors() {
+    let path = common::TestPath::new();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(&common::test_remote(), requests.clone(), |_, count| {
+        (count <= 2).then(unavailable)
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(fs::read(path.join(&"repo").join("a.txt")).unwrap(), b"a");
+    assert_eq!(requests.lock().unwrap().len(), 5);
+}
+
+#[test]
+fn clone_gives_up_after_max_retries() {
+    let path = common::TestPath::new();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(&common::test_remote(), requests.clone(), |_, _| {
+        Some(unavailable())
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_config("http.maxRetries", "2");
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.unwrap_err().to_string().contains("503"));
+    assert_eq!(requests.lock().unwrap().len(), 3);
+    assert!(!path.join(&"repo").exists());
+}
+
+#[test]
+fn clone_goes_through_http_proxy() {
+    let path = common::TestPath::new();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let proxy = start_server(&common::test_remote(), requests.clone(), |_, _| None);
+
+    // The host doesn't exist, so the clone only works through the proxy.
+    let config = clone::CloneConfig::new("http://git.example.invalid/repo".to_string(), None)
+        .with_config("http.proxy", &proxy.url);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(
+        requests[0].path,
+        "http://git.example.invalid/repo/info/refs?service=git-upload-pack"
+    );
+    assert_eq!(fs::read(path.join(&"repo").join("a.txt")).unwrap(), b"a");
+}
+
+#[test]
+fn clone_aborts_stalled_transfer_after_low_speed_time() {
+    let path = common::TestPath::new();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(&common::test_remote(), requests.clone(), |request, _| {
+        if request.method == "POST" {
+            std::thread::sleep(Duration::from_secs(2));
+        }
+        None
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_config("http.lowSpeedTime", "1")
+        .with_config("http.maxRetries", "0");
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.is_err());
+    assert!(!path.join(&"repo").exists());
+}
@@ -0,0 +1,406 @@
+use std::fs;
+
+use not_git::add::{self, AddChange, AddConfig};
+use not_git::index::Index;
+use not_git::objects::ObjectFile;
+use not_git::progress::NoProgress;
+use not_git::rm::{self, RmConfig};
+use not_git::{checkout, commit, history, init, merge, refs};
+
+mod common;
+
+fn setup_repo(path: &common::TestPath, files: &[(&str, &str)]) {
+    init::create_directories(init::InitConfig::new("main", path.to_optional_path())).unwrap();
+    for (name, contents) in files {
+        let file = path.join(name);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, contents).unwrap();
+    }
+}
+
+fn paths(index: &Index) -> Vec<&str> {
+    index
+        .entries
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect()
+}
+
+fn committed_paths(path: &common::TestPath) -> Vec<String> {
+    let base_path = path.to_optional_path();
+    let head = refs::read_ref(base_path, "HEAD").unwrap().unwrap();
+    let tree = history::read_commit(base_path, &head).unwrap().tree;
+    merge::flatten_tree(base_path, &tree)
+        .unwrap()
+        .into_keys()
+        .collect()
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+#[test]
+fn add_directory_stages_every_file_under_it() {
+    let path = common::TestPath::new();
+    setup_repo(
+        &path,
+        &[
+            ("a.txt", "a"),
+            ("src/lib.rs", "lib"),
+            ("src/bin/main.rs", "main"),
+        ],
+    );
+
+    let added = add::add(path.to_optional_path(), AddConfig::new(strings(&["src/"]))).unwrap();
+
+    assert_eq!(
+        added,
+        vec![
+            AddChange::Add("src/bin/main.rs".to_string()),
+            AddChange::Add("src/lib.rs".to_string())
+        ]
+    );
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["src/bin/main.rs", "src/lib.rs"]);
+
+    // The stat data is recorded, so the file doesn't look changed.
+    let metadata = fs::symlink_metadata(path.join(&"src/lib.rs")).unwrap();
+    assert!(index.get("src/lib.rs").unwrap().matches_stat(&metadata));
+
+    let data = fs::read(path.join(&"not-git/index")).unwrap();
+    assert_eq!(&data[..8], b"DIRC\0\0\0\x02");
+}
+
+#[test]
+fn commit_only_includes_staged_files() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("b.txt", "b")]);
+
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["a.txt"]))).unwrap();
+    commit::commit(
+        path.to_optional_path(),
+        commit::CommitConfig::new("First".to_string()),
+    )
+    .unwrap();
+
+    assert_eq!(committed_paths(&path), vec!["a.txt"]);
+}
+
+#[test]
+fn add_unstages_files_that_were_deleted() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("b.txt", "b")]);
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["."]))).unwrap();
+
+    fs::remove_file(path.join(&"b.txt")).unwrap();
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["b.txt"]))).unwrap();
+
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["a.txt"]);
+
+    let got = add::add(path.to_optional_path(), AddConfig::new(strings(&["c.txt"]))).unwrap_err();
+    assert_eq!(got.to_string(), "pathspec 'c.txt' did not match any files");
+}
+
+#[test]
+fn rm_cached_unstages_and_keeps_the_file() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("src/lib.rs", "lib")]);
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["."]))).unwrap();
+
+    let config = RmConfig::new(strings(&["a.txt"])).with_cached(true);
+    let removed = rm::rm(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(removed, vec!["a.txt"]);
+    assert!(path.join(&"a.txt").exists());
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["src/lib.rs"]);
+}
+
+#[test]
+fn rm_removes_directories_only_with_recursive() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("src/lib.rs", "lib")]);
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["."]))).unwrap();
+    commit::commit(
+        path.to_optional_path(),
+        commit::CommitConfig::new("First".to_string()),
+    )
+    .unwrap();
+
+    let got = rm::rm(path.to_optional_path(), RmConfig::new(strings(&["src"]))).unwrap_err();
+    assert_eq!(got.to_string(), "not removing 'src' recursively without -r");
+
+    let config = RmConfig::new(strings(&["src"])).with_recursive(true);
+    rm::rm(path.to_optional_path(), config).unwrap();
+
+    assert!(!path.join(&"src").exists());
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["a.txt"]);
+}
+
+#[test]
+fn rm_refuses_to_lose_uncommitted_changes_without_force() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("b.txt", "b")]);
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["."]))).unwrap();
+    commit::commit(
+        path.to_optional_path(),
+        commit::CommitConfig::new("First".to_string()),
+    )
+    .unwrap();
+
+    fs::write(path.join(&"a.txt"), "edited").unwrap();
+    let got = rm::rm(path.to_optional_path(), RmConfig::new(strings(&["a.txt"]))).unwrap_err();
+    assert_eq!(
+        got.to_string(),
+        "the following file has local modifications:\n    a.txt\n\
+         (use --cached to keep the file, or -f to force removal)"
+    );
+
+    fs::write(path.join(&"b.txt"), "staged").unwrap();
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["b.txt"]))).unwrap();
+    let got = rm::rm(path.to_optional_path(), RmConfig::new(strings(&["b.txt"]))).unwrap_err();
+    assert!(got
+        .to_string()
+        .starts_with("the following file has changes staged in the index:"));
+
+    // Nothing was removed.
+    assert_eq!(fs::read_to_string(path.join(&"a.txt")).unwrap(), "edited");
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["a.txt", "b.txt"]);
+
+    let config = RmConfig::new(strings(&["a.txt", "b.txt"])).with_force(true);
+    rm::rm(path.to_optional_path(), config).unwrap();
+    assert!(!path.join(&"a.txt").exists());
+    assert!(!path.join(&"b.txt").exists());
+}
+
+#[test]
+fn checkout_stages_the_files_it_writes() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("src/lib.rs", "lib")]);
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["."]))).unwrap();
+    commit::commit(
+        path.to_optional_path(),
+        commit::CommitConfig::new("First".to_string()),
+    )
+    .unwrap();
+
+    fs::remove_file(path.join(&"not-git/index")).unwrap();
+    let head = refs::read_ref(path.to_optional_path(), "HEAD")
+        .unwrap()
+        .unwrap();
+    checkout::checkout_commit(path.to_optional_path(), &head, &mut NoProgress).unwrap();
+
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["a.txt", "src/lib.rs"]);
+    let metadata = fs::symlink_metadata(path.join(&"a.txt")).unwrap();
+    assert!(index.get("a.txt").unwrap().matches_stat(&metadata));
+}
+
+#[test]
+fn write_tree_sorts_directories_like_git() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.b", "file"), ("a/x", "x")]);
+    add::add(path.to_optional_path(), AddConfig::new(strings(&["."]))).unwrap();
+
+    let index = Index::read(path.to_optional_path()).unwrap();
+    let tree = index.write_tree(path.to_optional_path()).unwrap();
+
+    // Git compares the directory as `a/`, which comes after `a.b`.
+    let entries = match ObjectFile::new(path.to_optional_path(), &tree).unwrap() {
+        ObjectFile::Tree(tree) => tree.contents,
+        ObjectFile::Other(_) => panic!("Expected a tree"),
+    };
+    let names: Vec<&str> = entries
+        .iter()
+        .map(|entry| entry.file_name.as_str())
+        .collect();
+    assert_eq!(names, vec!["a.b", "a"]);
+}
+
+#[test]
+fn writing_the_index_refuses_when_it_is_locked() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a")]);
+    let lock_path = path.join(&"not-git/index.lock");
+    fs::write(&lock_path, "").unwrap();
+
+    let got = add::add(path.to_optional_path(), config(&["a.txt"])).unwrap_err();
+    assert!(got.to_string().contains("index.lock exists"));
+    // The lock is someone else's, so it's left alone.
+    assert!(lock_path.exists());
+    assert!(!path.join(&"not-git/index").exists());
+
+    fs::remove_file(&lock_path).unwrap();
+    add::add(path.to_optional_path(), config(&["a.txt"])).unwrap();
+    assert!(path.join(&"not-git/index").exists());
+    assert!(!lock_path.exists());
+}
+
+fn config(pathspecs: &[&str]) -> AddConfig {
+    AddConfig::new(strings(pathspecs))
+}
+
+fn added(paths: &[&str]) -> Vec<AddChange> {
+    paths
+        .iter()
+        .map(|path| AddChange::Add(path.to_string()))
+        .collect()
+}
+
+#[test]
+fn add_glob_pathspecs_match_across_directories() {
+    let path = common::TestPath::new();
+    setup_repo(
+        &path,
+        &[
+            ("a.rs", "a"),
+            ("README.md", "readme"),
+            ("src/lib.rs", "lib"),
+            ("src/notes.txt", "notes"),
+        ],
+    );
+
+    let changes = add::add(path.to_optional_path(), config(&["*.rs"])).unwrap();
+    assert_eq!(changes, added(&["a.rs", "src/lib.rs"]));
+
+    let got = add::add(path.to_optional_path(), config(&["*.c"])).unwrap_err();
+    assert_eq!(got.to_string(), "pathspec '*.c' did not match any files");
+}
+
+#[test]
+fn add_leaves_out_ignored_files() {
+    let path = common::TestPath::new();
+    setup_repo(
+        &path,
+        &[
+            (".gitignore", "*.o\nbuild/\n"),
+            ("main.c", "main"),
+            ("main.o", "object"),
+            ("build/out.c", "out"),
+            ("lib/.gitignore", "!keep.o\n"),
+            ("lib/keep.o", "kept"),
+            ("lib/drop.o", "dropped"),
+            ("not-git/info/exclude", "*.c\n!main.c\n"),
+        ],
+    );
+
+    let changes = add::add(path.to_optional_path(), config(&["."])).unwrap();
+    assert_eq!(
+        changes,
+        added(&[".gitignore", "lib/.gitignore", "lib/keep.o", "main.c"])
+    );
+
+    let got = add::add(path.to_optional_path(), config(&["build/out.c"])).unwrap_err();
+    assert_eq!(
+        got.to_string(),
+        "The following paths are ignored by one of your .gitignore files:\nbuild/out.c"
+    );
+}
+
+#[test]
+fn add_update_only_stages_tracked_files() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("b.txt", "b")]);
+    add::add(path.to_optional_path(), config(&["."])).unwrap();
+
+    fs::write(path.join(&"a.txt"), "changed").unwrap();
+    fs::remove_file(path.join(&"b.txt")).unwrap();
+    fs::write(path.join(&"c.txt"), "c").unwrap();
+    let changes = add::add(path.to_optional_path(), config(&[]).with_update(true)).unwrap();
+
+    assert_eq!(
+        changes,
+        vec![
+            AddChange::Add("a.txt".to_string()),
+            AddChange::Remove("b.txt".to_string())
+        ]
+    );
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["a.txt"]);
+}
+
+#[test]
+fn add_all_stages_new_files_and_removals() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("src/b.txt", "b")]);
+    add::add(path.to_optional_path(), config(&["."])).unwrap();
+
+    fs::remove_file(path.join(&"src/b.txt")).unwrap();
+    fs::write(path.join(&"src/c.txt"), "c").unwrap();
+
+    // Without -A, only new and changed files are staged.
+    let changes = add::add(path.to_optional_path(), config(&["src"])).unwrap();
+    assert_eq!(changes, added(&["src/c.txt"]));
+
+    let changes = add::add(path.to_optional_path(), config(&["src"]).with_all(true)).unwrap();
+    assert_eq!(changes, vec![AddChange::Remove("src/b.txt".to_string())]);
+
+    // Unchanged files aren't staged again.
+    let changes = add::add(path.to_optional_path(), config(&[]).with_all(true)).unwrap();
+    assert_eq!(changes, vec![]);
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert_eq!(paths(&index), vec!["a.txt", "src/c.txt"]);
+}
+
+#[test]
+fn add_dry_run_lists_changes_without_staging_them() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a")]);
+    add::add(path.to_optional_path(), config(&["."])).unwrap();
+    let before = fs::read(path.join(&"not-git/index")).unwrap();
+
+    fs::remove_file(path.join(&"a.txt")).unwrap();
+    fs::write(path.join(&"b.txt"), "b").unwrap();
+    let dry_run = config(&["."]).with_all(true).with_dry_run(true);
+    let changes = add::add(path.to_optional_path(), dry_run).unwrap();
+
+    assert_eq!(
+        changes,
+        vec![
+            AddChange::Remove("a.txt".to_string()),
+            AddChange::Add("b.txt".to_string())
+        ]
+    );
+    assert_eq!(changes[0].to_string(), "remove 'a.txt'");
+    assert_eq!(changes[1].to_string(), "add 'b.txt'");
+    assert_eq!(fs::read(path.join(&"not-git/index")).unwrap(), before);
+    // The blob isn't written either.
+    assert!(!path
+        .join(&"not-git/objects/63/d8dbd40c23542e740659a7168a0ce3138ea748")
+        .exists());
+}
+
+#[test]
+fn intent_to_add_files_are_not_committed_until_added() {
+    let path = common::TestPath::new();
+    setup_repo(&path, &[("a.txt", "a"), ("new.txt", "new")]);
+    add::add(path.to_optional_path(), config(&["a.txt"])).unwrap();
+
+    let changes = add::add(
+        path.to_optional_path(),
+        config(&["new.txt"]).with_intent_to_add(true),
+    )
+    .unwrap();
+    assert_eq!(changes, added(&["new.txt"]));
+
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert!(index.get("new.txt").unwrap().is_intent_to_add());
+    let data = fs::read(path.join(&"not-git/index")).unwrap();
+    assert_eq!(&data[..8], b"DIRC\0\0\0\x03");
+
+    commit::commit(
+        path.to_optional_path(),
+        commit::CommitConfig::new("First".to_string()),
+    )
+    .unwrap();
+    assert_eq!(committed_paths(&path), vec!["a.txt"]);
+
+    let changes = add::add(path.to_optional_path(), config(&["new.txt"])).unwrap();
+    assert_eq!(changes, added(&["new.txt"]));
+    let index = Index::read(path.to_optional_path()).unwrap();
+    assert!(!index.get("new.txt").unwrap().is_intent_to_add());
+}
@@ -0,0 +1,90 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use not_git::config::Config;
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::objects::ObjectFile;
+use not_git::{clone, refs};
+
+mod common;
+
+#[test]
+fn clone_from_path_links_objects() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        None,
+        "Initial commit",
+    );
+    let source = common::setup_source(&path, &remote);
+
+    let url = source.to_str().unwrap().to_string();
+    let config = clone::CloneConfig::new(url, Some("repo"));
+    let (head_ref, objects) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let repo = path.join(&"repo");
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 4);
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(remote.commit_hash.clone())
+    );
+
+    // The objects are the same files as in the source.
+    let source_object = fs::metadata(source.join(remote.commit_hash.path())).unwrap();
+    let cloned_object = fs::metadata(repo.join(remote.commit_hash.path())).unwrap();
+    assert_eq!(source_object.ino(), cloned_object.ino());
+
+    // The source is recorded as an absolute path.
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(
+        config.get("remote.origin.url"),
+        fs::canonicalize(&source).unwrap().to_str()
+    );
+}
+
+#[test]
+fn fetch_from_file_url_sends_only_new_objects() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let source = common::setup_source(&path, &first);
+
+    let url = format!("file://{}", fs::canonicalize(&source).unwrap().display());
+    let config = clone::CloneConfig::new(url.clone(), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+    second.write_to(&source);
+    refs::write_ref(Some(&source), "refs/heads/main", &second.commit_hash).unwrap();
+
+    let repo = path.join(&"repo");
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert_eq!(result.url, url);
+    // The new commit, its tree and the new blob, but not the blob the first commit has.
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates.len(), 1);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+    assert!(ObjectFile::exists(Some(&repo), &second.tree_hash));
+}
+
+#[test]
+fn clone_fails_for_path_that_is_not_a_repository() {
+    let path = common::TestPath::new();
+    let source = path.join(&"source");
+    fs::create_dir_all(&source).unwrap();
+
+    let url = source.to_str().unwrap().to_string();
+    let config = clone::CloneConfig::new(url, Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config).unwrap_err();
+
+    assert!(got
+        .to_string()
+        .contains("does not appear to be a not-git repository"));
+    assert!(!path.join(&"repo").exists());
+}
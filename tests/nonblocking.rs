@@ -0,0 +1,167 @@
+#![cfg(feature = "async")]
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use common::server;
+use not_git::clone::CloneConfig;
+use not_git::config::Config;
+use not_git::fetch::{FetchConfig, RefUpdateStatus};
+use not_git::nonblocking;
+use not_git::objects::ObjectFile;
+use not_git::progress::Progress;
+use not_git::push::{PushConfig, PushStatus};
+use not_git::refs;
+
+mod common;
+
+#[tokio::test]
+async fn clones_concurrently_then_fetches_on_a_task() {
+    let path = common::TestPath::new();
+    let (served, first) = common::create_served_repo(&path);
+    let url = format!("{}/served", common::start_server(&path.0));
+
+    // Both clones run on this test's single thread.
+    let (one, two) = tokio::join!(
+        nonblocking::clone(
+            path.to_optional_path(),
+            CloneConfig::new(url.clone(), Some("one"))
+        ),
+        nonblocking::clone(
+            path.to_optional_path(),
+            CloneConfig::new(url.clone(), Some("two"))
+        ),
+    );
+
+    let (head_ref, objects) = one.unwrap();
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 3);
+    assert_eq!(two.unwrap().1, 3);
+    let repo = path.join(&"one");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+    assert_eq!(
+        fs::read_to_string(path.join(&"two").join("a.txt")).unwrap(),
+        "a"
+    );
+
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("c.txt", b"c")],
+        Some(&first.commit_hash),
+        "Second commit",
+    );
+    second.write_to(&served);
+    refs::write_ref(Some(&served), "refs/heads/main", &second.commit_hash).unwrap();
+
+    let task_repo = repo.clone();
+    let result = tokio::spawn(async move {
+        nonblocking::fetch(Some(&task_repo), FetchConfig::new("origin", vec![], false)).await
+    })
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(second.commit_hash.clone())
+    );
+}
+
+#[tokio::test]
+async fn clone_over_protocol_v2() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", remote.commit_hash.clone())],
+        remote.pack(),
+        fetch_requests.clone(),
+    );
+
+    let config = CloneConfig::new(server.url.clone(), Some("repo"));
+    let (head_ref, objects) = nonblocking::clone(path.to_optional_path(), config)
+        .await
+        .unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 3);
+    assert_eq!(fetch_requests.lock().unwrap().len(), 1);
+    let repo = path.join(&"repo");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+}
+
+// Records the phases that were started.
+#[derive(Default)]
+struct RecordingProgress {
+    phases: Vec<String>,
+}
+
+impl Progress for RecordingProgress {
+    fn start(&mut self, phase: &str, _total: Option<usize>) {
+        self.phases.push(phase.to_string());
+    }
+
+    fn update(&mut self, _done: usize, _bytes: Option<u64>) {}
+
+    fn finish(&mut self) {}
+}
+
+#[tokio::test]
+async fn clone_reports_progress_from_blocking_work() {
+    let path = common::TestPath::new();
+    common::create_served_repo(&path);
+    let url = format!("{}/served", common::start_server(&path.0));
+
+    let mut progress = RecordingProgress::default();
+    let config = CloneConfig::new(url, Some("repo")).with_progress(Box::new(&mut progress));
+    nonblocking::clone(path.to_optional_path(), config)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        progress.phases,
+        vec!["Receiving objects", "Unpacking objects", "Updating files"]
+    );
+}
+
+#[tokio::test]
+async fn push_over_smart_http() {
+    let path = common::TestPath::new();
+    let (served, _) = common::create_served_repo(&path);
+    let mut config = Config::load(Some(&served)).unwrap();
+    config.set("http.receivepack", "true").unwrap();
+    config.save().unwrap();
+    let url = format!("{}/served", common::start_server(&path.0));
+
+    let repo = path.join(&"repo");
+    common::init_with_origin(&repo, &url);
+    let commit = common::TestRemoteRepository::new(&[("b.txt", b"b")], None, "Other commit");
+    commit.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &commit.commit_hash).unwrap();
+
+    let refspecs = vec!["main:refs/heads/dev".parse().unwrap()];
+    let config = PushConfig::new("origin", refspecs, false, vec![]);
+    let result = nonblocking::push(Some(&repo), config).await.unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::New);
+    assert_eq!(
+        refs::read_ref(Some(&served), "refs/heads/dev").unwrap(),
+        Some(commit.commit_hash.clone())
+    );
+    assert!(ObjectFile::exists(Some(&served), &commit.tree_hash));
+}
+
+#[tokio::test]
+async fn only_http_remotes_are_supported() {
+    let path = common::TestPath::new();
+    let (served, _) = common::create_served_repo(&path);
+
+    let config = CloneConfig::new(served.display().to_string(), Some("repo"));
+    let got = nonblocking::clone(path.to_optional_path(), config)
+        .await
+        .unwrap_err();
+
+    assert!(got.to_string().contains("Only HTTP(S) remotes"));
+    assert!(!path.join(&"repo").exists());
+}
@@ -1,7 +1,9 @@
+use std::fs;
 use std::io::Cursor;
 
-use not_git::objects::{ObjectFile, ObjectType};
+use not_git::objects::{ObjectFile, ObjectHash, ObjectType};
 use not_git::packfile;
+use sha1::{Digest, Sha1};
 
 mod common;
 use common::RenderContent;
@@ -50,43 +52,44 @@ fn decode_undeltified_data_errors_if_data_not_zlib_encoded() {
     assert!(got.is_err());
 }
 
-#[test]
-fn read_obj_offset_delta_creates_new_object_file() {
-    let path = common::TestPath::new();
+/// Pack the objects, each given as its type bits and its data as it appears after its
+/// type and length, and end the packfile with its checksum.
+fn create_delta_pack(objects: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut pack = vec![];
+    pack.extend(b"PACK");
+    pack.extend(&[0, 0, 0, 2]);
+    pack.extend(&(objects.len() as u32).to_be_bytes());
+
+    for (type_bits, data) in objects {
+        // The length is of the inflated data, which the parser doesn't rely on.
+        pack.extend(packfile::encode_type_and_length(*type_bits, 10));
+        pack.extend(data);
+    }
 
-    let packfile_object = common::packfile_file_object();
-    let packfile_objects = vec![packfile_object];
-    let packfile_objects = packfile_objects.as_slice();
+    let mut hasher = Sha1::new();
+    hasher.update(&pack);
+    pack.extend(hasher.finalize());
+    pack
+}
 
+fn delta_instructions() -> Vec<common::TestDeltaInstruction> {
     let copy_instruction = common::TestCopyInstruction { offset: 3, size: 5 };
     let insert_instruction = common::TestInsertInstruction {
         content: b"testcontent".to_vec(),
     };
 
-    let instructions = vec![
+    vec![
         common::TestDeltaInstruction::Copy(copy_instruction),
         common::TestDeltaInstruction::Insert(insert_instruction),
-    ];
-
-    let offset_instruction = common::OffsetDeltaData::new(instructions, 10);
-
-    let mut data = b"123456789012345678901234567890".to_vec();
-    data.extend(offset_instruction.render());
-
-    let mut cursor = Cursor::new(data.as_slice());
-    cursor.set_position(30);
-
-    let (content, hash, object_type) =
-        packfile::read_obj_offset_data(&path.0, packfile_objects, &mut cursor).unwrap();
+    ]
+}
 
-    let content = String::from_utf8(content).unwrap();
-    assert_eq!(content, "lo, wtestcontent");
-    assert_eq!(object_type, ObjectType::Blob);
-    let object_file = ObjectFile::new(path.to_optional_path(), &hash).unwrap();
+fn assert_blob(path: &common::TestPath, hash: &ObjectHash, expected: &[u8]) {
+    let object_file = ObjectFile::new(path.to_optional_path(), hash).unwrap();
     match object_file {
         ObjectFile::Other(contents) => {
-            assert_eq!(contents.contents, content.as_bytes());
-            assert_eq!(contents.size, content.len());
+            assert_eq!(contents.contents, expected);
+            assert_eq!(contents.size, expected.len());
             assert_eq!(contents.object_type, ObjectType::Blob);
         }
         _ => panic!("Expected ObjectFile::Other"),
@@ -94,46 +97,69 @@ fn read_obj_offset_delta_creates_new_object_file() {
 }
 
 #[test]
-fn read_obj_ref_delta_creates_new_object_file() {
+fn unpack_objects_applies_offset_delta_to_earlier_object() {
     let path = common::TestPath::new();
 
-    let packfile_object = common::packfile_file_object();
-    let hash = packfile_object.file_hash.clone();
+    let base = common::UndeltifiedData::new(b"Hello, world!".to_vec()).render();
+    // The delta's offset is back from its own start to the start of the base, which is
+    // right after the header.
+    let offset = packfile::encode_type_and_length(3, 10).len() + base.len();
+    let delta = common::OffsetDeltaData::new(delta_instructions(), offset).render();
+    let pack = create_delta_pack(&[(3, base), (6, delta)]);
 
-    let packfile_objects = vec![packfile_object];
-    let packfile_objects = packfile_objects.as_slice();
+    let objects = packfile::unpack_objects(&path.0, &pack).unwrap();
 
-    let copy_instruction = common::TestCopyInstruction { offset: 3, size: 5 };
-    let insert_instruction = common::TestInsertInstruction {
-        content: b"testcontent".to_vec(),
-    };
+    assert_eq!(objects.len(), 2);
+    assert_eq!(objects[1].file_type, ObjectType::Blob);
+    assert_blob(&path, &objects[0].file_hash, b"Hello, world!");
+    assert_blob(&path, &objects[1].file_hash, b"lo, wtestcontent");
+}
 
-    let instructions = vec![
-        common::TestDeltaInstruction::Copy(copy_instruction),
-        common::TestDeltaInstruction::Insert(insert_instruction),
-    ];
+#[test]
+fn unpack_objects_applies_ref_delta_to_later_object() {
+    let path = common::TestPath::new();
 
-    let offset_instruction = common::RefDeltaData::new(instructions, hash);
+    let base_hash = common::hash_contents(&ObjectType::Blob, b"Hello, world!");
+    let delta = common::RefDeltaData::new(delta_instructions(), base_hash.clone()).render();
+    let base = common::UndeltifiedData::new(b"Hello, world!".to_vec()).render();
+    let pack = create_delta_pack(&[(7, delta), (3, base)]);
 
-    let mut data = b"123456789012345678901234567890".to_vec();
-    data.extend(offset_instruction.render());
+    let objects = packfile::unpack_objects(&path.0, &pack).unwrap();
 
-    let mut cursor = Cursor::new(data.as_slice());
-    cursor.set_position(30);
+    assert_eq!(objects.len(), 2);
+    assert_eq!(objects[0].file_hash, base_hash);
+    assert_eq!(objects[1].position, packfile::PACKFILE_HEADER_SIZE);
+    assert_blob(&path, &objects[1].file_hash, b"lo, wtestcontent");
+}
 
-    let (content, hash, object_type) =
-        packfile::read_obj_ref_data(&path.0, packfile_objects, &mut cursor).unwrap();
+#[test]
+fn unpack_objects_errors_if_delta_base_is_missing() {
+    let path = common::TestPath::new();
 
-    let content = String::from_utf8(content).unwrap();
-    assert_eq!(content, "lo, wtestcontent");
-    assert_eq!(object_type, ObjectType::Blob);
-    let object_file = ObjectFile::new(path.to_optional_path(), &hash).unwrap();
-    match object_file {
-        ObjectFile::Other(contents) => {
-            assert_eq!(contents.contents, content.as_bytes());
-            assert_eq!(contents.size, content.len());
-            assert_eq!(contents.object_type, ObjectType::Blob);
-        }
-        _ => panic!("Expected ObjectFile::Other"),
-    };
+    let base_hash = common::hash_contents(&ObjectType::Blob, b"Hello, world!");
+    let delta = common::RefDeltaData::new(delta_instructions(), base_hash).render();
+    let pack = create_delta_pack(&[(7, delta)]);
+
+    let got = packfile::unpack_objects(&path.0, &pack).unwrap_err();
+
+    assert!(got.to_string().contains("Unable to find the base"));
+}
+
+#[test]
+fn unpack_objects_errors_if_checksum_does_not_match() {
+    let path = common::TestPath::new();
+
+    let mut pack = common::create_pack(&[(ObjectType::Blob, b"Hello, world!".to_vec())]);
+    let last = pack.len() - 1;
+    pack[last] ^= 1;
+
+    let got = packfile::unpack_objects(&path.0, &pack).unwrap_err();
+
+    assert!(got.to_string().contains("checksum"));
+    assert!(!ObjectFile::exists(
+        path.to_optional_path(),
+        &common::hash_contents(&ObjectType::Blob, b"Hello, world!")
+    ));
+    let spools = fs::read_dir(path.0.join("not-git/objects/pack")).unwrap();
+    assert_eq!(spools.count(), 0);
 }
@@ -0,0 +1,181 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use not_git::clone;
+use not_git::config::Config;
+use not_git::objects::{ObjectFile, ObjectType};
+use not_git::protocol::ObjectFilter;
+use not_git::{init, promisor};
+
+mod common;
+use common::server::{self, TestResponse, TestServer};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+const RESULT: &str = "application/x-git-upload-pack-result";
+
+fn test_remote() -> common::TestRemoteRepository {
+    common::TestRemoteRepository::new(
+        &[("asset.bin", b"large binary"), ("readme.txt", b"hello")],
+        None,
+        "Initial commit",
+    )
+}
+
+/// A protocol v2 server with `main` at the commit. Filtered fetches get the commit and
+/// its tree, and the rest are answered with the blobs. The fetch requests are recorded.
+fn start_server(
+    remote: &common::TestRemoteRepository,
+    capabilities: &'static [&'static str],
+    fetch_requests: Arc<Mutex<Vec<String>>>,
+) -> TestServer {
+    let commit_hash = remote.commit_hash.clone();
+    let (blobs, rest): (Vec<_>, Vec<_>) = remote
+        .objects
+        .iter()
+        .cloned()
+        .partition(|(object_type, _)| *object_type == ObjectType::Blob);
+    let filtered_pack = common::create_pack(&rest);
+    let blob_pack = common::create_pack(&blobs);
+    let full_pack = remote.pack();
+
+    TestServer::start(move |request| {
+        if request.method == "GET" {
+            return TestResponse::new(200, ADVERTISEMENT, server::v2_advertisement(capabilities));
+        }
+
+        let body = String::from_utf8_lossy(&request.body).to_string();
+        if body.contains("command=ls-refs") {
+            return TestResponse::new(
+                200,
+                RESULT,
+                server::v2_ls_refs_response(&[("refs/heads/main", &commit_hash)]),
+            );
+        }
+
+        let pack = if body.contains("filter blob:none") {
+            &filtered_pack
+        } else if body.contains(&format!("want {}", commit_hash.full_hash())) {
+            &full_pack
+        } else {
+            &blob_pack
+        };
+        fetch_requests.lock().unwrap().push(body);
+        TestResponse::new(200, RESULT, server::v2_fetch_response(pack))
+    })
+}
+
+#[test]
+fn object_filter_parses_filter_specs() {
+    assert_eq!(
+        "blob:none".parse::<ObjectFilter>().unwrap(),
+        ObjectFilter::NoBlobs
+    );
+    assert_eq!(
+        "blob:limit=1k".parse::<ObjectFilter>().unwrap(),
+        ObjectFilter::BlobLimit(1024)
+    );
+    assert_eq!(
+        "blob:limit=500".parse::<ObjectFilter>().unwrap(),
+        ObjectFilter::BlobLimit(500)
+    );
+    assert_eq!(
+        "tree:0".parse::<ObjectFilter>().unwrap(),
+        ObjectFilter::TreeDepth(0)
+    );
+    assert!("blob:limit=big".parse::<ObjectFilter>().is_err());
+    assert!("sparse:oid=abc".parse::<ObjectFilter>().is_err());
+
+    assert_eq!(ObjectFilter::BlobLimit(1024).to_string(), "blob:limit=1024");
+    assert_eq!(ObjectFilter::TreeDepth(0).to_string(), "tree:0");
+}
+
+#[test]
+fn clone_with_blob_filter_prefetches_blobs_in_one_request() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(
+        &remote,
+        &["ls-refs", "fetch=shallow filter"],
+        fetch_requests.clone(),
+    );
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_filter(ObjectFilter::NoBlobs);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert_eq!(fetch_requests.len(), 2);
+    assert!(fetch_requests[0].contains("filter blob:none"));
+    assert!(!fetch_requests[1].contains("filter"));
+    for (name, contents) in &remote.files {
+        let hash = common::hash_contents(&ObjectType::Blob, contents);
+        assert!(fetch_requests[1].contains(&format!("want {}", hash.full_hash())));
+        assert_eq!(&fs::read(path.join(&"repo").join(name)).unwrap(), contents);
+    }
+
+    let repo = path.join(&"repo");
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(config.get("remote.origin.promisor"), Some("true"));
+    assert_eq!(
+        config.get("remote.origin.partialclonefilter"),
+        Some("blob:none")
+    );
+    assert_eq!(
+        promisor::promisor_remote(Some(&repo)).unwrap(),
+        Some("origin".to_string())
+    );
+}
+
+#[test]
+fn reading_missing_object_fetches_it_from_promisor_remote() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(
+        &remote,
+        &["ls-refs", "fetch=filter"],
+        fetch_requests.clone(),
+    );
+
+    let repo = path.join(&"repo");
+    init::create_directories(init::InitConfig::new("main", Some(&repo))).unwrap();
+    let mut config = Config::load(Some(&repo)).unwrap();
+    config.set("remote.origin.url", &server.url).unwrap();
+    config.save().unwrap();
+
+    let blob_hash = common::hash_contents(&ObjectType::Blob, b"hello");
+    assert!(ObjectFile::new(Some(&repo), &blob_hash).is_err());
+    assert!(fetch_requests.lock().unwrap().is_empty());
+
+    promisor::configure_promisor(Some(&repo), "origin", &ObjectFilter::NoBlobs).unwrap();
+
+    match ObjectFile::new(Some(&repo), &blob_hash).unwrap() {
+        ObjectFile::Other(contents) => assert_eq!(contents.contents, b"hello"),
+        ObjectFile::Tree(_) => panic!("Expected a blob"),
+    }
+    assert_eq!(fetch_requests.lock().unwrap().len(), 1);
+    assert!(ObjectFile::exists(Some(&repo), &blob_hash));
+}
+
+#[test]
+fn clone_ignores_filter_when_server_does_not_support_it() {
+    let path = common::TestPath::new();
+    let remote = test_remote();
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = start_server(&remote, &["ls-refs", "fetch"], fetch_requests.clone());
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_filter(ObjectFilter::NoBlobs);
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert_eq!(fetch_requests.len(), 1);
+    assert!(!fetch_requests[0].contains("filter"));
+    for (name, contents) in &remote.files {
+        assert_eq!(&fs::read(path.join(&"repo").join(name)).unwrap(), contents);
+    }
+}
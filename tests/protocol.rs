@@ -0,0 +1,265 @@
+use not_git::objects::ObjectHash;
+use not_git::pkt_line;
+use not_git::protocol::{
+    self, Deepen, FetchOptions, ProtocolVersion, RefStatus, RefUpdateCommand, ReportStatus,
+    ShallowInfo,
+};
+use not_git::sideband::{RemoteError, Sideband};
+
+mod common;
+
+const HASH_1: &str = "0123456789abcdef0123456789abcdef01234567";
+const HASH_2: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+#[test]
+fn parse_advertisement_reads_v0_refs_and_capabilities() {
+    let hash_1 = ObjectHash::new(HASH_1).unwrap();
+    let hash_2 = ObjectHash::new(HASH_2).unwrap();
+
+    let mut data = common::server::v0_advertisement(
+        &[("refs/heads/main", &hash_1), ("refs/tags/v1", &hash_2)],
+        &["side-band-64k", "agent=git/2.45.0"],
+    );
+    // Append the peeled line for the annotated tag before the final flush.
+    data.truncate(data.len() - 4);
+    data.extend(pkt_line::encode_text(&format!(
+        "{} refs/tags/v1^{{}}",
+        HASH_1
+    )));
+    data.extend(pkt_line::FLUSH_PKT);
+
+    let advertisement = protocol::parse_advertisement(&data, "git-upload-pack").unwrap();
+
+    assert_eq!(advertisement.version, ProtocolVersion::V0);
+    assert!(advertisement.has_capability("side-band-64k"));
+    assert_eq!(advertisement.capability_value("agent"), Some("git/2.45.0"));
+
+    assert_eq!(advertisement.refs.len(), 2);
+    assert_eq!(advertisement.refs[0].branch, "refs/heads/main");
+    assert!(advertisement.refs[0].is_head);
+    assert_eq!(advertisement.refs[1].branch, "refs/tags/v1");
+    assert_eq!(
+        advertisement.refs[1].peeled.as_ref().unwrap().full_hash(),
+        HASH_1
+    );
+}
+
+#[test]
+fn parse_advertisement_reads_v2_capabilities() {
+    let data = common::server::v2_advertisement(&["ls-refs=unborn", "fetch=shallow filter"]);
+
+    let advertisement = protocol::parse_advertisement(&data, "git-upload-pack").unwrap();
+
+    assert_eq!(advertisement.version, ProtocolVersion::V2);
+    assert!(advertisement.refs.is_empty());
+    assert!(advertisement.has_capability("ls-refs"));
+    assert!(advertisement.has_command_feature("fetch", "filter"));
+    assert!(!advertisement.has_command_feature("fetch", "wait-for-done"));
+}
+
+#[test]
+fn parse_advertisement_errors_on_wrong_service() {
+    let mut data = pkt_line::encode_text("# service=git-receive-pack");
+    data.extend(pkt_line::FLUSH_PKT);
+
+    let got = protocol::parse_advertisement(&data, "git-upload-pack");
+    assert!(got.is_err());
+}
+
+#[test]
+fn parse_ls_refs_response_reads_symrefs_and_peeled_tags() {
+    let mut data = pkt_line::encode_text(&format!("{} HEAD symref-target:refs/heads/dev", HASH_1));
+    data.extend(pkt_line::encode_text(&format!(
+        "{} refs/heads/main",
+        HASH_1
+    )));
+    data.extend(pkt_line::encode_text(&format!("{} refs/heads/dev", HASH_1)));
+    data.extend(pkt_line::encode_text(&format!(
+        "{} refs/tags/v1 peeled:{}",
+        HASH_2, HASH_1
+    )));
+    data.extend(pkt_line::FLUSH_PKT);
+
+    let refs = protocol::parse_ls_refs_response(&data).unwrap();
+
+    assert_eq!(refs.len(), 3);
+    assert!(!refs[0].is_head);
+    assert!(refs[1].is_head);
+    assert_eq!(refs[2].peeled.as_ref().unwrap().full_hash(), HASH_1);
+}
+
+#[test]
+fn parse_fetch_response_errors_on_remote_error() {
+    let mut data = pkt_line::encode_text("packfile");
+    data.extend(pkt_line::encode(b"\x03upload-pack: not our ref"));
+    data.extend(pkt_line::FLUSH_PKT);
+
+    let got = protocol::parse_fetch_response(&data, &mut vec![]).unwrap_err();
+    assert_eq!(
+        got.downcast_ref::<RemoteError>(),
+        Some(&RemoteError::Fatal("upload-pack: not our ref".to_string()))
+    );
+}
+
+#[test]
+fn parse_upload_pack_response_strips_nak() {
+    let pack = common::create_pack(&[]);
+    let data = common::server::v0_upload_pack_response(&pack);
+
+    let got = protocol::parse_upload_pack_response(&data, Sideband::Disabled, &mut vec![]).unwrap();
+    assert_eq!(got.pack, Some(pack));
+}
+
+#[test]
+fn parse_upload_pack_response_demultiplexes_sideband() {
+    let pack = common::create_pack(&[]);
+    let data = common::server::v0_sideband_upload_pack_response(&pack);
+
+    let mut progress = vec![];
+    let got =
+        protocol::parse_upload_pack_response(&data, Sideband::Sideband64k, &mut progress).unwrap();
+
+    assert_eq!(got.pack, Some(pack));
+    assert_eq!(progress, b"remote: Enumerating objects: done.\n");
+}
+
+#[test]
+fn parse_upload_pack_response_reads_shallow_lines() {
+    let shallow = ObjectHash::new(&"a".repeat(40)).unwrap();
+    let unshallow = ObjectHash::new(&"b".repeat(40)).unwrap();
+    let pack = common::create_pack(&[]);
+
+    let mut data = pkt_line::encode_text(&format!("shallow {}", shallow.full_hash()));
+    data.extend(pkt_line::encode_text(&format!(
+        "unshallow {}",
+        unshallow.full_hash()
+    )));
+    data.extend(pkt_line::FLUSH_PKT);
+    data.extend(common::server::v0_upload_pack_response(&pack));
+
+    let got = protocol::parse_upload_pack_response(&data, Sideband::Disabled, &mut vec![]).unwrap();
+    assert_eq!(
+        got.shallow_info,
+        ShallowInfo {
+            shallow: vec![shallow],
+            unshallow: vec![unshallow],
+        }
+    );
+    assert_eq!(got.pack, Some(pack));
+}
+
+#[test]
+fn create_fetch_request_sends_shallow_commits_and_deepen() {
+    let advertisement = protocol::parse_advertisement(
+        &common::server::v2_advertisement(&["fetch=shallow"]),
+        "git-upload-pack",
+    )
+    .unwrap();
+    let want = ObjectHash::new(&"a".repeat(40)).unwrap();
+    let shallow = ObjectHash::new(&"b".repeat(40)).unwrap();
+
+    let request = FetchOptions::new(vec![shallow.clone()], Some(Deepen::Unshallow));
+    let got = protocol::create_fetch_request(&advertisement, &[&want], &[], &request, true);
+
+    let got = String::from_utf8(got).unwrap();
+    assert!(got.contains(&format!("shallow {}", shallow.full_hash())));
+    assert!(got.contains("deepen 2147483647"));
+
+    let request = FetchOptions::new(vec![], Some(Deepen::Since(1700000000)));
+    let got = protocol::create_upload_pack_request(&[&want], &[], &request, &[]);
+
+    let got = String::from_utf8(got).unwrap();
+    assert!(got.contains("deepen-since 1700000000"));
+    assert!(!got.contains("shallow "));
+}
+
+#[test]
+fn parse_upload_pack_response_errors_on_err_packet() {
+    let data = pkt_line::encode_text("ERR access denied");
+
+    let got = protocol::parse_upload_pack_response(&data, Sideband::Sideband64k, &mut vec![])
+        .unwrap_err();
+    assert_eq!(
+        got.downcast_ref::<RemoteError>(),
+        Some(&RemoteError::Err("access denied".to_string()))
+    );
+}
+
+#[test]
+fn upload_pack_capabilities_prefers_sideband_64k() {
+    let hash = ObjectHash::new(HASH_1).unwrap();
+    let data = common::server::v0_advertisement(
+        &[("refs/heads/main", &hash)],
+        &["side-band", "side-band-64k"],
+    );
+    let advertisement = protocol::parse_advertisement(&data, "git-upload-pack").unwrap();
+
+    assert_eq!(
+        protocol::upload_pack_capabilities(&advertisement),
+        vec!["side-band-64k"]
+    );
+}
+
+#[test]
+fn create_receive_pack_request_uses_zero_hash_for_missing_refs() {
+    let hash = ObjectHash::new(HASH_1).unwrap();
+    let commands = [
+        RefUpdateCommand {
+            old_hash: None,
+            new_hash: Some(hash.clone()),
+            name: "refs/heads/main".to_string(),
+        },
+        RefUpdateCommand {
+            old_hash: Some(hash),
+            new_hash: None,
+            name: "refs/heads/dev".to_string(),
+        },
+    ];
+
+    let request = protocol::create_receive_pack_request(
+        &commands,
+        &["report-status".to_string()],
+        Some(b"PACK"),
+    );
+    let (received, capabilities, pack) = common::server::parse_receive_pack_request(&request);
+
+    assert_eq!(
+        received[0],
+        (
+            protocol::ZERO_HASH.to_string(),
+            HASH_1.to_string(),
+            "refs/heads/main".to_string()
+        )
+    );
+    assert_eq!(received[1].1, protocol::ZERO_HASH);
+    assert_eq!(capabilities, vec!["report-status"]);
+    assert_eq!(pack, b"PACK");
+}
+
+#[test]
+fn parse_receive_pack_response_reads_ref_statuses() {
+    let mut data = pkt_line::encode_text("unpack index-pack failed");
+    data.extend(pkt_line::encode_text("ok refs/heads/main"));
+    data.extend(pkt_line::encode_text("ng refs/heads/dev non-fast-forward"));
+    data.extend(pkt_line::FLUSH_PKT);
+
+    let report =
+        protocol::parse_receive_pack_response(&data, Sideband::Disabled, &mut vec![]).unwrap();
+
+    assert_eq!(
+        report,
+        ReportStatus {
+            unpack_error: Some("index-pack failed".to_string()),
+            refs: vec![
+                RefStatus {
+                    name: "refs/heads/main".to_string(),
+                    error: None
+                },
+                RefStatus {
+                    name: "refs/heads/dev".to_string(),
+                    error: Some("non-fast-forward".to_string())
+                },
+            ],
+        }
+    );
+}
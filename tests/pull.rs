@@ -0,0 +1,392 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use not_git::add::{self, AddConfig};
+use not_git::checkout::{self, CheckoutConfig};
+use not_git::config::Config;
+use not_git::index::Index;
+use not_git::merge::MergeConflict;
+use not_git::objects::ObjectType;
+use not_git::progress::NoProgress;
+use not_git::pull::{self, PullConfig, PullOutcome};
+use not_git::{hash_object, history, init, refs};
+
+mod common;
+use common::server::{self, TestServer};
+
+/// A repository on `main` whose upstream is `main` on `origin`, with the commit checked out.
+fn setup_repo(
+    path: &common::TestPath,
+    url: &str,
+    commit: &common::TestRemoteRepository,
+) -> PathBuf {
+    let repo = path.join(&"repo");
+    common::init_with_origin(&repo, url);
+
+    let mut config = Config::load(Some(&repo)).unwrap();
+    config.set("branch.main.remote", "origin").unwrap();
+    config.set("branch.main.merge", "refs/heads/main").unwrap();
+    config.save().unwrap();
+
+    commit.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &commit.commit_hash).unwrap();
+    checkout_main(&repo);
+
+    repo
+}
+
+fn checkout_main(repo: &PathBuf) {
+    checkout::checkout_branch(
+        Some(repo),
+        &CheckoutConfig::new("main".to_string()),
+        &mut NoProgress,
+    )
+    .unwrap();
+}
+
+/// A server whose `main` is the commit and which sends the objects of that commit.
+fn start_server(main: &common::TestRemoteRepository) -> TestServer {
+    server::upload_pack_server(
+        vec![("refs/heads/main", main.commit_hash.clone())],
+        main.pack(),
+        Arc::new(Mutex::new(vec![])),
+    )
+}
+
+#[test]
+fn pull_fast_forwards_branch_and_work_tree() {
+    let path = common::TestPath::new();
+    let first =
+        common::TestRemoteRepository::new(&[("a.txt", b"a"), ("old.txt", b"old")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"changed"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    let server = start_server(&second);
+    let repo = setup_repo(&path, &server.url, &first);
+
+    let result = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap();
+
+    assert_eq!(
+        result.outcome,
+        PullOutcome::FastForward {
+            from: Some(first.commit_hash.clone()),
+            to: second.commit_hash.clone(),
+        }
+    );
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(second.commit_hash.clone())
+    );
+    assert_eq!(
+        common::read_ref(&repo, "refs/remotes/origin/main"),
+        Some(second.commit_hash.clone())
+    );
+    assert_eq!(fs::read(repo.join("a.txt")).unwrap(), b"changed");
+    assert_eq!(fs::read(repo.join("b.txt")).unwrap(), b"b");
+    assert!(!repo.join("old.txt").exists());
+}
+
+#[test]
+fn pull_is_up_to_date_when_remote_has_nothing_new() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+
+    let server = start_server(&first);
+    let repo = setup_repo(&path, &server.url, &first);
+
+    let result = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap();
+
+    assert_eq!(result.outcome, PullOutcome::UpToDate);
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(first.commit_hash.clone())
+    );
+}
+
+#[test]
+fn pull_ff_only_refuses_diverged_histories() {
+    let path = common::TestPath::new();
+    let base = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Base");
+    let ours =
+        common::TestRemoteRepository::new(&[("a.txt", b"ours")], Some(&base.commit_hash), "Ours");
+    let theirs = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&base.commit_hash),
+        "Theirs",
+    );
+
+    let server = start_server(&theirs);
+    let repo = setup_repo(&path, &server.url, &base);
+    ours.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &ours.commit_hash).unwrap();
+    checkout_main(&repo);
+
+    let got = pull::pull(Some(&repo), PullConfig::new(None, None, true));
+
+    assert!(got.is_err());
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(ours.commit_hash.clone())
+    );
+    assert!(!repo.join("b.txt").exists());
+}
+
+#[test]
+fn pull_merges_diverged_histories() {
+    let path = common::TestPath::new();
+    let base = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Base");
+    let ours =
+        common::TestRemoteRepository::new(&[("a.txt", b"ours")], Some(&base.commit_hash), "Ours");
+    let theirs = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&base.commit_hash),
+        "Theirs",
+    );
+
+    let server = start_server(&theirs);
+    let repo = setup_repo(&path, &server.url, &base);
+    ours.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &ours.commit_hash).unwrap();
+    checkout_main(&repo);
+
+    let result = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap();
+
+    let merge_commit = match result.outcome {
+        PullOutcome::Merged(hash) => hash,
+        outcome => panic!("Expected a merge, got {:?}", outcome),
+    };
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(merge_commit.clone())
+    );
+
+    let commit = history::read_commit(Some(&repo), &merge_commit).unwrap();
+    assert_eq!(
+        commit.parents,
+        vec![ours.commit_hash.clone(), theirs.commit_hash.clone()]
+    );
+    assert!(commit.message.contains("Merge branch 'main'"));
+    assert_eq!(fs::read(repo.join("a.txt")).unwrap(), b"ours");
+    assert_eq!(fs::read(repo.join("b.txt")).unwrap(), b"b");
+}
+
+#[test]
+fn pull_merges_from_the_nearest_common_ancestor() {
+    let path = common::TestPath::new();
+    let root = common::TestRemoteRepository::new(&[("a.txt", b"a"), ("x.txt", b"x")], None, "Root");
+    let base =
+        common::TestRemoteRepository::new(&[("a.txt", b"a")], Some(&root.commit_hash), "Base");
+    let ours = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("x.txt", b"x")],
+        Some(&base.commit_hash),
+        "Ours",
+    );
+    // Their merge reaches the root in fewer steps than the base, which it also contains.
+    let side = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("x.txt", b"x"), ("s.txt", b"s")],
+        Some(&root.commit_hash),
+        "Side",
+    );
+    let other = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("c.txt", b"c")],
+        Some(&base.commit_hash),
+        "Other",
+    );
+    let theirs = common::TestRemoteRepository::with_parents(
+        &[("a.txt", b"a"), ("c.txt", b"c"), ("s.txt", b"s")],
+        &[&side.commit_hash, &other.commit_hash],
+        "Merge",
+    );
+
+    let objects: Vec<_> = [&theirs, &side, &other]
+        .iter()
+        .flat_map(|commit| commit.objects.clone())
+        .collect();
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", theirs.commit_hash.clone())],
+        common::create_pack(&objects),
+        Arc::new(Mutex::new(vec![])),
+    );
+    let repo = setup_repo(&path, &server.url, &root);
+    base.write_to(&repo);
+    ours.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &ours.commit_hash).unwrap();
+    checkout_main(&repo);
+
+    let result = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap();
+
+    assert!(matches!(result.outcome, PullOutcome::Merged(_)));
+    // The file we added back since the base is kept.
+    assert_eq!(fs::read(repo.join("x.txt")).unwrap(), b"x");
+    assert_eq!(fs::read(repo.join("c.txt")).unwrap(), b"c");
+    assert_eq!(fs::read(repo.join("s.txt")).unwrap(), b"s");
+}
+
+#[test]
+fn pull_refuses_criss_cross_merges() {
+    let path = common::TestPath::new();
+    let base = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Base");
+    let left = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("l.txt", b"l")],
+        Some(&base.commit_hash),
+        "Left",
+    );
+    let right = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("r.txt", b"r")],
+        Some(&base.commit_hash),
+        "Right",
+    );
+    let files: &[(&str, &[u8])] = &[("a.txt", b"a"), ("l.txt", b"l"), ("r.txt", b"r")];
+    let ours = common::TestRemoteRepository::with_parents(
+        files,
+        &[&left.commit_hash, &right.commit_hash],
+        "Ours",
+    );
+    let theirs = common::TestRemoteRepository::with_parents(
+        files,
+        &[&right.commit_hash, &left.commit_hash],
+        "Theirs",
+    );
+
+    let server = start_server(&theirs);
+    let repo = setup_repo(&path, &server.url, &base);
+    for commit in [&left, &right, &ours] {
+        commit.write_to(&repo);
+    }
+    refs::write_ref(Some(&repo), "refs/heads/main", &ours.commit_hash).unwrap();
+    checkout_main(&repo);
+
+    let err = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap_err();
+
+    assert!(err.to_string().contains("merge bases"));
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(ours.commit_hash.clone())
+    );
+}
+
+#[test]
+fn pull_refuses_conflicting_changes() {
+    let path = common::TestPath::new();
+    let base = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Base");
+    let ours =
+        common::TestRemoteRepository::new(&[("a.txt", b"ours")], Some(&base.commit_hash), "Ours");
+    let theirs = common::TestRemoteRepository::new(
+        &[("a.txt", b"theirs")],
+        Some(&base.commit_hash),
+        "Theirs",
+    );
+
+    let server = start_server(&theirs);
+    let repo = setup_repo(&path, &server.url, &base);
+    ours.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &ours.commit_hash).unwrap();
+    checkout_main(&repo);
+
+    let err = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap_err();
+
+    assert_eq!(
+        err.downcast_ref::<MergeConflict>(),
+        Some(&MergeConflict {
+            paths: vec!["a.txt".to_string()]
+        })
+    );
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(ours.commit_hash.clone())
+    );
+}
+
+#[test]
+fn pull_refuses_to_overwrite_local_changes() {
+    let path = common::TestPath::new();
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"changed")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    let server = start_server(&second);
+    let repo = setup_repo(&path, &server.url, &first);
+    fs::write(repo.join("a.txt"), b"local edit").unwrap();
+
+    let got = pull::pull(Some(&repo), PullConfig::new(None, None, false));
+
+    assert!(got.is_err());
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(first.commit_hash.clone())
+    );
+    assert_eq!(fs::read(repo.join("a.txt")).unwrap(), b"local edit");
+}
+
+#[test]
+fn pull_keeps_local_changes_to_files_it_does_not_change() {
+    let path = common::TestPath::new();
+    let first =
+        common::TestRemoteRepository::new(&[("a.txt", b"a"), ("b.txt", b"b")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"changed")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    let server = start_server(&second);
+    let repo = setup_repo(&path, &server.url, &first);
+    fs::write(repo.join("a.txt"), b"local edit").unwrap();
+
+    pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap();
+
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(second.commit_hash.clone())
+    );
+    assert_eq!(fs::read(repo.join("a.txt")).unwrap(), b"local edit");
+    assert_eq!(fs::read(repo.join("b.txt")).unwrap(), b"changed");
+    let index = Index::read(Some(&repo)).unwrap();
+    assert_eq!(
+        index.get("b.txt").map(|entry| entry.hash.clone()),
+        Some(hash_object::hash_contents(&ObjectType::Blob, b"changed").unwrap())
+    );
+}
+
+#[test]
+fn pull_refuses_when_the_index_has_staged_changes() {
+    let path = common::TestPath::new();
+    let first =
+        common::TestRemoteRepository::new(&[("a.txt", b"a"), ("b.txt", b"b")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"changed")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+
+    let server = start_server(&second);
+    let repo = setup_repo(&path, &server.url, &first);
+    fs::write(repo.join("a.txt"), b"staged edit").unwrap();
+    add::add(Some(&repo), AddConfig::new(vec!["a.txt".to_string()])).unwrap();
+
+    let err = pull::pull(Some(&repo), PullConfig::new(None, None, false)).unwrap_err();
+
+    assert!(err.to_string().contains("a.txt"));
+    assert_eq!(
+        common::read_ref(&repo, "refs/heads/main"),
+        Some(first.commit_hash.clone())
+    );
+    assert_eq!(fs::read(repo.join("b.txt")).unwrap(), b"b");
+}
+
+#[test]
+fn pull_errors_without_upstream() {
+    let path = common::TestPath::new();
+    let repo = path.join(&"repo");
+    init::create_directories(init::InitConfig::new("main", Some(&repo))).unwrap();
+
+    let got = pull::pull(Some(&repo), PullConfig::new(None, None, false));
+    assert!(got.is_err());
+}
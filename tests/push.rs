@@ -0,0 +1,314 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use not_git::objects::ObjectFile;
+use not_git::push::{self, Lease, PushConfig, PushStatus};
+use not_git::{init, refs};
+
+mod common;
+use common::server::{self, TestResponse, TestServer};
+
+const CAPABILITIES: &[&str] = &["report-status", "delete-refs", "agent=git/2.45.0"];
+
+/// Create a local repository with `origin` pointing at the url and an empty remote repository.
+fn setup_repos(path: &common::TestPath) -> (PathBuf, PathBuf) {
+    let local = path.join(&"local");
+    let remote = path.join(&"remote");
+    init::create_directories(init::InitConfig::new("main", Some(&local))).unwrap();
+    init::create_directories(init::InitConfig::new("main", Some(&remote))).unwrap();
+
+    (local, remote)
+}
+
+fn commit_to(repo: &PathBuf, branch: &str, commit: &common::TestRemoteRepository) {
+    commit.write_to(repo);
+    refs::write_ref(Some(repo), branch, &commit.commit_hash).unwrap();
+}
+
+#[test]
+fn push_creates_branch_and_sends_objects() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let server = TestServer::start(server::receive_pack_handler(remote.clone(), CAPABILITIES));
+    common::configure_origin(&local, &server.url);
+
+    let commit = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        None,
+        "Initial commit",
+    );
+    commit_to(&local, "refs/heads/main", &commit);
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(result.objects, 4);
+    assert_eq!(result.updates.len(), 1);
+    assert_eq!(result.updates[0].status, PushStatus::New);
+    assert_eq!(
+        common::read_ref(&remote, "refs/heads/main"),
+        Some(commit.commit_hash.clone())
+    );
+    assert!(ObjectFile::exists(Some(&remote), &commit.tree_hash));
+    assert_eq!(
+        common::read_ref(&local, "refs/remotes/origin/main"),
+        Some(commit.commit_hash.clone())
+    );
+}
+
+#[test]
+fn push_without_refspecs_pushes_current_branch_and_only_missing_objects() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let server = TestServer::start(server::receive_pack_handler(remote.clone(), CAPABILITIES));
+    common::configure_origin(&local, &server.url);
+
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+    commit_to(&remote, "refs/heads/main", &first);
+    commit_to(&local, "refs/heads/main", &first);
+    commit_to(&local, "refs/heads/main", &second);
+
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", vec![], false, vec![]),
+    )
+    .unwrap();
+
+    // The new commit, its tree and the new blob.
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates[0].status, PushStatus::FastForward);
+    assert_eq!(
+        common::read_ref(&remote, "refs/heads/main"),
+        Some(second.commit_hash.clone())
+    );
+}
+
+#[test]
+fn push_rejects_non_fast_forward_unless_forced() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let server = TestServer::start(server::receive_pack_handler(remote.clone(), CAPABILITIES));
+    common::configure_origin(&local, &server.url);
+
+    let theirs = common::TestRemoteRepository::new(&[("a.txt", b"theirs")], None, "Theirs");
+    let ours = common::TestRemoteRepository::new(&[("a.txt", b"ours")], None, "Ours");
+    commit_to(&remote, "refs/heads/main", &theirs);
+    theirs.write_to(&local);
+    commit_to(&local, "refs/heads/main", &ours);
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(
+        result.updates[0].status,
+        PushStatus::Rejected("non-fast-forward".to_string())
+    );
+    assert_eq!(result.objects, 0);
+    assert_eq!(
+        common::read_ref(&remote, "refs/heads/main"),
+        Some(theirs.commit_hash.clone())
+    );
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, true, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::Forced);
+    assert_eq!(
+        common::read_ref(&remote, "refs/heads/main"),
+        Some(ours.commit_hash.clone())
+    );
+}
+
+#[test]
+fn push_rejects_missing_remote_commit_as_fetch_first() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let server = TestServer::start(server::receive_pack_handler(remote.clone(), CAPABILITIES));
+    common::configure_origin(&local, &server.url);
+
+    let theirs = common::TestRemoteRepository::new(&[("a.txt", b"theirs")], None, "Theirs");
+    let ours = common::TestRemoteRepository::new(&[("a.txt", b"ours")], None, "Ours");
+    commit_to(&remote, "refs/heads/main", &theirs);
+    commit_to(&local, "refs/heads/main", &ours);
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(
+        result.updates[0].status,
+        PushStatus::Rejected("fetch first".to_string())
+    );
+}
+
+#[test]
+fn push_does_not_retry_the_ref_update() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let posts = Arc::new(Mutex::new(0));
+    let handler = server::receive_pack_handler(remote.clone(), CAPABILITIES);
+    let server_posts = posts.clone();
+    let server = TestServer::start(move |request| {
+        if request.method == "GET" {
+            return handler(request);
+        }
+        *server_posts.lock().unwrap() += 1;
+        let mut response = TestResponse::new(503, "text/plain", b"Try again".to_vec());
+        response
+            .headers
+            .push(("Retry-After".to_string(), "0".to_string()));
+        response
+    });
+    common::configure_origin(&local, &server.url);
+    let commit = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    commit_to(&local, "refs/heads/main", &commit);
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let got = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    );
+
+    assert!(format!("{:#}", got.unwrap_err()).contains("503"));
+    assert_eq!(*posts.lock().unwrap(), 1);
+}
+
+#[test]
+fn push_force_with_lease_checks_remote_tracking_ref() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let server = TestServer::start(server::receive_pack_handler(remote.clone(), CAPABILITIES));
+    common::configure_origin(&local, &server.url);
+
+    let seen = common::TestRemoteRepository::new(&[("a.txt", b"seen")], None, "Seen");
+    let theirs = common::TestRemoteRepository::new(&[("a.txt", b"theirs")], None, "Theirs");
+    let ours = common::TestRemoteRepository::new(&[("a.txt", b"ours")], None, "Ours");
+    commit_to(&remote, "refs/heads/main", &theirs);
+    commit_to(&local, "refs/remotes/origin/main", &seen);
+    commit_to(&local, "refs/heads/main", &ours);
+
+    // Someone else pushed since we last fetched, so the lease is broken.
+    let refspecs = vec!["main".parse().unwrap()];
+    let leases = vec![Lease::All];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, leases),
+    )
+    .unwrap();
+
+    assert_eq!(
+        result.updates[0].status,
+        PushStatus::Rejected("stale info".to_string())
+    );
+    assert_eq!(
+        common::read_ref(&remote, "refs/heads/main"),
+        Some(theirs.commit_hash.clone())
+    );
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let leases = vec![Lease::Expect(
+        "refs/heads/main".to_string(),
+        theirs.commit_hash.clone(),
+    )];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, leases),
+    )
+    .unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::Forced);
+    assert_eq!(
+        common::read_ref(&remote, "refs/heads/main"),
+        Some(ours.commit_hash.clone())
+    );
+}
+
+#[test]
+fn push_deletes_branch() {
+    let path = common::TestPath::new();
+    let (local, remote) = setup_repos(&path);
+    let server = TestServer::start(server::receive_pack_handler(remote.clone(), CAPABILITIES));
+    common::configure_origin(&local, &server.url);
+
+    let commit = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    commit_to(&remote, "refs/heads/main", &commit);
+    commit_to(&remote, "refs/heads/dev", &commit);
+    commit_to(&local, "refs/remotes/origin/dev", &commit);
+
+    let refspecs = vec![":dev".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::Deleted);
+    assert_eq!(result.objects, 0);
+    assert_eq!(common::read_ref(&remote, "refs/heads/dev"), None);
+    assert!(common::read_ref(&remote, "refs/heads/main").is_some());
+    assert_eq!(common::read_ref(&local, "refs/remotes/origin/dev"), None);
+}
+
+#[test]
+fn push_reports_refs_rejected_by_remote() {
+    let path = common::TestPath::new();
+    let (local, _) = setup_repos(&path);
+
+    let server = TestServer::start(|request| match request.method.as_str() {
+        "GET" => TestResponse::new(
+            200,
+            "application/x-git-receive-pack-advertisement",
+            server::receive_pack_advertisement(&[], &["report-status", "side-band-64k"]),
+        ),
+        _ => {
+            let (commands, capabilities, _) = server::parse_receive_pack_request(&request.body);
+            assert_eq!(commands.len(), 1);
+            assert!(capabilities.contains(&"side-band-64k".to_string()));
+
+            let report = server::report_status(&[("refs/heads/main", Some("hook declined"))]);
+            TestResponse::new(
+                200,
+                "application/x-git-receive-pack-result",
+                server::sideband_packets(&report),
+            )
+        }
+    });
+    common::configure_origin(&local, &server.url);
+
+    let commit = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    commit_to(&local, "refs/heads/main", &commit);
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(
+        result.updates[0].status,
+        PushStatus::RemoteRejected("hook declined".to_string())
+    );
+    assert!(result.updates[0].is_error());
+    assert_eq!(common::read_ref(&local, "refs/remotes/origin/main"), None);
+}
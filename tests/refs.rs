@@ -0,0 +1,84 @@
+use std::fs;
+
+use not_git::objects::ObjectHash;
+use not_git::{init, refs};
+
+mod common;
+
+const HASH_1: &str = "0123456789abcdef0123456789abcdef01234567";
+const HASH_2: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+#[test]
+fn read_ref_follows_symbolic_refs_and_packed_refs() {
+    let path = common::TestPath::new();
+    init::create_directories(init::InitConfig::new("main", path.to_optional_path())).unwrap();
+
+    let packed = format!(
+        "# pack-refs with: peeled fully-peeled sorted\n{} refs/remotes/origin/main\n{} refs/tags/v1\n^{}\n",
+        HASH_1, HASH_2, HASH_1
+    );
+    fs::write(path.join(&"not-git/packed-refs"), packed).unwrap();
+    refs::write_symbolic_ref(
+        path.to_optional_path(),
+        "refs/remotes/origin/HEAD",
+        "refs/remotes/origin/main",
+    )
+    .unwrap();
+
+    let hash = refs::read_ref(path.to_optional_path(), "refs/remotes/origin/HEAD").unwrap();
+    assert_eq!(hash, Some(ObjectHash::new(HASH_1).unwrap()));
+    assert_eq!(
+        refs::read_symbolic_ref(path.to_optional_path(), "refs/remotes/origin/HEAD").unwrap(),
+        Some("refs/remotes/origin/main".to_string())
+    );
+    assert_eq!(
+        refs::read_ref(path.to_optional_path(), "refs/heads/main").unwrap(),
+        None
+    );
+
+    // Loose refs take precedence over packed refs.
+    let hash_2 = ObjectHash::new(HASH_2).unwrap();
+    refs::write_ref(path.to_optional_path(), "refs/remotes/origin/main", &hash_2).unwrap();
+    let listed = refs::list_refs(path.to_optional_path(), "refs/remotes/").unwrap();
+    assert_eq!(
+        listed,
+        vec![("refs/remotes/origin/main".to_string(), hash_2)]
+    );
+}
+
+#[test]
+fn delete_ref_removes_loose_and_packed_refs() {
+    let path = common::TestPath::new();
+    init::create_directories(init::InitConfig::new("main", path.to_optional_path())).unwrap();
+
+    let packed = format!(
+        "# pack-refs with: peeled fully-peeled sorted\n{} refs/remotes/origin/a/b\n",
+        HASH_1
+    );
+    fs::write(path.join(&"not-git/packed-refs"), packed).unwrap();
+    let hash = ObjectHash::new(HASH_2).unwrap();
+    refs::write_ref(path.to_optional_path(), "refs/remotes/origin/a/b", &hash).unwrap();
+
+    assert!(refs::delete_ref(path.to_optional_path(), "refs/remotes/origin/a/b").unwrap());
+
+    assert_eq!(
+        refs::read_ref(path.to_optional_path(), "refs/remotes/origin/a/b").unwrap(),
+        None
+    );
+    assert!(!path.join(&"not-git/refs/remotes").exists());
+    assert!(path.join(&"not-git/refs/heads").exists());
+    assert!(!refs::delete_ref(path.to_optional_path(), "refs/remotes/origin/a/b").unwrap());
+}
+
+#[test]
+fn is_valid_ref_name_rejects_names_outside_refs() {
+    assert!(refs::is_valid_ref_name("refs/heads/main"));
+    assert!(refs::is_valid_ref_name("refs/tags/v1.0"));
+
+    assert!(!refs::is_valid_ref_name("HEAD"));
+    assert!(!refs::is_valid_ref_name("refs/heads/../../config"));
+    assert!(!refs::is_valid_ref_name("refs/heads/.hidden"));
+    assert!(!refs::is_valid_ref_name("refs/heads/main.lock"));
+    assert!(!refs::is_valid_ref_name("refs/heads/a b"));
+    assert!(!refs::is_valid_ref_name("refs/heads/"));
+}
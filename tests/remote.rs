@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+
+use not_git::config::Config;
+use not_git::objects::ObjectHash;
+use not_git::refspec::Refspec;
+use not_git::{init, refs, remote};
+
+mod common;
+
+fn setup_repo(path: &common::TestPath) -> PathBuf {
+    let repo = path.join(&"repo");
+    init::create_directories(init::InitConfig::new("main", Some(&repo))).unwrap();
+    repo
+}
+
+/// A repository cloned from origin: `main` tracks `origin/main` and origin has a HEAD.
+fn setup_cloned_repo(path: &common::TestPath) -> (PathBuf, ObjectHash) {
+    let repo = setup_repo(path);
+    remote::add_remote(Some(&repo), "origin", "https://example.com/repo.git", &[]).unwrap();
+
+    let mut config = Config::load(Some(&repo)).unwrap();
+    config.set("branch.main.remote", "origin").unwrap();
+    config.set("branch.main.merge", "refs/heads/main").unwrap();
+    config.save().unwrap();
+
+    let hash = ObjectHash::new("0123456789abcdef0123456789abcdef01234567").unwrap();
+    refs::write_ref(Some(&repo), "refs/remotes/origin/main", &hash).unwrap();
+    refs::write_ref(Some(&repo), "refs/remotes/origin/feature", &hash).unwrap();
+    refs::write_symbolic_ref(
+        Some(&repo),
+        "refs/remotes/origin/HEAD",
+        "refs/remotes/origin/main",
+    )
+    .unwrap();
+
+    (repo, hash)
+}
+
+#[test]
+fn add_remote_writes_url_and_default_refspec() {
+    let path = common::TestPath::new();
+    let repo = setup_repo(&path);
+
+    remote::add_remote(Some(&repo), "origin", "https://example.com/repo.git", &[]).unwrap();
+
+    let origin = remote::get_remote(Some(&repo), "origin").unwrap();
+    assert_eq!(origin.urls, vec!["https://example.com/repo.git"]);
+    assert_eq!(origin.fetch, vec![Refspec::default_fetch("origin")]);
+    assert_eq!(
+        origin.effective_push_urls(),
+        &["https://example.com/repo.git".to_string()]
+    );
+}
+
+#[test]
+fn add_remote_tracks_only_given_branches() {
+    let path = common::TestPath::new();
+    let repo = setup_repo(&path);
+
+    let branches = vec!["main".to_string(), "dev".to_string()];
+    remote::add_remote(
+        Some(&repo),
+        "upstream",
+        "https://example.com/up.git",
+        &branches,
+    )
+    .unwrap();
+
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(
+        config.get_all("remote.upstream.fetch"),
+        vec![
+            "+refs/heads/main:refs/remotes/upstream/main",
+            "+refs/heads/dev:refs/remotes/upstream/dev",
+        ]
+    );
+}
+
+#[test]
+fn add_remote_refuses_existing_and_invalid_names() {
+    let path = common::TestPath::new();
+    let repo = setup_repo(&path);
+
+    remote::add_remote(Some(&repo), "origin", "https://example.com/a.git", &[]).unwrap();
+    assert!(remote::add_remote(Some(&repo), "origin", "https://example.com/b.git", &[]).is_err());
+    assert!(remote::add_remote(Some(&repo), "bad name", "https://example.com/b.git", &[]).is_err());
+    assert!(remote::add_remote(Some(&repo), "", "https://example.com/b.git", &[]).is_err());
+
+    let remotes = remote::list_remotes(Some(&repo)).unwrap();
+    assert_eq!(remotes.len(), 1);
+    assert_eq!(remotes[0].urls, vec!["https://example.com/a.git"]);
+}
+
+#[test]
+fn set_url_replaces_or_adds_urls() {
+    let path = common::TestPath::new();
+    let repo = setup_repo(&path);
+    remote::add_remote(Some(&repo), "origin", "https://example.com/a.git", &[]).unwrap();
+
+    remote::set_url(
+        Some(&repo),
+        "origin",
+        "https://example.com/b.git",
+        false,
+        false,
+    )
+    .unwrap();
+    remote::set_url(
+        Some(&repo),
+        "origin",
+        "ssh://example.com/b.git",
+        true,
+        false,
+    )
+    .unwrap();
+    remote::set_url(Some(&repo), "origin", "ssh://mirror.com/b.git", true, true).unwrap();
+
+    let origin = remote::get_remote(Some(&repo), "origin").unwrap();
+    assert_eq!(origin.urls, vec!["https://example.com/b.git"]);
+    assert_eq!(
+        origin.effective_push_urls(),
+        &[
+            "ssh://example.com/b.git".to_string(),
+            "ssh://mirror.com/b.git".to_string()
+        ]
+    );
+
+    assert!(remote::set_url(Some(&repo), "missing", "https://example.com", false, false).is_err());
+}
+
+#[test]
+fn rename_remote_moves_refs_refspecs_and_upstreams() {
+    let path = common::TestPath::new();
+    let (repo, hash) = setup_cloned_repo(&path);
+
+    remote::rename_remote(Some(&repo), "origin", "upstream").unwrap();
+
+    let upstream = remote::get_remote(Some(&repo), "upstream").unwrap();
+    assert_eq!(upstream.urls, vec!["https://example.com/repo.git"]);
+    assert_eq!(upstream.fetch, vec![Refspec::default_fetch("upstream")]);
+    assert!(remote::get_remote(Some(&repo), "origin").is_err());
+
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(config.get("branch.main.remote"), Some("upstream"));
+    assert_eq!(config.get("branch.main.merge"), Some("refs/heads/main"));
+
+    assert!(refs::list_refs(Some(&repo), "refs/remotes/origin/")
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        refs::list_refs(Some(&repo), "refs/remotes/upstream/").unwrap(),
+        vec![
+            ("refs/remotes/upstream/feature".to_string(), hash.clone()),
+            ("refs/remotes/upstream/main".to_string(), hash.clone()),
+        ]
+    );
+    assert_eq!(
+        refs::read_symbolic_ref(Some(&repo), "refs/remotes/upstream/HEAD").unwrap(),
+        Some("refs/remotes/upstream/main".to_string())
+    );
+    assert!(!refs::ref_path(Some(&repo), "refs/remotes/origin").exists());
+}
+
+#[test]
+fn remove_remote_deletes_refs_and_upstreams() {
+    let path = common::TestPath::new();
+    let (repo, _) = setup_cloned_repo(&path);
+
+    remote::remove_remote(Some(&repo), "origin").unwrap();
+
+    assert!(remote::list_remotes(Some(&repo)).unwrap().is_empty());
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(config.get("branch.main.remote"), None);
+    assert_eq!(config.get("branch.main.merge"), None);
+    assert!(!refs::ref_path(Some(&repo), "refs/remotes/origin").exists());
+
+    assert!(remote::remove_remote(Some(&repo), "origin").is_err());
+}
+
+#[test]
+fn show_remote_reports_branches_and_upstreams() {
+    let path = common::TestPath::new();
+    let (repo, _) = setup_cloned_repo(&path);
+
+    let details = remote::show_remote(Some(&repo), "origin").unwrap();
+
+    assert_eq!(details.remote.name, "origin");
+    assert_eq!(details.head_branch, Some("main".to_string()));
+    assert_eq!(details.tracking_branches, vec!["feature", "main"]);
+    assert_eq!(
+        details.upstreams,
+        vec![("main".to_string(), "main".to_string())]
+    );
+}
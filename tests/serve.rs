@@ -0,0 +1,251 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use not_git::config::Config;
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::http_server::HttpServer;
+use not_git::local::LocalRepository;
+use not_git::objects::{ObjectFile, ObjectHash};
+use not_git::push::{self, PushConfig, PushStatus};
+use not_git::{clone, pkt_line, refs, server};
+
+mod common;
+
+fn init_repo_with_commit(
+    path: &common::TestPath,
+    url: &str,
+) -> (PathBuf, common::TestRemoteRepository) {
+    let repo = path.join(&"repo");
+    common::init_with_origin(&repo, url);
+
+    let commit = common::TestRemoteRepository::new(&[("b.txt", b"b")], None, "Other commit");
+    commit.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &commit.commit_hash).unwrap();
+    (repo, commit)
+}
+
+#[test]
+fn clone_and_fetch_over_smart_http() {
+    let path = common::TestPath::new();
+    let (served, first) = common::create_served_repo(&path);
+    let url = format!("{}/served", common::start_server(&path.0));
+
+    let config = clone::CloneConfig::new(url.clone(), Some("repo"));
+    let (head_ref, objects) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 3);
+    let repo = path.join(&"repo");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+
+    // Only the new commit and what it changed are sent back.
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("c.txt", b"c")],
+        Some(&first.commit_hash),
+        "Second commit",
+    );
+    second.write_to(&served);
+    refs::write_ref(Some(&served), "refs/heads/main", &second.commit_hash).unwrap();
+
+    let result = fetch::fetch(Some(&repo), FetchConfig::new("origin", vec![], false)).unwrap();
+
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::FastForward);
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(second.commit_hash.clone())
+    );
+}
+
+#[test]
+fn push_over_smart_http_updates_served_repo() {
+    let path = common::TestPath::new();
+    let (served, _) = common::create_served_repo(&path);
+    let mut config = Config::load(Some(&served)).unwrap();
+    config.set("http.receivepack", "true").unwrap();
+    config.save().unwrap();
+    let url = format!("{}/served", common::start_server(&path.0));
+    let (repo, commit) = init_repo_with_commit(&path, &url);
+
+    let refspecs = vec!["main:refs/heads/dev".parse().unwrap()];
+    let config = PushConfig::new("origin", refspecs, false, vec![]);
+    let result = push::push(Some(&repo), config).unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::New);
+    assert_eq!(
+        refs::read_ref(Some(&served), "refs/heads/dev").unwrap(),
+        Some(commit.commit_hash.clone())
+    );
+    assert!(ObjectFile::exists(Some(&served), &commit.tree_hash));
+}
+
+#[test]
+fn push_over_smart_http_must_be_enabled() {
+    let path = common::TestPath::new();
+    let (served, _) = common::create_served_repo(&path);
+    let url = format!("{}/served", common::start_server(&path.0));
+    let (repo, _) = init_repo_with_commit(&path, &url);
+
+    let refspecs = vec!["main:refs/heads/dev".parse().unwrap()];
+    let config = PushConfig::new("origin", refspecs, false, vec![]);
+    let got = push::push(Some(&repo), config).unwrap_err();
+
+    assert!(format!("{:#}", got).contains("403"));
+    assert_eq!(
+        refs::read_ref(Some(&served), "refs/heads/dev").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn upload_pack_only_sends_what_refs_reach() {
+    let path = common::TestPath::new();
+    let (served, first) = common::create_served_repo(&path);
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"changed")],
+        Some(&first.commit_hash),
+        "Second commit",
+    );
+    second.write_to(&served);
+    refs::write_ref(Some(&served), "refs/heads/main", &second.commit_hash).unwrap();
+    let unreferenced = common::TestRemoteRepository::new(&[("secret.txt", b"s")], None, "Secret");
+    unreferenced.write_to(&served);
+
+    let upload_pack = |want: &ObjectHash| {
+        let mut input = pkt_line::encode_text(&format!("want {}", want.full_hash()));
+        input.extend(pkt_line::FLUSH_PKT);
+        input.extend(pkt_line::encode_text("done"));
+        let mut repository = LocalRepository::open(&served).unwrap();
+        let mut output = vec![];
+        let result = server::upload_pack(&mut repository, &mut input.as_slice(), &mut output, true);
+        (result, output)
+    };
+
+    // The commit the ref has moved on from can still be fetched.
+    let (result, output) = upload_pack(&first.commit_hash);
+    result.unwrap();
+    assert!(output.windows(4).any(|window| window == b"PACK"));
+
+    let (result, output) = upload_pack(&unreferenced.commit_hash);
+    assert!(result.is_err());
+    let expected = format!(
+        "ERR upload-pack: not our ref {}",
+        unreferenced.commit_hash.full_hash()
+    );
+    assert_eq!(output, pkt_line::encode_text(&expected));
+}
+
+#[test]
+fn receive_pack_refuses_tips_with_missing_objects() {
+    let path = common::TestPath::new();
+    let (served, first) = common::create_served_repo(&path);
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second commit",
+    );
+
+    // The pack has the commit but not its tree or the new blob.
+    let command = format!(
+        "{} {} refs/heads/dev\0report-status",
+        "0".repeat(40),
+        second.commit_hash.full_hash()
+    );
+    let mut input = pkt_line::encode_text(&command);
+    input.extend(pkt_line::FLUSH_PKT);
+    input.extend(common::create_pack(&second.objects[..1]));
+    let mut repository = LocalRepository::open(&served).unwrap();
+    let mut output = vec![];
+    server::receive_pack(&mut repository, &mut input.as_slice(), &mut output).unwrap();
+
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.contains("ng refs/heads/dev missing necessary objects"));
+    assert_eq!(
+        refs::read_ref(Some(&served), "refs/heads/dev").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn requests_over_the_size_limit_are_dropped() {
+    let path = common::TestPath::new();
+    common::create_served_repo(&path);
+    let url = common::start_server(&path.0);
+    let address = url.strip_prefix("http://").unwrap();
+
+    let requests = [
+        "Content-Length: 1000000000000000\r\n\r\n",
+        "Transfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n",
+        "Transfer-Encoding: chunked\r\n\r\n1\r\na\r\nfffffffffffffff\r\n",
+    ];
+    for headers in requests {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let request = format!("POST /served/git-upload-pack HTTP/1.1\r\n{}", headers);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+    }
+
+    // The server is still up.
+    let config = clone::CloneConfig::new(format!("{}/served", url), Some("repo"));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+}
+
+#[test]
+fn connections_that_stop_sending_are_dropped() {
+    let path = common::TestPath::new();
+    common::create_served_repo(&path);
+    let server = HttpServer::bind("127.0.0.1:0", &path.0)
+        .unwrap()
+        .with_read_timeout(Duration::from_millis(100));
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"POST /served/git-upload-pack HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+        .unwrap();
+    // Long enough for the test to fail rather than hang if the server keeps waiting.
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.is_empty());
+}
+
+#[test]
+fn clone_and_push_over_ext_run_the_services() {
+    let path = common::TestPath::new();
+    let (served, _) = common::create_served_repo(&path);
+    let binary = env!("CARGO_BIN_EXE_not-git");
+
+    let url = format!("ext::{} %s {}", binary, served.display());
+    let config =
+        clone::CloneConfig::new(url, Some("repo")).with_config("protocol.ext.allow", "always");
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let repo = path.join(&"repo");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+
+    let second = common::TestRemoteRepository::new(&[("b.txt", b"b")], None, "Other commit");
+    second.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &second.commit_hash).unwrap();
+
+    let refspecs = vec!["main:refs/heads/dev".parse().unwrap()];
+    let config = PushConfig::new("origin", refspecs, false, vec![]);
+    let result = push::push(Some(&repo), config).unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::New);
+    assert_eq!(
+        refs::read_ref(Some(&served), "refs/heads/dev").unwrap(),
+        Some(second.commit_hash.clone())
+    );
+}
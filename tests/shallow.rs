@@ -0,0 +1,202 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use not_git::clone;
+use not_git::fetch::{self, FetchConfig};
+use not_git::objects::ObjectHash;
+use not_git::protocol::Deepen;
+use not_git::{history, init, refs, shallow};
+
+mod common;
+use common::server::{self, TestResponse, TestServer};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+const RESULT: &str = "application/x-git-upload-pack-result";
+
+/// Two commits on `main`, the second on top of the first.
+fn test_history() -> (common::TestRemoteRepository, common::TestRemoteRepository) {
+    let first = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "First");
+    let second = common::TestRemoteRepository::new(
+        &[("a.txt", b"a"), ("b.txt", b"b")],
+        Some(&first.commit_hash),
+        "Second",
+    );
+    (first, second)
+}
+
+/// A protocol v2 server with `main` at the commit that answers every fetch with the
+/// shallow-info and pack. The bodies of the fetch requests are recorded.
+fn start_shallow_server(
+    main: &ObjectHash,
+    shallow: Vec<ObjectHash>,
+    unshallow: Vec<ObjectHash>,
+    pack: Vec<u8>,
+    fetch_requests: Arc<Mutex<Vec<String>>>,
+) -> TestServer {
+    let main = main.clone();
+    TestServer::start(move |request| {
+        if request.method == "GET" {
+            return TestResponse::new(
+                200,
+                ADVERTISEMENT,
+                server::v2_advertisement(&["ls-refs", "fetch=shallow"]),
+            );
+        }
+
+        let body = String::from_utf8_lossy(&request.body).to_string();
+        if body.contains("command=ls-refs") {
+            return TestResponse::new(
+                200,
+                RESULT,
+                server::v2_ls_refs_response(&[("refs/heads/main", &main)]),
+            );
+        }
+
+        fetch_requests.lock().unwrap().push(body);
+        let shallow: Vec<&ObjectHash> = shallow.iter().collect();
+        let unshallow: Vec<&ObjectHash> = unshallow.iter().collect();
+        TestResponse::new(
+            200,
+            RESULT,
+            server::v2_shallow_fetch_response(&shallow, &unshallow, &pack),
+        )
+    })
+}
+
+fn setup_repo(path: &common::TestPath, url: &str) -> PathBuf {
+    let repo = path.join(&"repo");
+    common::init_with_origin(&repo, url);
+    repo
+}
+
+#[test]
+fn clone_with_depth_records_shallow_commits() {
+    let path = common::TestPath::new();
+    let (_, second) = test_history();
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = start_shallow_server(
+        &second.commit_hash,
+        vec![second.commit_hash.clone()],
+        vec![],
+        second.pack(),
+        fetch_requests.clone(),
+    );
+
+    let config =
+        clone::CloneConfig::new(server.url.clone(), Some("repo")).with_deepen(Deepen::Depth(1));
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert!(fetch_requests[0].contains("deepen 1"));
+
+    let repo = path.join(&"repo");
+    assert_eq!(
+        fs::read_to_string(repo.join("not-git/shallow")).unwrap(),
+        format!("{}\n", second.commit_hash.full_hash())
+    );
+    assert_eq!(fs::read(repo.join("b.txt")).unwrap(), b"b");
+
+    let commits =
+        history::walk_commits(Some(&repo), std::slice::from_ref(&second.commit_hash), None)
+            .unwrap();
+    assert_eq!(commits, vec![second.commit_hash.clone()]);
+}
+
+#[test]
+fn history_treats_shallow_commits_as_roots() {
+    let path = common::TestPath::new();
+    let (first, second) = test_history();
+    let repo = path.join(&"repo");
+    init::create_directories(init::InitConfig::new("main", Some(&repo))).unwrap();
+    first.write_to(&repo);
+    second.write_to(&repo);
+
+    let shallow_commits = [second.commit_hash.clone()].into_iter().collect();
+    shallow::write_shallow(Some(&repo), &shallow_commits).unwrap();
+
+    let commits =
+        history::walk_commits(Some(&repo), std::slice::from_ref(&second.commit_hash), None)
+            .unwrap();
+    assert_eq!(commits, vec![second.commit_hash.clone()]);
+    assert!(!history::is_ancestor(Some(&repo), &first.commit_hash, &second.commit_hash).unwrap());
+
+    let objects =
+        history::list_objects(Some(&repo), std::slice::from_ref(&second.commit_hash), &[]).unwrap();
+    assert!(!objects.contains(&first.commit_hash));
+}
+
+#[test]
+fn fetch_unshallow_completes_history() {
+    let path = common::TestPath::new();
+    let (first, second) = test_history();
+
+    let fetch_requests = Arc::new(Mutex::new(vec![]));
+    let server = start_shallow_server(
+        &second.commit_hash,
+        vec![],
+        vec![second.commit_hash.clone()],
+        first.pack(),
+        fetch_requests.clone(),
+    );
+    let repo = setup_repo(&path, &server.url);
+    second.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/remotes/origin/main", &second.commit_hash).unwrap();
+    let shallow_commits = [second.commit_hash.clone()].into_iter().collect();
+    shallow::write_shallow(Some(&repo), &shallow_commits).unwrap();
+
+    let config = FetchConfig::new("origin", vec![], false).with_deepen(Deepen::Unshallow);
+    fetch::fetch(Some(&repo), config).unwrap();
+
+    let fetch_requests = fetch_requests.lock().unwrap();
+    assert!(fetch_requests[0].contains(&format!("want {}", second.commit_hash.full_hash())));
+    assert!(fetch_requests[0].contains(&format!("shallow {}", second.commit_hash.full_hash())));
+    assert!(fetch_requests[0].contains("deepen 2147483647"));
+
+    assert!(!shallow::is_shallow(Some(&repo)).unwrap());
+    assert!(!repo.join("not-git/shallow").exists());
+    let commits =
+        history::walk_commits(Some(&repo), std::slice::from_ref(&second.commit_hash), None)
+            .unwrap();
+    assert_eq!(
+        commits,
+        vec![second.commit_hash.clone(), first.commit_hash.clone()]
+    );
+}
+
+#[test]
+fn fetch_unshallow_errors_on_complete_repository() {
+    let path = common::TestPath::new();
+    let (_, second) = test_history();
+
+    let server = server::upload_pack_server(
+        vec![("refs/heads/main", second.commit_hash.clone())],
+        second.pack(),
+        Arc::new(Mutex::new(vec![])),
+    );
+    let repo = setup_repo(&path, &server.url);
+
+    let config = FetchConfig::new("origin", vec![], false).with_deepen(Deepen::Unshallow);
+    assert!(fetch::fetch(Some(&repo), config).is_err());
+}
+
+#[test]
+fn shallow_clone_errors_when_server_does_not_support_it() {
+    let path = common::TestPath::new();
+    let (_, second) = test_history();
+
+    let commit_hash = second.commit_hash.clone();
+    let server = TestServer::start(move |request| {
+        assert_eq!(request.method, "GET");
+        let refs = [("refs/heads/main", &commit_hash)];
+        TestResponse::new(200, ADVERTISEMENT, server::v0_advertisement(&refs, &[]))
+    });
+
+    let config = clone::CloneConfig::new(server.url.clone(), Some("repo"))
+        .with_deepen(Deepen::Since(1700000000));
+    let got = clone::perform_clone(path.to_optional_path(), config);
+
+    assert!(got.is_err());
+    assert!(!path.join(&"repo").exists());
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use not_git::config::Config;
+use not_git::push::{self, PushConfig, PushStatus};
+use not_git::{clone, init, refs};
+
+mod common;
+use common::server;
+
+const CAPABILITIES: &[&str] = &["report-status", "delete-refs", "agent=git/2.45.0"];
+
+/// Stands in for ssh or an `ext::` command: it records its arguments and what it was sent,
+/// and answers with the canned advertisement and response in the directory.
+fn write_stand_in(dir: &PathBuf, advertisement: &[u8], response: &[u8]) -> PathBuf {
+    fs::create_dir_all(dir).unwrap();
+    let dir = fs::canonicalize(dir).unwrap();
+    fs::write(dir.join("advertisement"), advertisement).unwrap();
+    fs::write(dir.join("response"), response).unwrap();
+
+    let script = dir.join("stand-in.sh");
+    let d = dir.display();
+    fs::write(
+        &script,
+        format!(
+            "printf '%s\\n' \"$@\" > {d}/args\ncat {d}/advertisement\ncat > {d}/request\ncat {d}/response\n"
+        ),
+    )
+    .unwrap();
+    script
+}
+
+/// Over ssh, the advertisement comes without the `# service=` line and flush HTTP adds.
+fn without_service_line(advertisement: Vec<u8>) -> Vec<u8> {
+    let end = advertisement.windows(4).position(|w| w == b"0000").unwrap();
+    advertisement[end + 4..].to_vec()
+}
+
+fn read_args(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join("args"))
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn clone_over_ssh_runs_upload_pack_with_ssh_command() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    let dir = path.join(&"ssh");
+    let script = write_stand_in(
+        &dir,
+        &without_service_line(server::v0_advertisement(
+            &[("refs/heads/main", &remote.commit_hash)],
+            &[],
+        )),
+        &server::v0_upload_pack_response(&remote.pack()),
+    );
+
+    let url = "git@example.com:org/repo.git".to_string();
+    let config = clone::CloneConfig::new(url.clone(), Some("repo"))
+        .with_config("core.sshCommand", &format!("sh {}", script.display()));
+    let (head_ref, _) = clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(
+        read_args(&dir),
+        vec!["git@example.com", "git-upload-pack 'org/repo.git'"]
+    );
+    assert!(String::from_utf8(fs::read(dir.join("request")).unwrap())
+        .unwrap()
+        .contains(&format!("want {}", remote.commit_hash.full_hash())));
+
+    let repo = path.join(&"repo");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+    let config = Config::load(Some(&repo)).unwrap();
+    assert_eq!(config.get("remote.origin.url"), Some(url.as_str()));
+}
+
+#[test]
+fn clone_over_ext_url_speaks_protocol_v2() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    let mut output = server::v2_advertisement(&["ls-refs", "fetch=shallow"]);
+    output.extend(server::v2_ls_refs_response(&[(
+        "refs/heads/main",
+        &remote.commit_hash,
+    )]));
+    output.extend(server::v2_fetch_response(&remote.pack()));
+    let dir = path.join(&"ext");
+    let script = write_stand_in(&dir, &output, &[]);
+
+    let url = format!("ext::sh {} %S org/repo", script.display());
+    let config =
+        clone::CloneConfig::new(url, Some("repo")).with_config("protocol.ext.allow", "always");
+    clone::perform_clone(path.to_optional_path(), config).unwrap();
+
+    assert_eq!(read_args(&dir), vec!["git-upload-pack", "org/repo"]);
+    let repo = path.join(&"repo");
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+}
+
+#[test]
+fn clone_over_ext_url_must_be_allowed() {
+    let path = common::TestPath::new();
+    let url = "ext::sh -c false".to_string();
+    let config = clone::CloneConfig::new(url, Some("repo"));
+    let got = clone::perform_clone(path.to_optional_path(), config).unwrap_err();
+
+    assert!(got.to_string().contains("Transport 'ext' not allowed"));
+}
+
+#[test]
+fn push_over_ssh_sends_commands_and_pack() {
+    let path = common::TestPath::new();
+    let local = path.join(&"local");
+    init::create_directories(init::InitConfig::new("main", Some(&local))).unwrap();
+    let commit = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    commit.write_to(&local);
+    refs::write_ref(Some(&local), "refs/heads/main", &commit.commit_hash).unwrap();
+
+    let dir = path.join(&"ssh");
+    let script = write_stand_in(
+        &dir,
+        &without_service_line(server::receive_pack_advertisement(&[], CAPABILITIES)),
+        &server::report_status(&[("refs/heads/main", None)]),
+    );
+
+    let mut config = Config::load(Some(&local)).unwrap();
+    config
+        .set(
+            "remote.origin.url",
+            "ssh://git@example.com:2222/org/repo.git",
+        )
+        .unwrap();
+    config
+        .set("core.sshCommand", &format!("sh {}", script.display()))
+        .unwrap();
+    config.save().unwrap();
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let result = push::push(
+        Some(&local),
+        PushConfig::new("origin", refspecs, false, vec![]),
+    )
+    .unwrap();
+
+    assert_eq!(result.updates[0].status, PushStatus::New);
+    assert_eq!(result.objects, 3);
+    assert_eq!(
+        read_args(&dir),
+        vec![
+            "-p",
+            "2222",
+            "git@example.com",
+            "git-receive-pack '/org/repo.git'"
+        ]
+    );
+
+    let request = fs::read(dir.join("request")).unwrap();
+    let (commands, _, pack) = server::parse_receive_pack_request(&request);
+    assert_eq!(commands.len(), 1);
+    assert_eq!(commands[0].1, commit.commit_hash.full_hash());
+    assert_eq!(commands[0].2, "refs/heads/main");
+    assert!(!pack.is_empty());
+}
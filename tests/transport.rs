@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::PathBuf;
+
+use not_git::fetch::{self, FetchConfig, RefUpdateStatus};
+use not_git::http::UPLOAD_PACK;
+use not_git::objects::ObjectFile;
+use not_git::push::{self, PushConfig, PushStatus, RECEIVE_PACK};
+use not_git::transport::ScriptedTransport;
+use not_git::{clone, init, refs};
+
+mod common;
+use common::server;
+
+const CAPABILITIES: &[&str] = &["report-status", "delete-refs", "agent=git/2.45.0"];
+
+fn init_repo(path: &common::TestPath, name: &str, url: &str) -> PathBuf {
+    let repo = path.join(&name);
+    common::init_with_origin(&repo, url);
+    repo
+}
+
+fn request_text(transport: &ScriptedTransport, index: usize) -> String {
+    String::from_utf8_lossy(&transport.requests[index]).to_string()
+}
+
+#[test]
+fn clone_replays_protocol_v2_conversation() {
+    let path = common::TestPath::new();
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    let refs = [("refs/heads/main", &remote.commit_hash)];
+    let mut transport = ScriptedTransport::new(
+        UPLOAD_PACK,
+        &server::v2_advertisement(&["ls-refs", "fetch"]),
+        vec![
+            server::v2_ls_refs_response(&refs),
+            server::v2_fetch_response(&remote.pack()),
+        ],
+    )
+    .unwrap();
+
+    let repo = path.join(&"repo");
+    fs::create_dir_all(&repo).unwrap();
+    let config = clone::CloneConfig::new("https://example.com/repo.git".to_string(), None);
+    let (head_ref, objects) = clone::clone_from(&repo, config, &mut transport).unwrap();
+
+    assert_eq!(head_ref.branch, "refs/heads/main");
+    assert_eq!(objects, 3);
+    assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "a");
+
+    assert_eq!(transport.requests.len(), 2);
+    assert!(request_text(&transport, 0).contains("command=ls-refs"));
+    let fetch_request = request_text(&transport, 1);
+    assert!(fetch_request.contains("command=fetch"));
+    assert!(fetch_request.contains(&format!("want {}", remote.commit_hash.full_hash())));
+}
+
+#[test]
+fn fetch_replays_protocol_v0_conversation() {
+    let path = common::TestPath::new();
+    let repo = init_repo(&path, "repo", "https://example.com/repo.git");
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    let mut transport = ScriptedTransport::new(
+        UPLOAD_PACK,
+        &server::v0_advertisement(&[("refs/heads/main", &remote.commit_hash)], &[]),
+        vec![server::v0_upload_pack_response(&remote.pack())],
+    )
+    .unwrap();
+
+    let config = FetchConfig::new("origin", vec![], false);
+    let result = fetch::fetch_from(Some(&repo), config, &mut transport).unwrap();
+
+    assert_eq!(result.url, "https://example.com/repo.git");
+    assert_eq!(result.objects, 3);
+    assert_eq!(result.updates.len(), 1);
+    assert_eq!(result.updates[0].status, RefUpdateStatus::New);
+    assert_eq!(
+        refs::read_ref(Some(&repo), "refs/remotes/origin/main").unwrap(),
+        Some(remote.commit_hash.clone())
+    );
+}
+
+#[test]
+fn fetch_fails_when_script_runs_out() {
+    let path = common::TestPath::new();
+    let repo = init_repo(&path, "repo", "https://example.com/repo.git");
+    let remote = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    let mut transport = ScriptedTransport::new(
+        UPLOAD_PACK,
+        &server::v0_advertisement(&[("refs/heads/main", &remote.commit_hash)], &[]),
+        vec![],
+    )
+    .unwrap();
+
+    let config = FetchConfig::new("origin", vec![], false);
+    let got = fetch::fetch_from(Some(&repo), config, &mut transport).unwrap_err();
+
+    assert!(format!("{:#}", got).contains("The script has no response to request 1"));
+}
+
+#[test]
+fn push_replays_rejected_update() {
+    let path = common::TestPath::new();
+    let repo = init_repo(&path, "repo", "https://example.com/repo.git");
+    let commit = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    commit.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &commit.commit_hash).unwrap();
+
+    let mut transport = ScriptedTransport::new(
+        RECEIVE_PACK,
+        &server::receive_pack_advertisement(&[], CAPABILITIES),
+        vec![server::report_status(&[(
+            "refs/heads/main",
+            Some("hook declined"),
+        )])],
+    )
+    .unwrap();
+
+    let refspecs = vec!["main".parse().unwrap()];
+    let config = PushConfig::new("origin", refspecs, false, vec![]);
+    let result = push::push_to(Some(&repo), config, &mut transport).unwrap();
+
+    assert_eq!(
+        result.updates[0].status,
+        PushStatus::RemoteRejected("hook declined".to_string())
+    );
+    let (commands, _, pack) = server::parse_receive_pack_request(&transport.requests[0]);
+    assert_eq!(commands[0].2, "refs/heads/main");
+    assert!(!pack.is_empty());
+}
+
+#[test]
+fn push_to_local_repository_updates_refs() {
+    let path = common::TestPath::new();
+    let remote = path.join(&"remote");
+    init::create_directories(init::InitConfig::new("main", Some(&remote))).unwrap();
+    let repo = init_repo(&path, "repo", remote.to_str().unwrap());
+
+    let commit = common::TestRemoteRepository::new(&[("a.txt", b"a")], None, "Initial commit");
+    commit.write_to(&repo);
+    refs::write_ref(Some(&repo), "refs/heads/main", &commit.commit_hash).unwrap();
+
+    // The branch checked out in the remote is left alone.
+    let refspecs = vec![
+        "main".parse().unwrap(),
+        "main:refs/heads/dev".parse().unwrap(),
+    ];
+    let config = PushConfig::new("origin", refspecs, false, vec![]);
+    let result = push::push(Some(&repo), config).unwrap();
+
+    assert_eq!(
+        result.updates[0].status,
+        PushStatus::RemoteRejected("branch is currently checked out".to_string())
+    );
+    assert_eq!(result.updates[1].status, PushStatus::New);
+    assert_eq!(
+        refs::read_ref(Some(&remote), "refs/heads/main").unwrap(),
+        None
+    );
+    assert_eq!(
+        refs::read_ref(Some(&remote), "refs/heads/dev").unwrap(),
+        Some(commit.commit_hash.clone())
+    );
+    assert!(ObjectFile::exists(Some(&remote), &commit.tree_hash));
+}